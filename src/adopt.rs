@@ -0,0 +1,215 @@
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+
+use crate::config::Config;
+use crate::install::{check_path_component, generate_desktop_file, load_database, update_database, InstalledPackage};
+use crate::repo::cache::load_unified_index;
+use crate::utils::{extract_metadata, AppImageMetadata};
+use crate::verify::calculate_sha256;
+use crate::yaml_gen::extract_version_from_filename;
+
+/// Checks the AppImage magic bytes ("AI" followed by the type) at offset 8.
+async fn is_appimage(path: &Path) -> bool {
+    if path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("appimage"))
+    {
+        return true;
+    }
+
+    let mut header = [0u8; 11];
+    match fs::File::open(path).await {
+        Ok(mut file) => {
+            file.read_exact(&mut header).await.is_ok()
+                && &header[..4] == b"\x7fELF"
+                && &header[8..10] == b"AI"
+                && matches!(header[10], 1 | 2)
+        }
+        Err(_) => false,
+    }
+}
+
+async fn find_appimages(path: &Path) -> Result<Vec<PathBuf>> {
+    if path.is_file() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut found = Vec::new();
+    let mut dir = fs::read_dir(path).await
+        .context(format!("Failed to read directory: {}", path.display()))?;
+    while let Some(entry) = dir.next_entry().await? {
+        let entry_path = entry.path();
+        // Skip symlinks, such as the ones aipkg itself creates in bin_dir
+        let file_type = entry.file_type().await?;
+        if file_type.is_file() && is_appimage(&entry_path).await {
+            found.push(entry_path);
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Moves a file, falling back to copy and remove across filesystems.
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).await.is_err() {
+        fs::copy(from, to).await
+            .context(format!("Failed to copy {} to {}", from.display(), to.display()))?;
+        fs::remove_file(from).await?;
+    }
+    Ok(())
+}
+
+/// Whether the symlink at `link` points into appimages_dir, as the symlinks
+/// aipkg creates do; anything else belongs to someone else.
+async fn is_own_symlink(config: &Config, link: &Path) -> bool {
+    fs::read_link(link).await
+        .is_ok_and(|target| target.starts_with(config.runtime_path(&config.appimages_dir)))
+}
+
+/// Checksum and embedded metadata of an AppImage found on disk.
+async fn read_appimage(path: &str) -> Result<(String, AppImageMetadata)> {
+    Ok((calculate_sha256(path).await?, extract_metadata(path).await?))
+}
+
+pub async fn adopt(path: &str, in_place: bool) -> Result<()> {
+    adopt_into(&Config::new()?, path, in_place).await
+}
+
+/// Registers the AppImages at `path`, a file or a directory, with the
+/// installation `config` describes.
+pub async fn adopt_into(config: &Config, path: &str, in_place: bool) -> Result<()> {
+    config.ensure_directories().await?;
+
+    let path = Path::new(path);
+    if !path.exists() {
+        anyhow::bail!("Path not found: {}", path.display());
+    }
+
+    let appimages = find_appimages(path).await?;
+    if appimages.is_empty() {
+        println!("No AppImages found in {}", path.display());
+        return Ok(());
+    }
+
+    // The index is optional, without it we rely on embedded metadata only
+    let index = load_unified_index().await.ok();
+
+    let mut adopted = 0;
+    for appimage in appimages {
+        let db = load_database(config).await?;
+        let appimage = fs::canonicalize(&appimage).await?;
+        let appimage_str = appimage.to_string_lossy().to_string();
+
+        if db.list_packages().iter().any(|pkg| pkg.path == appimage) {
+            println!("Skipping {}: already managed by aipkg", appimage.display());
+            continue;
+        }
+
+        // One unreadable file does not stop the others from being adopted
+        let (sha256, metadata) = match read_appimage(&appimage_str).await {
+            Ok(read) => read,
+            Err(e) => {
+                println!("Warning: skipping {}: {:#}", appimage.display(), e);
+                continue;
+            }
+        };
+
+        // Prefer the identity from the index when the checksum matches a known release
+        let (name, version) = match index.as_ref().and_then(|i| i.find_by_sha256(&sha256)) {
+            Some(entry) => {
+                println!("Identified {} as {} {}", appimage.display(), entry.entry.name, entry.entry.version);
+                (entry.entry.name.clone(), entry.entry.version.clone())
+            }
+            None => {
+                let file_name = appimage.file_name()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or("unknown");
+                let version = metadata.version.clone()
+                    .unwrap_or_else(|| extract_version_from_filename(file_name));
+                (metadata.name.clone(), version)
+            }
+        };
+
+        if let Err(e) = check_path_component("package name", &name)
+            .and_then(|_| check_path_component("version", &version))
+        {
+            println!("Warning: skipping {}: {:#}", appimage.display(), e);
+            continue;
+        }
+
+        if db.get_package(&name).is_some() {
+            println!("Skipping {}: {} is already installed", appimage.display(), name);
+            continue;
+        }
+
+        let (target_path, adopted_from) = if in_place {
+            (appimage.clone(), None)
+        } else {
+            let install_dir = config.appimages_dir.join(&name).join(&version);
+            fs::create_dir_all(&install_dir).await?;
+            let target_path = install_dir.join(format!("{}.AppImage", name));
+            move_file(&appimage, &target_path).await?;
+            (target_path, Some(appimage.clone()))
+        };
+
+        // Make executable
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mut perms = fs::metadata(&target_path).await?.permissions();
+            perms.set_mode(0o755);
+            fs::set_permissions(&target_path, perms).await?;
+        }
+
+        let mut metadata = metadata;
+        metadata.name = name.clone();
+        let desktop_file = generate_desktop_file(config, &metadata, &target_path, &version).await?;
+
+        // Create symlink, unless the AppImage already lives there or a file of someone else is
+        // in the way. Only a symlink created here is recorded, so removing the package leaves
+        // other files alone
+        let mut symlink_path = config.bin_dir.join(&name);
+        if symlink_path == target_path {
+            symlink_path = PathBuf::new();
+        } else {
+            match fs::symlink_metadata(&symlink_path).await {
+                Ok(existing) if !existing.file_type().is_symlink() || !is_own_symlink(config, &symlink_path).await => {
+                    println!("Warning: {} exists, not creating symlink", symlink_path.display());
+                    symlink_path = PathBuf::new();
+                }
+                Ok(_) => {
+                    fs::remove_file(&symlink_path).await?;
+                    fs::symlink(config.runtime_path(&target_path), &symlink_path).await?;
+                }
+                Err(_) => {
+                    fs::symlink(config.runtime_path(&target_path), &symlink_path).await?;
+                }
+            }
+        }
+
+        update_database(config, InstalledPackage {
+            name: name.clone(),
+            version: version.clone(),
+            path: target_path.clone(),
+            desktop_file,
+            symlink: symlink_path,
+            installed_at: chrono::Utc::now().to_rfc3339(),
+            sha256: Some(sha256),
+            origin_url: None,
+            origin_validators: Default::default(),
+            source_url: None,
+            adopted_from,
+        }).await?;
+        if let Err(e) = crate::mime::register(config, &name, &target_path).await {
+            println!("Warning: failed to register file types of {}: {:#}", name, e);
+        }
+
+        println!("Adopted {} {} ({})", name, version, target_path.display());
+        adopted += 1;
+    }
+
+    println!("Adopted {} package(s)", adopted);
+    Ok(())
+}
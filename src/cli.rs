@@ -1,12 +1,25 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 use crate::install;
 use crate::repo;
 use crate::upgrade;
 use crate::uninstall;
 use crate::query;
+use crate::pkg_cache;
 use crate::yaml_gen;
+use crate::verify;
+use crate::doctor;
+use crate::adopt;
+use crate::config;
+use crate::config_edit;
+use crate::history;
+use crate::lockfile;
+use crate::manifest;
+use crate::mime;
+use crate::run;
+use crate::repo::throttle::parse_rate;
 
 #[derive(Parser)]
 #[command(name = "aipkg")]
@@ -14,15 +27,44 @@ use crate::yaml_gen;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    /// Manage the system-wide installation in /opt/aipkg and /usr/local (needs root)
+    #[arg(long, global = true)]
+    pub system: bool,
+    /// Prefix every path with this directory, e.g. to install into a chroot (overrides AIPKG_ROOT)
+    #[arg(long, global = true, value_name = "DIR")]
+    pub root: Option<PathBuf>,
+    /// Load this config file instead of config.toml in the config directory
+    #[arg(long, global = true, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Limit the total download rate, e.g. 500K or 2M (bytes per second)
+    #[arg(long, global = true, value_parser = parse_rate)]
+    pub max_download_rate: Option<u64>,
+    /// Number of AppImages downloaded at the same time
+    #[arg(long, global = true)]
+    pub max_parallel_downloads: Option<usize>,
+    /// Accept expired or rolled-back repository metadata
+    #[arg(long, global = true)]
+    pub allow_stale: bool,
 }
 
 #[derive(Subcommand)]
 pub enum Commands {
-    /// Install an AppImage from local file
+    /// Install an AppImage from local file or URL
     #[command(alias = "-i")]
     Install {
-        /// Path to AppImage file
+        /// Path to AppImage file, AppImage URL or GitHub release URL
         path: String,
+        /// Expected SHA256 of the downloaded AppImage
+        #[arg(long)]
+        sha256: Option<String>,
+    },
+    /// Register AppImages already present on disk
+    Adopt {
+        /// AppImage file or directory to scan
+        path: String,
+        /// Leave the AppImages where they are instead of moving them
+        #[arg(long)]
+        in_place: bool,
     },
     /// Install a package from repository
     #[command(alias = "-S")]
@@ -32,18 +74,43 @@ pub enum Commands {
         /// Update package database before installing
         #[arg(short = 'y')]
         refresh: bool,
+        /// Only download packages into the cache, do not install
+        #[arg(long)]
+        download_only: bool,
+        /// Print what would be done without changing anything
+        #[arg(long, alias = "print-plan")]
+        dry_run: bool,
     },
     /// Update package database
     #[command(alias = "-Sy")]
     Update,
-    /// Upgrade all packages
+    /// Upgrade installed packages
     #[command(alias = "-Su")]
-    Upgrade,
+    Upgrade {
+        /// Package name(s) to upgrade (all if omitted)
+        packages: Vec<String>,
+        /// Only download new versions into the cache, do not install
+        #[arg(long)]
+        download_only: bool,
+        /// Print what would be done without changing anything
+        #[arg(long, alias = "print-plan")]
+        dry_run: bool,
+    },
+    /// List installed packages with newer versions available; exits with
+    /// status 100 if there are any
+    #[command(alias = "-Qu")]
+    Outdated {
+        /// Package name(s) to check (all if omitted)
+        packages: Vec<String>,
+    },
     /// Remove a package
     #[command(alias = "-R")]
     Remove {
         /// Package name(s) to remove
         packages: Vec<String>,
+        /// Print what would be done without changing anything
+        #[arg(long, alias = "print-plan")]
+        dry_run: bool,
     },
     /// List installed packages
     #[command(alias = "-Q")]
@@ -70,6 +137,12 @@ pub enum Commands {
     AddSource {
         /// Source URL
         url: String,
+        /// Token for a private source, stored in credentials.toml
+        #[arg(long)]
+        token: Option<String>,
+        /// Send the token as the basic auth password for this user
+        #[arg(long, requires = "token")]
+        user: Option<String>,
     },
     /// Remove a repository source
     RemoveSource {
@@ -88,6 +161,87 @@ pub enum Commands {
         #[command(subcommand)]
         cmd: YamlCommands,
     },
+    /// Verify checksums and embedded signatures of installed packages
+    Verify {
+        /// Package name(s) to verify (all if omitted)
+        packages: Vec<String>,
+    },
+    /// Check the installation for broken or leftover files
+    Doctor {
+        /// Apply the suggested fixes
+        #[arg(long)]
+        fix: bool,
+    },
+    /// Manage trusted signing keys for sources
+    Key {
+        #[command(subcommand)]
+        cmd: KeyCommands,
+    },
+    /// Manage the GPG keyring trusted for embedded AppImage signatures
+    Keyring {
+        #[command(subcommand)]
+        cmd: KeyringCommands,
+    },
+    /// Manage the package download cache
+    Cache {
+        #[command(subcommand)]
+        cmd: CacheCommands,
+    },
+    /// Print the installed packages, sources and collectives as a lockfile
+    Export,
+    /// Install the packages of a lockfile written by `aipkg export`
+    Import {
+        /// Lockfile path
+        path: String,
+        /// Install the newest versions instead of the locked ones
+        #[arg(long)]
+        latest: bool,
+    },
+    /// Install, upgrade and remove packages to match a package manifest
+    Apply {
+        /// Manifest path (default: ~/.config/aipkg/aipkg.toml)
+        path: Option<String>,
+        /// Remove installed packages the manifest does not list
+        #[arg(long)]
+        prune: bool,
+        /// Print what would be done without changing anything
+        #[arg(long, alias = "print-plan")]
+        dry_run: bool,
+    },
+    /// Run a package from the repositories without installing it
+    Run {
+        /// Package name
+        package: String,
+        /// Version requirement, e.g. "1.4.*" or ">=2, <3" (default: newest)
+        #[arg(long)]
+        version: Option<String>,
+        /// Arguments passed to the app, after --
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Make an installed package the default application for a file type or URL scheme
+    Default {
+        /// Package name
+        package: String,
+        /// MIME type, e.g. application/pdf or x-scheme-handler/myapp
+        mime_type: String,
+    },
+    /// Show the log of installs, upgrades and removals
+    History {
+        #[command(subcommand)]
+        cmd: Option<HistoryCommands>,
+        /// Only show transactions since this date (YYYY-MM-DD or RFC 3339)
+        #[arg(long)]
+        since: Option<String>,
+        /// Only show transactions that changed this package
+        #[arg(long)]
+        package: Option<String>,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        cmd: ConfigCommands,
+    },
 }
 
 #[derive(Subcommand)]
@@ -108,6 +262,93 @@ pub enum CollectiveCommands {
     List,
 }
 
+#[derive(Subcommand)]
+pub enum KeyCommands {
+    /// Trust a minisign public key for a source URL or URL prefix
+    Add {
+        /// Source URL or URL prefix
+        source: String,
+        /// Public key (base64) or path to a minisign .pub file
+        key: String,
+        /// Refuse metadata from this source without a valid signature
+        #[arg(long)]
+        require: bool,
+    },
+    /// Remove trusted keys for a source
+    Remove {
+        /// Source URL or URL prefix
+        source: String,
+        /// Only remove this key
+        key: Option<String>,
+    },
+    /// List trusted keys
+    List,
+}
+
+#[derive(Subcommand)]
+pub enum KeyringCommands {
+    /// Import GPG public keys from a file, such as an exported .asc key
+    Import {
+        file: String,
+    },
+    /// List the keys in the trusted keyring
+    List,
+    /// Remove a key from the trusted keyring
+    Remove {
+        /// Fingerprint of the key
+        fingerprint: String,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    /// List cached AppImages
+    List,
+    /// Remove cached AppImages
+    Clean {
+        /// Number of most recent versions to keep per package
+        #[arg(long)]
+        keep: Option<usize>,
+    },
+    /// Show total size of the cache
+    Size,
+}
+
+#[derive(Subcommand)]
+pub enum HistoryCommands {
+    /// Revert a transaction, restoring the versions it replaced or removed
+    Undo {
+        /// Transaction number shown by `aipkg history`
+        id: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommands {
+    /// Show every setting with its effective value and where it comes from
+    #[command(alias = "show")]
+    List,
+    /// Print the effective value of a setting
+    Get {
+        /// Setting name, e.g. bin_dir or forge_tokens.<host>
+        key: String,
+    },
+    /// Set a value in config.toml, keeping its formatting and comments
+    Set {
+        key: String,
+        /// New value; lists are comma-separated
+        value: String,
+    },
+    /// Remove a setting from config.toml so its default applies again
+    Unset {
+        key: String,
+    },
+    /// Open config.toml in $VISUAL or $EDITOR and save it only if it is valid
+    Edit,
+    /// Check config.toml and that the configured paths are usable
+    Validate,
+}
+
 #[derive(Subcommand)]
 pub enum YamlCommands {
     /// Generate appimage.yaml
@@ -133,27 +374,62 @@ pub fn parse_args() -> Cli {
 }
 
 pub async fn handle_command(cli: Cli) -> Result<()> {
+    config::set_cli_overrides(config::CliOverrides {
+        system: cli.system,
+        root: cli.root.clone(),
+        config_file: cli.config.clone(),
+        max_download_rate: cli.max_download_rate,
+        max_parallel_downloads: cli.max_parallel_downloads,
+        allow_stale: cli.allow_stale,
+    });
+    // `aipkg config` has to work with a broken configuration to fix it
+    if !matches!(cli.command, Commands::Config { .. }) {
+        config_edit::check_config()?;
+    }
+    
     match cli.command {
-        Commands::Install { path } => {
-            install::install_from_file(&path).await?;
+        Commands::Install { path, sha256 } => {
+            if path.starts_with("http://") || path.starts_with("https://") {
+                history::record("install", install::install_from_url(&path, sha256.as_deref())).await?;
+            } else {
+                history::record("install", install::install_from_file(&path)).await?;
+            }
         }
-        Commands::Sync { packages, refresh } => {
-            if refresh {
+        Commands::Adopt { path, in_place } => {
+            history::record("adopt", adopt::adopt(&path, in_place)).await?;
+        }
+        Commands::Sync { packages, refresh, download_only, dry_run } => {
+            if refresh && dry_run {
+                println!("Dry run: not updating the package database");
+            } else if refresh {
                 repo::update_database().await?;
             }
-            for package in packages {
-                install::install_from_repo(&package).await?;
+            if !packages.is_empty() && (download_only || dry_run) {
+                install::install_from_repo(&packages, download_only, dry_run).await?;
+            } else if !packages.is_empty() {
+                history::record("install", install::install_from_repo(&packages, false, false)).await?;
             }
         }
         Commands::Update => {
             repo::update_database().await?;
         }
-        Commands::Upgrade => {
-            upgrade::upgrade_all().await?;
+        Commands::Upgrade { packages, download_only, dry_run } => {
+            if download_only || dry_run {
+                upgrade::upgrade_packages(&packages, download_only, dry_run).await?;
+            } else {
+                history::record("upgrade", upgrade::upgrade_packages(&packages, false, false)).await?;
+            }
         }
-        Commands::Remove { packages } => {
-            for package in packages {
-                uninstall::uninstall(&package).await?;
+        Commands::Outdated { packages } => {
+            if upgrade::list_outdated(&packages).await? {
+                std::process::exit(upgrade::UPDATES_AVAILABLE_EXIT_CODE);
+            }
+        }
+        Commands::Remove { packages, dry_run } => {
+            if dry_run {
+                uninstall::remove_packages(&packages, true).await?;
+            } else {
+                history::record("remove", uninstall::remove_packages(&packages, false)).await?;
             }
         }
         Commands::Query { info, package } => {
@@ -165,8 +441,8 @@ pub async fn handle_command(cli: Cli) -> Result<()> {
         Commands::Info { package } => {
             query::show_package_info(&package).await?;
         }
-        Commands::AddSource { url } => {
-            repo::add_source(&url).await?;
+        Commands::AddSource { url, token, user } => {
+            repo::add_source(&url, token.as_deref(), user.as_deref()).await?;
         }
         Commands::RemoveSource { url } => {
             repo::remove_source(&url).await?;
@@ -198,6 +474,102 @@ pub async fn handle_command(cli: Cli) -> Result<()> {
                 }
             }
         }
+        Commands::Verify { packages } => {
+            verify::verify_packages(packages).await?;
+        }
+        Commands::Doctor { fix } => {
+            doctor::doctor(fix).await?;
+        }
+        Commands::Key { cmd } => {
+            match cmd {
+                KeyCommands::Add { source, key, require } => {
+                    repo::keys::add_key(&source, &key, require).await?;
+                }
+                KeyCommands::Remove { source, key } => {
+                    repo::keys::remove_key(&source, key.as_deref()).await?;
+                }
+                KeyCommands::List => {
+                    repo::keys::list_keys().await?;
+                }
+            }
+        }
+        Commands::Keyring { cmd } => {
+            match cmd {
+                KeyringCommands::Import { file } => {
+                    verify::import_keyring(&file).await?;
+                }
+                KeyringCommands::List => {
+                    verify::list_keyring().await?;
+                }
+                KeyringCommands::Remove { fingerprint } => {
+                    verify::remove_from_keyring(&fingerprint).await?;
+                }
+            }
+        }
+        Commands::Cache { cmd } => {
+            match cmd {
+                CacheCommands::List => {
+                    pkg_cache::list_cache().await?;
+                }
+                CacheCommands::Clean { keep } => {
+                    pkg_cache::clean_cache(keep).await?;
+                }
+                CacheCommands::Size => {
+                    pkg_cache::cache_size().await?;
+                }
+            }
+        }
+        Commands::Export => {
+            lockfile::export().await?;
+        }
+        Commands::Import { path, latest } => {
+            history::record("import", lockfile::import(&path, latest)).await?;
+        }
+        Commands::Apply { path, prune, dry_run } => {
+            if dry_run {
+                manifest::apply(path.as_deref(), prune, true).await?;
+            } else {
+                history::record("apply", manifest::apply(path.as_deref(), prune, false)).await?;
+            }
+        }
+        Commands::Run { package, version, args } => {
+            run::run(&package, version.as_deref(), &args).await?;
+        }
+        Commands::Default { package, mime_type } => {
+            mime::set_default(&package, &mime_type).await?;
+        }
+        Commands::History { cmd, since, package } => {
+            match cmd {
+                Some(HistoryCommands::Undo { id }) => {
+                    history::record("undo", history::undo(id)).await?;
+                }
+                None => {
+                    history::show_history(since.as_deref(), package.as_deref()).await?;
+                }
+            }
+        }
+        Commands::Config { cmd } => {
+            match cmd {
+                ConfigCommands::List => {
+                    config_edit::list().await?;
+                }
+                ConfigCommands::Get { key } => {
+                    config_edit::get(&key).await?;
+                }
+                ConfigCommands::Set { key, value } => {
+                    config_edit::set(&key, &value).await?;
+                }
+                ConfigCommands::Unset { key } => {
+                    config_edit::unset(&key).await?;
+                }
+                ConfigCommands::Edit => {
+                    config_edit::edit().await?;
+                }
+                ConfigCommands::Validate => {
+                    config_edit::validate().await?;
+                }
+            }
+        }
     }
     Ok(())
 }
@@ -1,67 +1,267 @@
 use anyhow::{Context, Result};
-use dirs;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::repo::credentials::redact_url;
+use crate::repo::throttle::parse_rate;
 
 #[derive(Debug)]
 pub struct Config {
+    pub scope: Scope,
+    /// Prefix of every path, for installing into a chroot or image
+    pub root: Option<PathBuf>,
+    /// config.toml consulted for this configuration, whether or not it exists
+    pub config_file: PathBuf,
+    /// Settings not at their default, by config.toml key
+    pub origins: HashMap<&'static str, Origin>,
     pub appimages_dir: PathBuf,
     pub desktop_files_dir: PathBuf,
+    /// shared-mime-info database that AppImages add their file types to
+    pub mime_dir: PathBuf,
+    /// Default applications per MIME type, set by `aipkg default`
+    pub mimeapps_file: PathBuf,
     pub bin_dir: PathBuf,
     pub config_dir: PathBuf,
     pub cache_dir: PathBuf,
     pub sources_file: PathBuf,
     pub collectives_file: PathBuf,
+    pub keys_file: PathBuf,
+    pub keyring_file: PathBuf,
+    pub credentials_file: PathBuf,
+    pub netrc_file: PathBuf,
     pub unified_index_cache: PathBuf,
     pub database_file: PathBuf,
+    pub history_file: PathBuf,
+    /// Desired package set applied by `aipkg apply`
+    pub manifest_file: PathBuf,
+    pub signature_policy: SignaturePolicy,
+    pub github_api_url: String,
+    pub github_token: Option<String>,
+    /// API tokens for GitLab and Gitea hosts, keyed by host name
+    pub forge_tokens: HashMap<String, String>,
+    pub http_proxy: Option<String>,
+    pub https_proxy: Option<String>,
+    pub no_proxy: Vec<String>,
+    /// PEM file with additional trusted CA certificates
+    pub ca_bundle: Option<PathBuf>,
+    /// Hosts whose TLS certificates are not verified
+    pub insecure_hosts: Vec<String>,
+    /// Bytes per second shared by all AppImage downloads
+    pub max_download_rate: Option<u64>,
+    pub max_parallel_downloads: usize,
+    /// Replaced or removed versions of each package kept in the package
+    /// cache for undo, 0 to keep none
+    pub retained_versions: usize,
+}
+
+/// Settings given on the command line, applied on top of config.toml
+#[derive(Debug, Clone, Default)]
+pub struct CliOverrides {
+    /// Manage the system-wide installation instead of the user's
+    pub system: bool,
+    /// Prefix of every path, overriding AIPKG_ROOT
+    pub root: Option<PathBuf>,
+    /// config.toml to load instead of the one in the config directory
+    pub config_file: Option<PathBuf>,
+    pub max_download_rate: Option<u64>,
+    pub max_parallel_downloads: Option<usize>,
+    /// Use repository metadata that expired or rolled back
+    pub allow_stale: bool,
+}
+
+static CLI_OVERRIDES: OnceLock<CliOverrides> = OnceLock::new();
+
+/// Makes command line settings apply to every `Config::new()` of this process.
+pub fn set_cli_overrides(overrides: CliOverrides) {
+    let _ = CLI_OVERRIDES.set(overrides);
+}
+
+/// Whether expired or rolled-back repository metadata was accepted on the
+/// command line.
+pub fn stale_allowed() -> bool {
+    CLI_OVERRIDES.get().is_some_and(|overrides| overrides.allow_stale)
+}
+
+/// Keys of config.toml, in the order `aipkg config list` shows them.
+pub const SETTINGS: &[&str] = &[
+    "appimages_dir", "desktop_files_dir", "mime_dir", "mimeapps_file", "bin_dir", "config_dir", "cache_dir",
+    "sources_file", "collectives_file", "keys_file", "keyring_file", "credentials_file",
+    "netrc_file", "database_file", "history_file", "manifest_file",
+    "signature_policy", "github_api_url", "github_token", "forge_tokens",
+    "proxy", "no_proxy", "ca_bundle", "insecure_hosts",
+    "max_download_rate", "max_parallel_downloads", "retained_versions",
+];
+
+/// Where the effective value of a setting comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File,
+    Env,
+    Flag,
+}
+
+impl Origin {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Origin::Default => "default",
+            Origin::File => "file",
+            Origin::Env => "env",
+            Origin::Flag => "flag",
+        }
+    }
+}
+
+/// Which installation a command manages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Scope {
+    /// The user's XDG directories
+    #[default]
+    User,
+    /// /opt/aipkg and /usr/local, shared by all users and managed by root
+    System,
+}
+
+impl Scope {
+    /// The scope selected on the command line.
+    pub fn selected() -> Self {
+        if CLI_OVERRIDES.get().is_some_and(|overrides| overrides.system) {
+            Scope::System
+        } else {
+            Scope::User
+        }
+    }
+}
+
+/// What to do with AppImages whose embedded signature is missing or untrusted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignaturePolicy {
+    /// Report the signature status but install anyway
+    #[default]
+    Any,
+    /// Refuse unsigned AppImages
+    Signed,
+    /// Refuse AppImages not signed by a key in the trusted keyring
+    Trusted,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ConfigFile {
     pub appimages_dir: Option<PathBuf>,
     pub desktop_files_dir: Option<PathBuf>,
+    pub mime_dir: Option<PathBuf>,
+    pub mimeapps_file: Option<PathBuf>,
     pub bin_dir: Option<PathBuf>,
+    /// Moves sources.yaml, keys and credentials unless set individually
+    pub config_dir: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub sources_file: Option<PathBuf>,
+    pub collectives_file: Option<PathBuf>,
+    pub keys_file: Option<PathBuf>,
+    pub keyring_file: Option<PathBuf>,
+    pub credentials_file: Option<PathBuf>,
+    pub netrc_file: Option<PathBuf>,
+    pub database_file: Option<PathBuf>,
+    pub history_file: Option<PathBuf>,
+    pub manifest_file: Option<PathBuf>,
+    pub signature_policy: Option<SignaturePolicy>,
+    pub github_api_url: Option<String>,
+    pub github_token: Option<String>,
+    pub forge_tokens: Option<HashMap<String, String>>,
+    pub proxy: Option<String>,
+    pub no_proxy: Option<Vec<String>>,
+    pub ca_bundle: Option<PathBuf>,
+    pub insecure_hosts: Option<Vec<String>>,
+    pub max_download_rate: Option<String>,
+    pub max_parallel_downloads: Option<usize>,
+    pub retained_versions: Option<usize>,
+}
+
+impl ConfigFile {
+    /// Parses config.toml, rejecting unknown keys and invalid values. Paths
+    /// starting with "~/" are expanded, other paths must be absolute.
+    pub fn from_str(content: &str) -> Result<Self> {
+        let mut config_file: Self = toml::from_str(content)?;
+        if let Some(rate) = &config_file.max_download_rate {
+            parse_rate(rate).context("Invalid max_download_rate")?;
+        }
+        for (key, path) in config_file.paths_mut() {
+            if let Some(path) = path {
+                *path = expand_home(key, path)?;
+            }
+        }
+        Ok(config_file)
+    }
+
+    /// Path settings by config.toml key.
+    fn paths_mut(&mut self) -> [(&'static str, &mut Option<PathBuf>); 17] {
+        [
+            ("appimages_dir", &mut self.appimages_dir),
+            ("desktop_files_dir", &mut self.desktop_files_dir),
+            ("mime_dir", &mut self.mime_dir),
+            ("mimeapps_file", &mut self.mimeapps_file),
+            ("bin_dir", &mut self.bin_dir),
+            ("config_dir", &mut self.config_dir),
+            ("cache_dir", &mut self.cache_dir),
+            ("sources_file", &mut self.sources_file),
+            ("collectives_file", &mut self.collectives_file),
+            ("keys_file", &mut self.keys_file),
+            ("keyring_file", &mut self.keyring_file),
+            ("credentials_file", &mut self.credentials_file),
+            ("netrc_file", &mut self.netrc_file),
+            ("database_file", &mut self.database_file),
+            ("history_file", &mut self.history_file),
+            ("manifest_file", &mut self.manifest_file),
+            ("ca_bundle", &mut self.ca_bundle),
+        ]
+    }
+}
+
+/// Expands a leading "~" to the home directory. Relative paths would depend
+/// on the directory aipkg is run from, so they are refused.
+fn expand_home(key: &str, path: &Path) -> Result<PathBuf> {
+    let expanded = match path.strip_prefix("~") {
+        Ok(rest) => dirs::home_dir()
+            .context("Failed to find home directory")?
+            .join(rest),
+        Err(_) => path.to_path_buf(),
+    };
+    if !expanded.is_absolute() {
+        anyhow::bail!("{} must be an absolute path or start with ~/: {}", key, path.display());
+    }
+    Ok(expanded)
 }
 
 impl Config {
+    /// Configuration of the scope selected on the command line.
     pub fn new() -> Result<Self> {
-        let config_home = dirs::config_dir()
-            .context("Failed to find config directory")?
-            .join("aipkg");
-        
-        let cache_home = dirs::cache_dir()
-            .context("Failed to find cache directory")?
-            .join("aipkg");
-        
-        let data_home = dirs::data_dir()
-            .context("Failed to find data directory")?
-            .join("aipkg");
-
-        let config = Config {
-            appimages_dir: data_home.join("appimages"),
-            desktop_files_dir: dirs::data_dir()
-                .context("Failed to find data directory")?
-                .join("applications"),
-            bin_dir: dirs::home_dir()
-                .context("Failed to find home directory")?
-                .join(".local/bin"),
-            config_dir: config_home.clone(),
-            cache_dir: cache_home.clone(),
-            sources_file: config_home.join("sources.yaml"),
-            collectives_file: config_home.join("collectives.yaml"),
-            unified_index_cache: cache_home.join("unified_index.yaml"),
-            database_file: config_home.join("database.yaml"),
-        };
+        Self::for_scope(Scope::selected())
+    }
 
-        // Load config file if it exists and override defaults
-        let config_file_path = config_home.join("config.toml");
-        let mut final_config = config;
+    pub fn for_scope(scope: Scope) -> Result<Self> {
+        let mut final_config = Self::located(scope)?;
+        let overrides = CLI_OVERRIDES.get();
+
+        // A config file given with --config must exist
+        let config_file_path = final_config.config_file.clone();
+        if overrides.is_some_and(|o| o.config_file.is_some()) && !config_file_path.exists() {
+            anyhow::bail!("Config file not found: {}", config_file_path.display());
+        }
         
+        // Load config file if it exists and override defaults
         if config_file_path.exists() {
-            let config_file: ConfigFile = toml::from_str(
-                &std::fs::read_to_string(&config_file_path)
-                    .context("Failed to read config file")?
-            )?;
+            let content = std::fs::read_to_string(&config_file_path)
+                .context(format!("Failed to read config file: {}", config_file_path.display()))?;
+            let config_file = ConfigFile::from_str(&content)
+                .context(format!("Invalid config file: {}", config_file_path.display()))?;
+            let table: toml::Table = toml::from_str(&content)?;
+            for key in SETTINGS.iter().filter(|key| table.contains_key(**key)) {
+                final_config.origins.insert(key, Origin::File);
+            }
             
             if let Some(dir) = config_file.appimages_dir {
                 final_config.appimages_dir = dir;
@@ -69,15 +269,351 @@ impl Config {
             if let Some(dir) = config_file.desktop_files_dir {
                 final_config.desktop_files_dir = dir;
             }
+            if let Some(dir) = config_file.mime_dir {
+                final_config.mime_dir = dir;
+            }
+            if let Some(path) = config_file.mimeapps_file {
+                final_config.mimeapps_file = path;
+            }
             if let Some(dir) = config_file.bin_dir {
                 final_config.bin_dir = dir;
             }
+            if let Some(dir) = config_file.config_dir {
+                final_config.sources_file = dir.join("sources.yaml");
+                final_config.collectives_file = dir.join("collectives.yaml");
+                final_config.keys_file = dir.join("keys.yaml");
+                final_config.keyring_file = dir.join("trusted_keys.gpg");
+                final_config.credentials_file = dir.join("credentials.toml");
+                final_config.manifest_file = dir.join("aipkg.toml");
+                if final_config.scope == Scope::User {
+                    final_config.database_file = dir.join("database.yaml");
+                    final_config.history_file = dir.join("history.jsonl");
+                }
+                final_config.config_dir = dir;
+            }
+            if let Some(dir) = config_file.cache_dir {
+                final_config.unified_index_cache = dir.join("unified_index.yaml");
+                final_config.cache_dir = dir;
+            }
+            let files = [
+                (config_file.sources_file, &mut final_config.sources_file),
+                (config_file.collectives_file, &mut final_config.collectives_file),
+                (config_file.keys_file, &mut final_config.keys_file),
+                (config_file.keyring_file, &mut final_config.keyring_file),
+                (config_file.credentials_file, &mut final_config.credentials_file),
+                (config_file.netrc_file, &mut final_config.netrc_file),
+                (config_file.database_file, &mut final_config.database_file),
+                (config_file.history_file, &mut final_config.history_file),
+                (config_file.manifest_file, &mut final_config.manifest_file),
+            ];
+            for (value, field) in files {
+                if let Some(path) = value {
+                    *field = path;
+                }
+            }
+            if let Some(policy) = config_file.signature_policy {
+                final_config.signature_policy = policy;
+            }
+            if let Some(url) = config_file.github_api_url {
+                final_config.github_api_url = url;
+            }
+            if let Some(token) = config_file.github_token {
+                final_config.github_token = Some(token);
+            }
+            if let Some(tokens) = config_file.forge_tokens {
+                final_config.forge_tokens = tokens;
+            }
+            if let Some(proxy) = config_file.proxy {
+                final_config.http_proxy = Some(proxy.clone());
+                final_config.https_proxy = Some(proxy);
+            }
+            if let Some(hosts) = config_file.no_proxy {
+                final_config.no_proxy = hosts;
+            }
+            if let Some(path) = config_file.ca_bundle {
+                final_config.ca_bundle = Some(path);
+            }
+            if let Some(hosts) = config_file.insecure_hosts {
+                final_config.insecure_hosts = hosts;
+            }
+            if let Some(rate) = config_file.max_download_rate {
+                final_config.max_download_rate = Some(parse_rate(&rate)?);
+            }
+            if let Some(count) = config_file.max_parallel_downloads {
+                final_config.max_parallel_downloads = count;
+            }
+            if let Some(count) = config_file.retained_versions {
+                final_config.retained_versions = count;
+            }
+        }
+
+        // The standard proxy environment variables take precedence over config.toml
+        if let Some(proxy) = env_var(&["HTTPS_PROXY", "https_proxy", "ALL_PROXY", "all_proxy"]) {
+            final_config.https_proxy = Some(proxy);
+            final_config.origins.insert("proxy", Origin::Env);
+        }
+        if let Some(proxy) = env_var(&["HTTP_PROXY", "http_proxy", "ALL_PROXY", "all_proxy"]) {
+            final_config.http_proxy = Some(proxy);
+            final_config.origins.insert("proxy", Origin::Env);
+        }
+        if let Some(no_proxy) = env_var(&["NO_PROXY", "no_proxy"]) {
+            final_config.no_proxy = no_proxy.split(',')
+                .map(|host| host.trim().to_string())
+                .filter(|host| !host.is_empty())
+                .collect();
+            final_config.origins.insert("no_proxy", Origin::Env);
+        }
+
+        if let Some(overrides) = overrides {
+            if let Some(rate) = overrides.max_download_rate {
+                final_config.max_download_rate = Some(rate);
+                final_config.origins.insert("max_download_rate", Origin::Flag);
+            }
+            if let Some(count) = overrides.max_parallel_downloads {
+                final_config.max_parallel_downloads = count;
+                final_config.origins.insert("max_parallel_downloads", Origin::Flag);
+            }
         }
+        final_config.max_parallel_downloads = final_config.max_parallel_downloads.max(1);
 
+        final_config.move_under_root();
         Ok(final_config)
     }
 
+    /// Moves everything aipkg manages under the root, including paths from
+    /// config.toml.
+    fn move_under_root(&mut self) {
+        let Some(root) = self.root.clone() else {
+            return;
+        };
+        for path in [
+            &mut self.appimages_dir, &mut self.desktop_files_dir, &mut self.mime_dir,
+            &mut self.mimeapps_file, &mut self.bin_dir,
+            &mut self.config_dir, &mut self.cache_dir, &mut self.sources_file,
+            &mut self.collectives_file, &mut self.keys_file, &mut self.keyring_file,
+            &mut self.credentials_file, &mut self.netrc_file, &mut self.unified_index_cache,
+            &mut self.database_file, &mut self.history_file, &mut self.manifest_file,
+        ] {
+            *path = under_root(Some(&root), path);
+        }
+    }
+
+    /// Defaults of `scope` under the root, and where its config.toml is,
+    /// without reading it. `aipkg config` edits the file through this, so a
+    /// broken file can still be fixed.
+    pub fn located(scope: Scope) -> Result<Self> {
+        let overrides = CLI_OVERRIDES.get();
+        let mut config = Self::defaults(scope)?;
+
+        let (root, root_origin) = match overrides.and_then(|o| o.root.clone()) {
+            Some(root) => (Some(root), Origin::Flag),
+            None => (std::env::var_os("AIPKG_ROOT").map(PathBuf::from), Origin::Env),
+        };
+        config.root = root.filter(|root| !root.as_os_str().is_empty());
+        if config.root.is_some() {
+            config.origins.insert("root", root_origin);
+        }
+
+        config.config_file = match overrides.and_then(|o| o.config_file.clone()) {
+            Some(path) => {
+                config.origins.insert("config_file", Origin::Flag);
+                path
+            }
+            None => config.root_path(&config.config_dir.join("config.toml")),
+        };
+
+        // Defaults that the environment changed
+        let mut env_settings = vec![("GITHUB_TOKEN", &["github_token"][..]), ("NETRC", &["netrc_file"][..])];
+        if scope == Scope::User {
+            env_settings.extend([
+                ("XDG_CONFIG_HOME", &["config_dir", "mimeapps_file"][..]),
+                ("XDG_CACHE_HOME", &["cache_dir"][..]),
+                ("XDG_DATA_HOME", &["appimages_dir", "desktop_files_dir", "mime_dir"][..]),
+                ("XDG_BIN_HOME", &["bin_dir"][..]),
+            ]);
+        }
+        for (var, keys) in env_settings {
+            if std::env::var_os(var).is_some_and(|value| !value.is_empty()) {
+                for key in keys {
+                    config.origins.insert(key, Origin::Env);
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Origin of a setting; files kept in the config or cache directory
+    /// share the origin of that directory unless set themselves.
+    pub fn origin(&self, key: &str) -> Origin {
+        let parent = match key {
+            "sources_file" | "collectives_file" | "keys_file" | "keyring_file"
+            | "credentials_file" | "manifest_file" => Some("config_dir"),
+            "database_file" | "history_file" if self.scope == Scope::User => Some("config_dir"),
+            _ => None,
+        };
+        self.origins.get(key)
+            .or_else(|| parent.and_then(|parent| self.origins.get(parent)))
+            .copied()
+            .unwrap_or(Origin::Default)
+    }
+
+    fn defaults(scope: Scope) -> Result<Self> {
+        let (config_home, cache_home, state_dir, appimages_dir, data_dir, mimeapps_file, bin_dir) = match scope {
+            Scope::User => {
+                let xdg_config = dirs::config_dir()
+                    .context("Failed to find config directory")?;
+                let config_home = xdg_config.join("aipkg");
+                let cache_home = dirs::cache_dir()
+                    .context("Failed to find cache directory")?
+                    .join("aipkg");
+                let data_dir = dirs::data_dir()
+                    .context("Failed to find data directory")?;
+                let bin_dir = dirs::executable_dir()
+                    .context("Failed to find home directory")?;
+                // The user database lives next to the configuration
+                (config_home.clone(), cache_home, config_home, data_dir.join("aipkg/appimages"), data_dir, xdg_config.join("mimeapps.list"), bin_dir)
+            }
+            Scope::System => (
+                PathBuf::from("/etc/aipkg"),
+                PathBuf::from("/var/cache/aipkg"),
+                PathBuf::from("/var/lib/aipkg"),
+                PathBuf::from("/opt/aipkg/appimages"),
+                PathBuf::from("/usr/local/share"),
+                PathBuf::from("/etc/xdg/mimeapps.list"),
+                PathBuf::from("/usr/local/bin"),
+            ),
+        };
+
+        Ok(Config {
+            scope,
+            root: None,
+            config_file: PathBuf::new(),
+            origins: HashMap::new(),
+            appimages_dir,
+            desktop_files_dir: data_dir.join("applications"),
+            mime_dir: data_dir.join("mime"),
+            mimeapps_file,
+            bin_dir,
+            config_dir: config_home.clone(),
+            cache_dir: cache_home.clone(),
+            sources_file: config_home.join("sources.yaml"),
+            collectives_file: config_home.join("collectives.yaml"),
+            keys_file: config_home.join("keys.yaml"),
+            keyring_file: config_home.join("trusted_keys.gpg"),
+            credentials_file: config_home.join("credentials.toml"),
+            netrc_file: match std::env::var_os("NETRC") {
+                Some(path) => PathBuf::from(path),
+                None => dirs::home_dir()
+                    .context("Failed to find home directory")?
+                    .join(".netrc"),
+            },
+            unified_index_cache: cache_home.join("unified_index.yaml"),
+            database_file: state_dir.join("database.yaml"),
+            history_file: state_dir.join("history.jsonl"),
+            manifest_file: config_home.join("aipkg.toml"),
+            signature_policy: SignaturePolicy::default(),
+            github_api_url: "https://api.github.com".to_string(),
+            github_token: std::env::var("GITHUB_TOKEN").ok().filter(|t| !t.is_empty()),
+            forge_tokens: HashMap::new(),
+            http_proxy: None,
+            https_proxy: None,
+            no_proxy: Vec::new(),
+            ca_bundle: None,
+            insecure_hosts: Vec::new(),
+            max_download_rate: None,
+            max_parallel_downloads: 3,
+            retained_versions: 3,
+        })
+    }
+
+    /// Effective value of a config.toml key as shown to the user, or `None`
+    /// for unknown keys. Secrets are never shown.
+    pub fn setting_value(&self, key: &str) -> Option<String> {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "(not set)".to_string());
+        let list = |values: &[String]| if values.is_empty() {
+            "(none)".to_string()
+        } else {
+            values.join(", ")
+        };
+        let path = |path: &PathBuf| path.display().to_string();
+
+        let value = match key {
+            "appimages_dir" => path(&self.appimages_dir),
+            "desktop_files_dir" => path(&self.desktop_files_dir),
+            "mime_dir" => path(&self.mime_dir),
+            "mimeapps_file" => path(&self.mimeapps_file),
+            "bin_dir" => path(&self.bin_dir),
+            "config_dir" => path(&self.config_dir),
+            "cache_dir" => path(&self.cache_dir),
+            "sources_file" => path(&self.sources_file),
+            "collectives_file" => path(&self.collectives_file),
+            "keys_file" => path(&self.keys_file),
+            "keyring_file" => path(&self.keyring_file),
+            "credentials_file" => path(&self.credentials_file),
+            "netrc_file" => path(&self.netrc_file),
+            "database_file" => path(&self.database_file),
+            "history_file" => path(&self.history_file),
+            "manifest_file" => path(&self.manifest_file),
+            "signature_policy" => format!("{:?}", self.signature_policy).to_lowercase(),
+            "github_api_url" => self.github_api_url.clone(),
+            "github_token" => optional(self.github_token.as_ref().map(|_| "(set)".to_string())),
+            "forge_tokens" => {
+                let mut hosts: Vec<String> = self.forge_tokens.keys().cloned().collect();
+                hosts.sort();
+                list(&hosts)
+            }
+            "proxy" => match (&self.https_proxy, &self.http_proxy) {
+                (https, http) if https == http => optional(https.as_deref().map(redact_url)),
+                (https, http) => format!(
+                    "https: {}, http: {}",
+                    optional(https.as_deref().map(redact_url)),
+                    optional(http.as_deref().map(redact_url)),
+                ),
+            },
+            "no_proxy" => list(&self.no_proxy),
+            "ca_bundle" => optional(self.ca_bundle.as_ref().map(path)),
+            "insecure_hosts" => list(&self.insecure_hosts),
+            "max_download_rate" => optional(self.max_download_rate.map(|rate| format!("{} bytes/s", rate))),
+            "max_parallel_downloads" => self.max_parallel_downloads.to_string(),
+            "retained_versions" => self.retained_versions.to_string(),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Where `path` of the installed system is found from here.
+    pub fn root_path(&self, path: &Path) -> PathBuf {
+        under_root(self.root.as_deref(), path)
+    }
+
+    /// Path as seen from inside the root, for symlink targets and desktop
+    /// files that are used once the root is the installed system.
+    pub fn runtime_path(&self, path: &Path) -> PathBuf {
+        match self.root.as_deref().and_then(|root| path.strip_prefix(root).ok()) {
+            Some(inside) => Path::new("/").join(inside),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Fails for system-wide changes by anyone but root, before anything is
+    /// written.
+    pub fn check_privileges(&self) -> Result<()> {
+        // Writes under --root are left to the permissions of that tree
+        if self.scope == Scope::System && self.root.is_none() && !is_root() {
+            anyhow::bail!("System-wide changes need root privileges, run the command with sudo");
+        }
+        Ok(())
+    }
+
+    /// Token configured for the host of a forge base URL.
+    pub fn forge_token(&self, base_url: &str) -> Option<String> {
+        let host = url::Url::parse(base_url).ok()?.host_str()?.to_string();
+        self.forge_tokens.get(&host).cloned()
+    }
+
     pub async fn ensure_directories(&self) -> Result<()> {
+        self.check_privileges()?;
         tokio::fs::create_dir_all(&self.appimages_dir).await
             .context("Failed to create appimages directory")?;
         tokio::fs::create_dir_all(&self.desktop_files_dir).await
@@ -88,13 +624,110 @@ impl Config {
             .context("Failed to create config directory")?;
         tokio::fs::create_dir_all(&self.cache_dir).await
             .context("Failed to create cache directory")?;
+        if let Some(dir) = self.database_file.parent() {
+            tokio::fs::create_dir_all(dir).await
+                .context("Failed to create database directory")?;
+        }
         Ok(())
     }
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self::new().expect("Failed to initialize config")
+fn under_root(root: Option<&Path>, path: &Path) -> PathBuf {
+    match root {
+        Some(root) => root.join(path.strip_prefix("/").unwrap_or(path)),
+        None => path.to_path_buf(),
     }
 }
 
+/// Whether the process runs with root's effective user ID, as under sudo.
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no preconditions and cannot fail
+    unsafe { libc::geteuid() == 0 }
+}
+
+/// First non-empty environment variable among `names`.
+fn env_var(names: &[&str]) -> Option<String> {
+    names.iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn system_scope_uses_shared_directories() {
+        let mut system = Config::defaults(Scope::System).unwrap();
+        let paths = [
+            (&system.appimages_dir, "/opt/aipkg/appimages"),
+            (&system.bin_dir, "/usr/local/bin"),
+            (&system.desktop_files_dir, "/usr/local/share/applications"),
+            (&system.mime_dir, "/usr/local/share/mime"),
+            (&system.mimeapps_file, "/etc/xdg/mimeapps.list"),
+            (&system.config_dir, "/etc/aipkg"),
+            (&system.sources_file, "/etc/aipkg/sources.yaml"),
+            (&system.cache_dir, "/var/cache/aipkg"),
+            (&system.database_file, "/var/lib/aipkg/database.yaml"),
+            (&system.history_file, "/var/lib/aipkg/history.jsonl"),
+        ];
+        for (path, expected) in paths {
+            assert_eq!(path, Path::new(expected));
+        }
+
+        // The user database lives next to the configuration, and moves with it
+        let mut user = Config::defaults(Scope::User).unwrap();
+        assert_eq!(user.database_file.parent(), Some(user.config_dir.as_path()));
+        assert!(!user.appimages_dir.starts_with("/opt/aipkg"));
+        user.origins.insert("config_dir", Origin::File);
+        assert_eq!(user.origin("database_file"), Origin::File);
+        system.origins.insert("config_dir", Origin::File);
+        assert_eq!(system.origin("database_file"), Origin::Default);
+        assert_eq!(system.origin("sources_file"), Origin::File);
+    }
+
+    #[test]
+    fn root_prefixes_every_managed_path() {
+        let mut config = Config::defaults(Scope::System).unwrap();
+        config.root = Some(PathBuf::from("/mnt/image"));
+        config.move_under_root();
+
+        assert_eq!(config.appimages_dir, Path::new("/mnt/image/opt/aipkg/appimages"));
+        assert_eq!(config.bin_dir, Path::new("/mnt/image/usr/local/bin"));
+        assert_eq!(config.database_file, Path::new("/mnt/image/var/lib/aipkg/database.yaml"));
+        assert_eq!(config.unified_index_cache, Path::new("/mnt/image/var/cache/aipkg/unified_index.yaml"));
+        assert_eq!(config.mimeapps_file, Path::new("/mnt/image/etc/xdg/mimeapps.list"));
+
+        // Paths written into the image point at where they are once it boots
+        let installed = config.appimages_dir.join("app/1.0/app.AppImage");
+        assert_eq!(config.runtime_path(&installed), Path::new("/opt/aipkg/appimages/app/1.0/app.AppImage"));
+        assert_eq!(config.root_path(&config.runtime_path(&installed)), installed);
+        assert_eq!(config.runtime_path(Path::new("/usr/bin/env")), Path::new("/usr/bin/env"));
+    }
+
+    #[test]
+    fn without_a_root_paths_stay_as_they_are() {
+        let mut config = Config::defaults(Scope::User).unwrap();
+        let expected = config.appimages_dir.clone();
+        config.move_under_root();
+        assert_eq!(config.appimages_dir, expected);
+        assert_eq!(config.root_path(Path::new("/usr/local/bin")), Path::new("/usr/local/bin"));
+        assert_eq!(config.runtime_path(Path::new("/usr/local/bin")), Path::new("/usr/local/bin"));
+        assert_eq!(under_root(Some(Path::new("/mnt/image")), Path::new("relative")), Path::new("/mnt/image/relative"));
+    }
+
+    #[test]
+    fn config_paths_expand_home_and_must_be_absolute() {
+        let home = dirs::home_dir().unwrap();
+        let config_file = ConfigFile::from_str("appimages_dir = \"~/Apps\"\nbin_dir = \"/opt/bin\"\nca_bundle = \"~\"\n").unwrap();
+        assert_eq!(config_file.appimages_dir, Some(home.join("Apps")));
+        assert_eq!(config_file.bin_dir, Some(PathBuf::from("/opt/bin")));
+        assert_eq!(config_file.ca_bundle.map(|path| path.components().eq(home.components())), Some(true));
+
+        for content in ["appimages_dir = \"Apps\"", "cache_dir = \"./cache\"", "keys_file = \"~user/keys.yaml\"", "ca_bundle = \"\""] {
+            let error = ConfigFile::from_str(content).unwrap_err().to_string();
+            assert!(error.contains("must be an absolute path or start with ~/"), "{}: {}", content, error);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use anyhow::{Context, Result};
+use fuzzy_matcher::FuzzyMatcher;
+use fuzzy_matcher::skim::SkimMatcherV2;
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::{Config, ConfigFile, Origin, Scope, SETTINGS};
+use crate::utils::write_atomic;
+
+/// Settings whose values are never echoed back.
+fn is_secret(key: &str) -> bool {
+    key == "github_token" || key.starts_with("forge_tokens.")
+}
+
+/// Checks that `key` is a config.toml key, or `forge_tokens.<host>`.
+fn check_key(key: &str) -> Result<()> {
+    if SETTINGS.contains(&key) || key.strip_prefix("forge_tokens.").is_some_and(|host| !host.is_empty()) {
+        return Ok(());
+    }
+    let matcher = SkimMatcherV2::default();
+    let suggestion = SETTINGS.iter()
+        .filter_map(|setting| matcher.fuzzy_match(setting, key).map(|score| (score, setting)))
+        .max()
+        .map(|(_, setting)| format!(", did you mean '{}'?", setting))
+        .unwrap_or_default();
+    anyhow::bail!("Unknown setting: {}{} ('aipkg config list' shows all settings)", key, suggestion)
+}
+
+pub async fn list() -> Result<()> {
+    let config = Config::new()?;
+    println!("scope: {}", match config.scope {
+        Scope::User => "user",
+        Scope::System => "system",
+    });
+    if let Some(root) = &config.root {
+        println!("root: {} ({})", root.display(), config.origin("root").label());
+    }
+    let found = if config.config_file.exists() { "" } else { ", not found" };
+    println!("config file: {} ({}{})", config.config_file.display(), config.origin("config_file").label(), found);
+    println!();
+
+    let width = SETTINGS.iter().map(|key| key.len()).max().unwrap_or(0);
+    for key in SETTINGS {
+        let value = config.setting_value(key).unwrap_or_default();
+        println!("{:<width$} = {}  ({})", key, value, config.origin(key).label(), width = width);
+    }
+    Ok(())
+}
+
+pub async fn get(key: &str) -> Result<()> {
+    check_key(key)?;
+    let config = Config::new()?;
+    match key.strip_prefix("forge_tokens.") {
+        Some(host) => println!("{}", if config.forge_tokens.contains_key(host) { "(set)" } else { "(not set)" }),
+        None => println!("{}", config.setting_value(key).unwrap_or_default()),
+    }
+    Ok(())
+}
+
+/// config.toml of the selected scope, without requiring it to be valid.
+async fn load_document(config: &Config) -> Result<toml_edit::DocumentMut> {
+    let content = if config.config_file.exists() {
+        fs::read_to_string(&config.config_file).await
+            .context(format!("Failed to read config file: {}", config.config_file.display()))?
+    } else {
+        String::new()
+    };
+    content.parse()
+        .context(format!("Failed to parse config file: {}", config.config_file.display()))
+}
+
+/// Validates and writes an edited config.toml.
+async fn save_document(config: &Config, document: &toml_edit::DocumentMut) -> Result<()> {
+    let content = document.to_string();
+    ConfigFile::from_str(&content)?;
+    if let Some(dir) = config.config_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    write_atomic(&config.config_file, content.as_bytes()).await
+        .context(format!("Failed to write config file: {}", config.config_file.display()))
+}
+
+fn to_item(key: &str, value: &str) -> Result<toml_edit::Item> {
+    let item = match key {
+        "max_parallel_downloads" | "retained_versions" => {
+            let count: i64 = value.parse()
+                .context(format!("{} must be a number", key))?;
+            toml_edit::value(count)
+        }
+        "no_proxy" | "insecure_hosts" => {
+            let hosts: toml_edit::Array = value.split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .collect();
+            toml_edit::value(hosts)
+        }
+        "forge_tokens" => anyhow::bail!("Set forge tokens per host: aipkg config set forge_tokens.<host> <token>"),
+        _ => toml_edit::value(value),
+    };
+    Ok(item)
+}
+
+/// Sets `key` in the document, creating the forge_tokens table if needed.
+fn set_in(document: &mut toml_edit::DocumentMut, key: &str, value: &str) -> Result<()> {
+    match key.strip_prefix("forge_tokens.") {
+        Some(host) => {
+            let tokens = document.entry("forge_tokens")
+                .or_insert(toml_edit::table())
+                .as_table_mut()
+                .context("forge_tokens in config.toml is not a table")?;
+            tokens[host] = toml_edit::value(value);
+        }
+        None => document[key] = to_item(key, value)?,
+    }
+    Ok(())
+}
+
+/// Whether `key` was set in the document.
+fn unset_in(document: &mut toml_edit::DocumentMut, key: &str) -> bool {
+    match key.strip_prefix("forge_tokens.") {
+        Some(host) => document.get_mut("forge_tokens")
+            .and_then(|tokens| tokens.as_table_like_mut())
+            .and_then(|tokens| tokens.remove(host))
+            .is_some(),
+        None => document.remove(key).is_some(),
+    }
+}
+
+/// Sets a key in config.toml, keeping the rest of the file as it is.
+pub async fn set(key: &str, value: &str) -> Result<()> {
+    check_key(key)?;
+    let config = Config::located(Scope::selected())?;
+    config.check_privileges()?;
+    let mut document = load_document(&config).await?;
+    set_in(&mut document, key, value)?;
+    save_document(&config, &document).await
+        .context(format!("Invalid value for {}", key))?;
+
+    if is_secret(key) {
+        println!("Set {} in {}", key, config.config_file.display());
+    } else {
+        println!("Set {} = {} in {}", key, value, config.config_file.display());
+    }
+    // Point out unusable paths right away rather than on the next command
+    if key.ends_with("_dir") || key.ends_with("_file") || key == "ca_bundle" {
+        for problem in check_paths(&Config::new()?) {
+            println!("Warning: {} (other commands refuse to run until this is fixed)", problem);
+        }
+    }
+    Ok(())
+}
+
+/// Removes a key from config.toml, so its default applies again.
+pub async fn unset(key: &str) -> Result<()> {
+    check_key(key)?;
+    let config = Config::located(Scope::selected())?;
+    config.check_privileges()?;
+    let mut document = load_document(&config).await?;
+
+    if !unset_in(&mut document, key) {
+        println!("{} is not set in {}", key, config.config_file.display());
+        return Ok(());
+    }
+
+    save_document(&config, &document).await?;
+    println!("Unset {} in {}", key, config.config_file.display());
+    Ok(())
+}
+
+/// Writes the copy edited in place of config.toml, which may hold forge tokens.
+async fn write_draft(draft: &Path, content: &str) -> Result<()> {
+    // Restrict the file before any secret is written to it
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(draft).await
+        .context(format!("Failed to write {}", draft.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    file.write_all(content.as_bytes()).await
+        .context(format!("Failed to write {}", draft.display()))?;
+    file.flush().await
+        .context(format!("Failed to write {}", draft.display()))
+}
+
+/// Opens config.toml in the user's editor and only saves it if it is valid.
+pub async fn edit() -> Result<()> {
+    let config = Config::located(Scope::selected())?;
+    config.check_privileges()?;
+    let editor = ["VISUAL", "EDITOR"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| "vi".to_string());
+
+    let original = if config.config_file.exists() {
+        fs::read_to_string(&config.config_file).await
+            .context(format!("Failed to read config file: {}", config.config_file.display()))?
+    } else {
+        String::new()
+    };
+    let dir = config.config_file.parent().context("Invalid config file path")?;
+    fs::create_dir_all(dir).await?;
+    let draft = dir.join(".config.toml.edit");
+    write_draft(&draft, &original).await?;
+
+    // Through the shell, so editors with arguments like "code --wait" work
+    let status = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", editor))
+        .arg("aipkg")
+        .arg(&draft)
+        .status().await
+        .context(format!("Failed to run editor: {}", editor))?;
+    if !status.success() {
+        anyhow::bail!("Editor exited with {}, config file not changed (draft kept in {})", status, draft.display());
+    }
+
+    let content = fs::read_to_string(&draft).await?;
+    if content == original {
+        fs::remove_file(&draft).await?;
+        println!("No changes");
+        return Ok(());
+    }
+    if let Err(e) = ConfigFile::from_str(&content) {
+        anyhow::bail!("{:#}\nConfig file not changed, your edits are kept in {}", e, draft.display());
+    }
+    write_atomic(&config.config_file, content.as_bytes()).await?;
+    fs::remove_file(&draft).await?;
+    println!("Saved {}", config.config_file.display());
+    Ok(())
+}
+
+/// Where a directory will be created, or why it cannot be.
+fn check_writable_dir(key: &str, dir: &Path) -> Option<String> {
+    let Some(existing) = dir.ancestors().find(|ancestor| ancestor.exists()) else {
+        return Some(format!("{}: {} has no existing parent directory", key, dir.display()));
+    };
+    if !existing.is_dir() {
+        return Some(format!("{}: {} is not a directory", key, existing.display()));
+    }
+    let probe = existing.join(format!(".aipkg-write-test-{}", std::process::id()));
+    match std::fs::File::create(&probe) {
+        Ok(_) => {
+            let _ = std::fs::remove_file(&probe);
+            None
+        }
+        Err(e) if existing == dir => Some(format!("{}: {} is not writable: {}", key, dir.display(), e)),
+        Err(e) => Some(format!("{}: cannot create {} in {}: {}", key, dir.display(), existing.display(), e)),
+    }
+}
+
+/// Problems with the configured paths: directories aipkg writes to must be
+/// writable or creatable, and a configured CA bundle must exist.
+fn check_paths(config: &Config) -> Vec<String> {
+    let mut problems = Vec::new();
+
+    if config.check_privileges().is_ok() {
+        let dirs = [
+            ("appimages_dir", Some(config.appimages_dir.as_path())),
+            ("desktop_files_dir", Some(config.desktop_files_dir.as_path())),
+            ("mime_dir", Some(config.mime_dir.as_path())),
+            ("mimeapps_file", config.mimeapps_file.parent()),
+            ("bin_dir", Some(config.bin_dir.as_path())),
+            ("config_dir", Some(config.config_dir.as_path())),
+            ("cache_dir", Some(config.cache_dir.as_path())),
+            ("database_file", config.database_file.parent()),
+            ("history_file", config.history_file.parent()),
+        ];
+        for (key, dir) in dirs {
+            if let Some(problem) = dir.and_then(|dir| check_writable_dir(key, dir)) {
+                problems.push(problem);
+            }
+        }
+    }
+
+    if let Some(ca_bundle) = &config.ca_bundle {
+        if !ca_bundle.is_file() {
+            problems.push(format!("ca_bundle: {} does not exist", ca_bundle.display()));
+        }
+    }
+    let files = [
+        ("sources_file", &config.sources_file),
+        ("collectives_file", &config.collectives_file),
+        ("keys_file", &config.keys_file),
+        ("keyring_file", &config.keyring_file),
+        ("credentials_file", &config.credentials_file),
+        ("netrc_file", &config.netrc_file),
+        ("manifest_file", &config.manifest_file),
+    ];
+    for (key, path) in files {
+        if config.origin(key) != Origin::Default && path.is_dir() {
+            problems.push(format!("{}: {} is a directory", key, path.display()));
+        }
+    }
+
+    problems
+}
+
+/// Loads the configuration and fails on unusable paths, so commands other
+/// than `aipkg config` never start writing to them.
+pub fn check_config() -> Result<()> {
+    let config = Config::new()?;
+    let problems = check_paths(&config);
+    if !problems.is_empty() {
+        anyhow::bail!(
+            "Unusable paths in the configuration:\n  {}\nFix them with 'aipkg config set' or 'aipkg config edit'",
+            problems.join("\n  ")
+        );
+    }
+    Ok(())
+}
+
+/// Checks config.toml and the configured paths without changing anything.
+pub async fn validate() -> Result<()> {
+    let located = Config::located(Scope::selected())?;
+    let path = &located.config_file;
+
+    if path.exists() {
+        let content = fs::read_to_string(path).await
+            .context(format!("Failed to read config file: {}", path.display()))?;
+        ConfigFile::from_str(&content)
+            .context(format!("Invalid config file: {}", path.display()))?;
+        println!("Config file: {} (ok)", path.display());
+    } else {
+        println!("Config file: {} (not found, using defaults)", path.display());
+    }
+
+    let config = Config::new()?;
+    if config.check_privileges().is_err() {
+        println!("Not checking write access to system directories without root privileges");
+    }
+    let problems = check_paths(&config);
+
+    let path_var = std::env::var_os("PATH").unwrap_or_default();
+    if config.scope == Scope::User && !std::env::split_paths(&path_var).any(|dir| dir == config.bin_dir) {
+        println!("Note: bin_dir {} is not in PATH", config.bin_dir.display());
+    }
+
+    if !problems.is_empty() {
+        for problem in &problems {
+            println!("  {}", problem);
+        }
+        anyhow::bail!("Found {} problem(s) in the configuration", problems.len());
+    }
+    println!("Configuration is valid");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_known_settings_and_forge_hosts_are_keys() {
+        for key in ["appimages_dir", "max_parallel_downloads", "forge_tokens.gitlab.com"] {
+            assert!(check_key(key).is_ok(), "{}", key);
+        }
+        for key in ["forge_tokens.", "appimage_dir", ""] {
+            assert!(check_key(key).is_err(), "{}", key);
+        }
+        let error = check_key("appimage_dir").unwrap_err().to_string();
+        assert!(error.contains("did you mean 'appimages_dir'?"), "{}", error);
+    }
+
+    #[test]
+    fn values_are_coerced_to_the_setting_type() {
+        assert_eq!(to_item("retained_versions", "3").unwrap().as_integer(), Some(3));
+        assert!(to_item("max_parallel_downloads", "many").is_err());
+
+        let hosts = to_item("no_proxy", "localhost, .internal,,").unwrap();
+        let hosts: Vec<&str> = hosts.as_array().unwrap().iter().filter_map(|host| host.as_str()).collect();
+        assert_eq!(hosts, vec!["localhost", ".internal"]);
+
+        assert_eq!(to_item("appimages_dir", "~/Apps").unwrap().as_str(), Some("~/Apps"));
+        assert!(to_item("forge_tokens", "secret").is_err());
+    }
+
+    #[test]
+    fn set_and_unset_keep_comments_and_order() {
+        let original = "# My settings\nbin_dir = \"~/bin\" # on PATH\n\n[forge_tokens]\n# work\n\"git.example.com\" = \"abc\"\n";
+        let mut document: toml_edit::DocumentMut = original.parse().unwrap();
+
+        set_in(&mut document, "retained_versions", "2").unwrap();
+        set_in(&mut document, "forge_tokens.codeberg.org", "def").unwrap();
+        assert_eq!(document.to_string(), "# My settings\nbin_dir = \"~/bin\" # on PATH\nretained_versions = 2\n\n\
+            [forge_tokens]\n# work\n\"git.example.com\" = \"abc\"\n\"codeberg.org\" = \"def\"\n");
+
+        assert!(unset_in(&mut document, "retained_versions"));
+        assert!(unset_in(&mut document, "forge_tokens.codeberg.org"));
+        assert!(!unset_in(&mut document, "forge_tokens.codeberg.org"));
+        assert!(!unset_in(&mut document, "cache_dir"));
+        assert_eq!(document.to_string(), original);
+    }
+
+    #[test]
+    fn forge_tokens_table_is_created_when_missing() {
+        let mut document: toml_edit::DocumentMut = "bin_dir = \"~/bin\"\n".parse().unwrap();
+        set_in(&mut document, "forge_tokens.codeberg.org", "def").unwrap();
+        let config = ConfigFile::from_str(&document.to_string()).unwrap();
+        assert_eq!(config.forge_tokens.unwrap().get("codeberg.org").map(String::as_str), Some("def"));
+
+        let mut document: toml_edit::DocumentMut = "forge_tokens = \"abc\"\n".parse().unwrap();
+        assert!(set_in(&mut document, "forge_tokens.codeberg.org", "def").is_err());
+    }
+
+    #[test]
+    fn unusable_paths_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+
+        let mut config = Config::located(Scope::User).unwrap();
+        for path in [
+            &mut config.appimages_dir, &mut config.desktop_files_dir, &mut config.mime_dir,
+            &mut config.bin_dir, &mut config.config_dir, &mut config.cache_dir,
+        ] {
+            *path = dir.path().join("new").join("dir");
+        }
+        for path in [&mut config.mimeapps_file, &mut config.database_file, &mut config.history_file] {
+            *path = dir.path().join("file.txt");
+        }
+        assert_eq!(check_paths(&config), Vec::<String>::new());
+
+        config.appimages_dir = file.join("appimages");
+        config.ca_bundle = Some(dir.path().join("missing.pem"));
+        config.keys_file = dir.path().to_path_buf();
+        config.origins.insert("keys_file", Origin::File);
+        assert_eq!(check_paths(&config), vec![
+            format!("appimages_dir: {} is not a directory", file.display()),
+            format!("ca_bundle: {}/missing.pem does not exist", dir.path().display()),
+            format!("keys_file: {} is a directory", dir.path().display()),
+        ]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn edit_draft_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let draft = dir.path().join(".config.toml.edit");
+        std::fs::write(&draft, "stale draft with more content").unwrap();
+        std::fs::set_permissions(&draft, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_draft(&draft, "[forge_tokens]\n").await.unwrap();
+        assert_eq!(std::fs::metadata(&draft).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(std::fs::read_to_string(&draft).unwrap(), "[forge_tokens]\n");
+    }
+}
@@ -0,0 +1,444 @@
+use anyhow::Result;
+use std::fmt;
+
+pub const DESKTOP_ENTRY: &str = "Desktop Entry";
+
+/// A freedesktop.org key file, such as a .desktop file. Lines are kept as
+/// they are, so comments, group order, localized keys (`Name[de]`) and keys
+/// aipkg does not know survive a round trip.
+#[derive(Debug, Clone, Default)]
+pub struct KeyFile {
+    /// Comments and blank lines before the first group
+    header: Vec<String>,
+    groups: Vec<Group>,
+}
+
+#[derive(Debug, Clone)]
+struct Group {
+    name: String,
+    lines: Vec<Line>,
+}
+
+#[derive(Debug, Clone)]
+enum Line {
+    /// Key, including any locale, and the value as written in the file
+    Entry { key: String, value: String },
+    /// Comment or blank line
+    Other(String),
+}
+
+enum ParsedLine<'a> {
+    Group(&'a str),
+    Entry(&'a str, &'a str),
+    Other,
+}
+
+fn parse_line(line: &str) -> Option<ParsedLine<'_>> {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with('#') {
+        return Some(ParsedLine::Other);
+    }
+    if let Some(name) = trimmed.strip_prefix('[').and_then(|rest| rest.strip_suffix(']')) {
+        let valid = !name.is_empty() && !name.contains(['[', ']']) && name.chars().all(|c| !c.is_control());
+        return valid.then_some(ParsedLine::Group(name));
+    }
+
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    let (name, locale) = match key.split_once('[') {
+        Some((name, locale)) => (name, Some(locale.strip_suffix(']')?)),
+        None => (key, None),
+    };
+    // Desktop entries only use A-Za-z0-9-, but mimeapps.list and
+    // mimeinfo.cache have MIME types such as "text/x-c++src" as keys
+    let valid_name = !name.is_empty() && name.chars().all(|c| c.is_ascii_graphic() && !matches!(c, '[' | ']' | '='));
+    let valid_locale = locale.is_none_or(|locale| !locale.is_empty() && !locale.contains(['[', ']', '=']));
+    (valid_name && valid_locale).then_some(ParsedLine::Entry(key, value.trim_start()))
+}
+
+impl KeyFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses a key file, failing on lines that are neither a group header,
+    /// an entry, a comment nor blank, on entries before the first group and
+    /// on duplicate groups.
+    pub fn parse(content: &str) -> Result<Self> {
+        let (file, rest) = Self::parse_until_invalid(content, false);
+        if let Some((number, line)) = rest {
+            anyhow::bail!("Invalid line {} in key file: {}", number, line);
+        }
+        Ok(file)
+    }
+
+    /// Parses the key file at the start of `content` and stops at the first
+    /// line that does not belong to it, for desktop entries found in binary data.
+    pub fn parse_leading(content: &str) -> Self {
+        Self::parse_until_invalid(content, false).0
+    }
+
+    /// Parses a key file the way update-desktop-database reads installed
+    /// files: invalid lines, entries before the first group and duplicate
+    /// groups with their entries are skipped instead of failing.
+    pub fn parse_lenient(content: &str) -> Self {
+        Self::parse_until_invalid(content, true).0
+    }
+
+    fn parse_until_invalid(content: &str, lenient: bool) -> (Self, Option<(usize, String)>) {
+        let mut file = Self::new();
+        // Inside a duplicate group when lenient, whose lines are dropped
+        let mut skipping = false;
+        for (index, line) in content.lines().enumerate() {
+            let invalid = Some((index + 1, line.to_string()));
+            match parse_line(line) {
+                Some(ParsedLine::Group(name)) => {
+                    skipping = file.group(name).is_some();
+                    if !skipping {
+                        file.groups.push(Group { name: name.to_string(), lines: Vec::new() });
+                    } else if !lenient {
+                        return (file, invalid);
+                    }
+                }
+                _ if skipping => {}
+                Some(ParsedLine::Entry(key, value)) => match file.groups.last_mut() {
+                    Some(group) => group.lines.push(Line::Entry { key: key.to_string(), value: value.to_string() }),
+                    None if lenient => {}
+                    None => return (file, invalid),
+                },
+                Some(ParsedLine::Other) => match file.groups.last_mut() {
+                    Some(group) => group.lines.push(Line::Other(line.to_string())),
+                    None => file.header.push(line.to_string()),
+                },
+                None if lenient => {}
+                None => return (file, invalid),
+            }
+        }
+        (file, None)
+    }
+
+    fn group(&self, name: &str) -> Option<&Group> {
+        self.groups.iter().find(|group| group.name == name)
+    }
+
+    pub fn group_names(&self) -> Vec<String> {
+        self.groups.iter().map(|group| group.name.clone()).collect()
+    }
+
+    /// Unescaped value of `key`, which may include a locale like `Name[de]`.
+    pub fn get(&self, group: &str, key: &str) -> Option<String> {
+        self.group(group)?.lines.iter().find_map(|line| match line {
+            Line::Entry { key: k, value } if k == key => Some(unescape(value)),
+            _ => None,
+        })
+    }
+
+    /// Values of a list key such as `Categories=Graphics;Viewer;`.
+    pub fn get_list(&self, group: &str, key: &str) -> Option<Vec<String>> {
+        self.group(group)?.lines.iter().find_map(|line| match line {
+            Line::Entry { key: k, value } if k == key => Some(split_list(value)),
+            _ => None,
+        })
+    }
+
+    /// Keys of `group` in file order, including localized ones.
+    pub fn keys(&self, group: &str) -> Vec<String> {
+        self.group(group).map_or_else(Vec::new, |group| {
+            group.lines.iter()
+                .filter_map(|line| match line {
+                    Line::Entry { key, .. } => Some(key.clone()),
+                    Line::Other(_) => None,
+                })
+                .collect()
+        })
+    }
+
+    /// Index of `name`, appending an empty group if there is none.
+    pub fn add_group(&mut self, name: &str) -> usize {
+        match self.groups.iter().position(|g| g.name == name) {
+            Some(index) => index,
+            None => {
+                self.groups.push(Group { name: name.to_string(), lines: Vec::new() });
+                self.groups.len() - 1
+            }
+        }
+    }
+
+    /// Sets `key` in place, or appends it to `group`, creating the group if needed.
+    pub fn set(&mut self, group: &str, key: &str, value: &str) {
+        self.set_escaped(group, key, escape(value));
+    }
+
+    fn set_escaped(&mut self, group: &str, key: &str, value: String) {
+        let index = self.add_group(group);
+        let group = &mut self.groups[index];
+
+        let existing = group.lines.iter_mut().find_map(|line| match line {
+            Line::Entry { key: k, value } if k == key => Some(value),
+            _ => None,
+        });
+        match existing {
+            Some(existing) => *existing = value,
+            None => {
+                // Before trailing blank lines, so groups stay visually separated
+                let end = group.lines.iter()
+                    .rposition(|line| !matches!(line, Line::Other(text) if text.trim().is_empty()))
+                    .map_or(0, |i| i + 1);
+                group.lines.insert(end, Line::Entry { key: key.to_string(), value });
+            }
+        }
+    }
+
+    pub fn set_list(&mut self, group: &str, key: &str, values: &[String]) {
+        // Separators inside values are escaped after the value, not twice
+        let value: String = values.iter().map(|v| format!("{};", escape(v).replace(';', "\\;"))).collect();
+        self.set_escaped(group, key, value);
+    }
+
+    /// Removes `key` and its localized variants from `group`.
+    pub fn remove(&mut self, group: &str, key: &str) {
+        if let Some(group) = self.groups.iter_mut().find(|g| g.name == group) {
+            group.lines.retain(|line| match line {
+                Line::Entry { key: k, .. } => k != key && !k.starts_with(&format!("{}[", key)),
+                Line::Other(_) => true,
+            });
+        }
+    }
+}
+
+impl fmt::Display for KeyFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.header {
+            writeln!(f, "{}", line)?;
+        }
+        for group in &self.groups {
+            writeln!(f, "[{}]", group.name)?;
+            for line in &group.lines {
+                match line {
+                    Line::Entry { key, value } => writeln!(f, "{}={}", key, value)?,
+                    Line::Other(text) => writeln!(f, "{}", text)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a string value: backslashes, newlines, tabs, carriage returns and
+/// a leading space.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            ' ' if i == 0 => escaped.push_str("\\s"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('s') => unescaped.push(' '),
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some('r') => unescaped.push('\r'),
+            Some('\\') => unescaped.push('\\'),
+            // Unknown escapes, like "\;" in lists, are kept for the caller
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut current = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(';') => current.push(';'),
+                Some(next) => {
+                    current.push('\\');
+                    current.push(next);
+                }
+                None => current.push('\\'),
+            },
+            ';' => values.push(unescape(&std::mem::take(&mut current))),
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        values.push(unescape(&current));
+    }
+    values.into_iter().filter(|v| !v.is_empty()).collect()
+}
+
+/// Characters that make an Exec argument need quoting.
+const EXEC_RESERVED: &[char] = &[' ', '\t', '\n', '"', '\'', '\\', '>', '<', '~', '|', '&', ';', '$', '*', '?', '#', '(', ')', '`'];
+
+fn quote_exec_arg(arg: &str) -> String {
+    if !arg.contains(EXEC_RESERVED) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from("\"");
+    for c in arg.chars() {
+        if matches!(c, '"' | '`' | '$' | '\\') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Replaces the program of an (unescaped) Exec value with `program`, keeping
+/// its arguments and field codes such as `%U`.
+pub fn replace_exec_program(exec: &str, program: &str) -> String {
+    let exec = exec.trim_start();
+    let rest = if let Some(quoted) = exec.strip_prefix('"') {
+        // Skip to the closing quote, honouring backslash escapes
+        let mut escaped = false;
+        let end = quoted.char_indices()
+            .find(|&(_, c)| {
+                let closing = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closing
+            })
+            .map_or(quoted.len(), |(i, _)| i + 1);
+        &quoted[end..]
+    } else {
+        exec.find([' ', '\t']).map_or("", |i| &exec[i..])
+    };
+    format!("{}{}", quote_exec_arg(program), rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESKTOP_FILE: &str = "\
+# Generated by the app's build
+[Desktop Entry]
+Type=Application
+Name=Viewer
+Name[de]=Betrachter
+Comment=Shows images
+# A comment inside the group
+Exec=viewer --new-window %U
+Categories=Graphics;Viewer;
+X-Custom-Key=kept as it is
+
+[Desktop Action new]
+Name=New Window
+Exec=viewer --new-window
+";
+
+    #[test]
+    fn round_trip_keeps_the_file_as_it_is() {
+        let entry = KeyFile::parse(DESKTOP_FILE).unwrap();
+        assert_eq!(entry.to_string(), DESKTOP_FILE);
+        assert_eq!(entry.group_names(), vec![DESKTOP_ENTRY, "Desktop Action new"]);
+        assert_eq!(entry.get(DESKTOP_ENTRY, "Name[de]").as_deref(), Some("Betrachter"));
+        assert_eq!(entry.get_list(DESKTOP_ENTRY, "Categories"), Some(vec!["Graphics".to_string(), "Viewer".to_string()]));
+        assert_eq!(entry.get("Desktop Action new", "Exec").as_deref(), Some("viewer --new-window"));
+    }
+
+    #[test]
+    fn edits_keep_the_rest_of_the_file() {
+        let mut entry = KeyFile::parse(DESKTOP_FILE).unwrap();
+        entry.set(DESKTOP_ENTRY, "Exec", "/opt/viewer.AppImage %U");
+        entry.set(DESKTOP_ENTRY, "TryExec", "/opt/viewer.AppImage");
+        entry.remove(DESKTOP_ENTRY, "Name");
+
+        let content = entry.to_string();
+        assert!(content.contains("Exec=/opt/viewer.AppImage %U\nCategories="));
+        // New keys go before the blank line that separates the groups
+        assert!(content.contains("X-Custom-Key=kept as it is\nTryExec=/opt/viewer.AppImage\n\n[Desktop Action new]"));
+        assert!(!content.contains("Name[de]="));
+        assert!(content.contains("Name=New Window"));
+        assert!(content.starts_with("# Generated by the app's build\n"));
+    }
+
+    #[test]
+    fn values_are_escaped_and_unescaped() {
+        let mut entry = KeyFile::new();
+        let value = " leading space, tab\t, newline\n, backslash \\ and \\s";
+        entry.set(DESKTOP_ENTRY, "Comment", value);
+        assert!(entry.to_string().contains("Comment=\\s"));
+        assert!(!entry.to_string().contains('\t'));
+
+        let parsed = KeyFile::parse(&entry.to_string()).unwrap();
+        assert_eq!(parsed.get(DESKTOP_ENTRY, "Comment").as_deref(), Some(value));
+    }
+
+    #[test]
+    fn list_values_with_separators_round_trip() {
+        let values = vec!["text/plain".to_string(), "a;b".to_string(), "back\\slash".to_string()];
+        let mut entry = KeyFile::new();
+        entry.set_list(DESKTOP_ENTRY, "MimeType", &values);
+
+        let parsed = KeyFile::parse(&entry.to_string()).unwrap();
+        assert_eq!(parsed.get_list(DESKTOP_ENTRY, "MimeType"), Some(values));
+    }
+
+    #[test]
+    fn invalid_files_are_rejected_and_leading_parse_stops() {
+        assert!(KeyFile::parse("Name=before any group\n").is_err());
+        assert!(KeyFile::parse("[Desktop Entry]\n[Desktop Entry]\n").is_err());
+        assert!(KeyFile::parse("[Desktop Entry]\nnot an entry\n").is_err());
+
+        let entry = KeyFile::parse_leading("[Desktop Entry]\nName=App\n\u{1}binary data\nName=Other\n");
+        assert_eq!(entry.get(DESKTOP_ENTRY, "Name").as_deref(), Some("App"));
+        assert_eq!(entry.to_string(), "[Desktop Entry]\nName=App\n");
+    }
+
+    #[test]
+    fn lenient_parse_skips_invalid_lines_and_duplicate_groups() {
+        let entry = KeyFile::parse_lenient(
+            "Name=before any group\n[Desktop Entry]\nnot an entry\nMimeType=text/plain;\n[Desktop Entry]\nMimeType=image/png;\n[Other]\nKey=value\n",
+        );
+        assert_eq!(entry.get_list(DESKTOP_ENTRY, "MimeType"), Some(vec!["text/plain".to_string()]));
+        assert_eq!(entry.get("Other", "Key").as_deref(), Some("value"));
+        assert_eq!(entry.group_names(), vec![DESKTOP_ENTRY, "Other"]);
+    }
+
+    #[test]
+    fn mime_types_are_valid_keys() {
+        let mimeapps = KeyFile::parse("[Default Applications]\ntext/x-c++src=editor.desktop;\n").unwrap();
+        assert_eq!(mimeapps.keys("Default Applications"), vec!["text/x-c++src"]);
+    }
+
+    #[test]
+    fn replace_exec_program_quotes_the_program_and_keeps_arguments() {
+        let cases = [
+            ("app %U", "/opt/app.AppImage", "/opt/app.AppImage %U"),
+            ("app", "/opt/app.AppImage", "/opt/app.AppImage"),
+            ("  app --flag \"quoted arg\" %f", "/opt/app.AppImage", "/opt/app.AppImage --flag \"quoted arg\" %f"),
+            ("\"/usr/bin/my app\" %F", "/opt/app.AppImage", "/opt/app.AppImage %F"),
+            ("\"/usr/bin/say \\\"hi\\\"\" --x", "/opt/app.AppImage", "/opt/app.AppImage --x"),
+            ("app %U", "/home/me/My Apps/app.AppImage", "\"/home/me/My Apps/app.AppImage\" %U"),
+            ("app", "/opt/$HOME`x`\\\"q\".AppImage", "\"/opt/\\$HOME\\`x\\`\\\\\\\"q\\\".AppImage\""),
+            ("", "/opt/app.AppImage", "/opt/app.AppImage"),
+        ];
+        for (exec, program, expected) in cases {
+            assert_eq!(replace_exec_program(exec, program), expected, "{:?}", exec);
+        }
+    }
+}
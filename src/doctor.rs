@@ -0,0 +1,227 @@
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::config::Config;
+use crate::desktop_entry::{KeyFile, DESKTOP_ENTRY};
+use crate::install::{load_database, lock_database, save_database, PackageDatabase, GENERATED_BY_KEY};
+
+#[derive(Debug)]
+enum Problem {
+    /// Database entry whose AppImage no longer exists
+    MissingAppImage { name: String, path: PathBuf },
+    /// Installed AppImage without the executable bit
+    NotExecutable(PathBuf),
+    /// Symlink in bin_dir pointing to a missing AppImage
+    BrokenSymlink(PathBuf),
+    /// Desktop file generated by aipkg for a package that is not installed
+    OrphanedDesktopFile(PathBuf),
+    /// Directory under appimages_dir not referenced by the database
+    UntrackedDirectory(PathBuf),
+}
+
+impl Problem {
+    fn description(&self) -> String {
+        match self {
+            Problem::MissingAppImage { name, path } => {
+                format!("{} is installed but {} is missing", name, path.display())
+            }
+            Problem::NotExecutable(path) => format!("{} is not executable", path.display()),
+            Problem::BrokenSymlink(path) => format!("{} is a broken symlink", path.display()),
+            Problem::OrphanedDesktopFile(path) => {
+                format!("{} belongs to no installed package", path.display())
+            }
+            Problem::UntrackedDirectory(path) => {
+                format!("{} is not tracked by the package database", path.display())
+            }
+        }
+    }
+
+    fn suggestion(&self) -> String {
+        match self {
+            Problem::MissingAppImage { name, .. } => {
+                format!("remove {} from the database, then reinstall it with 'aipkg -S {}'", name, name)
+            }
+            Problem::NotExecutable(_) => "set mode 0755".to_string(),
+            Problem::BrokenSymlink(_) => "remove the symlink".to_string(),
+            Problem::OrphanedDesktopFile(_) => "remove the desktop file".to_string(),
+            Problem::UntrackedDirectory(_) => "remove the directory".to_string(),
+        }
+    }
+
+    async fn fix(&self, db: &mut PackageDatabase) -> Result<()> {
+        match self {
+            Problem::MissingAppImage { name, .. } => {
+                if let Some(pkg) = db.remove_package(name) {
+                    remove_symlink_if_present(&pkg.symlink).await?;
+                    remove_file_if_present(&pkg.desktop_file).await?;
+                }
+            }
+            Problem::NotExecutable(path) => {
+                #[cfg(unix)]
+                {
+                    use std::os::unix::fs::PermissionsExt;
+                    let mut perms = fs::metadata(path).await?.permissions();
+                    perms.set_mode(0o755);
+                    fs::set_permissions(path, perms).await?;
+                }
+            }
+            Problem::BrokenSymlink(path) => {
+                remove_symlink_if_present(path).await?;
+            }
+            Problem::OrphanedDesktopFile(path) => {
+                remove_file_if_present(path).await?;
+            }
+            Problem::UntrackedDirectory(path) => {
+                fs::remove_dir_all(path).await
+                    .context(format!("Failed to remove directory: {}", path.display()))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn remove_file_if_present(path: &Path) -> Result<()> {
+    // symlink_metadata so broken symlinks count as present
+    if fs::symlink_metadata(path).await.is_ok() {
+        fs::remove_file(path).await
+            .context(format!("Failed to remove: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Removes `path` only if it is a symlink, leaving files that took its place.
+async fn remove_symlink_if_present(path: &Path) -> Result<()> {
+    if fs::symlink_metadata(path).await.is_ok_and(|m| m.is_symlink()) {
+        fs::remove_file(path).await
+            .context(format!("Failed to remove: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+async fn find_problems(config: &Config, db: &PackageDatabase) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+
+    // Database entries and file modes
+    for pkg in db.list_packages() {
+        if !pkg.path.exists() {
+            problems.push(Problem::MissingAppImage {
+                name: pkg.name.clone(),
+                path: pkg.path.clone(),
+            });
+            continue;
+        }
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&pkg.path).await?.permissions().mode();
+            if mode & 0o111 == 0 {
+                problems.push(Problem::NotExecutable(pkg.path.clone()));
+            }
+        }
+    }
+
+    // Broken symlinks pointing into appimages_dir
+    if config.bin_dir.exists() {
+        let mut dir = fs::read_dir(&config.bin_dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if let Ok(target) = fs::read_link(&path).await {
+                let target = config.root_path(&target);
+                if target.starts_with(&config.appimages_dir) && !target.exists() {
+                    problems.push(Problem::BrokenSymlink(path));
+                }
+            }
+        }
+    }
+
+    // Desktop files that aipkg generated but no package references
+    let tracked_desktop_files: HashSet<&PathBuf> = db.list_packages()
+        .into_iter()
+        .map(|pkg| &pkg.desktop_file)
+        .collect();
+    if config.desktop_files_dir.exists() {
+        let appimages_dir = config.runtime_path(&config.appimages_dir).to_string_lossy().to_string();
+        let mut dir = fs::read_dir(&config.desktop_files_dir).await?;
+        while let Some(entry) = dir.next_entry().await? {
+            let path = entry.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("desktop")
+                || tracked_desktop_files.contains(&path)
+            {
+                continue;
+            }
+            let content = fs::read_to_string(&path).await.unwrap_or_default();
+            // Older versions did not mark their desktop files, only pointed Exec into appimages_dir
+            let generated = KeyFile::parse(&content).is_ok_and(|entry| entry.get(DESKTOP_ENTRY, GENERATED_BY_KEY).is_some())
+                || content.lines().any(|line| line.starts_with("Exec=") && line[5..].trim_start().starts_with(&appimages_dir));
+            if generated {
+                problems.push(Problem::OrphanedDesktopFile(path));
+            }
+        }
+    }
+
+    // Package and version directories nothing points to
+    let tracked_dirs: HashSet<PathBuf> = db.list_packages()
+        .into_iter()
+        .filter_map(|pkg| pkg.path.parent().map(Path::to_path_buf))
+        .collect();
+    if config.appimages_dir.exists() {
+        let mut packages_dir = fs::read_dir(&config.appimages_dir).await?;
+        while let Some(package_entry) = packages_dir.next_entry().await? {
+            let package_dir = package_entry.path();
+            if !package_dir.is_dir() {
+                continue;
+            }
+            if !tracked_dirs.iter().any(|dir| dir.starts_with(&package_dir)) {
+                problems.push(Problem::UntrackedDirectory(package_dir));
+                continue;
+            }
+            let mut versions_dir = fs::read_dir(&package_dir).await?;
+            while let Some(version_entry) = versions_dir.next_entry().await? {
+                let version_dir = version_entry.path();
+                if version_dir.is_dir() && !tracked_dirs.contains(&version_dir) {
+                    problems.push(Problem::UntrackedDirectory(version_dir));
+                }
+            }
+        }
+    }
+
+    Ok(problems)
+}
+
+pub async fn doctor(fix: bool) -> Result<()> {
+    let config = Config::new()?;
+    let mut db = load_database(&config).await?;
+
+    let problems = find_problems(&config, &db).await?;
+
+    if problems.is_empty() {
+        println!("No problems found");
+        return Ok(());
+    }
+
+    if !fix {
+        println!("Found {} problem(s):", problems.len());
+        for problem in &problems {
+            println!("  {}", problem.description());
+            println!("    fix: {}", problem.suggestion());
+        }
+        println!("Run 'aipkg doctor --fix' to apply the suggested fixes");
+        return Ok(());
+    }
+    config.check_privileges()?;
+    let _lock = lock_database(&config).await?;
+
+    for problem in &problems {
+        problem.fix(&mut db).await?;
+        println!("Fixed: {} ({})", problem.description(), problem.suggestion());
+    }
+
+    // Update database
+    save_database(&config, &db).await?;
+
+    println!("Fixed {} problem(s)", problems.len());
+    Ok(())
+}
@@ -0,0 +1,650 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+
+use crate::config::Config;
+use crate::install::{
+    install_recorded, load_database, lock_database, recorded_entry, resolve_url_download, InstalledPackage,
+    PackageDatabase,
+};
+use crate::pkg_cache;
+use crate::repo::fetcher::Fetcher;
+use crate::repo::github::GithubReleaseUrl;
+use crate::verify::is_sha256;
+use crate::version;
+
+/// One transaction in the history log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub time: String,
+    pub action: String,
+    pub user: String,
+    pub command: String,
+    /// "ok", or the error that ended the transaction
+    pub result: String,
+    pub changes: Vec<PackageChange>,
+}
+
+/// A package before and after a transaction; `None` when it was not installed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageChange {
+    pub name: String,
+    pub old: Option<PackageState>,
+    pub new: Option<PackageState>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageState {
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<String>,
+    /// Where an adopted AppImage was moved from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adopted_from: Option<PathBuf>,
+    #[serde(skip)]
+    installed_at: String,
+}
+
+impl PackageState {
+    fn from_installed(pkg: &InstalledPackage) -> Self {
+        Self {
+            version: pkg.version.clone(),
+            sha256: pkg.sha256.clone(),
+            source_url: pkg.source_url.clone(),
+            origin_url: pkg.origin_url.clone(),
+            adopted_from: pkg.adopted_from.clone(),
+            installed_at: pkg.installed_at.clone(),
+        }
+    }
+
+    fn source(&self) -> Option<&str> {
+        self.origin_url.as_deref().or(self.source_url.as_deref())
+    }
+
+    fn same_appimage(&self, other: &PackageState) -> bool {
+        self.version == other.version && self.sha256 == other.sha256
+    }
+}
+
+impl PackageChange {
+    fn describe(&self) -> String {
+        let source = |state: &PackageState| state.source()
+            .map(|source| format!(" ({})", source))
+            .unwrap_or_default();
+        match (&self.old, &self.new) {
+            (None, Some(new)) => format!("install {} {}{}", self.name, new.version, source(new)),
+            (Some(old), None) => format!("remove {} {}", self.name, old.version),
+            (Some(old), Some(new)) if old.same_appimage(new) => {
+                format!("reinstall {} {}{}", self.name, new.version, source(new))
+            }
+            (Some(old), Some(new)) => {
+                let kind = match version::compare(&new.version, &old.version) {
+                    Some(std::cmp::Ordering::Less) => "downgrade",
+                    _ => "upgrade",
+                };
+                format!("{} {} {} -> {}{}", kind, self.name, old.version, new.version, source(new))
+            }
+            (None, None) => self.name.clone(),
+        }
+    }
+}
+
+/// Packages that differ between two snapshots of the database.
+fn diff(before: &PackageDatabase, after: &PackageDatabase) -> Vec<PackageChange> {
+    let states = |db: &PackageDatabase| -> HashMap<String, PackageState> {
+        db.list_packages().into_iter()
+            .map(|pkg| (pkg.name.clone(), PackageState::from_installed(pkg)))
+            .collect()
+    };
+    let (before, after) = (states(before), states(after));
+
+    let names: BTreeSet<&String> = before.keys().chain(after.keys()).collect();
+    names.into_iter()
+        .filter(|name| before.get(*name) != after.get(*name))
+        .map(|name| PackageChange {
+            name: name.clone(),
+            old: before.get(name).cloned(),
+            new: after.get(name).cloned(),
+        })
+        .collect()
+}
+
+async fn load_history(config: &Config) -> Result<Vec<HistoryEntry>> {
+    if !config.history_file.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&config.history_file).await
+        .context("Failed to read history log")?;
+    let entries: Vec<HistoryEntry> = content.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).context("Failed to parse history log"))
+        .collect::<Result<_>>()?;
+    for entry in &entries {
+        for change in &entry.changes {
+            let mut states = change.old.iter().chain(&change.new);
+            if states.any(|state| state.sha256.as_deref().is_some_and(|sha256| !is_sha256(sha256))) {
+                anyhow::bail!("Invalid SHA256 for {} in transaction #{} of the history log", change.name, entry.id);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+async fn append_history(config: &Config, mut entry: HistoryEntry) -> Result<()> {
+    // Ids are only unique if no other process appends in between
+    let _lock = lock_database(config).await?;
+    entry.id = load_history(config).await?.last().map_or(1, |last| last.id + 1);
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+
+    if let Some(dir) = config.history_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.history_file).await
+        .context("Failed to open history log")?;
+    file.write_all(line.as_bytes()).await
+        .context("Failed to write history log")?;
+    // Finish the write while the database lock is held
+    file.flush().await
+        .context("Failed to write history log")?;
+    Ok(())
+}
+
+/// Runs a transaction and appends the packages it changed to the history log.
+pub async fn record<F>(action: &str, transaction: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let config = Config::new()?;
+    config.check_privileges()?;
+    record_in(&config, action, transaction).await
+}
+
+async fn record_in<F>(config: &Config, action: &str, transaction: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let before = load_database(config).await?;
+
+    let result = transaction.await;
+
+    let changes = match load_database(config).await {
+        Ok(after) => diff(&before, &after),
+        Err(_) => Vec::new(),
+    };
+    // Transactions that failed before changing anything are still worth a line
+    if !changes.is_empty() || result.is_err() {
+        let entry = HistoryEntry {
+            id: 0,
+            time: chrono::Utc::now().to_rfc3339(),
+            action: action.to_string(),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .unwrap_or_else(|_| "unknown".to_string()),
+            command: std::env::args().collect::<Vec<_>>().join(" "),
+            result: match &result {
+                Ok(()) => "ok".to_string(),
+                Err(e) => format!("failed: {:#}", e),
+            },
+            changes,
+        };
+        if let Err(e) = append_history(config, entry).await {
+            println!("Warning: failed to record history: {:#}", e);
+        }
+    }
+
+    result
+}
+
+fn parse_since(since: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(since) {
+        return Ok(time.with_timezone(&chrono::Utc));
+    }
+    let date = chrono::NaiveDate::parse_from_str(since, "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("Invalid date: {} (expected YYYY-MM-DD or RFC 3339)", since))?;
+    Ok(date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+pub async fn show_history(since: Option<&str>, package: Option<&str>) -> Result<()> {
+    let config = Config::new()?;
+    let since = since.map(parse_since).transpose()?;
+
+    let entries: Vec<HistoryEntry> = load_history(&config).await?
+        .into_iter()
+        .filter(|entry| since.is_none_or(|since| {
+            chrono::DateTime::parse_from_rfc3339(&entry.time).is_ok_and(|time| time >= since)
+        }))
+        .filter(|entry| package.is_none_or(|name| entry.changes.iter().any(|c| c.name == name)))
+        .collect();
+
+    if entries.is_empty() {
+        println!("No transactions recorded");
+        return Ok(());
+    }
+
+    for entry in entries {
+        let time = chrono::DateTime::parse_from_rfc3339(&entry.time)
+            .map(|time| time.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+            .unwrap_or(entry.time.clone());
+        println!("#{} {} {} by {}: {}", entry.id, time, entry.action, entry.user, entry.result);
+        println!("    {}", entry.command);
+        for change in &entry.changes {
+            println!("    {}", change.describe());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reverts transaction `id`: packages it installed are removed and packages it
+/// upgraded or removed are restored from the versions kept in the package cache.
+/// AppImages it adopted are moved back to where they were found.
+///
+/// Every change is checked before any is applied. An undo that still fails
+/// partway is recorded with the changes it made, and running it again
+/// finishes the packages it did not get to.
+pub async fn undo(id: u64) -> Result<()> {
+    undo_transaction(&Config::new()?, id).await
+}
+
+async fn undo_transaction(config: &Config, id: u64) -> Result<()> {
+    let history = load_history(config).await?;
+    let entry = history.iter()
+        .find(|entry| entry.id == id)
+        .context(format!("No transaction #{} in the history", id))?;
+    if entry.changes.is_empty() {
+        anyhow::bail!("Transaction #{} changed no packages", id);
+    }
+
+    // Refuse to undo over later changes, and check every old version can be restored
+    let db = load_database(config).await?;
+    let same = |a: Option<&PackageState>, b: Option<&PackageState>| {
+        a.map(|a| (&a.version, &a.sha256)) == b.map(|b| (&b.version, &b.sha256))
+    };
+    let mut pending = Vec::new();
+    for change in &entry.changes {
+        let current = db.get_package(&change.name).map(PackageState::from_installed);
+        if same(current.as_ref(), change.new.as_ref()) {
+            pending.push(change);
+        } else if !same(current.as_ref(), change.old.as_ref()) {
+            anyhow::bail!(
+                "{} changed after transaction #{}, undo the later transactions first",
+                change.name, id
+            );
+        }
+        // Otherwise an earlier undo that failed partway already reverted it
+    }
+    if pending.is_empty() {
+        anyhow::bail!("Transaction #{} is already undone", id);
+    }
+
+    let mut urls = HashMap::new();
+    for change in &pending {
+        if let Some(old) = &change.old {
+            let cached = old.sha256.as_ref()
+                .is_some_and(|sha256| pkg_cache::is_cached(config, sha256));
+            if !cached && (old.sha256.is_none() || old.source().is_none()) {
+                anyhow::bail!("No copy of {} {} was kept, it cannot be restored", change.name, old.version);
+            }
+            let url = restore_url(config, old, cached).await
+                .context(format!("Cannot restore {} {}", change.name, old.version))?;
+            urls.insert(change.name.as_str(), url);
+        }
+        if let Some(from) = adopted_from(change) {
+            if fs::symlink_metadata(from).await.is_ok() {
+                anyhow::bail!("Cannot move {} back to {}: the path is in use", change.name, from.display());
+            }
+        }
+    }
+
+    for (done, change) in pending.iter().rev().enumerate() {
+        let result = match &change.old {
+            Some(old) => {
+                println!("Restoring {} {}", change.name, old.version);
+                let url = urls.remove(change.name.as_str()).flatten();
+                restore(config, &change.name, old, url.as_deref()).await
+            }
+            None => remove_installed(config, change, &db).await,
+        };
+        if let Err(e) = result {
+            if done == 0 {
+                return Err(e);
+            }
+            return Err(e.context(format!(
+                "Undo of transaction #{} stopped at {} after {} of {} changes, run 'aipkg history undo {}' again to finish it",
+                id, change.name, done, pending.len(), id
+            )));
+        }
+    }
+
+    println!("Undid transaction #{}", id);
+    Ok(())
+}
+
+/// Removes a package the transaction installed, returning an adopted AppImage first.
+async fn remove_installed(config: &Config, change: &PackageChange, db: &PackageDatabase) -> Result<()> {
+    if let (Some(from), Some(pkg)) = (adopted_from(change), db.get_package(&change.name)) {
+        return_adopted(&pkg.path, from).await?;
+    }
+    crate::uninstall::uninstall(config, &change.name).await
+}
+
+/// Where the AppImage that a change adopted was moved from.
+fn adopted_from(change: &PackageChange) -> Option<&Path> {
+    match (&change.old, &change.new) {
+        (None, Some(new)) => new.adopted_from.as_deref(),
+        _ => None,
+    }
+}
+
+/// Copies an adopted AppImage back to where adopt found it, before the
+/// uninstall removes its copy in appimages_dir.
+async fn return_adopted(installed: &Path, from: &Path) -> Result<()> {
+    if let Some(dir) = from.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    fs::copy(installed, from).await
+        .context(format!("Failed to move {} back to {}", installed.display(), from.display()))?;
+    println!("Moved {} back to {}", installed.display(), from.display());
+    Ok(())
+}
+
+/// The URL to download a previously installed version from when it is not
+/// cached. A GitHub release page is resolved to its AppImage asset.
+async fn restore_url(config: &Config, state: &PackageState, cached: bool) -> Result<Option<String>> {
+    let Some(source) = state.source() else {
+        return Ok(None);
+    };
+    if !cached && GithubReleaseUrl::parse(source).is_some_and(|release| release.asset.is_none()) {
+        let fetcher = Fetcher::new(config)?;
+        return Ok(Some(resolve_url_download(config, &fetcher, source, false).await?.url));
+    }
+    Ok(Some(source.to_string()))
+}
+
+/// Installs a previously installed version again, from the package cache or
+/// the URL found by `restore_url`.
+async fn restore(config: &Config, name: &str, state: &PackageState, url: Option<&str>) -> Result<()> {
+    let sha256 = state.sha256.as_deref().unwrap_or_default();
+    let entry = recorded_entry(name, &state.version, sha256, url);
+    install_recorded(config, &entry, state.source_url.clone(), state.origin_url.clone()).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::verify::sha256_hex;
+
+    async fn temp_config(dir: &tempfile::TempDir) -> Config {
+        let root = dir.path();
+        let mut config = Config::located(Scope::User).unwrap();
+        config.appimages_dir = root.join("appimages");
+        config.desktop_files_dir = root.join("applications");
+        config.mime_dir = root.join("mime");
+        config.mimeapps_file = root.join("mimeapps.list");
+        config.bin_dir = root.join("bin");
+        config.config_dir = root.join("config");
+        config.cache_dir = root.join("cache");
+        config.database_file = root.join("config/database.yaml");
+        config.history_file = root.join("config/history.jsonl");
+        config.keyring_file = root.join("config/keyring.gpg");
+        config.credentials_file = root.join("config/credentials.toml");
+        config.netrc_file = root.join("netrc");
+        config.ensure_directories().await.unwrap();
+        config
+    }
+
+    fn state(version: &str, sha256: char) -> PackageState {
+        PackageState {
+            version: version.to_string(),
+            sha256: Some(sha256.to_string().repeat(64)),
+            source_url: None,
+            origin_url: None,
+            adopted_from: None,
+            installed_at: String::new(),
+        }
+    }
+
+    /// Runs `transaction` and appends what it changed to the history, like
+    /// `record` does for the configured installation. Returns the entry's id.
+    async fn recorded(config: &Config, action: &str, transaction: impl Future<Output = Result<()>>) -> u64 {
+        let before = load_database(config).await.unwrap();
+        transaction.await.unwrap();
+        let after = load_database(config).await.unwrap();
+        append_history(config, HistoryEntry {
+            id: 0,
+            time: chrono::Utc::now().to_rfc3339(),
+            action: action.to_string(),
+            user: "test".to_string(),
+            command: action.to_string(),
+            result: "ok".to_string(),
+            changes: diff(&before, &after),
+        }).await.unwrap();
+        load_history(config).await.unwrap().last().unwrap().id
+    }
+
+    /// Puts a fake AppImage into the package cache and returns its entry.
+    async fn cached_app(config: &Config, name: &str, version: &str) -> crate::repo::appimage_yaml::AppImageEntryWithSource {
+        let data = format!("{} {} appimage", name, version).into_bytes();
+        let sha256 = sha256_hex(&data);
+        pkg_cache::store(config, name, version, &sha256, &data).await.unwrap();
+        recorded_entry(name, version, &sha256, None)
+    }
+
+    #[test]
+    fn changes_are_described_by_kind() {
+        let change = |old: Option<PackageState>, new: Option<PackageState>| PackageChange { name: "app".to_string(), old, new };
+        assert_eq!(change(None, Some(state("1.0", 'a'))).describe(), "install app 1.0");
+        assert_eq!(change(Some(state("1.0", 'a')), None).describe(), "remove app 1.0");
+        assert_eq!(change(Some(state("1.0", 'a')), Some(state("1.0", 'a'))).describe(), "reinstall app 1.0");
+        assert_eq!(change(Some(state("1.0", 'a')), Some(state("1.2", 'b'))).describe(), "upgrade app 1.0 -> 1.2");
+        assert_eq!(change(Some(state("1.2", 'b')), Some(state("1.0", 'a'))).describe(), "downgrade app 1.2 -> 1.0");
+    }
+
+    #[tokio::test]
+    async fn diff_lists_only_changed_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let app = cached_app(&config, "app", "1.0").await;
+        let tool = cached_app(&config, "tool", "1.0").await;
+        install_recorded(&config, &app, None, None).await.unwrap();
+        install_recorded(&config, &tool, None, None).await.unwrap();
+        let before = load_database(&config).await.unwrap();
+
+        let upgrade = cached_app(&config, "tool", "2.0").await;
+        install_recorded(&config, &upgrade, None, None).await.unwrap();
+        let editor = cached_app(&config, "editor", "1.0").await;
+        install_recorded(&config, &editor, None, None).await.unwrap();
+        let after = load_database(&config).await.unwrap();
+
+        let changes: Vec<String> = diff(&before, &after).iter().map(PackageChange::describe).collect();
+        assert_eq!(changes, vec!["install editor 1.0", "upgrade tool 1.0 -> 2.0"]);
+    }
+
+    #[tokio::test]
+    async fn undo_reverts_installs_and_removals() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let app = cached_app(&config, "app", "1.0").await;
+
+        let install = recorded(&config, "install", install_recorded(&config, &app, None, None)).await;
+        let remove = recorded(&config, "remove", crate::uninstall::uninstall(&config, "app")).await;
+        assert!(load_database(&config).await.unwrap().get_package("app").is_none());
+
+        // The install cannot be undone over the later removal
+        assert!(undo_transaction(&config, install).await.is_err());
+
+        undo_transaction(&config, remove).await.unwrap();
+        let db = load_database(&config).await.unwrap();
+        let pkg = db.get_package("app").unwrap();
+        assert_eq!(pkg.sha256.as_deref(), Some(app.entry.sha256.as_str()));
+        assert_eq!(fs::read(&pkg.path).await.unwrap(), b"app 1.0 appimage");
+
+        let installed = pkg.path.clone();
+        undo_transaction(&config, install).await.unwrap();
+        assert!(load_database(&config).await.unwrap().get_package("app").is_none());
+        assert!(!installed.exists());
+    }
+
+    #[tokio::test]
+    async fn undoing_an_adoption_moves_the_appimage_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let downloads = dir.path().join("Downloads");
+        fs::create_dir_all(&downloads).await.unwrap();
+        let original = downloads.join("tool.AppImage");
+        fs::write(&original, b"tool appimage").await.unwrap();
+
+        let adopt = recorded(&config, "adopt", crate::adopt::adopt_into(&config, &original.to_string_lossy(), false)).await;
+        let db = load_database(&config).await.unwrap();
+        let installed = db.get_package("tool").unwrap().path.clone();
+        assert!(installed.starts_with(&config.appimages_dir));
+        assert!(!original.exists());
+
+        undo_transaction(&config, adopt).await.unwrap();
+        assert_eq!(fs::read(&original).await.unwrap(), b"tool appimage");
+        assert!(load_database(&config).await.unwrap().get_package("tool").is_none());
+        assert!(!installed.exists());
+    }
+
+    #[tokio::test]
+    async fn undoing_an_adoption_in_place_keeps_the_appimage() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let original = dir.path().join("tool.AppImage");
+        fs::write(&original, b"tool appimage").await.unwrap();
+
+        let adopt = recorded(&config, "adopt", crate::adopt::adopt_into(&config, &original.to_string_lossy(), true)).await;
+        undo_transaction(&config, adopt).await.unwrap();
+        assert_eq!(fs::read(&original).await.unwrap(), b"tool appimage");
+        assert!(load_database(&config).await.unwrap().get_package("tool").is_none());
+    }
+
+    #[tokio::test]
+    async fn release_pages_are_resolved_before_restoring() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = temp_config(&dir).await;
+        config.github_api_url = server.url();
+        config.github_token = None;
+
+        let release = serde_json::json!({
+            "tag_name": "v1.0",
+            "assets": [{
+                "name": "app-1.0.AppImage",
+                "browser_download_url": format!("{}/download/app-1.0.AppImage", server.url()),
+            }],
+        });
+        server.mock("GET", "/repos/owner/app/releases/tags/v1.0")
+            .with_header("content-type", "application/json")
+            .with_body(release.to_string())
+            .create_async().await;
+        let download = server.mock("GET", "/download/app-1.0.AppImage")
+            .with_body("app 1.0 appimage")
+            .expect(1)
+            .create_async().await;
+
+        let app = cached_app(&config, "app", "1.0").await;
+        let origin = "https://github.com/owner/app/releases/tag/v1.0".to_string();
+        install_recorded(&config, &app, None, Some(origin.clone())).await.unwrap();
+        let remove = recorded(&config, "remove", crate::uninstall::uninstall(&config, "app")).await;
+        fs::remove_dir_all(&config.cache_dir).await.unwrap();
+
+        undo_transaction(&config, remove).await.unwrap();
+        let db = load_database(&config).await.unwrap();
+        let pkg = db.get_package("app").unwrap();
+        assert_eq!(pkg.sha256.as_deref(), Some(app.entry.sha256.as_str()));
+        assert_eq!(pkg.origin_url.as_deref(), Some(origin.as_str()));
+        download.assert_async().await;
+    }
+
+    /// Installs app and tool 1.0, then records upgrading both to 2.0.
+    async fn upgraded_pair(config: &Config, app_source: Option<String>) -> u64 {
+        let app = cached_app(config, "app", "1.0").await;
+        let tool = cached_app(config, "tool", "1.0").await;
+        install_recorded(config, &app, app_source, None).await.unwrap();
+        install_recorded(config, &tool, None, None).await.unwrap();
+        let app = cached_app(config, "app", "2.0").await;
+        let tool = cached_app(config, "tool", "2.0").await;
+        recorded(config, "upgrade", async {
+            install_recorded(config, &app, None, None).await?;
+            install_recorded(config, &tool, None, None).await
+        }).await
+    }
+
+    async fn installed_versions(config: &Config) -> Vec<(String, String)> {
+        let mut versions: Vec<_> = load_database(config).await.unwrap().list_packages().into_iter()
+            .map(|pkg| (pkg.name.clone(), pkg.version.clone()))
+            .collect();
+        versions.sort();
+        versions
+    }
+
+    fn versions(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(name, version)| (name.to_string(), version.to_string())).collect()
+    }
+
+    #[tokio::test]
+    async fn undo_changes_nothing_unless_every_change_can_be_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let upgrade = upgraded_pair(&config, None).await;
+
+        // app is undone last, and its old version has neither a cached copy nor a URL
+        let app = load_history(&config).await.unwrap().last().unwrap().changes[0].clone();
+        let sha256 = app.old.unwrap().sha256.unwrap();
+        fs::remove_file(pkg_cache::cached_path(&config, &sha256).unwrap()).await.unwrap();
+
+        let err = undo_transaction(&config, upgrade).await.unwrap_err();
+        assert_eq!(err.to_string(), "No copy of app 1.0 was kept, it cannot be restored");
+        assert_eq!(installed_versions(&config).await, versions(&[("app", "2.0"), ("tool", "2.0")]));
+    }
+
+    #[tokio::test]
+    async fn a_failed_undo_is_recorded_and_can_be_finished() {
+        let mut server = mockito::Server::new_async().await;
+        server.mock("GET", "/app-1.0.AppImage")
+            .with_status(404)
+            .create_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let source = format!("{}/app-1.0.AppImage", server.url());
+        let upgrade = upgraded_pair(&config, Some(source)).await;
+
+        // The URL passes the check, but the download fails after tool was restored
+        let app = load_history(&config).await.unwrap().last().unwrap().changes[0].clone();
+        let sha256 = app.old.unwrap().sha256.unwrap();
+        fs::remove_file(pkg_cache::cached_path(&config, &sha256).unwrap()).await.unwrap();
+
+        let err = record_in(&config, "undo", undo_transaction(&config, upgrade)).await.unwrap_err();
+        assert!(err.to_string().starts_with(&format!("Undo of transaction #{} stopped at app after 1 of 2 changes", upgrade)));
+        assert_eq!(installed_versions(&config).await, versions(&[("app", "2.0"), ("tool", "1.0")]));
+
+        let history = load_history(&config).await.unwrap();
+        let partial = history.last().unwrap();
+        assert_eq!(partial.action, "undo");
+        assert!(partial.result.starts_with("failed: Undo of transaction"));
+        let changes: Vec<String> = partial.changes.iter().map(PackageChange::describe).collect();
+        assert_eq!(changes, vec!["downgrade tool 2.0 -> 1.0"]);
+
+        // Running the undo again only restores what is left
+        cached_app(&config, "app", "1.0").await;
+        undo_transaction(&config, upgrade).await.unwrap();
+        assert_eq!(installed_versions(&config).await, versions(&[("app", "1.0"), ("tool", "1.0")]));
+        let err = undo_transaction(&config, upgrade).await.unwrap_err();
+        assert_eq!(err.to_string(), format!("Transaction #{} is already undone", upgrade));
+    }
+}
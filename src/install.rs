@@ -1,15 +1,23 @@
 use anyhow::{Context, Result};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::config::{Config, Scope};
 use crate::repo::appimage_yaml::{AppImageEntry, AppImageEntryWithSource, UnifiedIndex};
 use crate::repo::cache::load_unified_index;
-use crate::repo::fetcher::Fetcher;
-use crate::verify::{verify_sha256_bytes};
-use crate::utils::extract_metadata;
+use crate::repo::fetcher::{DownloadProgress, Fetcher, Validators};
+use crate::verify::{check_signature_policy, sha256_hex, verify_embedded_signature, verify_sha256_bytes};
+use crate::utils::{extract_metadata, write_atomic, ExtractedFiles};
+use crate::desktop_entry::{replace_exec_program, KeyFile, DESKTOP_ENTRY};
+use crate::mime;
+use crate::pkg_cache;
+use crate::plan::{Action, Plan, PlanItem};
+use crate::repo::forge::{self, ForgeRelease};
+use crate::repo::github::{self, GithubClient, GithubReleaseUrl};
+use crate::yaml_gen::extract_version_from_filename;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InstalledPackage {
@@ -19,6 +27,23 @@ pub struct InstalledPackage {
     pub desktop_file: PathBuf,
     pub symlink: PathBuf,
     pub installed_at: String,
+    /// SHA256 of the installed AppImage, recorded at install time
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// URL the AppImage was installed from, checked again on upgrade
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<String>,
+    /// ETag and Last-Modified of the origin URL, so upgrades only download
+    /// the AppImage again once the server reports a change
+    #[serde(default, skip_serializing_if = "Validators::is_empty")]
+    pub origin_validators: Validators,
+    /// Download URL of an AppImage installed from a repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// Where an adopted AppImage was before it was moved into appimages_dir,
+    /// so undoing the adoption can put it back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adopted_from: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,7 +52,7 @@ pub struct PackageDatabase {
 }
 
 impl PackageDatabase {
-    fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             packages: HashMap::new(),
         }
@@ -63,114 +88,298 @@ impl PackageDatabase {
         serde_yaml::to_string(self)
             .context("Failed to serialize package database")
     }
+
+    /// Applies `map` to the file paths of every package. Empty paths, such
+    /// as the symlink of a package that has none, stay empty.
+    fn map_paths(&mut self, map: impl Fn(&Path) -> PathBuf) {
+        let map = |path: &Path| if path.as_os_str().is_empty() { PathBuf::new() } else { map(path) };
+        for pkg in self.packages.values_mut() {
+            pkg.path = map(&pkg.path);
+            pkg.desktop_file = map(&pkg.desktop_file);
+            pkg.symlink = map(&pkg.symlink);
+            pkg.adopted_from = pkg.adopted_from.as_deref().map(map);
+        }
+    }
 }
 
 pub async fn install_from_file(path: &str) -> Result<()> {
     let config = Config::new()?;
     config.ensure_directories().await?;
     
+    install_local_appimage(&config, path, None, Validators::default(), None).await
+}
+
+/// Installs an AppImage file. `origin_url` and the validators of its response
+/// are recorded for AppImages that were downloaded from a URL, `version_hint`
+/// is used when no version is embedded.
+pub async fn install_local_appimage(
+    config: &Config,
+    path: &str,
+    origin_url: Option<String>,
+    origin_validators: Validators,
+    version_hint: Option<String>,
+) -> Result<()> {
     // Verify file exists
     if !Path::new(path).exists() {
         anyhow::bail!("File not found: {}", path);
     }
     
+    // Check embedded signature
+    let data = fs::read(path).await?;
+    let signature = verify_embedded_signature(config, &data).await?;
+    println!("Signature: {}", signature);
+    
     // Extract metadata
     let metadata = extract_metadata(path).await?;
+    check_signature_policy(config.signature_policy, &signature, &metadata.name)?;
     
-    // Create installation directory
-    let version_str = metadata.version.as_ref().map(|v| v.as_str()).unwrap_or("unknown");
-    let install_dir = config.appimages_dir
-        .join(&metadata.name)
-        .join(version_str);
-    fs::create_dir_all(&install_dir).await?;
-    
-    // Copy AppImage
-    let appimage_name = format!("{}.AppImage", metadata.name);
-    let target_path = install_dir.join(&appimage_name);
-    fs::copy(path, &target_path).await?;
-    
-    // Make executable
-    #[cfg(unix)]
-    {
-        use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&target_path).await?.permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&target_path, perms).await?;
-    }
-    
-    // Generate desktop file
-    let desktop_file = generate_desktop_file(&config, &metadata, &target_path).await?;
-    
-    // Create symlink
-    let symlink_path = config.bin_dir.join(&metadata.name);
-    if symlink_path.exists() {
-        fs::remove_file(&symlink_path).await?;
-    }
-    fs::symlink(&target_path, &symlink_path).await?;
-    
-    // Update database
-    let version = metadata.version.clone().unwrap_or_else(|| "unknown".to_string());
-    update_database(&config, InstalledPackage {
+    let version = metadata.version.clone()
+        .or(version_hint)
+        .unwrap_or_else(|| "unknown".to_string());
+    let staged = stage_appimage(config, &metadata.name, &version, &data).await?;
+    deploy_appimage(config, &staged, &metadata, InstalledPackage {
         name: metadata.name.clone(),
         version: version.clone(),
-        path: target_path.clone(),
-        desktop_file: desktop_file.clone(),
-        symlink: symlink_path.clone(),
+        path: PathBuf::new(),
+        desktop_file: PathBuf::new(),
+        symlink: PathBuf::new(),
         installed_at: chrono::Utc::now().to_rfc3339(),
+        sha256: Some(sha256_hex(&data)),
+        origin_url,
+        origin_validators,
+        source_url: None,
+        adopted_from: None,
     }).await?;
     
     println!("Installed {} {}", metadata.name, version);
     Ok(())
 }
 
-pub async fn install_from_repo(package: &str) -> Result<()> {
+/// An AppImage download resolved from a user-supplied URL.
+#[derive(Debug, Clone)]
+pub struct UrlDownload {
+    pub url: String,
+    pub file_name: String,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+    pub version: Option<String>,
+}
+
+/// Resolves a direct AppImage URL or a GitHub release URL to a download.
+/// With `latest`, GitHub URLs resolve to the matching asset of the newest release.
+pub async fn resolve_url_download(
+    config: &Config,
+    fetcher: &Fetcher,
+    url: &str,
+    latest: bool,
+) -> Result<UrlDownload> {
+    if let Some(gh) = GithubReleaseUrl::parse(url) {
+        let tag = if latest { None } else { gh.tag.as_deref() };
+        let client = GithubClient::new(config);
+        let release: ForgeRelease = client.fetch_release(fetcher, &gh.owner, &gh.repo, tag).await?.into();
+        
+        // Match the same asset across releases when a download URL was given
+        let pattern = match &gh.asset {
+            Some(asset) => {
+                let version = gh.tag.as_deref().map(|t| t.strip_prefix('v').unwrap_or(t)).unwrap_or("");
+                Some(github::asset_pattern(asset, version)?)
+            }
+            None => None,
+        };
+        
+        let asset = forge::select_appimage_asset(&release, pattern.as_ref())
+            .ok_or_else(|| anyhow::anyhow!(
+                "No AppImage asset found in {}/{} release {}", gh.owner, gh.repo, release.tag
+            ))?;
+        
+        return Ok(UrlDownload {
+            url: asset.url.clone(),
+            file_name: asset.name.clone(),
+            sha256: asset.sha256.clone(),
+            size: asset.size,
+            version: Some(release.version().to_string()),
+        });
+    }
+    
+    let parsed = url::Url::parse(url)?;
+    let file_name = parsed.path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("download.AppImage")
+        .to_string();
+    let version = Some(extract_version_from_filename(&file_name))
+        .filter(|v| v != "unknown");
+    
+    Ok(UrlDownload {
+        url: url.to_string(),
+        file_name,
+        sha256: None,
+        size: None,
+        version,
+    })
+}
+
+/// Downloads a resolved URL into the cache, verifying `expected_sha256` or the
+/// checksum published alongside the download. Returns the file and the
+/// validators of the response.
+pub async fn download_url_to_file(
+    config: &Config,
+    fetcher: &Fetcher,
+    download: &UrlDownload,
+    expected_sha256: Option<&str>,
+) -> Result<(PathBuf, Validators)> {
+    let (data, validators) = fetcher.fetch_appimage_with_validators(&download.url, download.size).await?;
+    
+    if let Some(expected) = expected_sha256.or(download.sha256.as_deref()) {
+        if !verify_sha256_bytes(&data, expected)? {
+            anyhow::bail!("SHA256 verification failed for {}", download.url);
+        }
+    }
+    
+    let download_dir = config.cache_dir.join("downloads");
+    fs::create_dir_all(&download_dir).await?;
+    let path = download_dir.join(&download.file_name);
+    fs::write(&path, data).await?;
+    Ok((path, validators))
+}
+
+pub async fn install_from_url(url: &str, expected_sha256: Option<&str>) -> Result<()> {
     let config = Config::new()?;
     config.ensure_directories().await?;
     
-    // Load unified index
+    let fetcher = Fetcher::new(&config)?;
+    let download = resolve_url_download(&config, &fetcher, url, false).await?;
+    let (path, validators) = download_url_to_file(&config, &fetcher, &download, expected_sha256).await?;
+    
+    let result = install_local_appimage(
+        &config,
+        &path.to_string_lossy(),
+        Some(download.url.clone()),
+        validators,
+        download.version.clone(),
+    ).await;
+    
+    let _ = fs::remove_file(&path).await;
+    result
+}
+
+pub async fn install_from_repo(packages: &[String], download_only: bool, dry_run: bool) -> Result<()> {
+    let config = Config::new()?;
+    
+    // Load unified index and installed packages
     let index = load_unified_index().await?;
+    let db = load_database(&config).await?;
     
-    // Find best match (with fuzzy matching)
-    let entry = find_best_match(&index, package, None)?;
+    let transaction = plan_install(&index, &db, packages)?;
+    let plan = install_plan(&config, &db, &transaction);
+    if dry_run {
+        plan.print();
+        return Ok(());
+    }
+    config.ensure_directories().await?;
     
-    // Resolve dependencies
-    let dependencies = resolve_dependencies(&index, &entry.entry).await?;
+    let changes: Vec<_> = transaction.iter()
+        .filter(|(_, action)| *action != Action::Keep)
+        .collect();
+    let entries: Vec<_> = changes.iter().map(|(entry, _)| *entry).collect();
+    download_entries(&config, &entries).await?;
     
-    // Install dependencies sequentially to avoid conflicts
-    // Parallel installation could cause issues with shared resources
-    for dep in &dependencies {
-        println!("Installing dependency: {}", dep.entry.name);
-        install_appimage_entry(&config, dep, &index).await?;
+    if download_only {
+        println!("Downloaded {} package(s) to cache", entries.len());
+        return Ok(());
     }
     
-    // Install the requested package
-    println!("Installing: {}", entry.entry.name);
-    install_appimage_entry(&config, entry, &index).await?;
+    // Install sequentially, dependencies first, to avoid conflicts over shared files
+    for (entry, action) in changes {
+        match action {
+            Action::Upgrade { from } => {
+                println!("Upgrading {} from {} to {}", entry.entry.name, from, entry.entry.version);
+            }
+            Action::Downgrade { from } => {
+                println!("Downgrading {} from {} to {}", entry.entry.name, from, entry.entry.version);
+            }
+            _ if packages.iter().all(|p| p != &entry.entry.name) => {
+                println!("Installing dependency: {}", entry.entry.name);
+            }
+            _ => println!("Installing: {}", entry.entry.name),
+        }
+        install_appimage_entry(&config, entry, &index).await?;
+    }
     
     Ok(())
 }
 
-async fn resolve_dependencies<'a>(
+/// Resolves the requested packages and their dependencies into the ordered
+/// list of entries an install goes through. Dependencies that are already
+/// installed are kept as they are.
+pub fn plan_install<'a>(
     index: &'a UnifiedIndex,
-    entry: &AppImageEntry,
-) -> Result<Vec<&'a AppImageEntryWithSource>> {
-    let mut resolved = Vec::new();
+    db: &PackageDatabase,
+    packages: &[String],
+) -> Result<Vec<(&'a AppImageEntryWithSource, Action)>> {
+    let mut transaction: Vec<(&AppImageEntryWithSource, Action)> = Vec::new();
     let mut visited = HashSet::new();
-    let mut to_resolve = entry.dependencies.clone();
     
-    while let Some(dep_name) = to_resolve.pop() {
-        if visited.contains(&dep_name) {
-            continue;
+    for package in packages {
+        // Find best match (with fuzzy matching)
+        let entry = find_best_match(index, package, None)?;
+        
+        // Dependencies come first, so installing in this order is always safe
+        let mut resolved = Vec::new();
+        for dep_name in &entry.entry.dependencies {
+            visit_dependency(index, dep_name, &mut visited, &mut resolved);
+        }
+        for dep in resolved {
+            let action = match db.get_package(&dep.entry.name) {
+                Some(_) => Action::Keep,
+                None => Action::Install,
+            };
+            transaction.push((dep, action));
         }
-        visited.insert(dep_name.clone());
         
-        if let Some(dep_entry) = index.find_best_match(&dep_name, None) {
-            resolved.push(dep_entry);
-            to_resolve.extend(dep_entry.entry.dependencies.clone());
+        let action = match db.get_package(&entry.entry.name) {
+            Some(pkg) => Action::replacing(&pkg.version, &entry.entry.version),
+            None => Action::Install,
+        };
+        if visited.insert(entry.entry.name.clone()) {
+            transaction.push((entry, action));
+        } else if let Some(pulled_in) = transaction.iter_mut().find(|(e, _)| e.entry.name == entry.entry.name) {
+            // Already pulled in as a dependency of an earlier package
+            *pulled_in = (entry, action);
         }
     }
     
-    Ok(resolved)
+    Ok(transaction)
+}
+
+fn install_plan(config: &Config, db: &PackageDatabase, transaction: &[(&AppImageEntryWithSource, Action)]) -> Plan {
+    let mut plan = Plan::new();
+    for (entry, action) in transaction {
+        let installed = db.get_package(&entry.entry.name);
+        match (action, installed) {
+            (Action::Keep, Some(pkg)) => plan.push(PlanItem::for_installed(pkg, Action::Keep)),
+            _ => plan.push(PlanItem::for_entry(config, entry, action.clone(), installed)),
+        }
+    }
+    plan
+}
+
+fn visit_dependency<'a>(
+    index: &'a UnifiedIndex,
+    name: &str,
+    visited: &mut HashSet<String>,
+    resolved: &mut Vec<&'a AppImageEntryWithSource>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+    
+    if let Some(dep_entry) = index.find_best_match(name, None) {
+        for dep_name in &dep_entry.entry.dependencies {
+            visit_dependency(index, dep_name, visited, resolved);
+        }
+        resolved.push(dep_entry);
+    }
 }
 
 fn find_best_match<'a>(
@@ -188,32 +397,52 @@ fn find_best_match<'a>(
     use fuzzy_matcher::skim::SkimMatcherV2;
     
     let matcher = SkimMatcherV2::default();
-    let mut best_match: Option<(&String, &AppImageEntryWithSource, i64)> = None;
+    let mut best_match: Option<(&String, i64)> = None;
     
-    for (name, entries) in &index.apps {
-        for entry in entries {
-            if let Some(score) = matcher.fuzzy_match(name, query) {
-                if best_match.is_none() || score > best_match.unwrap().2 {
-                    best_match = Some((name, entry, score));
-                }
+    for name in index.apps.keys() {
+        if let Some(score) = matcher.fuzzy_match(name, query) {
+            // Ties go to the first name alphabetically, not to hash order
+            if best_match.is_none_or(|(best_name, best)| score > best || score == best && name < best_name) {
+                best_match = Some((name, score));
             }
         }
     }
     
-    if let Some((_, entry, _)) = best_match {
-        Ok(entry)
-    } else {
+    // The name decides the package, the version comes from the usual
+    // selection among its entries
+    let Some((name, _)) = best_match else {
         anyhow::bail!("Package not found: {}", query);
+    };
+    match index.find_best_match(name, version_req) {
+        Some(entry) => Ok(entry),
+        None => anyhow::bail!("No version of {} matches {}", name, version_req.unwrap_or("*")),
     }
 }
 
+/// Loads the package database. It records paths as seen from inside the
+/// root, so it stays valid once the root is the installed system; they are
+/// mapped to paths under the root here.
 pub async fn load_database(config: &Config) -> Result<PackageDatabase> {
     let content = if config.database_file.exists() {
         tokio::fs::read_to_string(&config.database_file).await?
     } else {
         String::new()
     };
-    PackageDatabase::load(&content)
+    let mut db = PackageDatabase::load(&content)?;
+    db.map_paths(|path| match config.root.as_deref() {
+        // Written before paths were recorded from inside the root
+        Some(root) if path.starts_with(root) => path.to_path_buf(),
+        _ => config.root_path(path),
+    });
+    Ok(db)
+}
+
+/// Writes the package database with paths as seen from inside the root.
+pub async fn save_database(config: &Config, db: &PackageDatabase) -> Result<()> {
+    let mut db = db.clone();
+    db.map_paths(|path| config.runtime_path(path));
+    write_atomic(&config.database_file, db.to_string()?.as_bytes()).await
+        .context("Failed to write package database")
 }
 
 pub async fn install_appimage_entry(
@@ -221,114 +450,614 @@ pub async fn install_appimage_entry(
     entry: &AppImageEntryWithSource,
     _index: &UnifiedIndex,
 ) -> Result<()> {
-    // Resolve download URL
-    let base_url = url::Url::parse(&entry.source_url)?;
-    let download_url = base_url.join(&entry.entry.file)?;
+    // Download AppImage (or reuse the cached copy)
+    let fetcher = Fetcher::new(config)?;
+    let appimage_data = download_appimage_entry(config, &fetcher, entry).await?;
     
-    // Download AppImage
-    let fetcher = Fetcher::new()?;
-    let appimage_data = fetcher.fetch_appimage(
-        download_url.as_str(),
-        entry.entry.size,
-    ).await?;
+    // Check embedded signature
+    let signature = verify_embedded_signature(config, &appimage_data).await?;
+    println!("Signature: {}", signature);
+    check_signature_policy(config.signature_policy, &signature, &entry.entry.name)?;
     
-    // Verify SHA256
-    if !verify_sha256_bytes(&appimage_data, &entry.entry.sha256)? {
-        anyhow::bail!("SHA256 verification failed for {}", entry.entry.name);
-    }
+    // Extract metadata for desktop file
+    let staged = stage_appimage(config, &entry.entry.name, &entry.entry.version, &appimage_data).await?;
+    let metadata = match extract_metadata(&staged.to_string_lossy()).await {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            discard_staged(&staged).await;
+            return Err(e);
+        }
+    };
     
-    // Create installation directory
-    let install_dir = config.appimages_dir
-        .join(&entry.entry.name)
-        .join(&entry.entry.version);
-    fs::create_dir_all(&install_dir).await?;
+    deploy_appimage(config, &staged, &metadata, InstalledPackage {
+        name: entry.entry.name.clone(),
+        version: entry.entry.version.clone(),
+        path: PathBuf::new(),
+        desktop_file: PathBuf::new(),
+        symlink: PathBuf::new(),
+        installed_at: chrono::Utc::now().to_rfc3339(),
+        sha256: Some(entry.entry.sha256.to_lowercase()),
+        origin_url: None,
+        origin_validators: Validators::default(),
+        source_url: url::Url::parse(&entry.source_url)
+            .and_then(|base| base.join(&entry.entry.file))
+            .map(|url| url.to_string())
+            .ok(),
+        adopted_from: None,
+    }).await
+}
+
+/// Index entry for an exact AppImage recorded in the history or a lockfile,
+/// found in the package cache by `sha256` or downloaded from `url`.
+pub fn recorded_entry(name: &str, version: &str, sha256: &str, url: Option<&str>) -> AppImageEntryWithSource {
+    let url = url.unwrap_or_default().to_string();
+    AppImageEntryWithSource {
+        entry: AppImageEntry {
+            name: name.to_string(),
+            version: version.to_string(),
+            file: url.clone(),
+            sha256: sha256.to_string(),
+            size: None,
+            description: None,
+            dependencies: Vec::new(),
+            provides: Vec::new(),
+            zsync: None,
+        },
+        source_url: url,
+    }
+}
+
+/// Installs a recorded AppImage and keeps the URLs it was recorded with, so
+/// upgrades of packages installed from a URL keep checking that URL.
+pub async fn install_recorded(
+    config: &Config,
+    entry: &AppImageEntryWithSource,
+    source_url: Option<String>,
+    origin_url: Option<String>,
+) -> Result<()> {
+    install_appimage_entry(config, entry, &UnifiedIndex::new()).await?;
     
-    // Save AppImage
-    let appimage_name = format!("{}.AppImage", entry.entry.name);
-    let target_path = install_dir.join(&appimage_name);
-    fs::write(&target_path, appimage_data).await?;
+    let db = load_database(config).await?;
+    if let Some(pkg) = db.get_package(&entry.entry.name) {
+        let mut pkg = pkg.clone();
+        pkg.source_url = source_url.or(pkg.source_url);
+        pkg.origin_url = origin_url;
+        update_database(config, pkg).await?;
+    }
+    Ok(())
+}
+
+/// Fails for a package name or version that cannot be used as a single
+/// directory name under appimages_dir, as release tags like "app/v1.2" or
+/// ".." from a forge could otherwise nest or escape the package directory.
+pub fn check_path_component(what: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\0']) {
+        anyhow::bail!("Invalid {} {:?}: not usable as a directory name", what, value);
+    }
+    Ok(())
+}
+
+/// Directory an AppImage is unpacked into before it replaces the installed version.
+fn staging_dir(config: &Config, name: &str, version: &str) -> PathBuf {
+    config.appimages_dir.join(name).join(version).join(".staging")
+}
+
+/// Writes a verified AppImage next to its final location, on the same
+/// filesystem, so it can be renamed into place.
+async fn stage_appimage(config: &Config, name: &str, version: &str, data: &[u8]) -> Result<PathBuf> {
+    check_path_component("package name", name)?;
+    check_path_component("version", version)?;
+    let staging_dir = staging_dir(config, name, version);
+    fs::create_dir_all(&staging_dir).await?;
+    let staged = staging_dir.join(format!("{}.AppImage", name));
+    fs::write(&staged, data).await?;
     
     // Make executable
     #[cfg(unix)]
     {
         use std::os::unix::fs::PermissionsExt;
-        let mut perms = fs::metadata(&target_path).await?.permissions();
-        perms.set_mode(0o755);
-        fs::set_permissions(&target_path, perms).await?;
+        fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o755)).await?;
     }
     
-    // Extract metadata for desktop file
-    let metadata = extract_metadata(target_path.to_str().unwrap()).await?;
+    Ok(staged)
+}
+
+/// Removes the staging directory, and the version and package directories
+/// above it when nothing else is in them, as when staging created them for
+/// an install that failed or moved the AppImage elsewhere.
+async fn discard_staged(staged: &Path) {
+    let Some(staging_dir) = staged.parent() else {
+        return;
+    };
+    let _ = fs::remove_dir_all(staging_dir).await;
+    // remove_dir only succeeds on empty directories; a failed switch may
+    // already have removed the version directory
+    for dir in staging_dir.ancestors().skip(1).take(2) {
+        match fs::remove_dir(dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => break,
+            _ => {}
+        }
+    }
+}
+
+/// Makes a staged AppImage the installed version of `pkg.name`. Each file is
+/// switched by a rename, the database last, and the previous version is only
+/// removed afterwards, so a failure leaves the previous install working.
+/// `pkg.path`, `pkg.desktop_file` and `pkg.symlink` are filled in here.
+async fn deploy_appimage(
+    config: &Config,
+    staged: &Path,
+    metadata: &crate::utils::AppImageMetadata,
+    mut pkg: InstalledPackage,
+) -> Result<()> {
+    let previous = load_database(config).await?.get_package(&pkg.name).cloned();
+    
+    let result = switch_to_staged(config, staged, metadata, &mut pkg).await;
+    discard_staged(staged).await;
+    result?;
     
-    // Generate desktop file
-    let desktop_file = generate_desktop_file(config, &metadata, &target_path).await?;
+    if let Some(previous) = previous {
+        if let Err(e) = remove_previous_install(config, &previous, &pkg).await {
+            println!("Warning: failed to clean up {} {}: {:#}", previous.name, previous.version, e);
+        }
+    }
+    // After the cleanup, so a desktop file renamed by the upgrade is not indexed
+    if let Err(e) = mime::register(config, &pkg.name, &pkg.path).await {
+        println!("Warning: failed to register file types of {}: {:#}", pkg.name, e);
+    }
+    warn_if_shadowed(config, &pkg.name).await;
+    Ok(())
+}
+
+/// User installs only take precedence over a system install of the same
+/// package if the user's bin directory comes first in PATH.
+async fn warn_if_shadowed(config: &Config, name: &str) {
+    if config.scope != Scope::User {
+        return;
+    }
+    let Ok(system) = Config::for_scope(Scope::System) else {
+        return;
+    };
+    if !load_database(&system).await.is_ok_and(|db| db.get_package(name).is_some()) {
+        return;
+    }
     
-    // Create symlink
-    let symlink_path = config.bin_dir.join(&entry.entry.name);
-    if symlink_path.exists() {
-        fs::remove_file(&symlink_path).await?;
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    let dirs: Vec<PathBuf> = std::env::split_paths(&path).collect();
+    let user_pos = dirs.iter().position(|dir| *dir == config.bin_dir);
+    let system_pos = dirs.iter().position(|dir| *dir == system.bin_dir);
+    if let Some(system_pos) = system_pos {
+        if user_pos.is_none_or(|user_pos| user_pos > system_pos) {
+            println!(
+                "Warning: {} is also installed system-wide and {} comes before {} in PATH, so the system version runs",
+                name, system.bin_dir.display(), config.bin_dir.display()
+            );
+        }
     }
-    fs::symlink(&target_path, &symlink_path).await?;
+}
+
+async fn switch_to_staged(
+    config: &Config,
+    staged: &Path,
+    metadata: &crate::utils::AppImageMetadata,
+    pkg: &mut InstalledPackage,
+) -> Result<()> {
+    let install_dir = config.appimages_dir.join(&pkg.name).join(&pkg.version);
+    let target_path = install_dir.join(format!("{}.AppImage", pkg.name));
+    let symlink_path = config.bin_dir.join(&pkg.name);
+    let desktop_path = desktop_file_path(config, &metadata.name);
     
-    // Update database
-    update_database(config, InstalledPackage {
-        name: entry.entry.name.clone(),
-        version: entry.entry.version.clone(),
-        path: target_path.clone(),
-        desktop_file: desktop_file.clone(),
-        symlink: symlink_path.clone(),
-        installed_at: chrono::Utc::now().to_rfc3339(),
-    }).await?;
+    // State to restore if the switch fails halfway
+    let reinstall = target_path.exists();
+    let previous_desktop = fs::read(&desktop_path).await.ok();
+    let previous_target = fs::read_link(&symlink_path).await.ok();
+    
+    // Move the AppImage into place, replacing it when reinstalling the same version
+    fs::rename(staged, &target_path).await
+        .context("Failed to move AppImage into place")?;
+    
+    let result = async {
+        // Point the symlink at the new version
+        replace_symlink(&config.runtime_path(&target_path), &symlink_path).await
+            .context("Failed to update symlink")?;
+        
+        // Generate desktop file
+        let desktop_file = generate_desktop_file(config, metadata, &target_path, &pkg.version).await?;
+        
+        // Update database
+        pkg.path = target_path.clone();
+        pkg.desktop_file = desktop_file;
+        pkg.symlink = symlink_path.clone();
+        update_database(config, pkg.clone()).await
+    }.await;
     
+    if result.is_err() {
+        let _ = match previous_desktop {
+            Some(content) => write_atomic(&desktop_path, &content).await,
+            None => fs::remove_file(&desktop_path).await.map_err(Into::into),
+        };
+        let _ = match previous_target {
+            Some(previous) => replace_symlink(&previous, &symlink_path).await,
+            None => fs::remove_file(&symlink_path).await.map_err(Into::into),
+        };
+        if !reinstall {
+            let _ = fs::remove_dir_all(&install_dir).await;
+        }
+    }
+    result
+}
+
+/// Creates the symlink next to `link` and renames it over `link`.
+async fn replace_symlink(target: &Path, link: &Path) -> Result<()> {
+    let file_name = link.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("link");
+    let tmp_link = link.with_file_name(format!(".{}.tmp", file_name));
+    if fs::symlink_metadata(&tmp_link).await.is_ok() {
+        fs::remove_file(&tmp_link).await?;
+    }
+    fs::symlink(target, &tmp_link).await?;
+    fs::rename(&tmp_link, link).await?;
+    Ok(())
+}
+
+/// Removes the version directory of an installed AppImage, keeping a copy in
+/// the package cache for undo. AppImages adopted in place outside
+/// appimages_dir belong to the user and stay where they are.
+pub async fn remove_installed_appimage(config: &Config, pkg: &InstalledPackage) -> Result<()> {
+    if !pkg.path.starts_with(&config.appimages_dir) || !pkg.path.exists() {
+        return Ok(());
+    }
+    pkg_cache::retain_installed(config, pkg).await?;
+    let appimage_dir = pkg.path.parent()
+        .context("Invalid package path")?;
+    fs::remove_dir_all(appimage_dir).await
+        .context("Failed to remove AppImage directory")
+}
+
+/// Removes the files of a replaced version that the new version does not use.
+async fn remove_previous_install(config: &Config, previous: &InstalledPackage, current: &InstalledPackage) -> Result<()> {
+    if previous.path != current.path {
+        remove_installed_appimage(config, previous).await?;
+    }
+    if previous.desktop_file != current.desktop_file && previous.desktop_file.exists() {
+        fs::remove_file(&previous.desktop_file).await?;
+    }
+    if previous.symlink != current.symlink && fs::symlink_metadata(&previous.symlink).await.is_ok_and(|m| m.is_symlink()) {
+        fs::remove_file(&previous.symlink).await?;
+    }
     Ok(())
 }
 
-async fn generate_desktop_file(
+/// Downloads the AppImages of a transaction into the package cache, several at
+/// a time, showing one progress bar per download and the overall total. The
+/// first failed download cancels the others.
+pub async fn download_entries(config: &Config, entries: &[&AppImageEntryWithSource]) -> Result<()> {
+    let missing: Vec<&AppImageEntryWithSource> = entries.iter()
+        .copied()
+        .filter(|entry| !pkg_cache::is_cached(config, &entry.entry.sha256))
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    
+    let progress = DownloadProgress::new(missing.iter().filter_map(|entry| entry.entry.size).sum());
+    let fetcher = Fetcher::new(config)?.with_progress(progress.clone());
+    
+    // Dropping the stream on the first error aborts the downloads still running
+    let result = stream::iter(&missing)
+        .map(Ok)
+        .try_for_each_concurrent(config.max_parallel_downloads, |entry| {
+            let fetcher = &fetcher;
+            async move {
+                download_appimage_entry(config, fetcher, entry).await
+                    .context(format!("Failed to download {} {}", entry.entry.name, entry.entry.version))
+                    .map(|_| ())
+            }
+        })
+        .await;
+    
+    progress.finish(result.is_ok());
+    result
+}
+
+/// Fetches the AppImage for `entry` from the package cache, downloading and
+/// caching it first if needed. The returned data is always SHA256-verified.
+pub async fn download_appimage_entry(
+    config: &Config,
+    fetcher: &Fetcher,
+    entry: &AppImageEntryWithSource,
+) -> Result<Vec<u8>> {
+    if let Some(data) = pkg_cache::get_cached(config, &entry.entry.sha256).await? {
+        println!("Using cached {} {}", entry.entry.name, entry.entry.version);
+        return Ok(data);
+    }
+    
+    // Resolve download URL
+    let base_url = url::Url::parse(&entry.source_url)?;
+    let download_url = base_url.join(&entry.entry.file)?;
+    
+    // Download AppImage
+    let appimage_data = fetcher.fetch_appimage(
+        download_url.as_str(),
+        entry.entry.size,
+    ).await?;
+    
+    // Verify SHA256
+    if !verify_sha256_bytes(&appimage_data, &entry.entry.sha256)? {
+        anyhow::bail!("SHA256 verification failed for {}", entry.entry.name);
+    }
+    
+    pkg_cache::store(
+        config,
+        &entry.entry.name,
+        &entry.entry.version,
+        &entry.entry.sha256,
+        &appimage_data,
+    ).await?;
+    
+    Ok(appimage_data)
+}
+
+fn desktop_file_path(config: &Config, name: &str) -> PathBuf {
+    config.desktop_files_dir.join(format!("{}.desktop", name))
+}
+
+/// Writes the desktop file of an installed AppImage. The desktop entry
+/// embedded in the AppImage is kept with all its groups, localized names,
+/// MIME types and actions; Exec and TryExec are pointed at the installed
+/// file, and X-AppImage-* keys mark the file as generated by aipkg.
+pub async fn generate_desktop_file(
     config: &Config,
     metadata: &crate::utils::AppImageMetadata,
     appimage_path: &Path,
+    version: &str,
 ) -> Result<PathBuf> {
-    let desktop_name = format!("{}.desktop", metadata.name);
-    let desktop_path = config.desktop_files_dir.join(&desktop_name);
-    
-    let exec_path = appimage_path.to_string_lossy().to_string();
-    let icon_path = metadata.icon.as_ref()
-        .map(|i| i.clone())
-        .unwrap_or_else(|| exec_path.clone());
-    
-    let desktop_content = format!(
-        "[Desktop Entry]\n\
-        Type=Application\n\
-        Name={}\n\
-        Exec={}\n\
-        Icon={}\n\
-        Categories={}\n\
-        Comment={}\n\
-        Terminal=false\n\
-        StartupNotify=true\n",
-        metadata.name,
-        exec_path,
-        icon_path,
-        metadata.categories.join(";"),
-        metadata.description.as_deref().unwrap_or(""),
-    );
-    
-    fs::write(&desktop_path, desktop_content).await?;
-    Ok(desktop_path)
-}
-
-async fn update_database(config: &Config, pkg: InstalledPackage) -> Result<()> {
-    let content = if config.database_file.exists() {
-        fs::read_to_string(&config.database_file).await?
+    let desktop_path = desktop_file_path(config, &metadata.name);
+    let exec_path = config.runtime_path(appimage_path).to_string_lossy().to_string();
+    
+    let mut entry = match &metadata.desktop_entry {
+        Some(entry) if entry.get(DESKTOP_ENTRY, "Name").is_some() => entry.clone(),
+        _ => {
+            let mut entry = KeyFile::new();
+            entry.set(DESKTOP_ENTRY, "Type", "Application");
+            entry.set(DESKTOP_ENTRY, "Name", &metadata.name);
+            if let Some(icon) = &metadata.icon {
+                entry.set(DESKTOP_ENTRY, "Icon", icon);
+            }
+            entry.set_list(DESKTOP_ENTRY, "Categories", &metadata.categories);
+            entry.set(DESKTOP_ENTRY, "Comment", metadata.description.as_deref().unwrap_or(""));
+            entry.set(DESKTOP_ENTRY, "Terminal", "false");
+            entry.set(DESKTOP_ENTRY, "StartupNotify", "true");
+            entry
+        }
+    };
+    
+    // Launch the installed AppImage, keeping arguments such as %U
+    for group in entry.group_names() {
+        if group != DESKTOP_ENTRY && !group.starts_with("Desktop Action ") {
+            continue;
+        }
+        let exec = entry.get(&group, "Exec").unwrap_or_default();
+        entry.set(&group, "Exec", &replace_exec_program(&exec, &exec_path));
+    }
+    if entry.get(DESKTOP_ENTRY, "TryExec").is_some() {
+        entry.set(DESKTOP_ENTRY, "TryExec", &exec_path);
+    }
+    // The icon named in the entry is only in the AppImage, not in any icon
+    // theme, so it is unpacked next to AppImages that aipkg manages
+    let icon = entry.get(DESKTOP_ENTRY, "Icon")
+        .filter(|icon| !icon.is_empty() && !icon.starts_with('/'));
+    let installed_icon = if appimage_path.starts_with(&config.appimages_dir) {
+        install_icon(appimage_path, icon.as_deref()).await
     } else {
-        String::new()
+        None
     };
+    match installed_icon {
+        Some(path) => entry.set(DESKTOP_ENTRY, "Icon", &config.runtime_path(&path).to_string_lossy()),
+        None if icon.is_none() => entry.set(DESKTOP_ENTRY, "Icon", &exec_path),
+        None => {}
+    }
+    // No D-Bus service file is installed for the app to be activated through
+    entry.remove(DESKTOP_ENTRY, "DBusActivatable");
     
-    let mut db = PackageDatabase::load(&content)?;
-    db.add_package(pkg);
+    entry.set(DESKTOP_ENTRY, "X-AppImage-Version", version);
+    entry.set(DESKTOP_ENTRY, "X-AppImage-Path", &exec_path);
+    entry.set(DESKTOP_ENTRY, GENERATED_BY_KEY, "aipkg");
     
-    let content = db.to_string()?;
-    fs::write(&config.database_file, content).await?;
-    Ok(())
+    write_atomic(&desktop_path, entry.to_string().as_bytes()).await
+        .context("Failed to write desktop file")?;
+    Ok(desktop_path)
+}
+
+/// Marks desktop files generated by aipkg.
+pub const GENERATED_BY_KEY: &str = "X-AppImage-Generated-By";
+
+/// File extensions of icons at the top of an AppImage
+const ICON_EXTENSIONS: &[&str] = &["png", "svg", "svgz", "xpm"];
+
+/// Unpacks the AppImage's icon next to it: the icon named by its desktop
+/// entry, which the AppImage format keeps at the top of the squashfs, or
+/// else .DirIcon. Returns the icon's path.
+async fn install_icon(appimage_path: &Path, icon: Option<&str>) -> Option<PathBuf> {
+    let extracted = ExtractedFiles::new().ok()?;
+
+    let mut found = None;
+    if let Some(icon) = icon.filter(|icon| !icon.contains(['/', '*', '?', '['])) {
+        if extracted.extract(appimage_path, &format!("{}.*", icon)).await {
+            for ext in ICON_EXTENSIONS {
+                if let Some(data) = extracted.read(&format!("{}.{}", icon, ext)).await {
+                    found = Some((data, *ext));
+                    break;
+                }
+            }
+        }
+    }
+    if found.is_none() && extracted.extract(appimage_path, ".DirIcon").await {
+        // Usually a symlink to the icon, which has to be unpacked as well
+        let mut dir_icon = ".DirIcon".to_string();
+        if let Some(target) = extracted.link_target(&dir_icon).await {
+            extracted.extract(appimage_path, &target).await;
+            dir_icon = target;
+        }
+        let data = extracted.read(&dir_icon).await?;
+        let ext = if data.starts_with(b"\x89PNG") {
+            "png"
+        } else if data.starts_with(b"/* XPM */") {
+            "xpm"
+        } else if String::from_utf8_lossy(&data[..data.len().min(1024)]).contains("<svg") {
+            "svg"
+        } else {
+            return None;
+        };
+        found = Some((data, ext));
+    }
+
+    let (data, ext) = found?;
+    let icon_path = appimage_path.with_extension(ext);
+    write_atomic(&icon_path, &data).await.ok()?;
+    // A reinstall may find an icon of another format than before
+    for other in ICON_EXTENSIONS.iter().filter(|other| **other != ext) {
+        let _ = fs::remove_file(appimage_path.with_extension(other)).await;
+    }
+    Some(icon_path)
 }
 
+/// Exclusive lock on the package database, released on drop.
+pub struct DatabaseLock {
+    _file: std::fs::File,
+}
+
+/// Locks the package database against other aipkg processes, for changes
+/// that read and then write database.yaml or the history log.
+pub async fn lock_database(config: &Config) -> Result<DatabaseLock> {
+    let path = config.database_file.with_extension("lock");
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .context(format!("Failed to open {}", path.display()))?;
+    let file = tokio::task::spawn_blocking(move || {
+        use std::os::fd::AsRawFd;
+        // SAFETY: the descriptor is open for the duration of the call
+        match unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } {
+            0 => Ok(file),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }).await?
+        .context("Failed to lock the package database")?;
+    Ok(DatabaseLock { _file: file })
+}
+
+pub async fn update_database(config: &Config, pkg: InstalledPackage) -> Result<()> {
+    let _lock = lock_database(config).await?;
+    let mut db = load_database(config).await?;
+    db.add_package(pkg);
+    save_database(config, &db).await
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+
+    fn installed(name: &str, path: PathBuf) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            version: "1.0".to_string(),
+            path,
+            desktop_file: PathBuf::new(),
+            symlink: PathBuf::new(),
+            installed_at: String::new(),
+            sha256: None,
+            origin_url: None,
+            origin_validators: Default::default(),
+            source_url: None,
+            adopted_from: None,
+        }
+    }
+
+    #[test]
+    fn names_and_versions_must_be_single_path_components() {
+        for valid in ["app", "1.2.0", "v1.2-beta+build", "..hidden"] {
+            assert!(check_path_component("version", valid).is_ok(), "{}", valid);
+        }
+        for invalid in ["", ".", "..", "app/v1.2", "../../etc", "/abs", "1.0\0"] {
+            assert!(check_path_component("version", invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn staging_refuses_versions_that_leave_the_package_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::located(Scope::User).unwrap();
+        config.appimages_dir = dir.path().join("appimages");
+
+        assert!(stage_appimage(&config, "app", "..", b"data").await.is_err());
+        assert!(stage_appimage(&config, "app", "app/v1.2", b"data").await.is_err());
+        assert!(stage_appimage(&config, "../app", "1.0", b"data").await.is_err());
+        assert!(!config.appimages_dir.exists());
+    }
+
+    #[tokio::test]
+    async fn database_under_a_root_records_paths_inside_it() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::located(Scope::System).unwrap();
+        config.root = Some(dir.path().to_path_buf());
+        config.database_file = dir.path().join("var/lib/aipkg/database.yaml");
+        fs::create_dir_all(config.database_file.parent().unwrap()).await.unwrap();
+
+        let mut pkg = installed("app", dir.path().join("opt/aipkg/appimages/app/1.0/app.AppImage"));
+        pkg.desktop_file = dir.path().join("usr/local/share/applications/aipkg-app.desktop");
+        pkg.adopted_from = Some(dir.path().join("srv/app.AppImage"));
+        let mut db = PackageDatabase::new();
+        db.add_package(pkg.clone());
+        db.add_package(installed("tool", PathBuf::from("/opt/aipkg/appimages/tool/1.0/tool.AppImage")));
+        save_database(&config, &db).await.unwrap();
+
+        let saved = PackageDatabase::load(&fs::read_to_string(&config.database_file).await.unwrap()).unwrap();
+        let app = saved.get_package("app").unwrap();
+        assert_eq!(app.path, Path::new("/opt/aipkg/appimages/app/1.0/app.AppImage"));
+        assert_eq!(app.desktop_file, Path::new("/usr/local/share/applications/aipkg-app.desktop"));
+        assert_eq!(app.symlink, Path::new(""));
+        assert_eq!(app.adopted_from.as_deref(), Some(Path::new("/srv/app.AppImage")));
+
+        let loaded = load_database(&config).await.unwrap();
+        let app = loaded.get_package("app").unwrap();
+        assert_eq!((&app.path, &app.desktop_file, &app.symlink), (&pkg.path, &pkg.desktop_file, &pkg.symlink));
+        assert_eq!(app.adopted_from, pkg.adopted_from);
+        assert_eq!(loaded.get_package("tool").unwrap().path, dir.path().join("opt/aipkg/appimages/tool/1.0/tool.AppImage"));
+    }
+
+    #[tokio::test]
+    async fn appimages_adopted_in_place_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::located(Scope::User).unwrap();
+        config.appimages_dir = dir.path().join("appimages");
+        config.cache_dir = dir.path().join("cache");
+
+        let managed_dir = config.appimages_dir.join("app/1.0");
+        fs::create_dir_all(&managed_dir).await.unwrap();
+        let managed = managed_dir.join("app.AppImage");
+        fs::write(&managed, b"managed").await.unwrap();
+        remove_installed_appimage(&config, &installed("app", managed.clone())).await.unwrap();
+        assert!(!managed_dir.exists());
+
+        let adopted = dir.path().join("Applications/tool.AppImage");
+        fs::create_dir_all(adopted.parent().unwrap()).await.unwrap();
+        fs::write(&adopted, b"adopted").await.unwrap();
+        let tool = installed("tool", adopted.clone());
+        remove_installed_appimage(&config, &tool).await.unwrap();
+        assert_eq!(fs::read(&adopted).await.unwrap(), b"adopted");
+
+        // Replacing the adopted version by an installed one keeps the file as well
+        let upgraded = installed("tool", config.appimages_dir.join("tool/2.0/tool.AppImage"));
+        remove_previous_install(&config, &tool, &upgraded).await.unwrap();
+        assert_eq!(fs::read(&adopted).await.unwrap(), b"adopted");
+    }
+}
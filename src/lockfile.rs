@@ -0,0 +1,376 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::config::Config;
+use crate::install::{self, download_entries, install_recorded, load_database, recorded_entry, PackageDatabase};
+use crate::repo::{self, cache::load_unified_index, load_collectives, load_sources};
+use crate::repo::appimage_yaml::{AppImageEntryWithSource, UnifiedIndex};
+use crate::repo::collectives::Collective;
+use crate::verify::is_sha256;
+
+/// Current lockfile format
+const LOCK_VERSION: u32 = 1;
+
+/// The installed package set of a machine, written by `aipkg export`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockFile {
+    pub lock_version: u32,
+    pub generated_at: String,
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub collectives: Vec<Collective>,
+    #[serde(default)]
+    pub packages: Vec<LockedPackage>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockedPackage {
+    pub name: String,
+    pub version: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Download URL of the AppImage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_url: Option<String>,
+    /// URL the package was installed from, for packages not from a repository
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin_url: Option<String>,
+}
+
+impl LockFile {
+    pub fn from_str(content: &str) -> Result<Self> {
+        let lock: Self = serde_yaml::from_str(content)
+            .context("Failed to parse lockfile")?;
+        if lock.lock_version > LOCK_VERSION {
+            anyhow::bail!("Lockfile version {} is newer than this aipkg supports", lock.lock_version);
+        }
+        for locked in &lock.packages {
+            if locked.sha256.as_deref().is_some_and(|sha256| !is_sha256(sha256)) {
+                anyhow::bail!("Invalid SHA256 for {} in lockfile", locked.name);
+            }
+        }
+        Ok(lock)
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        serde_yaml::to_string(self)
+            .context("Failed to serialize lockfile")
+    }
+}
+
+/// The installed packages as locked packages, sorted by name. `index` finds
+/// the download URL of packages installed before it was recorded.
+fn locked_packages(db: &PackageDatabase, index: Option<&UnifiedIndex>) -> Vec<LockedPackage> {
+    let mut packages: Vec<LockedPackage> = db.list_packages().into_iter()
+        .map(|pkg| {
+            // Installs from before download URLs were recorded are looked up by checksum
+            let source_url = pkg.source_url.clone().or_else(|| {
+                let entry = index?.find_by_sha256(pkg.sha256.as_deref()?)?;
+                let url = url::Url::parse(&entry.source_url).ok()?.join(&entry.entry.file).ok()?;
+                Some(url.to_string())
+            });
+            LockedPackage {
+                name: pkg.name.clone(),
+                version: pkg.version.clone(),
+                sha256: pkg.sha256.clone(),
+                source_url,
+                origin_url: pkg.origin_url.clone(),
+            }
+        })
+        .collect();
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    packages
+}
+
+/// Prints the lockfile of the installed packages to stdout.
+pub async fn export() -> Result<()> {
+    let config = Config::new()?;
+    let db = load_database(&config).await?;
+    let index = if config.unified_index_cache.exists() {
+        Some(load_unified_index().await?)
+    } else {
+        None
+    };
+
+    let lock = LockFile {
+        lock_version: LOCK_VERSION,
+        generated_at: chrono::Utc::now().to_rfc3339(),
+        sources: load_sources(&config).await?.sources,
+        collectives: load_collectives(&config).await?.collectives,
+        packages: locked_packages(&db, index.as_ref()),
+    };
+    print!("{}", lock.to_string()?);
+    Ok(())
+}
+
+/// Adds the sources and collectives of a lockfile that are not configured yet.
+/// Returns whether anything was added.
+async fn add_missing_sources(config: &Config, lock: &LockFile) -> Result<bool> {
+    let sources = load_sources(config).await?;
+    let collectives = load_collectives(config).await?;
+    let mut added = false;
+
+    for source in lock.sources.iter().filter(|s| !sources.sources.contains(s)) {
+        repo::add_source(source, None, None).await?;
+        added = true;
+    }
+    for collective in &lock.collectives {
+        let existing = collectives.collectives.iter().find(|c| c.name == collective.name);
+        let missing: Vec<String> = collective.sources.iter()
+            .filter(|s| existing.is_none_or(|c| !c.sources.contains(s)))
+            .cloned()
+            .collect();
+        if !missing.is_empty() {
+            repo::collectives::add_to_collective(&collective.name, missing).await?;
+            added = true;
+        }
+    }
+
+    Ok(added)
+}
+
+/// Installs the packages of a lockfile at their locked versions, or with
+/// `latest` the newest versions of the same packages.
+pub async fn import(path: &str, latest: bool) -> Result<()> {
+    let config = Config::new()?;
+    config.ensure_directories().await?;
+
+    let content = fs::read_to_string(path).await
+        .context(format!("Failed to read lockfile: {}", path))?;
+    let lock = LockFile::from_str(&content)?;
+
+    if add_missing_sources(&config, &lock).await? {
+        repo::update_database().await?;
+    }
+
+    if latest {
+        import_latest(&config, &lock).await
+    } else {
+        import_locked(&config, &lock).await
+    }
+}
+
+async fn import_latest(config: &Config, lock: &LockFile) -> Result<()> {
+    let db = load_database(config).await?;
+    let index = load_unified_index().await?;
+
+    let mut from_repo = Vec::new();
+    for locked in &lock.packages {
+        if db.get_package(&locked.name).is_some() {
+            println!("Skipping {}: already installed", locked.name);
+        } else if let Some(origin) = &locked.origin_url {
+            install::install_from_url(origin, None).await?;
+        } else if index.apps.contains_key(&locked.name) {
+            from_repo.push(locked.name.clone());
+        } else {
+            println!("Warning: {} is in no configured source, skipping", locked.name);
+        }
+    }
+
+    if !from_repo.is_empty() {
+        install::install_from_repo(&from_repo, false, false).await?;
+    }
+    Ok(())
+}
+
+/// Locked packages paired with the entry to install them from, and the ones
+/// that cannot be installed; installed packages with the locked checksum are
+/// left out.
+type LockedPlan<'a> = (Vec<(&'a LockedPackage, AppImageEntryWithSource)>, Vec<&'a LockedPackage>);
+
+/// Finds where to install each package of `lock` from: the repository entry
+/// with the locked checksum, else the recorded download URL or a copy in the
+/// package cache (`is_cached`).
+fn plan_locked<'a>(
+    lock: &'a LockFile,
+    db: &PackageDatabase,
+    index: &UnifiedIndex,
+    is_cached: impl Fn(&str) -> bool,
+) -> LockedPlan<'a> {
+    let mut entries = Vec::new();
+    let mut unavailable = Vec::new();
+    for locked in &lock.packages {
+        let Some(sha256) = &locked.sha256 else {
+            unavailable.push(locked);
+            continue;
+        };
+        if db.get_package(&locked.name).is_some_and(|pkg| pkg.sha256.as_ref() == Some(sha256)) {
+            continue;
+        }
+
+        // Prefer the repository entry, then the recorded download URL
+        let entry = match index.find_by_sha256(sha256) {
+            Some(entry) if entry.entry.name == locked.name => entry.clone(),
+            _ => {
+                let url = locked.source_url.as_deref().or(locked.origin_url.as_deref());
+                if url.is_none() && !is_cached(sha256) {
+                    unavailable.push(locked);
+                    continue;
+                }
+                recorded_entry(&locked.name, &locked.version, sha256, url)
+            }
+        };
+        entries.push((locked, entry));
+    }
+    (entries, unavailable)
+}
+
+async fn import_locked(config: &Config, lock: &LockFile) -> Result<()> {
+    let db = load_database(config).await?;
+    let index = if config.unified_index_cache.exists() {
+        load_unified_index().await?
+    } else {
+        UnifiedIndex::new()
+    };
+
+    let (entries, unavailable) = plan_locked(lock, &db, &index, |sha256| crate::pkg_cache::is_cached(config, sha256));
+
+    // Download and verify everything before installing anything
+    let to_download: Vec<_> = entries.iter().map(|(_, entry)| entry).collect();
+    download_entries(config, &to_download).await?;
+
+    for (locked, entry) in &entries {
+        println!("Installing {} {}", locked.name, locked.version);
+        install_recorded(config, entry, locked.source_url.clone(), locked.origin_url.clone()).await?;
+    }
+
+    if !unavailable.is_empty() {
+        for locked in &unavailable {
+            println!("Warning: cannot install {} {}: no checksum or download URL recorded", locked.name, locked.version);
+        }
+        anyhow::bail!("{} package(s) of the lockfile could not be installed", unavailable.len());
+    }
+
+    println!("Imported {} package(s), {} already installed", entries.len(), lock.packages.len() - entries.len() - unavailable.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::install::InstalledPackage;
+    use crate::repo::appimage_yaml::AppImageEntry;
+    use std::path::PathBuf;
+
+    fn installed(name: &str, version: &str, sha256: char) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            path: PathBuf::from(format!("/apps/{}/{}/{}.AppImage", name, version, name)),
+            desktop_file: PathBuf::new(),
+            symlink: PathBuf::new(),
+            installed_at: String::new(),
+            sha256: Some(sha256.to_string().repeat(64)),
+            origin_url: None,
+            origin_validators: Default::default(),
+            source_url: None,
+            adopted_from: None,
+        }
+    }
+
+    fn index_entry(name: &str, version: &str, sha256: char) -> AppImageEntry {
+        AppImageEntry {
+            name: name.to_string(),
+            version: version.to_string(),
+            file: format!("{}-{}.AppImage", name, version),
+            sha256: sha256.to_string().repeat(64),
+            size: None,
+            description: None,
+            dependencies: Vec::new(),
+            provides: Vec::new(),
+            zsync: None,
+        }
+    }
+
+    fn locked(name: &str, version: &str, sha256: Option<char>, source_url: Option<&str>) -> LockedPackage {
+        LockedPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            sha256: sha256.map(|c| c.to_string().repeat(64)),
+            source_url: source_url.map(str::to_string),
+            origin_url: None,
+        }
+    }
+
+    #[test]
+    fn exported_packages_survive_a_round_trip() {
+        let mut db = PackageDatabase::new();
+        let mut from_url = installed("tool", "2.0", 'b');
+        from_url.origin_url = Some("https://example.com/tool.AppImage".to_string());
+        db.add_package(from_url);
+        db.add_package(installed("app", "1.0", 'a'));
+        let mut index = UnifiedIndex::new();
+        index.add_entry(index_entry("app", "1.0", 'a'), "https://repo.example.com/apps/appimage.yaml".to_string());
+
+        let lock = LockFile {
+            lock_version: LOCK_VERSION,
+            generated_at: "2026-01-01T00:00:00Z".to_string(),
+            sources: vec!["https://repo.example.com/apps/appimage.yaml".to_string()],
+            collectives: Vec::new(),
+            packages: locked_packages(&db, Some(&index)),
+        };
+        let parsed = LockFile::from_str(&lock.to_string().unwrap()).unwrap();
+
+        let summary: Vec<(&str, &str, Option<&str>, Option<&str>)> = parsed.packages.iter()
+            .map(|p| (p.name.as_str(), p.version.as_str(), p.source_url.as_deref(), p.origin_url.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("app", "1.0", Some("https://repo.example.com/apps/app-1.0.AppImage"), None),
+            ("tool", "2.0", None, Some("https://example.com/tool.AppImage")),
+        ]);
+        assert_eq!(parsed.packages[0].sha256, Some("a".repeat(64)));
+        assert_eq!(parsed.sources, lock.sources);
+    }
+
+    #[test]
+    fn lockfiles_with_bad_checksums_or_newer_formats_are_refused() {
+        let lock = |version: u32, sha256: &str| format!(
+            "lock_version: {}\ngenerated_at: now\npackages:\n- name: app\n  version: '1.0'\n  sha256: {}\n",
+            version, sha256
+        );
+        assert!(LockFile::from_str(&lock(LOCK_VERSION, &"a".repeat(64))).is_ok());
+        assert!(LockFile::from_str(&lock(LOCK_VERSION, "../../etc/passwd")).is_err());
+        assert!(LockFile::from_str(&lock(LOCK_VERSION + 1, &"a".repeat(64))).is_err());
+    }
+
+    #[test]
+    fn import_installs_what_differs_from_the_best_source() {
+        let mut db = PackageDatabase::new();
+        db.add_package(installed("same", "1.0", 'a'));
+        db.add_package(installed("changed", "1.0", 'b'));
+        let mut index = UnifiedIndex::new();
+        index.add_entry(index_entry("changed", "0.9", 'c'), "https://repo.example.com/appimage.yaml".to_string());
+        // Same checksum under another name is not the locked package
+        index.add_entry(index_entry("other", "1.0", 'd'), "https://repo.example.com/appimage.yaml".to_string());
+
+        let lock = LockFile {
+            lock_version: LOCK_VERSION,
+            generated_at: String::new(),
+            sources: Vec::new(),
+            collectives: Vec::new(),
+            packages: vec![
+                locked("same", "1.0", Some('a'), None),
+                locked("changed", "0.9", Some('c'), None),
+                locked("renamed", "1.0", Some('d'), Some("https://mirror.example.com/renamed.AppImage")),
+                locked("cached", "1.0", Some('e'), None),
+                locked("lost", "1.0", Some('f'), None),
+                locked("unhashed", "1.0", None, Some("https://mirror.example.com/unhashed.AppImage")),
+            ],
+        };
+
+        let cached = "e".repeat(64);
+        let (entries, unavailable) = plan_locked(&lock, &db, &index, |sha256| sha256 == cached);
+        let planned: Vec<(&str, &str, &str)> = entries.iter()
+            .map(|(locked, entry)| (locked.name.as_str(), entry.entry.version.as_str(), entry.source_url.as_str()))
+            .collect();
+        assert_eq!(planned, vec![
+            ("changed", "0.9", "https://repo.example.com/appimage.yaml"),
+            ("renamed", "1.0", "https://mirror.example.com/renamed.AppImage"),
+            ("cached", "1.0", ""),
+        ]);
+        let unavailable: Vec<&str> = unavailable.iter().map(|locked| locked.name.as_str()).collect();
+        assert_eq!(unavailable, vec!["lost", "unhashed"]);
+    }
+}
@@ -2,6 +2,7 @@ use anyhow::Result;
 
 mod cli;
 mod config;
+mod config_edit;
 mod install;
 mod uninstall;
 mod upgrade;
@@ -9,7 +10,19 @@ mod repo;
 mod yaml_gen;
 mod verify;
 mod utils;
+mod desktop_entry;
 mod query;
+mod pkg_cache;
+mod doctor;
+mod adopt;
+mod zsync;
+mod version;
+mod plan;
+mod history;
+mod lockfile;
+mod manifest;
+mod mime;
+mod run;
 
 #[tokio::main]
 async fn main() -> Result<()> {
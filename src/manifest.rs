@@ -0,0 +1,543 @@
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::config::Config;
+use crate::install::{download_entries, install_appimage_entry, load_database, InstalledPackage, PackageDatabase};
+use crate::plan::{Action, Plan, PlanItem};
+use crate::repo::{self, appimage_yaml::{published_under, AppImageEntryWithSource, UnifiedIndex}, cache::load_unified_index, load_sources};
+use crate::version::satisfies;
+
+/// The desired package set of a machine, applied by `aipkg apply`:
+///
+/// ```toml
+/// sources = ["https://example.com/appimage.yaml"]
+///
+/// [packages]
+/// firefox = "*"
+/// krita = ">=5.1, <6"
+/// obsidian = { version = "1.4.*", hold = true }
+/// tool = { source = "https://tools.example.com/appimage.yaml" }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Sources added before resolving the packages
+    #[serde(default)]
+    pub sources: Vec<String>,
+    #[serde(default)]
+    pub packages: BTreeMap<String, PackageSpec>,
+}
+
+/// A version requirement, or a table with more options.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum PackageSpec {
+    Version(String),
+    Options(PackageOptions),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PackageOptions {
+    pub version: Option<String>,
+    /// Install if missing, but never upgrade or replace the installed version
+    #[serde(default)]
+    pub hold: bool,
+    /// Only install from this source
+    pub source: Option<String>,
+}
+
+impl PackageSpec {
+    pub fn version(&self) -> Option<&str> {
+        match self {
+            PackageSpec::Version(version) => Some(version.as_str()),
+            PackageSpec::Options(options) => options.version.as_deref(),
+        }
+        .filter(|version| version.trim() != "*")
+    }
+
+    pub fn hold(&self) -> bool {
+        matches!(self, PackageSpec::Options(options) if options.hold)
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        match self {
+            PackageSpec::Version(_) => None,
+            PackageSpec::Options(options) => options.source.as_deref(),
+        }
+    }
+}
+
+impl Manifest {
+    /// Parses TOML, or YAML for files ending in .yaml or .yml.
+    pub fn from_str(content: &str, path: &Path) -> Result<Self> {
+        let yaml = path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml");
+        if yaml {
+            serde_yaml::from_str(content)
+                .context(format!("Failed to parse manifest: {}", path.display()))
+        } else {
+            toml::from_str(content)
+                .context(format!("Failed to parse manifest: {}", path.display()))
+        }
+    }
+}
+
+/// What applying a manifest changes, in the order it is done.
+struct Changes<'a> {
+    installs: Vec<(&'a AppImageEntryWithSource, Action)>,
+    removals: Vec<&'a InstalledPackage>,
+    plan: Plan,
+    /// Packages that could not be resolved, when that is not an error
+    unresolved: Vec<String>,
+}
+
+/// Whether the installed package already satisfies its spec.
+fn is_satisfied(index: &UnifiedIndex, pkg: &InstalledPackage, spec: &PackageSpec) -> bool {
+    if spec.hold() {
+        return true;
+    }
+    let version_ok = spec.version().is_none_or(|req| satisfies(req, &pkg.version));
+    let source_ok = spec.source().is_none_or(|source| {
+        let downloaded_from_source = match pkg.source_url.as_deref() {
+            Some(url) => published_under(url, source),
+            // Installed from a URL or adopted, so only its checksum can tie it to the source
+            None => false,
+        };
+        // The same build counts wherever it was downloaded from
+        let published_by_source = index.apps.get(&pkg.name).is_some_and(|entries| {
+            entries.iter().any(|e| {
+                published_under(&e.source_url, source)
+                    && pkg.sha256.as_deref().is_some_and(|sha256| sha256.eq_ignore_ascii_case(&e.entry.sha256))
+            })
+        });
+        downloaded_from_source || published_by_source
+    });
+    version_ok && source_ok
+}
+
+/// Index entry of the installed build of `pkg`, if the index still has it.
+fn installed_entry<'a>(index: &'a UnifiedIndex, pkg: &InstalledPackage) -> Option<&'a AppImageEntryWithSource> {
+    let entries = index.apps.get(&pkg.name)?;
+    entries.iter()
+        .find(|e| pkg.sha256.as_deref().is_some_and(|sha256| sha256.eq_ignore_ascii_case(&e.entry.sha256)))
+        .or_else(|| entries.iter().find(|e| e.entry.version == pkg.version))
+}
+
+/// What a listed package ends up as.
+enum Target<'a> {
+    Keep(&'a InstalledPackage),
+    /// The entry to install, replacing the installed package if any
+    Install(&'a AppImageEntryWithSource, Option<&'a InstalledPackage>),
+    /// Left out of a dry run that does not add the manifest's sources
+    Unresolved,
+}
+
+/// Adds `name` to the changes after everything it depends on, listed or not.
+fn add_in_order<'a>(
+    config: &Config,
+    index: &'a UnifiedIndex,
+    db: &'a PackageDatabase,
+    targets: &BTreeMap<&str, Target<'a>>,
+    name: &str,
+    visited: &mut HashSet<String>,
+    changes: &mut Changes<'a>,
+) {
+    if !visited.insert(name.to_string()) {
+        return;
+    }
+    let (entry, installed) = match targets.get(name) {
+        Some(Target::Keep(pkg)) => {
+            changes.plan.push(PlanItem::for_installed(pkg, Action::Keep));
+            return;
+        }
+        Some(Target::Install(entry, installed)) => (*entry, *installed),
+        Some(Target::Unresolved) => return,
+        // A dependency that is not listed
+        None => {
+            let Some(entry) = index.find_best_match(name, None) else {
+                return;
+            };
+            (entry, db.get_package(name))
+        }
+    };
+
+    for dep_name in &entry.entry.dependencies {
+        add_in_order(config, index, db, targets, dep_name, visited, changes);
+    }
+    let action = match installed {
+        // Installed dependencies that are not listed stay as they are
+        Some(pkg) if !targets.contains_key(name) => {
+            changes.plan.push(PlanItem::for_installed(pkg, Action::Keep));
+            return;
+        }
+        Some(pkg) => Action::replacing(&pkg.version, &entry.entry.version),
+        None => Action::Install,
+    };
+    changes.plan.push(PlanItem::for_entry(config, entry, action.clone(), installed));
+    changes.installs.push((entry, action));
+}
+
+/// With `allow_unresolved`, packages missing from the index are collected in
+/// `Changes::unresolved` instead of failing, for dry runs that do not add
+/// the manifest's sources.
+fn compute_changes<'a>(
+    config: &Config,
+    manifest: &Manifest,
+    index: &'a UnifiedIndex,
+    db: &'a PackageDatabase,
+    prune: bool,
+    allow_unresolved: bool,
+) -> Result<Changes<'a>> {
+    let mut changes = Changes { installs: Vec::new(), removals: Vec::new(), plan: Plan::new(), unresolved: Vec::new() };
+    let mut targets: BTreeMap<&str, Target> = BTreeMap::new();
+    // Entry each listed package ends up with, for the dependencies kept by prune
+    let mut chosen: HashMap<&str, &AppImageEntryWithSource> = HashMap::new();
+
+    for (name, spec) in &manifest.packages {
+        let installed = db.get_package(name);
+        if let Some(pkg) = installed.filter(|pkg| is_satisfied(index, pkg, spec)) {
+            targets.insert(name, Target::Keep(pkg));
+            if let Some(entry) = installed_entry(index, pkg) {
+                chosen.insert(name, entry);
+            }
+            continue;
+        }
+
+        let entry = index.find_best_match_from(name, spec.version(), spec.source())
+            .with_context(|| {
+                if !index.apps.contains_key(name) {
+                    return format!("Package not found: {}", name);
+                }
+                let mut message = format!("No version of {}", name);
+                if let Some(req) = spec.version() {
+                    message.push_str(&format!(" matches {}", req));
+                }
+                if let Some(source) = spec.source() {
+                    message.push_str(&format!(" in source {}", source));
+                }
+                message
+            });
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) if allow_unresolved => {
+                changes.unresolved.push(e.to_string());
+                targets.insert(name, Target::Unresolved);
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        chosen.insert(name, entry);
+        targets.insert(name, Target::Install(entry, installed));
+    }
+
+    // Listed packages are installed after the listed packages they depend on
+    let mut visited = HashSet::new();
+    for name in targets.keys() {
+        add_in_order(config, index, db, &targets, name, &mut visited, &mut changes);
+    }
+
+    if prune {
+        // Keep the dependencies of the versions listed packages end up with,
+        // and of the installed versions of those dependencies
+        let mut required: HashSet<String> = HashSet::new();
+        let mut queue: Vec<&AppImageEntryWithSource> = chosen.values().copied().collect();
+        while let Some(entry) = queue.pop() {
+            for dep_name in &entry.entry.dependencies {
+                if !required.insert(dep_name.clone()) {
+                    continue;
+                }
+                let dep = chosen.get(dep_name.as_str()).copied()
+                    .or_else(|| db.get_package(dep_name).and_then(|pkg| installed_entry(index, pkg)))
+                    .or_else(|| index.find_best_match(dep_name, None));
+                queue.extend(dep);
+            }
+        }
+
+        let mut installed = db.list_packages();
+        installed.sort_by(|a, b| a.name.cmp(&b.name));
+        for pkg in installed {
+            if !manifest.packages.contains_key(&pkg.name) && !required.contains(&pkg.name) {
+                changes.plan.push(PlanItem::for_installed(pkg, Action::Remove));
+                changes.removals.push(pkg);
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Installs, upgrades and, with `prune`, removes packages until the installed
+/// set matches the manifest. Running it again changes nothing.
+pub async fn apply(path: Option<&str>, prune: bool, dry_run: bool) -> Result<()> {
+    let config = Config::new()?;
+    let path = path.map(PathBuf::from).unwrap_or(config.manifest_file.clone());
+    let content = fs::read_to_string(&path).await
+        .context(format!("Failed to read manifest: {}", path.display()))?;
+    let manifest = Manifest::from_str(&content, &path)?;
+
+    let configured = load_sources(&config).await?.sources;
+    let missing: Vec<&String> = manifest.sources.iter().filter(|s| !configured.contains(s)).collect();
+    if dry_run {
+        for source in &missing {
+            println!("Dry run: not adding source {}", source);
+        }
+    } else if !missing.is_empty() {
+        for source in &missing {
+            repo::add_source(source, None, None).await?;
+        }
+        repo::update_database().await?;
+    }
+
+    // A dry run with new sources resolves what it can without them
+    let unresolved_ok = dry_run && !missing.is_empty();
+    let index = if (manifest.packages.is_empty() || unresolved_ok) && !config.unified_index_cache.exists() {
+        UnifiedIndex::new()
+    } else {
+        load_unified_index().await?
+    };
+    let db = load_database(&config).await?;
+    let changes = compute_changes(&config, &manifest, &index, &db, prune, unresolved_ok)?;
+
+    if dry_run {
+        changes.plan.print();
+        if !changes.unresolved.is_empty() {
+            println!("Not resolved until the new sources are added:");
+            for message in &changes.unresolved {
+                println!("  {}", message);
+            }
+        }
+        return Ok(());
+    }
+    if changes.installs.is_empty() && changes.removals.is_empty() {
+        println!("Nothing to do, installed packages match {}", path.display());
+        return Ok(());
+    }
+    config.ensure_directories().await?;
+
+    // Download and verify everything before changing anything
+    let entries: Vec<_> = changes.installs.iter().map(|(entry, _)| *entry).collect();
+    download_entries(&config, &entries).await?;
+
+    for (entry, action) in &changes.installs {
+        match action {
+            Action::Upgrade { from } => {
+                println!("Upgrading {} from {} to {}", entry.entry.name, from, entry.entry.version);
+            }
+            Action::Downgrade { from } => {
+                println!("Downgrading {} from {} to {}", entry.entry.name, from, entry.entry.version);
+            }
+            Action::Reinstall => println!("Reinstalling: {}", entry.entry.name),
+            _ => println!("Installing: {}", entry.entry.name),
+        }
+        install_appimage_entry(&config, entry, &index).await?;
+    }
+    for pkg in &changes.removals {
+        crate::uninstall::uninstall(&config, &pkg.name).await?;
+    }
+
+    println!(
+        "Applied {}: {} installed or upgraded, {} removed",
+        path.display(), changes.installs.len(), changes.removals.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::repo::appimage_yaml::AppImageEntry;
+    use crate::verify::sha256_hex;
+
+    const SOURCE: &str = "https://repo.example.com/appimage.yaml";
+
+    fn temp_config(dir: &tempfile::TempDir) -> Config {
+        let mut config = Config::located(Scope::User).unwrap();
+        config.cache_dir = dir.path().join("cache");
+        config
+    }
+
+    fn manifest(content: &str) -> Manifest {
+        Manifest::from_str(content, Path::new("aipkg.toml")).unwrap()
+    }
+
+    fn entry(name: &str, version: &str, dependencies: &[&str]) -> AppImageEntry {
+        AppImageEntry {
+            name: name.to_string(),
+            version: version.to_string(),
+            file: format!("{}-{}.AppImage", name, version),
+            sha256: sha256_hex(format!("{}-{}", name, version).as_bytes()),
+            size: None,
+            description: None,
+            dependencies: dependencies.iter().map(|dep| dep.to_string()).collect(),
+            provides: Vec::new(),
+            zsync: None,
+        }
+    }
+
+    fn installed(entry: &AppImageEntry) -> InstalledPackage {
+        InstalledPackage {
+            name: entry.name.clone(),
+            version: entry.version.clone(),
+            path: PathBuf::from(format!("/nonexistent/{}", entry.file)),
+            desktop_file: PathBuf::new(),
+            symlink: PathBuf::new(),
+            installed_at: String::new(),
+            sha256: Some(entry.sha256.clone()),
+            origin_url: None,
+            origin_validators: Default::default(),
+            source_url: Some(SOURCE.to_string()),
+            adopted_from: None,
+        }
+    }
+
+    fn actions(changes: &Changes) -> Vec<(String, String, Action)> {
+        changes.plan.items.iter()
+            .map(|item| (item.name.clone(), item.version.clone(), item.action.clone()))
+            .collect()
+    }
+
+    fn item(name: &str, version: &str, action: Action) -> (String, String, Action) {
+        (name.to_string(), version.to_string(), action)
+    }
+
+    #[test]
+    fn satisfied_packages_are_kept_and_the_rest_upgraded() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let mut index = UnifiedIndex::new();
+        let mut db = PackageDatabase::new();
+        for (name, version) in [("editor", "1.0"), ("viewer", "1.0"), ("pinned", "1.0")] {
+            let old = entry(name, version, &[]);
+            db.add_package(installed(&old));
+            index.add_entry(old, SOURCE.to_string());
+            index.add_entry(entry(name, "2.0", &[]), SOURCE.to_string());
+        }
+        index.add_entry(entry("browser", "3.0", &["codecs"]), SOURCE.to_string());
+        index.add_entry(entry("codecs", "1.0", &[]), SOURCE.to_string());
+
+        let manifest = manifest(r#"
+            [packages]
+            browser = "*"
+            editor = ">=1, <2"
+            pinned = { version = ">=2", hold = true }
+            viewer = ">=2"
+        "#);
+        let changes = compute_changes(&config, &manifest, &index, &db, false, false).unwrap();
+
+        assert_eq!(actions(&changes), vec![
+            item("codecs", "1.0", Action::Install),
+            item("browser", "3.0", Action::Install),
+            item("editor", "1.0", Action::Keep),
+            item("pinned", "1.0", Action::Keep),
+            item("viewer", "2.0", Action::Upgrade { from: "1.0".to_string() }),
+        ]);
+        let installs: Vec<&str> = changes.installs.iter().map(|(entry, _)| entry.entry.name.as_str()).collect();
+        assert_eq!(installs, vec!["codecs", "browser", "viewer"]);
+        assert!(changes.removals.is_empty());
+    }
+
+    #[test]
+    fn listed_dependencies_are_installed_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let mut index = UnifiedIndex::new();
+        index.add_entry(entry("browser", "3.0", &["plugins"]), SOURCE.to_string());
+        index.add_entry(entry("plugins", "1.0", &["codecs"]), SOURCE.to_string());
+        index.add_entry(entry("codecs", "1.0", &[]), SOURCE.to_string());
+        index.add_entry(entry("codecs", "2.0", &[]), SOURCE.to_string());
+        let db = PackageDatabase::new();
+
+        let manifest = manifest(r#"
+            [packages]
+            browser = "*"
+            codecs = "^1"
+        "#);
+        let changes = compute_changes(&config, &manifest, &index, &db, false, false).unwrap();
+
+        let installs: Vec<(&str, &str)> = changes.installs.iter()
+            .map(|(entry, _)| (entry.entry.name.as_str(), entry.entry.version.as_str()))
+            .collect();
+        assert_eq!(installs, vec![("codecs", "1.0"), ("plugins", "1.0"), ("browser", "3.0")]);
+    }
+
+    #[test]
+    fn packages_installed_from_a_url_are_replaced_from_their_source_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let mut index = UnifiedIndex::new();
+        let published = entry("tool", "1.0", &[]);
+        index.add_entry(published.clone(), SOURCE.to_string());
+        let manifest = manifest(&format!("[packages]\ntool = {{ source = \"{}\" }}\n", SOURCE));
+
+        // A build the source does not publish is replaced
+        let mut from_url = installed(&entry("tool", "1.0-custom", &[]));
+        from_url.source_url = None;
+        from_url.origin_url = Some("https://example.com/tool.AppImage".to_string());
+        let mut db = PackageDatabase::new();
+        db.add_package(from_url);
+        let changes = compute_changes(&config, &manifest, &index, &db, false, false).unwrap();
+        assert_eq!(actions(&changes), vec![item("tool", "1.0", Action::Downgrade { from: "1.0-custom".to_string() })]);
+
+        // The same build as the source's is kept, as is the one apply installed
+        let mut same_build = installed(&published);
+        same_build.source_url = None;
+        let mut mirrored = installed(&published);
+        mirrored.source_url = Some("https://mirror.example.com/tool-1.0.AppImage".to_string());
+        for pkg in [same_build, mirrored] {
+            let mut db = PackageDatabase::new();
+            db.add_package(pkg);
+            let changes = compute_changes(&config, &manifest, &index, &db, false, false).unwrap();
+            assert_eq!(actions(&changes), vec![item("tool", "1.0", Action::Keep)]);
+        }
+    }
+
+    #[test]
+    fn prune_removes_only_unlisted_packages_nothing_depends_on() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let mut index = UnifiedIndex::new();
+        let mut db = PackageDatabase::new();
+        for (name, dependencies) in [("app", &["runtime"][..]), ("runtime", &["base"]), ("base", &[]), ("stale", &[])] {
+            let app = entry(name, "1.0", dependencies);
+            db.add_package(installed(&app));
+            index.add_entry(app, SOURCE.to_string());
+        }
+
+        let manifest = manifest("[packages]\napp = \"*\"\n");
+        let changes = compute_changes(&config, &manifest, &index, &db, false, false).unwrap();
+        assert!(changes.removals.is_empty());
+
+        let changes = compute_changes(&config, &manifest, &index, &db, true, false).unwrap();
+        assert_eq!(actions(&changes), vec![
+            item("app", "1.0", Action::Keep),
+            item("stale", "1.0", Action::Remove),
+        ]);
+        let removals: Vec<&str> = changes.removals.iter().map(|pkg| pkg.name.as_str()).collect();
+        assert_eq!(removals, vec!["stale"]);
+    }
+
+    #[test]
+    fn unresolved_packages_fail_unless_allowed() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let mut index = UnifiedIndex::new();
+        index.add_entry(entry("app", "1.0", &[]), SOURCE.to_string());
+        let db = PackageDatabase::new();
+
+        let manifest = manifest(r#"
+            [packages]
+            app = ">=2"
+            missing = "*"
+        "#);
+        let error = compute_changes(&config, &manifest, &index, &db, false, false).err().unwrap();
+        assert_eq!(error.to_string(), "No version of app matches >=2");
+
+        let changes = compute_changes(&config, &manifest, &index, &db, false, true).unwrap();
+        assert!(changes.installs.is_empty());
+        assert_eq!(changes.unresolved, vec!["No version of app matches >=2", "Package not found: missing"]);
+    }
+}
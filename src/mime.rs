@@ -0,0 +1,332 @@
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+use crate::config::Config;
+use crate::desktop_entry::{KeyFile, DESKTOP_ENTRY};
+use crate::install::{load_database, InstalledPackage};
+use crate::utils::{write_atomic, ExtractedFiles};
+
+const MIME_CACHE: &str = "MIME Cache";
+const DEFAULT_APPLICATIONS: &str = "Default Applications";
+const ADDED_ASSOCIATIONS: &str = "Added Associations";
+const MIME_NAMESPACE: &str = "http://www.freedesktop.org/standards/shared-mime-info";
+
+/// Where the MIME package of an installed AppImage is kept.
+fn mime_package_path(config: &Config, name: &str) -> PathBuf {
+    config.mime_dir.join("packages").join(format!("aipkg-{}.xml", name))
+}
+
+/// Desktop file ID, the name other desktop files and mimeapps.list refer to.
+fn desktop_id(pkg: &InstalledPackage) -> Option<&str> {
+    pkg.desktop_file.file_name().and_then(|name| name.to_str())
+}
+
+/// Whether `mime_type` looks like `media/subtype`, including URL schemes
+/// such as `x-scheme-handler/myapp`.
+fn is_mime_type(mime_type: &str) -> bool {
+    mime_type.split_once('/').is_some_and(|(media, subtype)| {
+        let valid = |part: &str| !part.is_empty()
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c));
+        valid(media) && valid(subtype)
+    })
+}
+
+async fn remove_if_present(path: &Path) -> Result<bool> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).context(format!("Failed to remove {}", path.display())),
+    }
+}
+
+/// Combines shared-mime-info packages into one, keeping every definition.
+fn merge_mime_packages(packages: &[String]) -> Option<String> {
+    match packages {
+        [] => None,
+        [package] => Some(package.clone()),
+        _ => {
+            let mut merged = format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<mime-info xmlns=\"{}\">\n", MIME_NAMESPACE);
+            for package in packages {
+                let Some(start) = package.find("<mime-info") else {
+                    continue;
+                };
+                let (Some(open_end), Some(end)) = (package[start..].find('>'), package.rfind("</mime-info>")) else {
+                    continue;
+                };
+                let body = package.get(start + open_end + 1..end).unwrap_or_default();
+                merged.push_str(body.trim_matches('\n'));
+                merged.push('\n');
+            }
+            merged.push_str("</mime-info>\n");
+            Some(merged)
+        }
+    }
+}
+
+/// The shared-mime-info packages an AppImage ships in
+/// usr/share/mime/packages, unpacked from its squashfs and combined.
+async fn extract_mime_package(appimage: &Path) -> Option<String> {
+    let extracted = ExtractedFiles::new().ok()?;
+    if !extracted.extract(appimage, "usr/share/mime/packages/*.xml").await {
+        return None;
+    }
+    let mut entries = fs::read_dir(extracted.root().join("usr/share/mime/packages")).await.ok()?;
+    let mut names = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if let Some(name) = entry.file_name().to_str().filter(|name| name.ends_with(".xml")) {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+
+    let mut packages = Vec::new();
+    for name in names {
+        let data = extracted.read(&format!("usr/share/mime/packages/{}", name)).await;
+        if let Some(xml) = data.and_then(|data| String::from_utf8(data).ok()) {
+            packages.push(xml);
+        }
+    }
+    merge_mime_packages(&packages)
+}
+
+/// Makes the file types and URL schemes of an installed AppImage known: its
+/// MIME packages are added to the shared-mime-info database, and the MimeType
+/// keys of the desktop files are indexed in mimeinfo.cache.
+pub async fn register(config: &Config, name: &str, appimage: &Path) -> Result<()> {
+    let package_path = mime_package_path(config, name);
+    let mime_changed = match &extract_mime_package(appimage).await {
+        Some(xml) => {
+            let current = fs::read_to_string(&package_path).await.ok();
+            if current.as_deref() != Some(xml.as_str()) {
+                fs::create_dir_all(config.mime_dir.join("packages")).await?;
+                write_atomic(&package_path, xml.as_bytes()).await
+                    .context("Failed to write MIME package")?;
+                true
+            } else {
+                false
+            }
+        }
+        // A new version may no longer ship one
+        None => remove_if_present(&package_path).await?,
+    };
+
+    update_desktop_database(config).await?;
+    if mime_changed {
+        update_mime_database(config).await;
+    }
+    Ok(())
+}
+
+/// Undoes `register` for a removed package, including the defaults set
+/// with `aipkg default`.
+pub async fn unregister(config: &Config, pkg: &InstalledPackage) -> Result<()> {
+    let mime_changed = remove_if_present(&mime_package_path(config, &pkg.name)).await?;
+    update_desktop_database(config).await?;
+    if mime_changed {
+        update_mime_database(config).await;
+    }
+
+    match desktop_id(pkg) {
+        Some(id) => remove_associations(config, id).await,
+        None => Ok(()),
+    }
+}
+
+/// Writes mimeinfo.cache for the desktop files directory, as
+/// update-desktop-database does: for each MIME type, the desktop files
+/// that declare it.
+async fn update_desktop_database(config: &Config) -> Result<()> {
+    let dir = &config.desktop_files_dir;
+    if !dir.exists() {
+        return Ok(());
+    }
+
+    // Desktop files in subdirectories get the directory names as a prefix
+    // of their ID, so kde/foo.desktop is kde-foo.desktop
+    let mut files = Vec::new();
+    let mut pending = vec![(dir.clone(), String::new())];
+    while let Some((subdir, prefix)) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&subdir).await else {
+            continue;
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let path = entry.path();
+            // Symlinked directories are not followed, they could loop
+            if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                pending.push((path, format!("{}{}-", prefix, name)));
+            } else if name.ends_with(".desktop") {
+                files.push((format!("{}{}", prefix, name), path));
+            }
+        }
+    }
+    files.sort();
+
+    let mut handlers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (id, path) in files {
+        // Unreadable files are skipped, and invalid lines within a file are
+        // ignored like update-desktop-database does
+        let Ok(content) = fs::read_to_string(&path).await else {
+            continue;
+        };
+        let entry = KeyFile::parse_lenient(&content);
+        if entry.get(DESKTOP_ENTRY, "Hidden").as_deref() == Some("true") {
+            continue;
+        }
+        for mime_type in entry.get_list(DESKTOP_ENTRY, "MimeType").unwrap_or_default() {
+            handlers.entry(mime_type).or_default().push(id.clone());
+        }
+    }
+
+    let mut cache = KeyFile::new();
+    cache.add_group(MIME_CACHE);
+    for (mime_type, ids) in &handlers {
+        cache.set_list(MIME_CACHE, mime_type, ids);
+    }
+    write_atomic(&dir.join("mimeinfo.cache"), cache.to_string().as_bytes()).await
+        .context("Failed to write mimeinfo.cache")
+}
+
+/// Compiles the MIME packages into the caches applications read. This needs
+/// update-mime-database from shared-mime-info; without it the packages are
+/// picked up the next time anything else rebuilds the database.
+async fn update_mime_database(config: &Config) {
+    let result = tokio::process::Command::new("update-mime-database")
+        .arg(&config.mime_dir)
+        .output().await;
+    match result {
+        Ok(output) if output.status.success() => {}
+        Ok(output) => println!(
+            "Warning: update-mime-database failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => println!(
+            "Note: update-mime-database is not installed, new file types are known once {} is rebuilt",
+            config.mime_dir.display()
+        ),
+        Err(e) => println!("Warning: failed to run update-mime-database: {}", e),
+    }
+}
+
+async fn load_mimeapps(config: &Config) -> Result<KeyFile> {
+    if !config.mimeapps_file.exists() {
+        return Ok(KeyFile::new());
+    }
+    let content = fs::read_to_string(&config.mimeapps_file).await
+        .context(format!("Failed to read {}", config.mimeapps_file.display()))?;
+    KeyFile::parse(&content)
+        .context(format!("Failed to parse {}", config.mimeapps_file.display()))
+}
+
+/// Drops a removed desktop file from the defaults and associations in
+/// mimeapps.list, so file managers do not offer an app that is gone.
+async fn remove_associations(config: &Config, id: &str) -> Result<()> {
+    if !config.mimeapps_file.exists() {
+        return Ok(());
+    }
+    let mut mimeapps = load_mimeapps(config).await?;
+    let mut changed = false;
+    for group in [DEFAULT_APPLICATIONS, ADDED_ASSOCIATIONS] {
+        for mime_type in mimeapps.keys(group) {
+            let ids = mimeapps.get_list(group, &mime_type).unwrap_or_default();
+            if !ids.iter().any(|other| other == id) {
+                continue;
+            }
+            let remaining: Vec<String> = ids.into_iter().filter(|other| other != id).collect();
+            if remaining.is_empty() {
+                mimeapps.remove(group, &mime_type);
+            } else {
+                mimeapps.set_list(group, &mime_type, &remaining);
+            }
+            changed = true;
+        }
+    }
+    if changed {
+        write_atomic(&config.mimeapps_file, mimeapps.to_string().as_bytes()).await
+            .context(format!("Failed to write {}", config.mimeapps_file.display()))?;
+    }
+    Ok(())
+}
+
+/// Makes an installed package the default application for a MIME type or
+/// URL scheme in mimeapps.list.
+pub async fn set_default(package: &str, mime_type: &str) -> Result<()> {
+    let config = Config::new()?;
+    config.check_privileges()?;
+    if !is_mime_type(mime_type) {
+        anyhow::bail!("Invalid MIME type: {} (expected e.g. text/plain or x-scheme-handler/myapp)", mime_type);
+    }
+
+    let db = load_database(&config).await?;
+    let pkg = db.get_package(package)
+        .ok_or_else(|| anyhow::anyhow!("Package not installed: {}", package))?;
+    let id = desktop_id(pkg)
+        .with_context(|| format!("{} has no desktop file", package))?;
+
+    let declared = fs::read_to_string(&pkg.desktop_file).await.ok()
+        .and_then(|content| KeyFile::parse(&content).ok())
+        .and_then(|entry| entry.get_list(DESKTOP_ENTRY, "MimeType"))
+        .unwrap_or_default();
+    if !declared.iter().any(|declared| declared == mime_type) {
+        println!("Warning: {} does not declare {} in its desktop file and may not be able to open it", package, mime_type);
+    }
+
+    let mut mimeapps = load_mimeapps(&config).await?;
+    mimeapps.set_list(DEFAULT_APPLICATIONS, mime_type, &[id.to_string()]);
+    if let Some(dir) = config.mimeapps_file.parent() {
+        fs::create_dir_all(dir).await?;
+    }
+    write_atomic(&config.mimeapps_file, mimeapps.to_string().as_bytes()).await
+        .context(format!("Failed to write {}", config.mimeapps_file.display()))?;
+
+    println!("{} is now the default application for {}", package, mime_type);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_packages_are_merged_into_one() {
+        let first = "<?xml version=\"1.0\"?>\n<mime-info xmlns=\"x\">\n  <mime-type type=\"application/x-a\"/>\n</mime-info>\n";
+        let second = "<mime-info xmlns=\"x\"><mime-type type=\"application/x-b\"/></mime-info>";
+        assert_eq!(merge_mime_packages(&[]), None);
+        assert_eq!(merge_mime_packages(&[first.to_string()]).as_deref(), Some(first));
+
+        let merged = merge_mime_packages(&[first.to_string(), second.to_string()]).unwrap();
+        assert_eq!(
+            merged,
+            format!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<mime-info xmlns=\"{}\">\n  <mime-type type=\"application/x-a\"/>\n<mime-type type=\"application/x-b\"/>\n</mime-info>\n",
+                MIME_NAMESPACE
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn desktop_database_indexes_subdirectories_without_following_links() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::located(crate::config::Scope::User).unwrap();
+        config.desktop_files_dir = dir.path().join("applications");
+        let kde = config.desktop_files_dir.join("kde");
+        fs::create_dir_all(&kde).await.unwrap();
+        fs::write(kde.join("viewer.desktop"), "[Desktop Entry]\nMimeType=image/png;\n").await.unwrap();
+        fs::write(config.desktop_files_dir.join("editor.desktop"), "[Desktop Entry]\nMimeType=image/png;text/plain;\n").await.unwrap();
+        // A link back up would loop forever if followed
+        fs::symlink(&config.desktop_files_dir, kde.join("loop")).await.unwrap();
+
+        update_desktop_database(&config).await.unwrap();
+        let cache = fs::read_to_string(config.desktop_files_dir.join("mimeinfo.cache")).await.unwrap();
+        let cache = KeyFile::parse_lenient(&cache);
+        assert_eq!(
+            cache.get_list(MIME_CACHE, "image/png").unwrap(),
+            vec!["editor.desktop".to_string(), "kde-viewer.desktop".to_string()]
+        );
+        assert_eq!(cache.get_list(MIME_CACHE, "text/plain").unwrap(), vec!["editor.desktop".to_string()]);
+    }
+}
@@ -0,0 +1,398 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::config::Config;
+use crate::install::InstalledPackage;
+use crate::verify::{is_sha256, verify_sha256_bytes};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedPackage {
+    pub name: String,
+    pub version: String,
+    pub size: u64,
+    pub cached_at: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageCacheIndex {
+    #[serde(default)]
+    packages: HashMap<String, CachedPackage>,
+}
+
+impl PackageCacheIndex {
+    fn new() -> Self {
+        Self {
+            packages: HashMap::new(),
+        }
+    }
+
+    fn load(content: &str) -> Result<Self> {
+        if content.trim().is_empty() {
+            Ok(Self::new())
+        } else {
+            serde_yaml::from_str(content)
+                .context("Failed to parse package cache index")
+        }
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        serde_yaml::to_string(self)
+            .context("Failed to serialize package cache index")
+    }
+}
+
+/// Serializes index updates from concurrent downloads
+static INDEX_LOCK: Mutex<()> = Mutex::const_new(());
+
+/// Directory holding cached AppImages, one file per sha256.
+pub fn cache_pkg_dir(config: &Config) -> PathBuf {
+    config.cache_dir.join("pkg")
+}
+
+fn cache_index_file(config: &Config) -> PathBuf {
+    cache_pkg_dir(config).join("index.yaml")
+}
+
+/// Path of the cached AppImage for `sha256`. Anything but a hex digest is
+/// refused, since it would name a file outside the cache.
+pub fn cached_path(config: &Config, sha256: &str) -> Result<PathBuf> {
+    if !is_sha256(sha256) {
+        anyhow::bail!("Invalid SHA256 checksum: {}", sha256);
+    }
+    Ok(cache_pkg_dir(config).join(sha256.to_lowercase()))
+}
+
+/// Whether the AppImage for `sha256` is in the cache.
+pub fn is_cached(config: &Config, sha256: &str) -> bool {
+    cached_path(config, sha256).is_ok_and(|path| path.exists())
+}
+
+async fn load_cache_index(config: &Config) -> Result<PackageCacheIndex> {
+    let index_file = cache_index_file(config);
+    let content = if index_file.exists() {
+        fs::read_to_string(&index_file).await?
+    } else {
+        String::new()
+    };
+    PackageCacheIndex::load(&content)
+}
+
+async fn save_cache_index(config: &Config, index: &PackageCacheIndex) -> Result<()> {
+    fs::create_dir_all(cache_pkg_dir(config)).await
+        .context("Failed to create package cache directory")?;
+    fs::write(cache_index_file(config), index.to_string()?).await
+        .context("Failed to write package cache index")?;
+    Ok(())
+}
+
+/// Returns the cached AppImage for `sha256`, discarding it if it no longer matches.
+pub async fn get_cached(config: &Config, sha256: &str) -> Result<Option<Vec<u8>>> {
+    let path = cached_path(config, sha256)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let data = fs::read(&path).await
+        .context(format!("Failed to read cached file: {}", path.display()))?;
+
+    if !verify_sha256_bytes(&data, sha256)? {
+        // Corrupted cache entry, drop it and download again
+        fs::remove_file(&path).await?;
+        let _guard = INDEX_LOCK.lock().await;
+        let mut index = load_cache_index(config).await?;
+        index.packages.remove(&sha256.to_lowercase());
+        save_cache_index(config, &index).await?;
+        return Ok(None);
+    }
+
+    Ok(Some(data))
+}
+
+pub async fn store(config: &Config, name: &str, version: &str, sha256: &str, data: &[u8]) -> Result<()> {
+    let path = cached_path(config, sha256)?;
+    fs::create_dir_all(cache_pkg_dir(config)).await
+        .context("Failed to create package cache directory")?;
+
+    // Write under a temporary name so a cancelled download leaves no partial file
+    let partial_path = path.with_extension("part");
+    fs::write(&partial_path, data).await
+        .context(format!("Failed to write cached file: {}", path.display()))?;
+    fs::rename(&partial_path, &path).await
+        .context(format!("Failed to write cached file: {}", path.display()))?;
+
+    let _guard = INDEX_LOCK.lock().await;
+    let mut index = load_cache_index(config).await?;
+    index.packages.insert(sha256.to_lowercase(), CachedPackage {
+        name: name.to_string(),
+        version: version.to_string(),
+        size: data.len() as u64,
+        cached_at: chrono::Utc::now().to_rfc3339(),
+    });
+    save_cache_index(config, &index).await
+}
+
+/// Keeps a copy of an installed AppImage that is about to be replaced or
+/// removed, so `aipkg history undo` can restore it. Only the
+/// `retained_versions` most recent copies of each package are kept.
+pub async fn retain_installed(config: &Config, pkg: &InstalledPackage) -> Result<()> {
+    if config.retained_versions == 0 {
+        return Ok(());
+    }
+    let Some(sha256) = pkg.sha256.as_ref().filter(|sha256| is_sha256(sha256)) else {
+        return Ok(());
+    };
+    if !is_cached(config, sha256) {
+        let Ok(data) = fs::read(&pkg.path).await else {
+            return Ok(());
+        };
+        // Modified since it was installed, it cannot be restored by checksum
+        if !verify_sha256_bytes(&data, sha256)? {
+            return Ok(());
+        }
+        store(config, &pkg.name, &pkg.version, sha256, &data).await?;
+    }
+
+    let _guard = INDEX_LOCK.lock().await;
+    let mut index = load_cache_index(config).await?;
+    let mut versions: Vec<(String, String)> = index.packages.iter()
+        .filter(|(_, cached)| cached.name == pkg.name)
+        .map(|(sha256, cached)| (cached.cached_at.clone(), sha256.clone()))
+        .collect();
+    if versions.len() <= config.retained_versions {
+        return Ok(());
+    }
+    // Newest first; the copy just retained is never the one dropped
+    versions.sort_by(|a, b| b.0.cmp(&a.0));
+    for (_, old) in versions.iter().skip(config.retained_versions).filter(|(_, old)| old != sha256) {
+        let path = cached_path(config, old)?;
+        if path.exists() {
+            fs::remove_file(&path).await
+                .context(format!("Failed to remove cached file: {}", path.display()))?;
+        }
+        index.packages.remove(old);
+    }
+    save_cache_index(config, &index).await
+}
+
+pub async fn list_cache() -> Result<()> {
+    let config = Config::new()?;
+    let index = load_cache_index(&config).await?;
+
+    let mut entries: Vec<(&String, &CachedPackage)> = index.packages.iter()
+        .filter(|(sha256, _)| is_cached(&config, sha256))
+        .collect();
+
+    if entries.is_empty() {
+        println!("Package cache is empty");
+        return Ok(());
+    }
+
+    entries.sort_by(|a, b| (&a.1.name, &a.1.cached_at).cmp(&(&b.1.name, &b.1.cached_at)));
+
+    println!("Cached packages:");
+    for (sha256, pkg) in entries {
+        println!("  {} {} - {} ({:.2} MB)", pkg.name, pkg.version, sha256, pkg.size as f64 / 1_000_000.0);
+    }
+
+    Ok(())
+}
+
+pub async fn cache_size() -> Result<()> {
+    let config = Config::new()?;
+    let (count, size) = disk_usage(&config).await?;
+    println!("{} file(s), {} bytes ({:.2} MB)", count, size, size as f64 / 1_000_000.0);
+    Ok(())
+}
+
+async fn disk_usage(config: &Config) -> Result<(usize, u64)> {
+    let pkg_dir = cache_pkg_dir(config);
+    if !pkg_dir.exists() {
+        return Ok((0, 0));
+    }
+
+    let mut count = 0;
+    let mut size = 0;
+    let mut dir = fs::read_dir(&pkg_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() && entry.path() != cache_index_file(config) {
+            count += 1;
+            size += metadata.len();
+        }
+    }
+
+    Ok((count, size))
+}
+
+/// Removes cached AppImages. With `keep`, the `keep` most recently cached
+/// versions of each package are retained.
+pub async fn clean_cache(keep: Option<usize>) -> Result<()> {
+    let config = Config::new()?;
+    config.check_privileges()?;
+    if !cache_pkg_dir(&config).exists() {
+        println!("Package cache is empty");
+        return Ok(());
+    }
+
+    let (removed, freed) = remove_cached(&config, keep.unwrap_or(0)).await?;
+    println!("Removed {} cached file(s), freed {:.2} MB", removed, freed as f64 / 1_000_000.0);
+    Ok(())
+}
+
+/// Removes all but the `keep` most recently cached versions of each package,
+/// returning the number of files removed and the bytes freed.
+async fn remove_cached(config: &Config, keep: usize) -> Result<(usize, u64)> {
+    let pkg_dir = cache_pkg_dir(config);
+    let mut index = load_cache_index(config).await?;
+
+    // Group by package name, newest first
+    let mut by_name: HashMap<String, Vec<(String, String)>> = HashMap::new();
+    for (sha256, pkg) in &index.packages {
+        by_name.entry(pkg.name.clone())
+            .or_default()
+            .push((pkg.cached_at.clone(), sha256.clone()));
+    }
+
+    let mut retained = std::collections::HashSet::new();
+    for versions in by_name.values_mut() {
+        versions.sort_by(|a, b| b.0.cmp(&a.0));
+        for (_, sha256) in versions.iter().take(keep) {
+            retained.insert(sha256.clone());
+        }
+    }
+
+    let mut removed = 0;
+    let mut freed = 0;
+    let mut dir = fs::read_dir(&pkg_dir).await?;
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path == cache_index_file(config) {
+            continue;
+        }
+
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if retained.contains(&file_name) {
+            continue;
+        }
+
+        let metadata = entry.metadata().await?;
+        if metadata.is_file() {
+            freed += metadata.len();
+            fs::remove_file(&path).await
+                .context(format!("Failed to remove cached file: {}", path.display()))?;
+            removed += 1;
+        }
+        index.packages.remove(&file_name);
+    }
+
+    index.packages.retain(|sha256, _| retained.contains(sha256));
+    save_cache_index(config, &index).await?;
+    Ok((removed, freed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::verify::sha256_hex;
+
+    fn temp_config(dir: &tempfile::TempDir) -> Config {
+        let mut config = Config::located(Scope::User).unwrap();
+        config.cache_dir = dir.path().join("cache");
+        config
+    }
+
+    async fn cached_names(config: &Config) -> Vec<(String, String)> {
+        let index = load_cache_index(config).await.unwrap();
+        let mut names: Vec<(String, String)> = index.packages.into_iter()
+            .filter(|(sha256, _)| is_cached(config, sha256))
+            .map(|(_, cached)| (cached.name, cached.version))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn cached_path_refuses_anything_but_a_digest() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let digest = "A".repeat(64);
+        assert_eq!(cached_path(&config, &digest).unwrap(), cache_pkg_dir(&config).join("a".repeat(64)));
+        assert!(cached_path(&config, &format!("../../{}", "a".repeat(58))).is_err());
+        assert!(cached_path(&config, &"g".repeat(64)).is_err());
+        assert!(cached_path(&config, "abc").is_err());
+        assert!(!is_cached(&config, "../index.yaml"));
+    }
+
+    #[tokio::test]
+    async fn corrupted_entries_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        let data = b"appimage";
+        let sha256 = sha256_hex(data);
+        store(&config, "app", "1.0", &sha256, data).await.unwrap();
+        assert_eq!(get_cached(&config, &sha256).await.unwrap().as_deref(), Some(&data[..]));
+
+        fs::write(cached_path(&config, &sha256).unwrap(), b"tampered").await.unwrap();
+        assert_eq!(get_cached(&config, &sha256).await.unwrap(), None);
+        assert!(!is_cached(&config, &sha256));
+        assert!(load_cache_index(&config).await.unwrap().packages.is_empty());
+
+        assert!(store(&config, "app", "1.0", "not-a-digest", data).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clean_keeps_the_newest_versions_of_each_package() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir);
+        for (name, version) in [("app", "1"), ("app", "2"), ("app", "3"), ("other", "1")] {
+            let data = format!("{} {}", name, version);
+            store(&config, name, version, &sha256_hex(data.as_bytes()), data.as_bytes()).await.unwrap();
+        }
+
+        let (removed, _) = remove_cached(&config, 1).await.unwrap();
+        assert_eq!(removed, 2);
+        assert_eq!(cached_names(&config).await, vec![
+            ("app".to_string(), "3".to_string()),
+            ("other".to_string(), "1".to_string()),
+        ]);
+
+        remove_cached(&config, 0).await.unwrap();
+        assert!(cached_names(&config).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn retained_versions_are_capped() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = temp_config(&dir);
+        config.retained_versions = 2;
+
+        for version in ["1", "2", "3"] {
+            let path = dir.path().join(format!("app-{}.AppImage", version));
+            fs::write(&path, version).await.unwrap();
+            let pkg = InstalledPackage {
+                name: "app".to_string(),
+                version: version.to_string(),
+                path,
+                desktop_file: PathBuf::new(),
+                symlink: PathBuf::new(),
+                installed_at: String::new(),
+                sha256: Some(sha256_hex(version.as_bytes())),
+                origin_url: None,
+                origin_validators: Default::default(),
+                source_url: None,
+                adopted_from: None,
+            };
+            retain_installed(&config, &pkg).await.unwrap();
+        }
+
+        assert_eq!(cached_names(&config).await, vec![
+            ("app".to_string(), "2".to_string()),
+            ("app".to_string(), "3".to_string()),
+        ]);
+    }
+}
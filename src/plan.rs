@@ -0,0 +1,185 @@
+use std::cmp::Ordering;
+use std::path::Path;
+
+use crate::config::Config;
+use crate::install::InstalledPackage;
+use crate::pkg_cache;
+use crate::repo::appimage_yaml::AppImageEntryWithSource;
+use crate::version;
+
+/// What a transaction does to one package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Install,
+    Reinstall,
+    Upgrade { from: String },
+    Downgrade { from: String },
+    Remove,
+    Keep,
+    /// Installed from a URL without a published checksum; only the download
+    /// itself shows whether it changed
+    Check,
+}
+
+impl Action {
+    /// Replaces the installed version `from` with `to`. Versions that cannot
+    /// be compared count as an upgrade.
+    pub fn replacing(from: &str, to: &str) -> Self {
+        if from == to {
+            return Action::Reinstall;
+        }
+        match version::compare(to, from) {
+            Some(Ordering::Equal) => Action::Reinstall,
+            Some(Ordering::Less) => Action::Downgrade { from: from.to_string() },
+            Some(Ordering::Greater) | None => Action::Upgrade { from: from.to_string() },
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Action::Install => "install",
+            Action::Reinstall => "reinstall",
+            Action::Upgrade { .. } => "upgrade",
+            Action::Downgrade { .. } => "downgrade",
+            Action::Remove => "remove",
+            Action::Keep => "keep",
+            Action::Check => "check",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlanItem {
+    pub name: String,
+    pub action: Action,
+    pub version: String,
+    pub source: Option<String>,
+    /// Bytes to download, `None` if the size is not published
+    pub download_size: Option<u64>,
+    /// Change in used disk space, `None` if not known in advance
+    pub disk_delta: Option<i64>,
+}
+
+impl PlanItem {
+    /// Installs `entry`, replacing `installed` if given.
+    pub fn for_entry(config: &Config, entry: &AppImageEntryWithSource, action: Action, installed: Option<&InstalledPackage>) -> Self {
+        let cached = pkg_cache::cached_path(config, &entry.entry.sha256).ok()
+            .and_then(|path| std::fs::metadata(path).ok());
+        let new_size = entry.entry.size.or(cached.as_ref().map(|m| m.len()));
+        let download_size = match (&action, cached) {
+            (Action::Keep, _) | (_, Some(_)) => Some(0),
+            _ => entry.entry.size,
+        };
+        let disk_delta = match action {
+            Action::Keep => Some(0),
+            _ => new_size.map(|size| size as i64 - installed.map_or(0, |pkg| file_size(&pkg.path)) as i64),
+        };
+
+        Self {
+            name: entry.entry.name.clone(),
+            action,
+            version: entry.entry.version.clone(),
+            source: Some(entry.source_url.clone()),
+            download_size,
+            disk_delta,
+        }
+    }
+
+    /// Leaves the installed package untouched, or removes it.
+    pub fn for_installed(pkg: &InstalledPackage, action: Action) -> Self {
+        let disk_delta = match action {
+            Action::Remove => -(file_size(&pkg.path) as i64),
+            _ => 0,
+        };
+        Self {
+            name: pkg.name.clone(),
+            action,
+            version: pkg.version.clone(),
+            source: pkg.origin_url.clone(),
+            download_size: Some(0),
+            disk_delta: Some(disk_delta),
+        }
+    }
+}
+
+fn file_size(path: &Path) -> u64 {
+    std::fs::metadata(path).map(|m| m.len()).unwrap_or(0)
+}
+
+/// The resolved effect of an install, upgrade or remove, printed by --dry-run.
+#[derive(Debug, Clone, Default)]
+pub struct Plan {
+    pub items: Vec<PlanItem>,
+}
+
+impl Plan {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, item: PlanItem) {
+        self.items.push(item);
+    }
+
+    pub fn print(&self) {
+        println!("Dry run, nothing will be changed:");
+        if self.items.is_empty() {
+            println!("  nothing to do");
+            return;
+        }
+
+        let name_width = self.items.iter().map(|item| item.name.len()).max().unwrap_or(0);
+        for item in &self.items {
+            let version = match &item.action {
+                Action::Upgrade { from } | Action::Downgrade { from } => format!("{} -> {}", from, item.version),
+                _ => item.version.clone(),
+            };
+            let mut line = format!("  {:<9} {:<width$} {}", item.action.label(), item.name, version, width = name_width);
+            if item.action != Action::Keep {
+                line.push_str(&format!(
+                    "  (download {}, disk {})",
+                    item.download_size.map_or("unknown".to_string(), format_size),
+                    item.disk_delta.map_or("unknown".to_string(), format_delta),
+                ));
+            }
+            if let Some(source) = &item.source {
+                line.push_str(&format!("  from {}", source));
+            }
+            println!("{}", line);
+        }
+
+        let changes: Vec<&PlanItem> = self.items.iter().filter(|item| item.action != Action::Keep).collect();
+        let download: u64 = changes.iter().filter_map(|item| item.download_size).sum();
+        let delta: i64 = changes.iter().filter_map(|item| item.disk_delta).sum();
+        let unknown = changes.iter().any(|item| item.download_size.is_none() || item.disk_delta.is_none());
+        println!(
+            "Total download: {}, disk space change: {}{}",
+            format_size(download),
+            format_delta(delta),
+            if unknown { " (some sizes unknown)" } else { "" },
+        );
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.2} MB", bytes as f64 / 1_000_000.0)
+}
+
+fn format_delta(bytes: i64) -> String {
+    format!("{}{:.2} MB", if bytes < 0 { "-" } else { "+" }, bytes.unsigned_abs() as f64 / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replacing_compares_versions() {
+        let from = |from: &str| from.to_string();
+        assert_eq!(Action::replacing("1.2", "1.10"), Action::Upgrade { from: from("1.2") });
+        assert_eq!(Action::replacing("2.0", "2.0-rc1"), Action::Downgrade { from: from("2.0") });
+        assert_eq!(Action::replacing("1.2", "1.2.0"), Action::Reinstall);
+        assert_eq!(Action::replacing("nightly", "nightly"), Action::Reinstall);
+        assert_eq!(Action::replacing("nightly", "1.0"), Action::Upgrade { from: from("nightly") });
+    }
+}
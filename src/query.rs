@@ -2,21 +2,34 @@ use anyhow::Result;
 use fuzzy_matcher::FuzzyMatcher;
 use fuzzy_matcher::skim::SkimMatcherV2;
 
-use crate::config::Config;
+use crate::config::{Config, Scope};
 use crate::repo::cache::load_unified_index;
-use crate::install::load_database;
+use crate::install::{load_database, PackageDatabase};
 
+/// Installed packages of both scopes. User installs take precedence, so a
+/// system package with the same name is marked as shadowed. With --system,
+/// only the system-wide installation is shown.
 pub async fn query_packages(info: bool, package: Option<&str>) -> Result<()> {
     let config = Config::new()?;
     let db = load_database(&config).await?;
+    let system_db = match config.scope {
+        Scope::User => load_database(&Config::for_scope(Scope::System)?).await?,
+        Scope::System => PackageDatabase::new(),
+    };
     
     if let Some(pkg_name) = package {
-        if let Some(pkg) = db.get_package(pkg_name) {
+        let found = db.get_package(pkg_name).map(|pkg| (pkg, config.scope))
+            .or_else(|| system_db.get_package(pkg_name).map(|pkg| (pkg, Scope::System)));
+        if let Some((pkg, scope)) = found {
             if info {
                 println!("Name: {}", pkg.name);
                 println!("Version: {}", pkg.version);
+                println!("Scope: {}", scope_label(scope));
                 println!("Path: {}", pkg.path.display());
                 println!("Installed at: {}", pkg.installed_at);
+                if let Some(system_pkg) = system_db.get_package(pkg_name).filter(|_| scope == Scope::User) {
+                    println!("Shadows: system install of version {}", system_pkg.version);
+                }
             } else {
                 println!("{} {}", pkg.name, pkg.version);
             }
@@ -24,15 +37,25 @@ pub async fn query_packages(info: bool, package: Option<&str>) -> Result<()> {
             println!("Package not installed: {}", pkg_name);
         }
     } else {
-        let packages = db.list_packages();
+        let mut packages: Vec<_> = db.list_packages().into_iter()
+            .map(|pkg| (pkg, ""))
+            .chain(system_db.list_packages().into_iter().map(|pkg| {
+                if db.get_package(&pkg.name).is_some() {
+                    (pkg, " [system, shadowed by user install]")
+                } else {
+                    (pkg, " [system]")
+                }
+            }))
+            .collect();
+        packages.sort_by(|(a, a_scope), (b, b_scope)| a.name.cmp(&b.name).then(a_scope.cmp(b_scope)));
         if packages.is_empty() {
             println!("No packages installed");
         } else {
-            for pkg in packages {
+            for (pkg, scope) in packages {
                 if info {
-                    println!("{} {} - {}", pkg.name, pkg.version, pkg.path.display());
+                    println!("{} {}{} - {}", pkg.name, pkg.version, scope, pkg.path.display());
                 } else {
-                    println!("{} {}", pkg.name, pkg.version);
+                    println!("{} {}{}", pkg.name, pkg.version, scope);
                 }
             }
         }
@@ -41,6 +64,13 @@ pub async fn query_packages(info: bool, package: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+fn scope_label(scope: Scope) -> &'static str {
+    match scope {
+        Scope::User => "user",
+        Scope::System => "system",
+    }
+}
+
 pub async fn search_packages(query: &str) -> Result<()> {
     let index = load_unified_index().await?;
     
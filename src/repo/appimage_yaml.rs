@@ -2,9 +2,18 @@ use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
+use crate::verify::is_sha256;
+use crate::version::{satisfies, Version};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AppImageYaml {
     pub apps: Vec<AppImageEntry>,
+    /// Metadata must not be used after this time (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// Monotonically increasing metadata version, guards against rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -21,6 +30,9 @@ pub struct AppImageEntry {
     pub dependencies: Vec<String>,
     #[serde(default)]
     pub provides: Vec<String>,
+    /// .zsync file for delta updates, relative to the source like `file`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zsync: Option<String>,
 }
 
 impl AppImageYaml {
@@ -34,8 +46,8 @@ impl AppImageYaml {
             if app.sha256.is_empty() {
                 anyhow::bail!("SHA256 is mandatory for app: {}", app.name);
             }
-            if app.sha256.len() != 64 {
-                anyhow::bail!("Invalid SHA256 length for app: {}", app.name);
+            if !is_sha256(&app.sha256) {
+                anyhow::bail!("Invalid SHA256 for app: {}", app.name);
             }
             if app.name.is_empty() {
                 anyhow::bail!("App name cannot be empty");
@@ -70,33 +82,50 @@ impl UnifiedIndex {
     }
 
     pub fn add_entry(&mut self, entry: AppImageEntry, source_url: String) {
-        self.apps
+        let entries = self.apps
             .entry(entry.name.clone())
-            .or_insert_with(Vec::new)
-            .push(AppImageEntryWithSource {
-                entry,
-                source_url,
-            });
+            .or_default();
+        
+        // Re-resolved sources replace their previous entry instead of duplicating it
+        entries.retain(|e| !(e.entry.version == entry.version && e.source_url == source_url));
+        entries.push(AppImageEntryWithSource {
+            entry,
+            source_url,
+        });
+        
+        // Keep versions in ascending order for listings
+        entries.sort_by_cached_key(|e| Version::parse(&e.entry.version));
     }
 
+    /// Newest entry of `name`, optionally limited to versions matching
+    /// `version_req`. A requirement that is not a valid version range only
+    /// matches that exact version string.
     pub fn find_best_match(&self, name: &str, version_req: Option<&str>) -> Option<&AppImageEntryWithSource> {
-        if let Some(entries) = self.apps.get(name) {
-            if let Some(req) = version_req {
-                // Use semver for version matching
-                if let Ok(version_req) = semver::VersionReq::parse(req) {
-                    return entries.iter()
-                        .filter_map(|e| {
-                            semver::Version::parse(&e.entry.version).ok()
-                                .and_then(|v| if version_req.matches(&v) { Some(e) } else { None })
-                        })
-                        .max_by_key(|e| semver::Version::parse(&e.entry.version).ok());
-                }
-            }
-            // Return latest version
-            return entries.iter()
-                .max_by_key(|e| semver::Version::parse(&e.entry.version).ok());
-        }
-        None
+        self.find_best_match_from(name, version_req, None)
+    }
+
+    /// Like `find_best_match`, but only considers entries published by the
+    /// source `source` if given: entries of that index or of indexes and
+    /// release pages next to it.
+    pub fn find_best_match_from(
+        &self,
+        name: &str,
+        version_req: Option<&str>,
+        source: Option<&str>,
+    ) -> Option<&AppImageEntryWithSource> {
+        self.apps.get(name)?
+            .iter()
+            .filter(|e| version_req.is_none_or(|req| satisfies(req, &e.entry.version)))
+            .filter(|e| source.is_none_or(|source| published_under(&e.source_url, source)))
+            // Versions that cannot be parsed sort before all others
+            .max_by_key(|e| Version::parse(&e.entry.version))
+    }
+
+    pub fn find_by_sha256(&self, sha256: &str) -> Option<&AppImageEntryWithSource> {
+        let sha256 = sha256.to_lowercase();
+        self.apps.values()
+            .flatten()
+            .find(|e| e.entry.sha256.to_lowercase() == sha256)
     }
 }
 
@@ -106,3 +135,13 @@ impl Default for UnifiedIndex {
     }
 }
 
+/// Whether an index entry or download URL belongs to the source `source`.
+pub fn published_under(entry_source: &str, source: &str) -> bool {
+    if entry_source == source {
+        return true;
+    }
+    let Ok(dir) = url::Url::parse(source).and_then(|url| url.join(".")) else {
+        return false;
+    };
+    entry_source.starts_with(dir.as_str())
+}
@@ -4,15 +4,47 @@ use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use hex;
 
-use crate::config::Config;
+use crate::config::{stale_allowed, Config};
 use crate::repo::appimage_yaml::UnifiedIndex;
 use crate::repo::collectives::{CollectivesYaml, SourcesYaml};
-use crate::repo::resolver::Resolver;
+use crate::repo::resolver::{parse_expiry, Resolver};
+
+/// Version of cache_metadata.yaml. Hashes recorded by older versions may
+/// belong to sources with nested release sources and are dropped.
+const CACHE_FORMAT: u32 = 2;
 
 #[derive(Debug, Serialize, Deserialize)]
 struct CacheMetadata {
+    #[serde(default)]
+    format: u32,
     last_updated: String,
     source_hashes: std::collections::HashMap<String, String>,
+    /// Highest metadata serial seen per source URL
+    #[serde(default)]
+    source_serials: std::collections::HashMap<String, u64>,
+    /// Earliest expiry of the metadata of each source, after which its
+    /// entries in the unified index are not used
+    #[serde(default)]
+    source_expiries: std::collections::HashMap<String, String>,
+}
+
+async fn load_cache_metadata(config: &Config) -> Result<CacheMetadata> {
+    let cache_metadata_path = config.cache_dir.join("cache_metadata.yaml");
+    if !cache_metadata_path.exists() {
+        return Ok(CacheMetadata {
+            format: CACHE_FORMAT,
+            last_updated: String::new(),
+            source_hashes: std::collections::HashMap::new(),
+            source_serials: std::collections::HashMap::new(),
+            source_expiries: std::collections::HashMap::new(),
+        });
+    }
+    // The serials seen so far guard against rollbacks, so a broken file
+    // must not silently reset them
+    let content = fs::read_to_string(&cache_metadata_path).await
+        .context(format!("Failed to read {}", cache_metadata_path.display()))?;
+    serde_yaml::from_str::<CacheMetadata>(&content)
+        .context(format!("Failed to parse {}, fix or remove it to accept any metadata serial again", cache_metadata_path.display()))
 }
 
 pub async fn update_unified_index() -> Result<()> {
@@ -24,32 +56,27 @@ pub async fn update_unified_index() -> Result<()> {
     
     // Load existing cache metadata if available
     let cache_metadata_path = config.cache_dir.join("cache_metadata.yaml");
-    let mut existing_metadata = if cache_metadata_path.exists() {
-        let content = fs::read_to_string(&cache_metadata_path).await?;
-        serde_yaml::from_str::<CacheMetadata>(&content).unwrap_or_else(|_| CacheMetadata {
-            last_updated: String::new(),
-            source_hashes: std::collections::HashMap::new(),
-        })
-    } else {
-        CacheMetadata {
-            last_updated: String::new(),
-            source_hashes: std::collections::HashMap::new(),
-        }
-    };
+    let mut existing_metadata = load_cache_metadata(&config).await?;
+    if existing_metadata.format < CACHE_FORMAT {
+        existing_metadata.source_hashes.clear();
+        existing_metadata.format = CACHE_FORMAT;
+    }
     
     // Load existing unified index if available for incremental updates
     let mut existing_index = if config.unified_index_cache.exists() {
-        load_unified_index().await.ok()
+        read_unified_index(&config).await.ok()
     } else {
         None
     };
     
     // Resolve sources with incremental updates
-    let mut resolver = Resolver::new()?;
+    let mut resolver = Resolver::new()?.allow_stale(stale_allowed());
     let index = resolver.resolve_sources_incremental(
         sources,
         &mut existing_index,
         &mut existing_metadata.source_hashes,
+        &mut existing_metadata.source_serials,
+        &mut existing_metadata.source_expiries,
     ).await?;
     
     // Update cache metadata
@@ -74,6 +101,9 @@ pub async fn calculate_yaml_hash(content: &str) -> String {
     hex::encode(hasher.finalize())
 }
 
+/// The unified index built by the last update. Fails once the metadata of a
+/// source has expired, so a frozen repository cannot keep serving outdated
+/// packages, unless --allow-stale is given.
 pub async fn load_unified_index() -> Result<UnifiedIndex> {
     let config = Config::new()?;
     
@@ -81,6 +111,32 @@ pub async fn load_unified_index() -> Result<UnifiedIndex> {
         anyhow::bail!("Unified index not found. Run 'aipkg update' first.");
     }
     
+    let metadata = load_cache_metadata(&config).await?;
+    for (source, expires) in expired_sources(&metadata, chrono::Utc::now()) {
+        if !stale_allowed() {
+            anyhow::bail!(
+                "Metadata of source {} expired at {}, run 'aipkg update' (or use --allow-stale to use it anyway)",
+                source, expires
+            );
+        }
+        println!("Warning: metadata of source {} expired at {}", source, expires);
+    }
+    
+    read_unified_index(&config).await
+}
+
+/// Sources whose metadata expired before `now`, with their expiry.
+fn expired_sources(metadata: &CacheMetadata, now: chrono::DateTime<chrono::Utc>) -> Vec<(&str, &str)> {
+    let mut expired: Vec<(&str, &str)> = metadata.source_expiries.iter()
+        // An unreadable expiry counts as expired
+        .filter(|(_, expires)| !parse_expiry(expires).is_ok_and(|expiry| expiry >= now))
+        .map(|(source, expires)| (source.as_str(), expires.as_str()))
+        .collect();
+    expired.sort();
+    expired
+}
+
+async fn read_unified_index(config: &Config) -> Result<UnifiedIndex> {
     let content = fs::read_to_string(&config.unified_index_cache).await
         .context("Failed to read unified index cache")?;
     
@@ -114,3 +170,28 @@ async fn load_all_sources(config: &Config) -> Result<Vec<String>> {
     Ok(sources)
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sources_past_their_expiry_are_reported() {
+        let metadata: CacheMetadata = serde_yaml::from_str(r#"
+            format: 2
+            last_updated: "2026-01-01T00:00:00Z"
+            source_hashes: {}
+            source_expiries:
+              https://a.example.com/index.yaml: "2026-03-01T00:00:00+00:00"
+              https://b.example.com/index.yaml: "2026-06-01T00:00:00+00:00"
+              https://c.example.com/index.yaml: "soon"
+        "#).unwrap();
+        let at = |time: &str| parse_expiry(time).unwrap();
+
+        assert_eq!(expired_sources(&metadata, at("2026-02-01")), vec![("https://c.example.com/index.yaml", "soon")]);
+        assert_eq!(expired_sources(&metadata, at("2026-04-01")), vec![
+            ("https://a.example.com/index.yaml", "2026-03-01T00:00:00+00:00"),
+            ("https://c.example.com/index.yaml", "soon"),
+        ]);
+    }
+}
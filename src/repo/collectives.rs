@@ -144,6 +144,7 @@ pub async fn add_to_collective(name: &str, urls: Vec<String>) -> Result<()> {
 
 pub async fn remove_collective(name: &str) -> Result<()> {
     let config = Config::new()?;
+    config.check_privileges()?;
     
     if !config.collectives_file.exists() {
         anyhow::bail!("No collectives file found");
@@ -0,0 +1,482 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::fs;
+use tokio::io::AsyncWriteExt;
+use url::Url;
+
+use crate::config::Config;
+use crate::repo::url_under_prefix;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CredentialsToml {
+    #[serde(default)]
+    pub credentials: Vec<HostCredential>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostCredential {
+    /// Host name ("example.com", "example.com:8443") or URL prefix ("https://example.com/private/")
+    #[serde(rename = "match")]
+    pub pattern: String,
+    /// Sent as a bearer token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Sent as basic auth, together with `password`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Auth {
+    Bearer(String),
+    Basic { username: String, password: Option<String> },
+}
+
+impl HostCredential {
+    /// How specifically this credential matches `url`; URL prefixes beat host
+    /// names and longer patterns beat shorter ones. Host names only match
+    /// https URLs, plain HTTP needs a URL prefix that names its origin.
+    fn match_rank(&self, url: &Url) -> Option<(bool, usize)> {
+        if self.pattern.contains("://") {
+            let prefix = Url::parse(&self.pattern).ok()?;
+            return url_under_prefix(url, &prefix)
+                .then_some((true, self.pattern.len()));
+        }
+
+        if url.scheme() != "https" {
+            return None;
+        }
+        let host = url.host_str()?;
+        let host_port = url.port().map(|port| format!("{}:{}", host, port));
+        let matches = self.pattern.eq_ignore_ascii_case(host)
+            || host_port.is_some_and(|hp| self.pattern.eq_ignore_ascii_case(&hp));
+        matches.then_some((false, self.pattern.len()))
+    }
+
+    fn auth(&self) -> Option<Auth> {
+        if let Some(token) = &self.token {
+            return Some(Auth::Bearer(token.clone()));
+        }
+        self.username.as_ref().map(|username| Auth::Basic {
+            username: username.clone(),
+            password: self.password.clone(),
+        })
+    }
+}
+
+impl CredentialsToml {
+    pub fn from_str(content: &str) -> Result<Self> {
+        toml::from_str(content)
+            .context("Failed to parse credentials.toml")
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        toml::to_string(self)
+            .context("Failed to serialize credentials.toml")
+    }
+
+    /// Replaces any credential stored for exactly `pattern`.
+    pub fn set(&mut self, credential: HostCredential) {
+        self.credentials.retain(|c| c.pattern != credential.pattern);
+        self.credentials.push(credential);
+    }
+
+    pub fn remove(&mut self, pattern: &str) -> bool {
+        let initial_len = self.credentials.len();
+        self.credentials.retain(|c| c.pattern != pattern);
+        self.credentials.len() < initial_len
+    }
+}
+
+/// A `machine` (or `default`) entry of a netrc file
+#[derive(Debug, Clone)]
+struct NetrcEntry {
+    /// `None` for the `default` entry, which is never used: it would hand
+    /// the credentials to any host an index points at
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+fn parse_netrc(content: &str) -> Vec<NetrcEntry> {
+    let mut entries: Vec<NetrcEntry> = Vec::new();
+    let mut tokens = content.split_whitespace();
+
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => entries.push(NetrcEntry {
+                machine: tokens.next().map(|s| s.to_string()),
+                login: None,
+                password: None,
+            }),
+            "default" => entries.push(NetrcEntry {
+                machine: None,
+                login: None,
+                password: None,
+            }),
+            "login" => {
+                let value = tokens.next().map(|s| s.to_string());
+                if let Some(entry) = entries.last_mut() {
+                    entry.login = value;
+                }
+            }
+            "password" => {
+                let value = tokens.next().map(|s| s.to_string());
+                if let Some(entry) = entries.last_mut() {
+                    entry.password = value;
+                }
+            }
+            "account" => {
+                tokens.next();
+            }
+            _ => {}
+        }
+    }
+
+    entries
+}
+
+/// Credentials from credentials.toml and the forge tokens of config.toml,
+/// falling back to ~/.netrc.
+#[derive(Debug, Clone, Default)]
+pub struct Credentials {
+    stored: Vec<HostCredential>,
+    netrc: Vec<NetrcEntry>,
+}
+
+impl Credentials {
+    pub fn load(config: &Config) -> Result<Self> {
+        // Forge tokens also download the release assets of private projects.
+        // They come first, so an entry of credentials.toml for the same host wins
+        let mut stored: Vec<HostCredential> = config.forge_tokens.iter()
+            .map(|(host, token)| HostCredential {
+                pattern: host.clone(),
+                token: Some(token.clone()),
+                username: None,
+                password: None,
+            })
+            .collect();
+        if config.credentials_file.exists() {
+            warn_if_world_readable(&config.credentials_file);
+            let content = std::fs::read_to_string(&config.credentials_file)
+                .context("Failed to read credentials.toml")?;
+            stored.extend(CredentialsToml::from_str(&content)?.credentials);
+        }
+
+        let netrc = std::fs::read_to_string(&config.netrc_file)
+            .map(|content| parse_netrc(&content))
+            .unwrap_or_default();
+
+        Ok(Self { stored, netrc })
+    }
+
+    /// Credentials to send with a request to `url`, if any.
+    pub fn auth_for(&self, url: &str) -> Option<Auth> {
+        let url = Url::parse(url).ok()?;
+
+        let stored = self.stored.iter()
+            .filter_map(|c| c.match_rank(&url).map(|rank| (rank, c)))
+            .max_by_key(|(rank, _)| *rank)
+            .and_then(|(_, c)| c.auth());
+        if stored.is_some() {
+            return stored;
+        }
+
+        // netrc names hosts only, so like host name patterns it needs TLS
+        if url.scheme() != "https" {
+            return None;
+        }
+        let host = url.host_str()?;
+        let entry = self.netrc.iter()
+            .find(|e| e.machine.as_deref() == Some(host))?;
+        entry.login.as_ref().map(|login| Auth::Basic {
+            username: login.clone(),
+            password: entry.password.clone(),
+        })
+    }
+}
+
+fn warn_if_world_readable(path: &Path) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            if metadata.permissions().mode() & 0o077 != 0 {
+                println!(
+                    "Warning: {} is accessible by other users, run 'chmod 600 {}'",
+                    path.display(), path.display()
+                );
+            }
+        }
+    }
+}
+
+/// Removes the user info from a URL so it can be shown in messages.
+pub fn redact_url(url: &str) -> String {
+    match Url::parse(url) {
+        Ok(mut parsed) if !parsed.username().is_empty() || parsed.password().is_some() => {
+            let _ = parsed.set_username("");
+            let _ = parsed.set_password(None);
+            parsed.to_string()
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// Directory of a source URL, the prefix its credentials are stored under.
+/// A last path segment without an extension is taken to be a directory
+/// itself, so "https://host/repo" does not hand the token to all of host.
+pub fn source_prefix(url: &str) -> Result<String> {
+    let mut parsed = Url::parse(url).context(format!("Invalid URL: {}", redact_url(url)))?;
+    parsed.set_query(None);
+    parsed.set_fragment(None);
+
+    let path = parsed.path().to_string();
+    let last = path.rsplit('/').next().unwrap_or("");
+    let dir = if last.contains('.') {
+        &path[..path.len() - last.len()]
+    } else {
+        path.trim_end_matches('/')
+    };
+    parsed.set_path(&format!("{}/", dir.trim_end_matches('/')));
+    Ok(parsed.to_string())
+}
+
+/// Refuses to store a secret for a URL it would be sent to in the clear.
+/// Plain HTTP is only accepted for the local machine.
+pub fn check_secure_transport(url: &str) -> Result<()> {
+    let parsed = Url::parse(url).context(format!("Invalid URL: {}", redact_url(url)))?;
+    let local = match parsed.host() {
+        Some(url::Host::Domain(domain)) => domain.eq_ignore_ascii_case("localhost"),
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    };
+    if parsed.scheme() != "https" && !local {
+        anyhow::bail!(
+            "Refusing to store credentials for {}: they would be sent unencrypted, use an https:// URL",
+            redact_url(url)
+        );
+    }
+    Ok(())
+}
+
+async fn load_credentials_file(config: &Config) -> Result<CredentialsToml> {
+    if config.credentials_file.exists() {
+        let content = fs::read_to_string(&config.credentials_file).await?;
+        CredentialsToml::from_str(&content)
+    } else {
+        Ok(CredentialsToml::default())
+    }
+}
+
+async fn save_credentials_file(config: &Config, credentials: &CredentialsToml) -> Result<()> {
+    let content = credentials.to_string()?;
+
+    // Restrict the file before any secret is written to it
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&config.credentials_file).await
+        .context("Failed to write credentials.toml")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600)).await?;
+    }
+    file.write_all(content.as_bytes()).await
+        .context("Failed to write credentials.toml")?;
+    file.flush().await
+        .context("Failed to write credentials.toml")?;
+
+    Ok(())
+}
+
+/// Stores a token for the URL prefix of a source. With `username` the token is
+/// sent as the basic auth password instead of a bearer token.
+pub async fn store_source_token(config: &Config, url: &str, token: &str, username: Option<&str>) -> Result<()> {
+    check_secure_transport(url)?;
+    let pattern = source_prefix(url)?;
+    let credential = match username {
+        Some(username) => HostCredential {
+            pattern: pattern.clone(),
+            token: None,
+            username: Some(username.to_string()),
+            password: Some(token.to_string()),
+        },
+        None => HostCredential {
+            pattern: pattern.clone(),
+            token: Some(token.to_string()),
+            username: None,
+            password: None,
+        },
+    };
+
+    let mut credentials = load_credentials_file(config).await?;
+    credentials.set(credential);
+    save_credentials_file(config, &credentials).await?;
+
+    println!("Stored credentials for {}", pattern);
+    Ok(())
+}
+
+/// Drops the credentials `add-source --token` stored for a source.
+pub async fn remove_source_token(config: &Config, url: &str) -> Result<()> {
+    if !config.credentials_file.exists() {
+        return Ok(());
+    }
+
+    let pattern = source_prefix(url)?;
+    let mut credentials = load_credentials_file(config).await?;
+    if credentials.remove(&pattern) {
+        save_credentials_file(config, &credentials).await?;
+        println!("Removed credentials for {}", pattern);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_prefix_is_the_directory_of_the_source() {
+        let cases = [
+            ("https://example.com/repo/index.yaml", "https://example.com/repo/"),
+            ("https://example.com/repo/index.yaml?ref=main#top", "https://example.com/repo/"),
+            ("https://example.com/repo", "https://example.com/repo/"),
+            ("https://example.com/repo/", "https://example.com/repo/"),
+            ("https://example.com/a/b/appimage.yml", "https://example.com/a/b/"),
+            ("https://example.com/index.yaml", "https://example.com/"),
+            ("https://example.com", "https://example.com/"),
+        ];
+        for (url, prefix) in cases {
+            assert_eq!(source_prefix(url).unwrap(), prefix, "{}", url);
+        }
+    }
+
+    #[test]
+    fn credentials_are_scoped_to_the_source_directory() {
+        let credentials = Credentials {
+            stored: vec![HostCredential {
+                pattern: source_prefix("https://example.com/private").unwrap(),
+                token: Some("secret".to_string()),
+                username: None,
+                password: None,
+            }],
+            netrc: Vec::new(),
+        };
+        assert_eq!(
+            credentials.auth_for("https://example.com/private/app.AppImage"),
+            Some(Auth::Bearer("secret".to_string()))
+        );
+        assert_eq!(credentials.auth_for("https://example.com/public/index.yaml"), None);
+        assert_eq!(credentials.auth_for("https://example.com/private-other/index.yaml"), None);
+    }
+
+    #[test]
+    fn url_patterns_do_not_match_lookalike_hosts() {
+        let credentials = Credentials {
+            stored: vec![HostCredential {
+                pattern: "https://example.com".to_string(),
+                token: Some("secret".to_string()),
+                username: None,
+                password: None,
+            }],
+            netrc: Vec::new(),
+        };
+        assert_eq!(
+            credentials.auth_for("https://example.com/repo/index.yaml"),
+            Some(Auth::Bearer("secret".to_string()))
+        );
+        assert_eq!(credentials.auth_for("https://example.com.attacker.net/index.yaml"), None);
+        assert_eq!(credentials.auth_for("https://example.com@attacker.net/index.yaml"), None);
+        assert_eq!(credentials.auth_for("https://example.com:8443/index.yaml"), None);
+        assert_eq!(credentials.auth_for("http://example.com/index.yaml"), None);
+    }
+
+    #[test]
+    fn host_names_only_match_https() {
+        let credentials = Credentials {
+            stored: vec![
+                HostCredential {
+                    pattern: "files.example.com".to_string(),
+                    token: Some("secret".to_string()),
+                    username: None,
+                    password: None,
+                },
+                HostCredential {
+                    pattern: "http://localhost:8080/repo/".to_string(),
+                    token: Some("local".to_string()),
+                    username: None,
+                    password: None,
+                },
+            ],
+            netrc: parse_netrc("machine mirror.example.com login ci password s3cr3t"),
+        };
+        assert_eq!(
+            credentials.auth_for("https://files.example.com/app.AppImage"),
+            Some(Auth::Bearer("secret".to_string()))
+        );
+        assert_eq!(credentials.auth_for("http://files.example.com/app.AppImage"), None);
+        assert_eq!(
+            credentials.auth_for("https://mirror.example.com/index.yaml"),
+            Some(Auth::Basic { username: "ci".to_string(), password: Some("s3cr3t".to_string()) })
+        );
+        assert_eq!(credentials.auth_for("http://mirror.example.com/index.yaml"), None);
+        // A URL prefix naming the http origin is explicit enough
+        assert_eq!(
+            credentials.auth_for("http://localhost:8080/repo/index.yaml"),
+            Some(Auth::Bearer("local".to_string()))
+        );
+    }
+
+    #[test]
+    fn forge_tokens_download_assets_from_their_host() {
+        let mut config = Config::located(crate::config::Scope::User).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        config.credentials_file = dir.path().join("credentials.toml");
+        config.netrc_file = dir.path().join("netrc");
+        config.forge_tokens = [("codeberg.org".to_string(), "forge".to_string())].into();
+        let credentials = Credentials::load(&config).unwrap();
+
+        assert_eq!(
+            credentials.auth_for("https://codeberg.org/owner/app/releases/download/v1.0/app.AppImage"),
+            Some(Auth::Bearer("forge".to_string()))
+        );
+        assert_eq!(credentials.auth_for("http://codeberg.org/owner/app/releases/download/v1.0/app.AppImage"), None);
+        assert_eq!(credentials.auth_for("https://files.example.com/app.AppImage"), None);
+
+        // credentials.toml takes precedence for the same host
+        std::fs::write(&config.credentials_file, "[[credentials]]\nmatch = \"codeberg.org\"\ntoken = \"stored\"\n").unwrap();
+        let credentials = Credentials::load(&config).unwrap();
+        assert_eq!(
+            credentials.auth_for("https://codeberg.org/owner/app/releases/download/v1.0/app.AppImage"),
+            Some(Auth::Bearer("stored".to_string()))
+        );
+    }
+
+    #[test]
+    fn netrc_default_is_never_sent() {
+        let credentials = Credentials {
+            stored: Vec::new(),
+            netrc: parse_netrc("machine mirror.example.com login ci password s3cr3t\ndefault login anonymous password me@example.com"),
+        };
+        assert!(credentials.auth_for("https://mirror.example.com/index.yaml").is_some());
+        assert_eq!(credentials.auth_for("https://attacker.example.net/app.AppImage"), None);
+        assert_eq!(credentials.auth_for("http://attacker.example.net/app.AppImage"), None);
+    }
+
+    #[test]
+    fn secrets_need_tls_except_locally() {
+        assert!(check_secure_transport("https://example.com/repo/index.yaml").is_ok());
+        assert!(check_secure_transport("http://localhost:8080/index.yaml").is_ok());
+        assert!(check_secure_transport("http://127.0.0.1/index.yaml").is_ok());
+        assert!(check_secure_transport("http://[::1]/index.yaml").is_ok());
+        assert!(check_secure_transport("http://example.com/repo/index.yaml").is_err());
+    }
+}
@@ -1,22 +1,175 @@
 use anyhow::{Context, Result};
-use indicatif::{ProgressBar, ProgressStyle};
-use reqwest::Client;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::{Certificate, Client, NoProxy, Proxy, RequestBuilder};
+use reqwest::header::{HeaderMap, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
 use std::time::Duration;
 use futures_util::StreamExt;
 
+use crate::config::Config;
+use crate::repo::credentials::{redact_url, Auth, Credentials};
+use crate::repo::throttle::RateLimiter;
+
+/// Timeout for metadata requests; AppImage downloads may take longer
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Progress display shared by concurrent downloads: one bar per download
+/// plus an overall total.
+#[derive(Clone)]
+pub struct DownloadProgress {
+    multi: MultiProgress,
+    total: ProgressBar,
+}
+
+impl DownloadProgress {
+    /// `known_size` is the combined size of the downloads that declare one;
+    /// the others are added once the server reports their length.
+    pub fn new(known_size: u64) -> Self {
+        let multi = MultiProgress::new();
+        let total = multi.add(ProgressBar::new(known_size));
+        total.set_style(
+            ProgressStyle::default_bar()
+                .template("Total [{elapsed_precise}] [{wide_bar:.green}] {bytes}/{total_bytes} ({eta})")
+                .unwrap()
+                .progress_chars("#>-")
+        );
+        Self { multi, total }
+    }
+
+    /// Ends the display. After a failure all bars are removed.
+    pub fn finish(&self, success: bool) {
+        if success {
+            self.total.finish();
+        } else {
+            let _ = self.multi.clear();
+        }
+    }
+}
+
+/// Response headers that tell whether a URL still serves the same file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validators {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub etag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<String>,
+}
+
+impl Validators {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let header = |name| headers.get(name)
+            .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+            .map(str::to_string);
+        Self {
+            etag: header(ETAG),
+            last_modified: header(LAST_MODIFIED),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.etag.is_none() && self.last_modified.is_none()
+    }
+
+    /// Whether a response with `other` is the same file. The ETag decides
+    /// when both have one, otherwise the modification time.
+    fn matches(&self, other: &Validators) -> bool {
+        match (&self.etag, &other.etag) {
+            (Some(a), Some(b)) => a == b,
+            _ => self.last_modified.is_some() && self.last_modified == other.last_modified,
+        }
+    }
+}
+
 pub struct Fetcher {
     client: Client,
+    /// Client without certificate verification, for `insecure_hosts`
+    insecure_client: Option<Client>,
+    insecure_hosts: Vec<String>,
+    credentials: Credentials,
+    limiter: Option<RateLimiter>,
+    progress: Option<DownloadProgress>,
 }
 
 impl Fetcher {
-    pub fn new() -> Result<Self> {
-        let client = Client::builder()
-            .timeout(Duration::from_secs(30))
+    pub fn new(config: &Config) -> Result<Self> {
+        let client = Self::build_client(config, false)?;
+        let insecure_client = if config.insecure_hosts.is_empty() {
+            None
+        } else {
+            Some(Self::build_client(config, true)?)
+        };
+        
+        Ok(Self {
+            client,
+            insecure_client,
+            insecure_hosts: config.insecure_hosts.clone(),
+            credentials: Credentials::load(config)?,
+            limiter: config.max_download_rate.map(RateLimiter::new),
+            progress: None,
+        })
+    }
+
+    /// Show AppImage downloads as part of a shared multi-download display.
+    pub fn with_progress(mut self, progress: DownloadProgress) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    fn build_client(config: &Config, accept_invalid_certs: bool) -> Result<Client> {
+        // Proxies come from the config, which already merged the environment
+        let mut builder = Client::builder()
+            .connect_timeout(REQUEST_TIMEOUT)
             .user_agent("aipkg/0.1.0")
-            .build()
-            .context("Failed to create HTTP client")?;
+            .no_proxy();
+        
+        let no_proxy = NoProxy::from_string(&config.no_proxy.join(","));
+        if let Some(proxy) = &config.https_proxy {
+            let proxy = Proxy::https(proxy)
+                .context(format!("Invalid proxy: {}", redact_url(proxy)))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy.clone()));
+        }
+        if let Some(proxy) = &config.http_proxy {
+            let proxy = Proxy::http(proxy)
+                .context(format!("Invalid proxy: {}", redact_url(proxy)))?;
+            builder = builder.proxy(proxy.no_proxy(no_proxy));
+        }
+        
+        if let Some(ca_bundle) = &config.ca_bundle {
+            let pem = std::fs::read(ca_bundle)
+                .context(format!("Failed to read CA bundle: {}", ca_bundle.display()))?;
+            let certificates = Certificate::from_pem_bundle(&pem)
+                .context(format!("Invalid CA bundle: {}", ca_bundle.display()))?;
+            for certificate in certificates {
+                builder = builder.add_root_certificate(certificate);
+            }
+        }
         
-        Ok(Self { client })
+        builder
+            .danger_accept_invalid_certs(accept_invalid_certs)
+            .build()
+            .context("Failed to create HTTP client")
+    }
+
+    fn client_for(&self, url: &str) -> &Client {
+        let host = url::Url::parse(url).ok()
+            .and_then(|u| u.host_str().map(|h| h.to_string()));
+        match (&self.insecure_client, host) {
+            (Some(insecure), Some(host)) if self.insecure_hosts.iter().any(|h| h.eq_ignore_ascii_case(&host)) => insecure,
+            _ => &self.client,
+        }
+    }
+
+    /// GET request carrying the configured credentials for `url`.
+    fn get(&self, url: &str) -> RequestBuilder {
+        self.authorized(self.client_for(url).get(url), url)
+    }
+
+    fn authorized(&self, request: RequestBuilder, url: &str) -> RequestBuilder {
+        match self.credentials.auth_for(url) {
+            Some(Auth::Bearer(token)) => request.bearer_auth(token),
+            Some(Auth::Basic { username, password }) => request.basic_auth(username, password),
+            None => request,
+        }
     }
 
     pub async fn fetch_yaml(&self, url: &str) -> Result<String> {
@@ -28,65 +181,199 @@ impl Fetcher {
                 .template("{spinner:.green} Fetching {msg}...")
                 .unwrap()
         );
-        pb.set_message(url.clone());
+        pb.set_message(redact_url(&url));
         
-        let response = self.client
-            .get(&url)
+        let response = self.get(&url)
+            .timeout(REQUEST_TIMEOUT)
             .send()
             .await
-            .context(format!("Failed to fetch: {}", url))?;
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch: {}", redact_url(&url)))?;
         
         pb.finish_with_message("Done");
         
         if !response.status().is_success() {
-            anyhow::bail!("HTTP error {}: {}", response.status(), url);
+            anyhow::bail!("HTTP error {}: {}", response.status(), redact_url(&url));
         }
         
         let content = response.text().await
-            .context(format!("Failed to read response from: {}", url))?;
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to read response from: {}", redact_url(&url)))?;
         
         Ok(content)
     }
 
+    pub async fn fetch_bytes(&self, url: &str) -> Result<Vec<u8>> {
+        let response = self.get(url)
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch: {}", redact_url(url)))?;
+        
+        if !response.status().is_success() {
+            anyhow::bail!("HTTP error {}: {}", response.status(), redact_url(url));
+        }
+        
+        let bytes = response.bytes().await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to read response from: {}", redact_url(url)))?;
+        Ok(bytes.to_vec())
+    }
+
+    /// Fetches bytes `start..=end` of a file with an HTTP range request.
+    pub async fn fetch_range(&self, url: &str, start: u64, end: u64) -> Result<Vec<u8>> {
+        let url = self.normalize_github_url(url)?;
+        
+        let response = self.get(&url)
+            .header(reqwest::header::RANGE, format!("bytes={}-{}", start, end))
+            .send()
+            .await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch: {}", redact_url(&url)))?;
+        
+        if response.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+            anyhow::bail!(
+                "Server does not support range requests (HTTP {}): {}",
+                response.status(), redact_url(&url)
+            );
+        }
+        
+        let mut bytes = Vec::new();
+        let mut stream = response.bytes_stream();
+        while let Some(item) = stream.next().await {
+            let chunk = item.map_err(|e| e.without_url()).context("Failed to read chunk")?;
+            if let Some(limiter) = &self.limiter {
+                limiter.consume(chunk.len()).await;
+            }
+            bytes.extend_from_slice(&chunk);
+        }
+        
+        Ok(bytes)
+    }
+
+    pub async fn fetch_json<T: serde::de::DeserializeOwned>(&self, url: &str, token: Option<&str>) -> Result<T> {
+        // An explicit API token takes precedence over stored credentials
+        let request = match token {
+            Some(token) => self.client_for(url).get(url).bearer_auth(token),
+            None => self.get(url),
+        };
+        
+        let response = request
+            .header("Accept", "application/json")
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch: {}", redact_url(url)))?;
+        
+        if !response.status().is_success() {
+            anyhow::bail!("HTTP error {}: {}", response.status(), redact_url(url));
+        }
+        
+        response.json().await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to parse JSON response from: {}", redact_url(url)))
+    }
+
+    /// Asks the server with a conditional HEAD request whether `url` still
+    /// serves the file an earlier download returned `validators` for. Servers
+    /// that cannot tell count as changed.
+    pub async fn unchanged_since(&self, url: &str, validators: &Validators) -> Result<bool> {
+        if validators.is_empty() {
+            return Ok(false);
+        }
+        let url = self.normalize_github_url(url)?;
+        let mut request = self.authorized(self.client_for(&url).head(&url), &url);
+        if let Some(etag) = &validators.etag {
+            request = request.header(IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(IF_MODIFIED_SINCE, last_modified);
+        }
+        
+        let response = request
+            .timeout(REQUEST_TIMEOUT)
+            .send()
+            .await
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch: {}", redact_url(&url)))?;
+        
+        if response.status() == reqwest::StatusCode::NOT_MODIFIED {
+            return Ok(true);
+        }
+        // Some servers ignore conditional headers on HEAD but still send validators
+        Ok(response.status().is_success() && validators.matches(&Validators::from_headers(response.headers())))
+    }
+
     pub async fn fetch_appimage(&self, url: &str, expected_size: Option<u64>) -> Result<Vec<u8>> {
+        Ok(self.fetch_appimage_with_validators(url, expected_size).await?.0)
+    }
+
+    /// Downloads an AppImage along with the validators of the response, for
+    /// checking later whether the URL changed without downloading it again.
+    pub async fn fetch_appimage_with_validators(&self, url: &str, expected_size: Option<u64>) -> Result<(Vec<u8>, Validators)> {
         let url = self.normalize_github_url(url)?;
         
         let pb = ProgressBar::new(100);
         pb.set_style(
             ProgressStyle::default_bar()
-                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+                .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta}) {msg}")
                 .unwrap()
                 .progress_chars("#>-")
         );
+        let pb = match &self.progress {
+            Some(progress) => progress.multi.add(pb),
+            None => pb,
+        };
+        let file_name = url.rsplit('/').next().unwrap_or_default().to_string();
+        pb.set_message(file_name);
         
-        let response = self.client
-            .get(&url)
+        let response = self.get(&url)
             .send()
             .await
-            .context(format!("Failed to fetch AppImage: {}", url))?;
+            .map_err(|e| e.without_url())
+            .context(format!("Failed to fetch AppImage: {}", redact_url(&url)))?;
         
         if !response.status().is_success() {
-            anyhow::bail!("HTTP error {}: {}", response.status(), url);
+            anyhow::bail!("HTTP error {}: {}", response.status(), redact_url(&url));
         }
+        let validators = Validators::from_headers(response.headers());
         
         let total_size = expected_size
             .or_else(|| response.content_length())
             .unwrap_or(0);
         
         pb.set_length(total_size);
+        if let Some(progress) = &self.progress {
+            // Sizes declared in the index are already part of the total
+            if expected_size.is_none() {
+                progress.total.inc_length(total_size);
+            }
+        }
         
         let mut bytes = Vec::new();
         let mut stream = response.bytes_stream();
         
         while let Some(item) = stream.next().await {
-            let chunk = item.context("Failed to read chunk")?;
+            let chunk = item.map_err(|e| e.without_url()).context("Failed to read chunk")?;
+            if let Some(limiter) = &self.limiter {
+                limiter.consume(chunk.len()).await;
+            }
             bytes.extend_from_slice(&chunk);
             pb.set_position(bytes.len() as u64);
+            if let Some(progress) = &self.progress {
+                progress.total.inc(chunk.len() as u64);
+            }
         }
         
-        pb.finish_with_message("Download complete");
+        if self.progress.is_some() {
+            pb.finish_and_clear();
+        } else {
+            pb.finish_with_message("Download complete");
+        }
         
-        Ok(bytes)
+        Ok((bytes, validators))
     }
 
     fn normalize_github_url(&self, url: &str) -> Result<String> {
@@ -99,9 +386,3 @@ impl Fetcher {
     }
 }
 
-impl Default for Fetcher {
-    fn default() -> Self {
-        Self::new().expect("Failed to create fetcher")
-    }
-}
-
@@ -0,0 +1,258 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+
+use crate::config::Config;
+use crate::repo::appimage_yaml::{AppImageEntry, AppImageYaml};
+use crate::repo::fetcher::Fetcher;
+use crate::repo::gitea::GiteaClient;
+use crate::repo::github::GithubClient;
+use crate::repo::gitlab::GitlabClient;
+use crate::repo::index_yaml::{IndexSource, SourceType};
+use crate::verify::is_sha256;
+
+/// Number of most recent releases turned into index entries
+pub const RELEASES_PER_SOURCE: usize = 10;
+
+/// Pages of releases fetched at most per source, for projects that publish
+/// many prereleases between releases
+const MAX_RELEASE_PAGES: usize = 5;
+
+/// A release as reported by any forge.
+#[derive(Debug, Clone)]
+pub struct ForgeRelease {
+    pub tag: String,
+    pub draft: bool,
+    pub prerelease: bool,
+    pub assets: Vec<ForgeAsset>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ForgeAsset {
+    pub name: String,
+    pub url: String,
+    pub size: Option<u64>,
+    /// Checksum reported by the forge API itself, if any
+    pub sha256: Option<String>,
+}
+
+impl ForgeRelease {
+    /// Version derived from the release tag, without a leading "v".
+    pub fn version(&self) -> &str {
+        self.tag.strip_prefix('v').unwrap_or(&self.tag)
+    }
+}
+
+/// A code forge that publishes AppImages as release assets.
+#[async_trait]
+pub trait Forge: Send + Sync {
+    /// Web page listing the releases, used as the source URL of index entries.
+    fn releases_page(&self, project: &str) -> String;
+
+    /// Recent releases of `project`, newest first.
+    async fn releases(&self, fetcher: &Fetcher, project: &str) -> Result<Vec<ForgeRelease>>;
+}
+
+/// Pages through the releases at `page_url(page)` until there are enough
+/// published ones, since drafts and prereleases count towards the page size.
+pub async fn fetch_release_pages<T>(
+    fetcher: &Fetcher,
+    token: Option<&str>,
+    page_url: impl Fn(usize) -> String,
+) -> Result<Vec<ForgeRelease>>
+where
+    T: DeserializeOwned + Into<ForgeRelease>,
+{
+    let mut releases: Vec<ForgeRelease> = Vec::new();
+    for page in 1..=MAX_RELEASE_PAGES {
+        let batch: Vec<T> = fetcher.fetch_json(&page_url(page), token).await?;
+        let last_page = batch.len() < RELEASES_PER_SOURCE;
+        releases.extend(batch.into_iter().map(Into::into));
+
+        let published = releases.iter().filter(|r| !r.draft && !r.prerelease).count();
+        if last_page || published >= RELEASES_PER_SOURCE {
+            break;
+        }
+    }
+    Ok(releases)
+}
+
+/// Creates the forge adapter for a release source in index.yaml.
+pub fn forge_for_source(config: &Config, source: &IndexSource) -> Result<Box<dyn Forge>> {
+    let base_url = (!source.url.is_empty()).then_some(source.url.as_str());
+    let forge: Box<dyn Forge> = match source.source_type {
+        SourceType::GithubReleases => Box::new(GithubClient::new(config)),
+        SourceType::Gitlab => {
+            let base_url = base_url.unwrap_or("https://gitlab.com");
+            Box::new(GitlabClient::new(base_url, config.forge_token(base_url)))
+        }
+        SourceType::Gitea => {
+            let Some(base_url) = base_url else {
+                anyhow::bail!("Gitea source {} needs the forge base URL in 'url'", source.project()?);
+            };
+            Box::new(GiteaClient::new(base_url, config.forge_token(base_url)))
+        }
+        SourceType::Appimage | SourceType::Index => {
+            anyhow::bail!("Not a release source: {:?}", source.source_type);
+        }
+    };
+    Ok(forge)
+}
+
+/// Turns the recent published releases of a project into index entries,
+/// one per release, using the asset matching `asset_glob`.
+pub async fn release_entries(
+    forge: &dyn Forge,
+    fetcher: &Fetcher,
+    project: &str,
+    asset_glob: Option<&str>,
+    name: &str,
+) -> Result<Vec<AppImageEntry>> {
+    let pattern = glob_to_regex(asset_glob.unwrap_or("*.AppImage"))?;
+    let releases = forge.releases(fetcher, project).await?;
+
+    let mut entries = Vec::new();
+    for release in releases.iter().filter(|r| !r.draft && !r.prerelease).take(RELEASES_PER_SOURCE) {
+        let Some(asset) = select_appimage_asset(release, Some(&pattern)) else {
+            continue;
+        };
+
+        let sha256 = match &asset.sha256 {
+            Some(sha256) => Some(sha256.clone()),
+            None => find_published_checksum(fetcher, release, asset).await,
+        };
+        let Some(sha256) = sha256 else {
+            println!("Warning: skipping {} {}: no SHA256 published for {}", name, release.version(), asset.name);
+            continue;
+        };
+        if !is_sha256(&sha256) {
+            println!("Warning: skipping {} {}: invalid SHA256 published for {}", name, release.version(), asset.name);
+            continue;
+        }
+
+        entries.push(AppImageEntry {
+            name: name.to_string(),
+            version: release.version().to_string(),
+            file: asset.url.clone(),
+            sha256: sha256.to_lowercase(),
+            size: asset.size,
+            description: None,
+            dependencies: Vec::new(),
+            provides: Vec::new(),
+            zsync: release.assets.iter()
+                .find(|a| a.name == format!("{}.zsync", asset.name))
+                .map(|a| a.url.clone()),
+        });
+    }
+
+    // The same checks as for entries of an appimage.yaml
+    let releases = AppImageYaml { apps: entries, expires: None, serial: None };
+    releases.validate()?;
+    Ok(releases.apps)
+}
+
+/// Picks the AppImage asset of a release, preferring one matching `pattern`
+/// and then one built for the host architecture.
+pub fn select_appimage_asset<'a>(release: &'a ForgeRelease, pattern: Option<&Regex>) -> Option<&'a ForgeAsset> {
+    let appimages: Vec<&ForgeAsset> = release.assets.iter()
+        .filter(|a| a.name.to_lowercase().ends_with(".appimage"))
+        .filter(|a| pattern.is_none_or(|p| p.is_match(&a.name)))
+        .collect();
+
+    let arch = std::env::consts::ARCH;
+    appimages.iter()
+        .find(|a| a.name.contains(arch))
+        .or_else(|| appimages.first())
+        .copied()
+}
+
+/// Looks for `<asset>.sha256` or a `SHA256SUMS`-style file among the release assets.
+async fn find_published_checksum(fetcher: &Fetcher, release: &ForgeRelease, asset: &ForgeAsset) -> Option<String> {
+    let per_asset = format!("{}.sha256", asset.name);
+    if let Some(checksum_asset) = release.assets.iter().find(|a| a.name == per_asset) {
+        let content = fetcher.fetch_yaml(&checksum_asset.url).await.ok()?;
+        return content.split_whitespace().next()
+            .filter(|s| is_sha256(s))
+            .map(|s| s.to_string());
+    }
+
+    let sums_asset = release.assets.iter()
+        .find(|a| a.name.to_lowercase().contains("sha256sum"))?;
+    let content = fetcher.fetch_yaml(&sums_asset.url).await.ok()?;
+    parse_sha256sums(&content, &asset.name)
+}
+
+/// Finds the checksum for `file_name` in `sha256sum` output ("<hex>  [*]<name>").
+pub fn parse_sha256sums(content: &str, file_name: &str) -> Option<String> {
+    content.lines().find_map(|line| {
+        let (hash, name) = line.trim().split_once(char::is_whitespace)?;
+        let name = name.trim().trim_start_matches('*');
+        let name = name.rsplit('/').next().unwrap_or(name);
+        (name == file_name && is_sha256(hash)).then(|| hash.to_lowercase())
+    })
+}
+
+/// Converts a shell-style glob (`*`, `?`) into an anchored regex.
+pub fn glob_to_regex(glob: &str) -> Result<Regex> {
+    let pattern = regex::escape(glob)
+        .replace(r"\*", ".*")
+        .replace(r"\?", ".");
+    Ok(Regex::new(&format!("^{}$", pattern))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+
+    /// A forge serving a fixed list of releases.
+    struct StaticForge(Vec<ForgeRelease>);
+
+    #[async_trait]
+    impl Forge for StaticForge {
+        fn releases_page(&self, project: &str) -> String {
+            format!("https://forge.example.com/{}/releases", project)
+        }
+
+        async fn releases(&self, _fetcher: &Fetcher, _project: &str) -> Result<Vec<ForgeRelease>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn release(tag: &str, sha256: &str) -> ForgeRelease {
+        ForgeRelease {
+            tag: tag.to_string(),
+            draft: false,
+            prerelease: false,
+            assets: vec![ForgeAsset {
+                name: "app-x86_64.AppImage".to_string(),
+                url: format!("https://forge.example.com/download/{}/app-x86_64.AppImage", tag),
+                size: None,
+                sha256: Some(sha256.to_string()),
+            }],
+        }
+    }
+
+    fn fetcher(dir: &tempfile::TempDir) -> Fetcher {
+        let mut config = Config::located(Scope::User).unwrap();
+        config.credentials_file = dir.path().join("credentials.toml");
+        config.netrc_file = dir.path().join("netrc");
+        Fetcher::new(&config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn release_checksums_are_validated_like_index_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = fetcher(&dir);
+
+        let forge = StaticForge(vec![release("v2.0", "not-a-digest"), release("v1.0", &"A".repeat(64))]);
+        let entries = release_entries(&forge, &fetcher, "owner/app", None, "app").await.unwrap();
+        let versions: Vec<(&str, &str)> = entries.iter().map(|e| (e.version.as_str(), e.sha256.as_str())).collect();
+        assert_eq!(versions, vec![("1.0", "a".repeat(64).as_str())]);
+
+        // A bare "v" tag leaves no version
+        let forge = StaticForge(vec![release("v", &"a".repeat(64))]);
+        assert!(release_entries(&forge, &fetcher, "owner/app", None, "app").await.is_err());
+    }
+}
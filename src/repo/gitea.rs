@@ -0,0 +1,137 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::repo::fetcher::Fetcher;
+use crate::repo::forge::{fetch_release_pages, Forge, ForgeAsset, ForgeRelease, RELEASES_PER_SOURCE};
+
+#[derive(Debug, Clone, Deserialize)]
+struct GiteaRelease {
+    tag_name: String,
+    #[serde(default)]
+    draft: bool,
+    #[serde(default)]
+    prerelease: bool,
+    #[serde(default)]
+    assets: Vec<GiteaAsset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct GiteaAsset {
+    name: String,
+    browser_download_url: String,
+    #[serde(default)]
+    size: Option<u64>,
+}
+
+impl From<GiteaRelease> for ForgeRelease {
+    fn from(release: GiteaRelease) -> Self {
+        Self {
+            tag: release.tag_name,
+            draft: release.draft,
+            prerelease: release.prerelease,
+            assets: release.assets.into_iter()
+                .map(|asset| ForgeAsset {
+                    name: asset.name,
+                    url: asset.browser_download_url,
+                    size: asset.size,
+                    sha256: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Client for the releases API of Gitea and Forgejo instances such as Codeberg.
+pub struct GiteaClient {
+    base_url: String,
+    token: Option<String>,
+}
+
+impl GiteaClient {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+}
+
+#[async_trait]
+impl Forge for GiteaClient {
+    fn releases_page(&self, project: &str) -> String {
+        format!("{}/{}/releases", self.base_url, project)
+    }
+
+    async fn releases(&self, fetcher: &Fetcher, project: &str) -> Result<Vec<ForgeRelease>> {
+        fetch_release_pages::<GiteaRelease>(fetcher, self.token.as_deref(), |page| format!(
+            "{}/api/v1/repos/{}/releases?limit={}&page={}",
+            self.base_url, project, RELEASES_PER_SOURCE, page
+        )).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Config, Scope};
+    use crate::repo::forge::release_entries;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn drafts_and_prereleases_do_not_use_up_the_releases() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+        let asset = |tag: &str, name: &str| json!({
+            "name": name,
+            "browser_download_url": format!("{}/owner/app/releases/download/{}/{}", server.url(), tag, name),
+            "size": 1024,
+        });
+
+        let page1: Vec<serde_json::Value> = (1..=RELEASES_PER_SOURCE)
+            .map(|n| {
+                let tag = format!("v2.0-rc{}", n);
+                json!({
+                    "tag_name": tag,
+                    "draft": n == 1,
+                    "prerelease": n > 1,
+                    "assets": [asset(&tag, "app-x86_64.AppImage")],
+                })
+            })
+            .collect();
+        let page2 = json!([{
+            "tag_name": "v1.0",
+            "assets": [asset("v1.0", "app-x86_64.AppImage"), asset("v1.0", "app-x86_64.AppImage.sha256")],
+        }]);
+
+        let mut page_mock = |page: &str, body: String| {
+            server.mock("GET", "/api/v1/repos/owner/app/releases")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("limit".into(), RELEASES_PER_SOURCE.to_string()),
+                    Matcher::UrlEncoded("page".into(), page.into()),
+                ]))
+                .with_header("content-type", "application/json")
+                .with_body(body)
+                .expect(1)
+        };
+        let page1_mock = page_mock("1", serde_json::to_string(&page1).unwrap()).create_async().await;
+        let page2_mock = page_mock("2", page2.to_string()).create_async().await;
+        server.mock("GET", "/owner/app/releases/download/v1.0/app-x86_64.AppImage.sha256")
+            .with_body(format!("{}  app-x86_64.AppImage\n", "e".repeat(64)))
+            .create_async().await;
+
+        let mut config = Config::located(Scope::User).unwrap();
+        config.credentials_file = dir.path().join("credentials.toml");
+        config.netrc_file = dir.path().join("netrc");
+        let fetcher = Fetcher::new(&config).unwrap();
+
+        let entries = release_entries(&GiteaClient::new(&server.url(), None), &fetcher, "owner/app", None, "app")
+            .await.unwrap();
+        let versions: Vec<(&str, &str)> = entries.iter().map(|e| (e.version.as_str(), e.sha256.as_str())).collect();
+        assert_eq!(versions, vec![("1.0", "e".repeat(64).as_str())]);
+
+        page1_mock.assert_async().await;
+        page2_mock.assert_async().await;
+    }
+}
@@ -0,0 +1,256 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::Config;
+use crate::repo::fetcher::Fetcher;
+use crate::repo::forge::{fetch_release_pages, Forge, ForgeAsset, ForgeRelease, RELEASES_PER_SOURCE};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubRelease {
+    pub tag_name: String,
+    #[serde(default)]
+    pub draft: bool,
+    #[serde(default)]
+    pub prerelease: bool,
+    #[serde(default)]
+    pub assets: Vec<GithubAsset>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubAsset {
+    pub name: String,
+    pub browser_download_url: String,
+    #[serde(default)]
+    pub size: Option<u64>,
+    /// "sha256:<hex>" when GitHub has computed it
+    #[serde(default)]
+    pub digest: Option<String>,
+}
+
+impl GithubAsset {
+    pub fn sha256(&self) -> Option<&str> {
+        self.digest.as_deref().and_then(|d| d.strip_prefix("sha256:"))
+    }
+}
+
+impl From<GithubRelease> for ForgeRelease {
+    fn from(release: GithubRelease) -> Self {
+        Self {
+            tag: release.tag_name,
+            draft: release.draft,
+            prerelease: release.prerelease,
+            assets: release.assets.into_iter()
+                .map(|asset| ForgeAsset {
+                    sha256: asset.sha256().map(|s| s.to_string()),
+                    name: asset.name,
+                    url: asset.browser_download_url,
+                    size: asset.size,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A github.com release URL, either a release page or an asset download.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GithubReleaseUrl {
+    pub owner: String,
+    pub repo: String,
+    /// Release tag, `None` for the latest release
+    pub tag: Option<String>,
+    /// Asset file name for download URLs
+    pub asset: Option<String>,
+}
+
+impl GithubReleaseUrl {
+    /// Recognises `/releases`, `/releases/latest`, `/releases/tag/<tag>`,
+    /// `/releases/download/<tag>/<asset>` and `/releases/latest/download/<asset>`.
+    pub fn parse(url: &str) -> Option<Self> {
+        let parsed = url::Url::parse(url).ok()?;
+        if parsed.host_str() != Some("github.com") {
+            return None;
+        }
+
+        let segments: Vec<&str> = parsed.path_segments()?.filter(|s| !s.is_empty()).collect();
+        let (owner, repo, rest) = match segments.as_slice() {
+            [owner, repo, "releases", rest @ ..] => (owner.to_string(), repo.to_string(), rest),
+            _ => return None,
+        };
+
+        let (tag, asset) = match rest {
+            [] | ["latest"] => (None, None),
+            ["tag", tag] => (Some(tag.to_string()), None),
+            ["download", tag, asset] => (Some(tag.to_string()), Some(asset.to_string())),
+            ["latest", "download", asset] => (None, Some(asset.to_string())),
+            _ => return None,
+        };
+
+        Some(Self { owner, repo, tag, asset })
+    }
+}
+
+/// Client for the GitHub releases API. The API URL comes from the config so
+/// that GitHub Enterprise or a local mock server can stand in for api.github.com.
+pub struct GithubClient {
+    api_url: String,
+    token: Option<String>,
+}
+
+impl GithubClient {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            api_url: config.github_api_url.trim_end_matches('/').to_string(),
+            token: config.github_token.clone(),
+        }
+    }
+
+    pub async fn fetch_release(
+        &self,
+        fetcher: &Fetcher,
+        owner: &str,
+        repo: &str,
+        tag: Option<&str>,
+    ) -> Result<GithubRelease> {
+        let url = match tag {
+            Some(tag) => format!("{}/repos/{}/{}/releases/tags/{}", self.api_url, owner, repo, tag),
+            None => format!("{}/repos/{}/{}/releases/latest", self.api_url, owner, repo),
+        };
+        fetcher.fetch_json(&url, self.token.as_deref()).await
+    }
+}
+
+#[async_trait]
+impl Forge for GithubClient {
+    fn releases_page(&self, project: &str) -> String {
+        format!("https://github.com/{}/releases", project)
+    }
+
+    async fn releases(&self, fetcher: &Fetcher, project: &str) -> Result<Vec<ForgeRelease>> {
+        fetch_release_pages::<GithubRelease>(fetcher, self.token.as_deref(), |page| format!(
+            "{}/repos/{}/releases?per_page={}&page={}",
+            self.api_url, project, RELEASES_PER_SOURCE, page
+        )).await
+    }
+}
+
+/// Turns an asset name into a pattern that matches the same asset in other
+/// releases, by replacing the release version with a wildcard.
+pub fn asset_pattern(asset: &str, version: &str) -> Result<Regex> {
+    let escaped = regex::escape(asset);
+    let pattern = if version.is_empty() {
+        escaped
+    } else {
+        escaped.replace(&regex::escape(version), r"[0-9A-Za-z._+-]+")
+    };
+    Ok(Regex::new(&format!("^{}$", pattern))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::repo::forge::release_entries;
+    use mockito::Matcher;
+    use serde_json::json;
+
+    const SHA_DIGEST: &str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const SHA_SUMS: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB";
+
+    fn asset(server: &mockito::Server, tag: &str, name: &str, digest: Option<&str>) -> serde_json::Value {
+        json!({
+            "name": name,
+            "browser_download_url": format!("{}/download/{}/{}", server.url(), tag, name),
+            "size": 1024,
+            "digest": digest.map(|d| format!("sha256:{}", d)),
+        })
+    }
+
+    #[tokio::test]
+    async fn release_entries_page_through_releases_and_find_checksums() {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir().unwrap();
+
+        // Page 1: one release and enough prereleases to fill the page
+        let mut page1 = vec![json!({
+            "tag_name": "v2.0",
+            "assets": [
+                asset(&server, "v2.0", "app-2.0-x86_64.AppImage", Some("cccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccccc")),
+                asset(&server, "v2.0", "app-2.0-aarch64.AppImage", Some(SHA_DIGEST)),
+            ],
+        })];
+        for rc in 1..RELEASES_PER_SOURCE {
+            let tag = format!("v2.1-rc{}", rc);
+            page1.push(json!({
+                "tag_name": tag,
+                "prerelease": true,
+                "assets": [asset(&server, &tag, &format!("app-{}-aarch64.AppImage", tag), Some(SHA_DIGEST))],
+            }));
+        }
+        // Page 2: a release with a SHA256SUMS file and one without any checksum
+        let page2 = json!([
+            {
+                "tag_name": "v1.0",
+                "assets": [
+                    asset(&server, "v1.0", "app-1.0-x86_64.AppImage", None),
+                    asset(&server, "v1.0", "app-1.0-aarch64.AppImage", None),
+                    asset(&server, "v1.0", "SHA256SUMS", None),
+                ],
+            },
+            {
+                "tag_name": "v0.9",
+                "assets": [asset(&server, "v0.9", "app-0.9-aarch64.AppImage", None)],
+            },
+        ]);
+
+        let mut page_mock = |page: &str, body: String| {
+            server.mock("GET", "/repos/owner/app/releases")
+                .match_query(Matcher::AllOf(vec![
+                    Matcher::UrlEncoded("per_page".into(), RELEASES_PER_SOURCE.to_string()),
+                    Matcher::UrlEncoded("page".into(), page.into()),
+                ]))
+                .with_header("content-type", "application/json")
+                .with_body(body)
+                .expect(1)
+        };
+        let page1_mock = page_mock("1", serde_json::to_string(&page1).unwrap()).create_async().await;
+        let page2_mock = page_mock("2", page2.to_string()).create_async().await;
+        let sums_mock = server.mock("GET", "/download/v1.0/SHA256SUMS")
+            .with_body(format!(
+                "{}  app-1.0-x86_64.AppImage\n{} *app-1.0-aarch64.AppImage\n",
+                "d".repeat(64), SHA_SUMS
+            ))
+            .expect(1)
+            .create_async().await;
+
+        let mut config = Config::located(Scope::User).unwrap();
+        config.github_api_url = server.url();
+        config.github_token = None;
+        config.credentials_file = dir.path().join("credentials.toml");
+        config.netrc_file = dir.path().join("netrc");
+        let fetcher = Fetcher::new(&config).unwrap();
+
+        let entries = release_entries(
+            &GithubClient::new(&config),
+            &fetcher,
+            "owner/app",
+            Some("*-aarch64.AppImage"),
+            "app",
+        ).await.unwrap();
+
+        let summary: Vec<(&str, &str, &str)> = entries.iter()
+            .map(|e| (e.version.as_str(), e.sha256.as_str(), e.file.rsplit('/').next().unwrap()))
+            .collect();
+        let sums = SHA_SUMS.to_lowercase();
+        assert_eq!(summary, vec![
+            ("2.0", SHA_DIGEST, "app-2.0-aarch64.AppImage"),
+            ("1.0", sums.as_str(), "app-1.0-aarch64.AppImage"),
+        ]);
+        assert!(entries.iter().all(|e| e.name == "app" && e.size == Some(1024)));
+
+        page1_mock.assert_async().await;
+        page2_mock.assert_async().await;
+        sums_mock.assert_async().await;
+    }
+}
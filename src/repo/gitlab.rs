@@ -0,0 +1,79 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::repo::fetcher::Fetcher;
+use crate::repo::forge::{fetch_release_pages, Forge, ForgeAsset, ForgeRelease, RELEASES_PER_SOURCE};
+
+#[derive(Debug, Clone, Deserialize)]
+struct GitlabRelease {
+    tag_name: String,
+    /// Releases with a future release date
+    #[serde(default)]
+    upcoming_release: bool,
+    #[serde(default)]
+    assets: GitlabAssets,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct GitlabAssets {
+    #[serde(default)]
+    links: Vec<GitlabLink>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct GitlabLink {
+    name: String,
+    url: String,
+    /// Permanent URL, set when the link has a filepath
+    #[serde(default)]
+    direct_asset_url: Option<String>,
+}
+
+impl From<GitlabRelease> for ForgeRelease {
+    fn from(release: GitlabRelease) -> Self {
+        Self {
+            tag: release.tag_name,
+            draft: false,
+            prerelease: release.upcoming_release,
+            assets: release.assets.links.into_iter()
+                .map(|link| ForgeAsset {
+                    name: link.name,
+                    url: link.direct_asset_url.unwrap_or(link.url),
+                    size: None,
+                    sha256: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Client for the releases API of gitlab.com or a self-hosted GitLab.
+pub struct GitlabClient {
+    base_url: String,
+    token: Option<String>,
+}
+
+impl GitlabClient {
+    pub fn new(base_url: &str, token: Option<String>) -> Self {
+        Self {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            token,
+        }
+    }
+}
+
+#[async_trait]
+impl Forge for GitlabClient {
+    fn releases_page(&self, project: &str) -> String {
+        format!("{}/{}/-/releases", self.base_url, project)
+    }
+
+    async fn releases(&self, fetcher: &Fetcher, project: &str) -> Result<Vec<ForgeRelease>> {
+        // The API addresses projects by their URL-encoded path
+        fetch_release_pages::<GitlabRelease>(fetcher, self.token.as_deref(), |page| format!(
+            "{}/api/v4/projects/{}/releases?per_page={}&page={}",
+            self.base_url, project.replace('/', "%2F"), RELEASES_PER_SOURCE, page
+        )).await
+    }
+}
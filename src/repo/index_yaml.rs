@@ -4,20 +4,78 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexYaml {
     pub sources: Vec<IndexSource>,
+    /// Metadata must not be used after this time (RFC 3339)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<String>,
+    /// Monotonically increasing metadata version, guards against rollback
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<u64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct IndexSource {
     #[serde(rename = "type")]
     pub source_type: SourceType,
+    /// Forge base URL for GitLab and Gitea release sources
+    #[serde(default)]
     pub url: String,
+    /// "owner/repo" for release sources, GitLab also accepts "group/subgroup/repo"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repo: Option<String>,
+    /// Glob selecting the release asset, e.g. "*-x86_64.AppImage"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset: Option<String>,
+    /// Package name for release sources, defaults to the repository name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 pub enum SourceType {
     Appimage,
     Index,
+    #[serde(rename = "github-releases", alias = "github")]
+    GithubReleases,
+    #[serde(rename = "gitlab-releases", alias = "gitlab")]
+    Gitlab,
+    #[serde(rename = "gitea-releases", alias = "gitea", alias = "forgejo")]
+    Gitea,
+}
+
+impl IndexSource {
+    /// Validated project path of a release source.
+    pub fn project(&self) -> Result<&str> {
+        let repo = self.repo.as_deref()
+            .context("Release source is missing 'repo'")?;
+        let segments: Vec<&str> = repo.split('/').collect();
+        let valid = segments.iter().all(|s| !s.is_empty())
+            && match self.source_type {
+                // GitLab projects can live in nested subgroups
+                SourceType::Gitlab => segments.len() >= 2,
+                _ => segments.len() == 2,
+            };
+        if !valid {
+            anyhow::bail!("Invalid repo format. Expected 'owner/repo', got: {}", repo);
+        }
+        Ok(repo)
+    }
+
+    /// Package name for release sources, defaults to the last segment of `repo`.
+    pub fn package_name(&self) -> Result<&str> {
+        match self.name.as_deref() {
+            Some(name) => Ok(name),
+            None => Ok(self.project()?.rsplit('/').next().unwrap_or_default()),
+        }
+    }
+
+    /// Release sources change without the index itself changing.
+    pub fn is_dynamic(&self) -> bool {
+        matches!(
+            self.source_type,
+            SourceType::GithubReleases | SourceType::Gitlab | SourceType::Gitea
+        )
+    }
 }
 
 impl IndexYaml {
@@ -28,6 +86,17 @@ impl IndexYaml {
 
     pub fn validate(&self) -> Result<()> {
         for source in &self.sources {
+            if source.is_dynamic() {
+                source.project()?;
+                if source.source_type == SourceType::Gitea && source.url.is_empty() {
+                    anyhow::bail!("Gitea source {} needs the forge base URL in 'url'", source.project()?);
+                }
+                if !source.url.is_empty() {
+                    url::Url::parse(&source.url)
+                        .context(format!("Invalid URL: {}", source.url))?;
+                }
+                continue;
+            }
             if source.url.is_empty() {
                 anyhow::bail!("Source URL cannot be empty");
             }
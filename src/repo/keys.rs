@@ -0,0 +1,256 @@
+use anyhow::{Context, Result};
+use minisign_verify::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::repo::url_under_prefix;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeysYaml {
+    #[serde(default)]
+    pub keys: Vec<TrustedKey>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrustedKey {
+    /// Source URL or URL prefix this key is trusted for
+    pub source: String,
+    /// Minisign public key (base64)
+    pub key: String,
+    /// Refuse metadata from this source without a valid signature
+    #[serde(default)]
+    pub require_signature: bool,
+}
+
+impl KeysYaml {
+    pub fn new() -> Self {
+        Self {
+            keys: Vec::new(),
+        }
+    }
+
+    pub fn from_str(content: &str) -> Result<Self> {
+        if content.trim().is_empty() {
+            Ok(Self::new())
+        } else {
+            serde_yaml::from_str(content)
+                .context("Failed to parse keys.yaml")
+        }
+    }
+
+    pub fn to_string(&self) -> Result<String> {
+        serde_yaml::to_string(self)
+            .context("Failed to serialize keys.yaml")
+    }
+
+    pub fn add_key(&mut self, source: &str, key: &str, require_signature: bool) {
+        if let Some(existing) = self.keys.iter_mut().find(|k| k.source == source && k.key == key) {
+            existing.require_signature = require_signature;
+        } else {
+            self.keys.push(TrustedKey {
+                source: source.to_string(),
+                key: key.to_string(),
+                require_signature,
+            });
+        }
+    }
+
+    pub fn remove_keys(&mut self, source: &str, key: Option<&str>) -> bool {
+        let initial_len = self.keys.len();
+        self.keys.retain(|k| k.source != source || key.is_some_and(|key| k.key != key));
+        self.keys.len() < initial_len
+    }
+
+    /// Keys whose source is `url` itself or a directory above it on the
+    /// same origin.
+    pub fn keys_for(&self, url: &str) -> Vec<&TrustedKey> {
+        let Ok(url) = Url::parse(url) else {
+            return Vec::new();
+        };
+        self.keys.iter()
+            .filter(|k| Url::parse(&k.source).is_ok_and(|source| url_under_prefix(&url, &source)))
+            .collect()
+    }
+
+    /// Whether a key trusted for `url` refuses unsigned metadata.
+    pub fn requires_signature(&self, url: &str) -> bool {
+        self.keys_for(url).iter().any(|k| k.require_signature)
+    }
+
+    /// Checks `content` fetched from `url` against the trusted keys for that URL.
+    /// `signature` is the detached minisign signature, if the source published one.
+    /// `inherited` is set for metadata referenced by an index that requires
+    /// signatures, which then needs a valid signature by a key of its own origin.
+    pub fn verify(&self, url: &str, content: &str, signature: Option<&str>, inherited: bool) -> Result<()> {
+        let keys = self.keys_for(url);
+        if keys.is_empty() {
+            if inherited {
+                anyhow::bail!(
+                    "No trusted key for {}, which is referenced by metadata that requires signatures (add one with 'aipkg key add')",
+                    url
+                );
+            }
+            return Ok(());
+        }
+
+        let required = inherited || keys.iter().any(|k| k.require_signature);
+
+        let signature = match signature {
+            Some(sig) => Signature::decode(sig)
+                .map_err(|e| anyhow::anyhow!("Invalid signature for {}: {}", url, e))?,
+            None if required => {
+                anyhow::bail!("Missing signature for {} (signature required)", url);
+            }
+            None => {
+                println!("Warning: no signature found for {}", url);
+                return Ok(());
+            }
+        };
+
+        for trusted in &keys {
+            let public_key = decode_public_key(&trusted.key)?;
+            if public_key.verify(content.as_bytes(), &signature, false).is_ok() {
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Signature verification failed for {}", url);
+    }
+}
+
+impl Default for KeysYaml {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Accepts either a bare base64 key or the contents of a minisign `.pub` file.
+fn decode_public_key(key: &str) -> Result<PublicKey> {
+    let key = key.trim();
+    let result = if key.contains('\n') {
+        PublicKey::decode(key)
+    } else {
+        PublicKey::from_base64(key)
+    };
+    result.map_err(|e| anyhow::anyhow!("Invalid public key: {}", e))
+}
+
+/// URL of the detached signature for a metadata file.
+pub fn signature_url(url: &str) -> String {
+    format!("{}.sig", url)
+}
+
+// Module-level functions for key management
+use crate::config::Config;
+use tokio::fs;
+
+pub fn load_keys(config: &Config) -> Result<KeysYaml> {
+    if config.keys_file.exists() {
+        let content = std::fs::read_to_string(&config.keys_file)
+            .context("Failed to read keys.yaml")?;
+        KeysYaml::from_str(&content)
+    } else {
+        Ok(KeysYaml::new())
+    }
+}
+
+pub async fn add_key(source: &str, key: &str, require_signature: bool) -> Result<()> {
+    let config = Config::new()?;
+    config.ensure_directories().await?;
+    Url::parse(source).context(format!("Invalid source URL: {}", source))?;
+
+    // Resolve a key file path to its contents
+    let key = if std::path::Path::new(key).is_file() {
+        fs::read_to_string(key).await
+            .context(format!("Failed to read key file: {}", key))?
+    } else {
+        key.to_string()
+    };
+    let key = key.trim();
+    decode_public_key(key)?;
+
+    let mut keys_yaml = load_keys(&config)?;
+    keys_yaml.add_key(source, key, require_signature);
+
+    let content = keys_yaml.to_string()?;
+    fs::write(&config.keys_file, content).await?;
+
+    println!("Added key for {}", source);
+    Ok(())
+}
+
+pub async fn remove_key(source: &str, key: Option<&str>) -> Result<()> {
+    let config = Config::new()?;
+    config.check_privileges()?;
+
+    if !config.keys_file.exists() {
+        anyhow::bail!("No keys file found");
+    }
+
+    let mut keys_yaml = load_keys(&config)?;
+
+    if keys_yaml.remove_keys(source, key) {
+        let content = keys_yaml.to_string()?;
+        fs::write(&config.keys_file, content).await?;
+        println!("Removed key(s) for {}", source);
+    } else {
+        println!("No matching key found for {}", source);
+    }
+
+    Ok(())
+}
+
+pub async fn list_keys() -> Result<()> {
+    let config = Config::new()?;
+    let keys_yaml = load_keys(&config)?;
+
+    if keys_yaml.keys.is_empty() {
+        println!("No trusted keys configured");
+    } else {
+        println!("Trusted keys:");
+        for key in &keys_yaml.keys {
+            let required = if key.require_signature { " (required)" } else { "" };
+            println!("  {}{}", key.source, required);
+            for line in key.key.lines() {
+                println!("    {}", line);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_apply_to_the_source_and_below_it_only() {
+        let mut keys = KeysYaml::new();
+        keys.add_key("https://example.com/repo", "key", false);
+        let applies = |url: &str| !keys.keys_for(url).is_empty();
+
+        assert!(applies("https://example.com/repo"));
+        assert!(applies("https://example.com/repo/appimage.yaml"));
+        assert!(applies("https://EXAMPLE.com:443/repo/sub/index.yaml"));
+        assert!(!applies("https://example.com/repository-evil/appimage.yaml"));
+        assert!(!applies("https://example.com.attacker.net/repo/appimage.yaml"));
+        assert!(!applies("http://example.com/repo/appimage.yaml"));
+        assert!(!applies("https://example.com:8443/repo/appimage.yaml"));
+        assert!(!applies("not a url"));
+    }
+
+    #[test]
+    fn metadata_under_a_signed_index_inherits_its_requirement() {
+        let mut keys = KeysYaml::new();
+        keys.add_key("https://mirror.example.com/", "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3", false);
+        let content = "apps: []";
+
+        // Without a requirement, unsigned metadata is accepted with or without a key
+        assert!(keys.verify("https://other.example.com/appimage.yaml", content, None, false).is_ok());
+        assert!(keys.verify("https://mirror.example.com/appimage.yaml", content, None, false).is_ok());
+        // Referenced by an index requiring signatures, it needs a key and a signature
+        assert!(keys.verify("https://other.example.com/appimage.yaml", content, None, true).is_err());
+        assert!(keys.verify("https://mirror.example.com/appimage.yaml", content, None, true).is_err());
+    }
+}
@@ -2,19 +2,60 @@ pub mod appimage_yaml;
 pub mod index_yaml;
 pub mod collectives;
 pub mod fetcher;
+pub mod throttle;
 pub mod resolver;
 pub mod cache;
+pub mod keys;
+pub mod credentials;
+pub mod github;
+pub mod gitlab;
+pub mod gitea;
+pub mod forge;
 
 use anyhow::Result;
 use crate::config::Config;
 use tokio::fs;
+use url::Url;
+
+/// Whether `url` is `prefix` or below it: the same scheme, host and port,
+/// and a path that continues the prefix's at a `/` boundary.
+pub fn url_under_prefix(url: &Url, prefix: &Url) -> bool {
+    if url.scheme() != prefix.scheme()
+        || url.host_str() != prefix.host_str()
+        || url.port_or_known_default() != prefix.port_or_known_default()
+    {
+        return false;
+    }
+    let base = prefix.path().trim_end_matches('/');
+    url.path().strip_prefix(base)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+pub async fn load_sources(config: &Config) -> Result<collectives::SourcesYaml> {
+    if config.sources_file.exists() {
+        collectives::SourcesYaml::from_str(&fs::read_to_string(&config.sources_file).await?)
+    } else {
+        Ok(collectives::SourcesYaml::new())
+    }
+}
+
+pub async fn load_collectives(config: &Config) -> Result<collectives::CollectivesYaml> {
+    if config.collectives_file.exists() {
+        collectives::CollectivesYaml::from_str(&fs::read_to_string(&config.collectives_file).await?)
+    } else {
+        Ok(collectives::CollectivesYaml::new())
+    }
+}
 
 pub async fn update_database() -> Result<()> {
     cache::update_unified_index().await
 }
 
-pub async fn add_source(url: &str) -> Result<()> {
+pub async fn add_source(url: &str, token: Option<&str>, user: Option<&str>) -> Result<()> {
     let config = Config::new()?;
+    if token.is_some() {
+        credentials::check_secure_transport(url)?;
+    }
     config.ensure_directories().await?;
     
     let mut sources_yaml = if config.sources_file.exists() {
@@ -29,12 +70,17 @@ pub async fn add_source(url: &str) -> Result<()> {
     let content = sources_yaml.to_string()?;
     fs::write(&config.sources_file, content).await?;
     
+    if let Some(token) = token {
+        credentials::store_source_token(&config, url, token, user).await?;
+    }
+    
     println!("Added source: {}", url);
     Ok(())
 }
 
 pub async fn remove_source(url: &str) -> Result<()> {
     let config = Config::new()?;
+    config.check_privileges()?;
     
     if !config.sources_file.exists() {
         anyhow::bail!("No sources file found");
@@ -46,6 +92,14 @@ pub async fn remove_source(url: &str) -> Result<()> {
     if sources_yaml.remove_source(url) {
         let content = sources_yaml.to_string()?;
         fs::write(&config.sources_file, content).await?;
+        
+        // Keep credentials still needed by another source in the same directory
+        let prefix = credentials::source_prefix(url)?;
+        let shared = sources_yaml.sources.iter()
+            .any(|s| credentials::source_prefix(s).is_ok_and(|p| p == prefix));
+        if !shared {
+            credentials::remove_source_token(&config, url).await?;
+        }
         println!("Removed source: {}", url);
     } else {
         println!("Source not found: {}", url);
@@ -1,48 +1,185 @@
-use anyhow::Result;
-use std::collections::HashSet;
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use url::Url;
 
 use crate::repo::appimage_yaml::UnifiedIndex;
-use crate::repo::index_yaml::{IndexYaml, SourceType};
+use crate::repo::index_yaml::{IndexSource, IndexYaml, SourceType};
+use crate::repo::forge::{forge_for_source, release_entries};
 use crate::repo::fetcher::Fetcher;
 use crate::repo::cache::calculate_yaml_hash;
+use crate::repo::keys::{load_keys, signature_url, KeysYaml};
+use crate::config::Config;
 
 pub struct Resolver {
     fetcher: Fetcher,
     visited: HashSet<String>,
+    keys: KeysYaml,
+    config: Config,
+    freshness: Freshness,
+    /// Whether the source being resolved has a release source at any depth
+    found_dynamic: bool,
+}
+
+/// Freshness fields shared by appimage.yaml and index.yaml
+#[derive(Debug, Default, Deserialize)]
+struct MetadataFreshness {
+    #[serde(default)]
+    expires: Option<String>,
+    #[serde(default)]
+    serial: Option<u64>,
+}
+
+/// What the metadata seen during an update must not go back on.
+#[derive(Debug, Default)]
+struct Freshness {
+    /// Highest serial seen per metadata URL
+    serials: HashMap<String, u64>,
+    /// Earliest expiry of the metadata read for the source being resolved
+    earliest_expiry: Option<chrono::DateTime<chrono::Utc>>,
+    allow_stale: bool,
+}
+
+pub fn parse_expiry(expires: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(expires) {
+        return Ok(time.with_timezone(&chrono::Utc));
+    }
+    // Plain dates expire at the end of that day
+    let date = chrono::NaiveDate::parse_from_str(expires, "%Y-%m-%d")
+        .map_err(|_| anyhow::anyhow!("Invalid expires value: {}", expires))?;
+    Ok(date.and_hms_opt(23, 59, 59).unwrap().and_utc())
+}
+
+impl Freshness {
+    /// Refuses metadata that has expired or is older than what was seen before.
+    fn check_freshness(&mut self, url: &str, content: &str) -> Result<()> {
+        let freshness: MetadataFreshness = serde_yaml::from_str(content)
+            .context(format!("Invalid expires or serial in metadata from {}", url))?;
+        
+        if let Some(expires) = &freshness.expires {
+            let expiry = parse_expiry(expires)?;
+            self.earliest_expiry = Some(self.earliest_expiry.map_or(expiry, |earliest| earliest.min(expiry)));
+            if expiry < chrono::Utc::now() {
+                if !self.allow_stale {
+                    anyhow::bail!(
+                        "Metadata from {} expired at {} (use --allow-stale to accept it)",
+                        url, expires
+                    );
+                }
+                println!("Warning: metadata from {} expired at {}", url, expires);
+            }
+        }
+        
+        match freshness.serial {
+            Some(serial) => {
+                if let Some(last) = self.serials.get(url).copied().filter(|last| serial < *last) {
+                    if !self.allow_stale {
+                        anyhow::bail!(
+                            "Metadata from {} has serial {} older than last seen {} (possible rollback, use --allow-stale to accept it)",
+                            url, serial, last
+                        );
+                    }
+                    println!("Warning: metadata from {} rolled back from serial {} to {}", url, last, serial);
+                }
+                self.serials.insert(url.to_string(), serial);
+            }
+            // Dropping the serial must not be a way around the rollback check
+            None => if let Some(last) = self.serials.get(url) {
+                if !self.allow_stale {
+                    anyhow::bail!(
+                        "Metadata from {} has no serial, but serial {} was seen before (possible rollback, use --allow-stale to accept it)",
+                        url, last
+                    );
+                }
+                println!("Warning: metadata from {} no longer has a serial, last seen {}", url, last);
+            },
+        }
+        
+        Ok(())
+    }
 }
 
 impl Resolver {
     pub fn new() -> Result<Self> {
+        let config = Config::new()?;
         Ok(Self {
-            fetcher: Fetcher::new()?,
+            fetcher: Fetcher::new(&config)?,
             visited: HashSet::new(),
+            keys: load_keys(&config)?,
+            config,
+            freshness: Freshness::default(),
+            found_dynamic: false,
         })
     }
 
+    /// Accept expired metadata and metadata whose serial went backwards.
+    pub fn allow_stale(mut self, allow_stale: bool) -> Self {
+        self.freshness.allow_stale = allow_stale;
+        self
+    }
+
+    /// Fetches a YAML file and checks its detached signature against the
+    /// trusted keys configured for that URL. Metadata referenced by an index
+    /// that requires signatures (`inherited`) must be signed as well. Returns
+    /// the content and whether the metadata it references must be signed.
+    async fn fetch_verified_yaml(&mut self, url: &str, inherited: bool) -> Result<(String, bool)> {
+        let content = self.fetcher.fetch_yaml(url).await?;
+        
+        if inherited || !self.keys.keys_for(url).is_empty() {
+            let signature = self.fetcher.fetch_yaml(&signature_url(url)).await.ok();
+            self.keys.verify(url, &content, signature.as_deref(), inherited)?;
+        }
+        
+        self.freshness.check_freshness(url, &content)?;
+        
+        Ok((content, inherited || self.keys.requires_signature(url)))
+    }
+
     pub async fn resolve_sources_incremental(
         &mut self,
         sources: Vec<String>,
         existing_index: &mut Option<UnifiedIndex>,
-        source_hashes: &mut std::collections::HashMap<String, String>,
+        source_hashes: &mut HashMap<String, String>,
+        source_serials: &mut HashMap<String, u64>,
+        source_expiries: &mut HashMap<String, String>,
     ) -> Result<UnifiedIndex> {
-        let mut index = existing_index.take().unwrap_or_else(UnifiedIndex::new);
+        let mut index = existing_index.take().unwrap_or_default();
         self.visited.clear();
+        // A copy, so the serials seen before survive a failed update
+        self.freshness.serials = source_serials.clone();
+        source_expiries.retain(|source, _| sources.contains(source));
         
         // Process sources sequentially, checking hashes for incremental updates
         for source_url in sources {
             let cached_hash = source_hashes.get(&source_url).cloned();
-            let (entries, hash) = self.resolve_source_incremental(&source_url, cached_hash).await?;
+            self.freshness.earliest_expiry = None;
+            let (entries, hash) = self.resolve_source_incremental(&source_url, cached_hash.clone()).await?;
             
-            if let Some(h) = hash {
-                source_hashes.insert(source_url.clone(), h);
+            // An unchanged source was not read beyond its own file, so the
+            // expiry recorded for everything it references still applies
+            let unchanged = hash.is_some() && hash == cached_hash;
+            match self.freshness.earliest_expiry {
+                _ if unchanged && source_expiries.contains_key(&source_url) => {}
+                Some(expiry) => {
+                    source_expiries.insert(source_url.clone(), expiry.to_rfc3339());
+                }
+                None => {
+                    source_expiries.remove(&source_url);
+                }
             }
             
+            match hash {
+                Some(h) => source_hashes.insert(source_url.clone(), h),
+                None => source_hashes.remove(&source_url),
+            };
+            
             for entry in entries {
                 index.add_entry(entry.entry, entry.source_url);
             }
         }
         
+        *source_serials = std::mem::take(&mut self.freshness.serials);
+        
         Ok(index)
     }
 
@@ -59,25 +196,32 @@ impl Resolver {
         
         self.visited.insert(normalized.clone());
         
-        let content = self.fetcher.fetch_yaml(&normalized).await?;
+        let (content, required) = self.fetch_verified_yaml(&normalized, false).await?;
         let current_hash = calculate_yaml_hash(&content).await;
         
-        // Skip if hash hasn't changed
+        // Skip if hash hasn't changed. Sources with release sources get no
+        // hash recorded, since releases change without the index changing.
         if let Some(ref cached) = cached_hash {
             if cached == &current_hash {
                 return Ok((Vec::new(), Some(current_hash)));
             }
         }
         
-        let entries = self.parse_yaml_content(&content, &normalized).await?;
+        self.found_dynamic = false;
+        let entries = self.parse_yaml_content(&content, &normalized, required).await?;
+        let hash = (!self.found_dynamic).then_some(current_hash);
         
-        Ok((entries, Some(current_hash)))
+        Ok((entries, hash))
     }
 
+    /// Collects the entries of an appimage.yaml, or of everything an
+    /// index.yaml references. With `required`, the referenced metadata must
+    /// be signed like the index itself.
     async fn parse_yaml_content(
         &mut self,
         content: &str,
         source_url: &str,
+        required: bool,
     ) -> Result<Vec<crate::repo::appimage_yaml::AppImageEntryWithSource>> {
         let mut entries = Vec::new();
         
@@ -89,14 +233,18 @@ impl Resolver {
             let mut appimage_urls = Vec::new();
             let mut index_urls = Vec::new();
             
+            let mut release_sources = Vec::new();
+            
             for source in index_yaml.sources {
-                let resolved_url = self.resolve_relative_url(source_url, &source.url)?;
                 match source.source_type {
                     SourceType::Index => {
-                        index_urls.push(resolved_url);
+                        index_urls.push((self.resolve_relative_url(source_url, &source.url)?, required));
                     }
                     SourceType::Appimage => {
-                        appimage_urls.push(resolved_url);
+                        appimage_urls.push((self.resolve_relative_url(source_url, &source.url)?, required));
+                    }
+                    SourceType::GithubReleases | SourceType::Gitlab | SourceType::Gitea => {
+                        release_sources.push(source);
                     }
                 }
             }
@@ -104,25 +252,27 @@ impl Resolver {
             // Process index URLs recursively (flattening) - sequential to maintain visited set
             // Use a work queue to avoid deep recursion
             let mut work_queue = index_urls;
-            while let Some(index_url) = work_queue.pop() {
+            while let Some((index_url, inherited)) = work_queue.pop() {
                 if self.visited.contains(&index_url) {
                     continue;
                 }
                 self.visited.insert(index_url.clone());
                 
-                let sub_content = self.fetcher.fetch_yaml(&index_url).await?;
+                let (sub_content, sub_required) = self.fetch_verified_yaml(&index_url, inherited).await?;
                 // Try to parse as index.yaml
                 if let Ok(sub_index) = IndexYaml::from_str(&sub_content) {
                     sub_index.validate()?;
                     // Add sub-sources to work queue
                     for sub_source in sub_index.sources {
-                        let resolved = self.resolve_relative_url(&index_url, &sub_source.url)?;
                         match sub_source.source_type {
                             SourceType::Index => {
-                                work_queue.push(resolved);
+                                work_queue.push((self.resolve_relative_url(&index_url, &sub_source.url)?, sub_required));
                             }
                             SourceType::Appimage => {
-                                appimage_urls.push(resolved);
+                                appimage_urls.push((self.resolve_relative_url(&index_url, &sub_source.url)?, sub_required));
+                            }
+                            SourceType::GithubReleases | SourceType::Gitlab | SourceType::Gitea => {
+                                release_sources.push(sub_source);
                             }
                         }
                     }
@@ -138,10 +288,10 @@ impl Resolver {
             }
             
             // Process appimage URLs sequentially to maintain visited set
-            for appimage_url in appimage_urls {
+            for (appimage_url, inherited) in appimage_urls {
                 if !self.visited.contains(&appimage_url) {
                     self.visited.insert(appimage_url.clone());
-                    let appimage_content = self.fetcher.fetch_yaml(&appimage_url).await?;
+                    let (appimage_content, _) = self.fetch_verified_yaml(&appimage_url, inherited).await?;
                     let appimage_yaml = crate::repo::appimage_yaml::AppImageYaml::from_str(&appimage_content)?;
                     appimage_yaml.validate()?;
                     
@@ -153,6 +303,12 @@ impl Resolver {
                     }
                 }
             }
+            
+            // Resolve release sources against their forge API
+            self.found_dynamic |= !release_sources.is_empty();
+            for source in release_sources {
+                entries.extend(self.resolve_release_source(&source).await?);
+            }
         } else {
             // Try to parse as appimage.yaml
             let appimage_yaml = crate::repo::appimage_yaml::AppImageYaml::from_str(content)?;
@@ -169,6 +325,35 @@ impl Resolver {
         Ok(entries)
     }
 
+    async fn resolve_release_source(
+        &mut self,
+        source: &IndexSource,
+    ) -> Result<Vec<crate::repo::appimage_yaml::AppImageEntryWithSource>> {
+        let project = source.project()?;
+        let forge = forge_for_source(&self.config, source)?;
+        let releases_url = forge.releases_page(project);
+        
+        if self.visited.contains(&releases_url) {
+            return Ok(Vec::new());
+        }
+        self.visited.insert(releases_url.clone());
+        
+        let entries = release_entries(
+            forge.as_ref(),
+            &self.fetcher,
+            project,
+            source.asset.as_deref(),
+            source.package_name()?,
+        ).await?;
+        
+        Ok(entries.into_iter()
+            .map(|entry| crate::repo::appimage_yaml::AppImageEntryWithSource {
+                entry,
+                source_url: releases_url.clone(),
+            })
+            .collect())
+    }
+
     fn normalize_url(&self, url: &str) -> Result<String> {
         let parsed = Url::parse(url)?;
         Ok(parsed.as_str().to_string())
@@ -186,3 +371,63 @@ impl Default for Resolver {
         Self::new().expect("Failed to create resolver")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "https://example.com/appimage.yaml";
+
+    fn metadata(expires: Option<&str>, serial: Option<u64>) -> String {
+        let mut content = "apps: []\n".to_string();
+        if let Some(expires) = expires {
+            content.push_str(&format!("expires: \"{}\"\n", expires));
+        }
+        if let Some(serial) = serial {
+            content.push_str(&format!("serial: {}\n", serial));
+        }
+        content
+    }
+
+    #[test]
+    fn expired_metadata_is_refused_unless_stale_is_allowed() {
+        let mut freshness = Freshness::default();
+        let error = freshness.check_freshness(URL, &metadata(Some("2000-01-01"), None)).unwrap_err();
+        assert!(error.to_string().contains("expired at 2000-01-01"), "{}", error);
+
+        freshness.allow_stale = true;
+        freshness.check_freshness(URL, &metadata(Some("2000-01-01"), None)).unwrap();
+        assert!(freshness.check_freshness(URL, &metadata(Some("tomorrow"), None)).is_err());
+    }
+
+    #[test]
+    fn earliest_expiry_of_a_source_is_kept() {
+        let mut freshness = Freshness::default();
+        freshness.check_freshness(URL, &metadata(Some("2999-06-01T12:00:00Z"), None)).unwrap();
+        freshness.check_freshness("https://example.com/other.yaml", &metadata(Some("2999-01-01"), None)).unwrap();
+        freshness.check_freshness("https://example.com/unversioned.yaml", &metadata(None, None)).unwrap();
+        assert_eq!(freshness.earliest_expiry, Some(parse_expiry("2999-01-01T23:59:59Z").unwrap()));
+    }
+
+    #[test]
+    fn serials_must_not_go_back_or_disappear() {
+        let mut freshness = Freshness::default();
+        freshness.check_freshness(URL, &metadata(None, Some(5))).unwrap();
+        freshness.check_freshness(URL, &metadata(None, Some(5))).unwrap();
+        freshness.check_freshness(URL, &metadata(None, Some(7))).unwrap();
+
+        let error = freshness.check_freshness(URL, &metadata(None, Some(6))).unwrap_err();
+        assert!(error.to_string().contains("serial 6 older than last seen 7"), "{}", error);
+        let error = freshness.check_freshness(URL, &metadata(None, None)).unwrap_err();
+        assert!(error.to_string().contains("no serial, but serial 7 was seen before"), "{}", error);
+        assert_eq!(freshness.serials.get(URL), Some(&7));
+
+        // Other URLs have their own serials
+        freshness.check_freshness("https://example.com/other.yaml", &metadata(None, Some(1))).unwrap();
+
+        freshness.allow_stale = true;
+        freshness.check_freshness(URL, &metadata(None, None)).unwrap();
+        freshness.check_freshness(URL, &metadata(None, Some(6))).unwrap();
+        assert_eq!(freshness.serials.get(URL), Some(&6));
+    }
+}
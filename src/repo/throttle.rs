@@ -0,0 +1,97 @@
+use anyhow::Result;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+
+/// Unused budget accumulates for at most this long
+const MAX_BURST: Duration = Duration::from_secs(1);
+
+/// Bandwidth budget shared by all downloads of a Fetcher, so concurrent
+/// downloads together stay within one rate.
+pub struct RateLimiter {
+    bytes_per_second: u64,
+    /// Time at which all bytes handed out so far have been paid for
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until `bytes` more bytes fit within the rate.
+    pub async fn consume(&self, bytes: usize) {
+        let cost = Duration::from_secs_f64(bytes as f64 / self.bytes_per_second as f64);
+        let deadline = {
+            let mut next_free = self.next_free.lock().await;
+            let now = Instant::now();
+            let earliest = now.checked_sub(MAX_BURST).unwrap_or(now);
+            *next_free = (*next_free).max(earliest) + cost;
+            *next_free
+        };
+        tokio::time::sleep_until(deadline).await;
+    }
+}
+
+/// Parses a rate such as "500K", "2M" or "1G" (bytes per second, 1024-based).
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let rate = rate.trim();
+    let rate = rate.strip_suffix("/s").unwrap_or(rate);
+    let rate = rate.strip_suffix(['B', 'b']).unwrap_or(rate);
+    let (number, multiplier) = match rate.chars().last() {
+        Some('k' | 'K') => (&rate[..rate.len() - 1], 1024),
+        Some('m' | 'M') => (&rate[..rate.len() - 1], 1024 * 1024),
+        Some('g' | 'G') => (&rate[..rate.len() - 1], 1024 * 1024 * 1024),
+        _ => (rate, 1),
+    };
+    let number: f64 = number.trim().parse()
+        .map_err(|_| anyhow::anyhow!("Invalid download rate: {} (expected e.g. 500K or 2M)", rate))?;
+    let bytes = (number * multiplier as f64) as u64;
+    if bytes == 0 {
+        anyhow::bail!("Download rate must be at least 1 byte per second: {}", rate);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_accept_units_and_suffixes() {
+        for (rate, bytes) in [
+            ("100", 100), ("500K", 500 * 1024), ("500k", 500 * 1024), ("2M", 2 * 1024 * 1024),
+            ("1.5M", 3 * 512 * 1024), ("1G", 1024 * 1024 * 1024), ("2MB", 2 * 1024 * 1024),
+            ("2MB/s", 2 * 1024 * 1024), (" 64kb/s ", 64 * 1024),
+        ] {
+            assert_eq!(parse_rate(rate).unwrap(), bytes, "{}", rate);
+        }
+    }
+
+    #[test]
+    fn invalid_rates_are_refused() {
+        for rate in ["", "fast", "M", "-1M", "0", "0.1", "1T", "1.2.3K", "64KiB"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn budget_refills_at_the_rate_up_to_one_burst() {
+        let limiter = RateLimiter::new(1000);
+        let start = Instant::now();
+        limiter.consume(500).await;
+        limiter.consume(1500).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+
+        // Idle time beyond one burst is not saved up
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        let start = Instant::now();
+        limiter.consume(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.consume(1000).await;
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+    }
+}
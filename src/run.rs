@@ -0,0 +1,110 @@
+use anyhow::{Context, Result};
+use std::os::unix::process::CommandExt;
+use std::path::PathBuf;
+use tokio::fs;
+
+use crate::config::Config;
+use crate::install::{download_appimage_entry, load_database};
+use crate::pkg_cache;
+use crate::repo::appimage_yaml::{AppImageEntryWithSource, UnifiedIndex};
+use crate::repo::cache::load_unified_index;
+use crate::repo::fetcher::Fetcher;
+use crate::verify::{check_signature_policy, verify_embedded_signature};
+
+/// Newest entry of `package` matching `version_req`. No fuzzy matching here,
+/// a near miss would run someone else's code.
+fn find_entry<'a>(index: &'a UnifiedIndex, package: &str, version_req: Option<&str>) -> Result<&'a AppImageEntryWithSource> {
+    index.find_best_match(package, version_req)
+        .with_context(|| match version_req {
+            Some(req) if index.apps.contains_key(package) => format!("No version of {} matches {}", package, req),
+            _ => format!("Package not found: {}", package),
+        })
+}
+
+/// Runs a package from the repositories without installing it: the AppImage
+/// is downloaded and verified into the package cache, or taken from there,
+/// and executed in place of aipkg. No symlink, desktop file or database entry
+/// is created. aipkg's own messages go to stderr, so the app's output can
+/// be piped.
+pub async fn run(package: &str, version_req: Option<&str>, args: &[String]) -> Result<()> {
+    let config = Config::new()?;
+    let index = load_unified_index().await?;
+    let entry = find_entry(&index, package, version_req)?;
+
+    let db = load_database(&config).await?;
+    for dep_name in &entry.entry.dependencies {
+        if db.get_package(dep_name).is_none() {
+            eprintln!("Note: {} depends on {}, which is not installed", entry.entry.name, dep_name);
+        }
+    }
+
+    // An installed copy of the same build is as good as a cached one
+    let installed = db.get_package(&entry.entry.name)
+        .filter(|pkg| pkg.sha256.as_deref().is_some_and(|sha256| sha256.eq_ignore_ascii_case(&entry.entry.sha256)))
+        .filter(|pkg| pkg.path.exists())
+        .map(|pkg| pkg.path.clone());
+
+    let path: PathBuf = match installed {
+        Some(path) => path,
+        None => {
+            let data = match pkg_cache::get_cached(&config, &entry.entry.sha256).await? {
+                Some(data) => data,
+                None => download_appimage_entry(&config, &Fetcher::new(&config)?, entry).await?,
+            };
+            let signature = verify_embedded_signature(&config, &data).await?;
+            eprintln!("Signature: {}", signature);
+            check_signature_policy(config.signature_policy, &signature, &entry.entry.name)?;
+
+            let path = pkg_cache::cached_path(&config, &entry.entry.sha256)?;
+            let mut perms = fs::metadata(&path).await?.permissions();
+            std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o755);
+            fs::set_permissions(&path, perms).await
+                .context(format!("Failed to make {} executable", path.display()))?;
+            path
+        }
+    };
+
+    eprintln!("Running {} {}", entry.entry.name, entry.entry.version);
+    // Replace aipkg, so signals and the exit status are the app's own
+    let error = std::process::Command::new(&path)
+        .arg0(&entry.entry.name)
+        .args(args)
+        .exec();
+    Err(error).context(format!("Failed to run {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repo::appimage_yaml::AppImageEntry;
+
+    fn index() -> UnifiedIndex {
+        let mut index = UnifiedIndex::new();
+        for version in ["1.2.0", "1.4.1", "2.0.0"] {
+            index.add_entry(AppImageEntry {
+                name: "jq".to_string(),
+                version: version.to_string(),
+                file: format!("jq-{}.AppImage", version),
+                sha256: "a".repeat(64),
+                size: None,
+                description: None,
+                dependencies: Vec::new(),
+                provides: Vec::new(),
+                zsync: None,
+            }, "https://example.com/appimage.yaml".to_string());
+        }
+        index
+    }
+
+    #[test]
+    fn only_exact_names_and_matching_versions_run() {
+        let index = index();
+        assert_eq!(find_entry(&index, "jq", None).unwrap().entry.version, "2.0.0");
+        assert_eq!(find_entry(&index, "jq", Some("~1.4")).unwrap().entry.version, "1.4.1");
+
+        let error = |package, version_req| find_entry(&index, package, version_req).unwrap_err().to_string();
+        assert_eq!(error("j", None), "Package not found: j");
+        assert_eq!(error("jqq", Some("1.4.*")), "Package not found: jqq");
+        assert_eq!(error("jq", Some(">=3")), "No version of jq matches >=3");
+    }
+}
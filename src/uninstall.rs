@@ -1,26 +1,58 @@
 use anyhow::{Context, Result};
 use tokio::fs;
 
-use crate::config::Config;
-use crate::install::load_database;
+use crate::config::{Config, Scope};
+use crate::install::{load_database, lock_database, remove_installed_appimage, save_database};
+use crate::plan::{Action, Plan, PlanItem};
 
-pub async fn uninstall(package: &str) -> Result<()> {
+/// Removes the given packages, after checking that all of them are installed.
+pub async fn remove_packages(packages: &[String], dry_run: bool) -> Result<()> {
     let config = Config::new()?;
+    let db = load_database(&config).await?;
     
+    let mut plan = Plan::new();
+    for package in packages {
+        let Some(pkg) = db.get_package(package) else {
+            if config.scope == Scope::User {
+                let system_db = load_database(&Config::for_scope(Scope::System)?).await?;
+                if system_db.get_package(package).is_some() {
+                    anyhow::bail!("{} is installed system-wide, remove it with 'sudo aipkg --system remove {}'", package, package);
+                }
+            }
+            anyhow::bail!("Package not installed: {}", package);
+        };
+        plan.push(PlanItem::for_installed(pkg, Action::Remove));
+    }
+    
+    if dry_run {
+        plan.print();
+        if config.retained_versions > 0 {
+            println!(
+                "Removed AppImages are kept in the package cache for undo (up to {} per package), 'aipkg cache clean' frees them",
+                config.retained_versions
+            );
+        }
+        return Ok(());
+    }
+    config.check_privileges()?;
+    
+    for item in &plan.items {
+        uninstall(&config, &item.name).await?;
+    }
+    Ok(())
+}
+
+pub async fn uninstall(config: &Config, package: &str) -> Result<()> {
     // Load database
-    let mut db = load_database(&config).await?;
+    let _lock = lock_database(config).await?;
+    let mut db = load_database(config).await?;
     
     // Find package
     let pkg = db.remove_package(package)
         .ok_or_else(|| anyhow::anyhow!("Package not installed: {}", package))?;
     
-    // Remove AppImage directory
-    if pkg.path.exists() {
-        let appimage_dir = pkg.path.parent()
-            .context("Invalid package path")?;
-        fs::remove_dir_all(appimage_dir).await
-            .context("Failed to remove AppImage directory")?;
-    }
+    // Remove AppImage directory; AppImages adopted in place are only unregistered
+    remove_installed_appimage(config, &pkg).await?;
     
     // Remove desktop file
     if pkg.desktop_file.exists() {
@@ -28,15 +60,20 @@ pub async fn uninstall(package: &str) -> Result<()> {
             .context("Failed to remove desktop file")?;
     }
     
-    // Remove symlink
-    if pkg.symlink.exists() {
+    // Remove symlink, which dangles by now since the AppImage is gone. A file
+    // that replaced it in the meantime is not ours to remove
+    if fs::symlink_metadata(&pkg.symlink).await.is_ok_and(|m| m.is_symlink()) {
         fs::remove_file(&pkg.symlink).await
             .context("Failed to remove symlink")?;
     }
     
+    // Unregister file types and default applications
+    if let Err(e) = crate::mime::unregister(config, &pkg).await {
+        println!("Warning: failed to unregister file types of {}: {:#}", package, e);
+    }
+    
     // Update database
-    let content = db.to_string()?;
-    fs::write(&config.database_file, content).await?;
+    save_database(config, &db).await?;
     
     println!("Uninstalled {}", package);
     Ok(())
@@ -1,12 +1,72 @@
 use anyhow::Result;
-use semver::Version;
+use std::cmp::Ordering;
 
 use crate::config::Config;
+use crate::repo::appimage_yaml::{AppImageEntryWithSource, UnifiedIndex};
 use crate::repo::cache::load_unified_index;
-use crate::install::{install_appimage_entry, load_database};
+use crate::install::{
+    download_entries, download_url_to_file, install_appimage_entry, install_local_appimage,
+    load_database, resolve_url_download, update_database, InstalledPackage, PackageDatabase, UrlDownload,
+};
+use crate::repo::fetcher::Fetcher;
+use crate::repo::github::GithubReleaseUrl;
+use crate::verify::calculate_sha256;
+use crate::pkg_cache;
+use crate::plan::{Action, Plan, PlanItem};
+use crate::version;
+use crate::zsync;
 
-pub async fn upgrade_all() -> Result<()> {
+/// Exit code of `aipkg outdated` when updates are available
+pub const UPDATES_AVAILABLE_EXIT_CODE: i32 = 100;
+
+/// How an installed package compares to the newest version in the index.
+enum IndexStatus<'a> {
+    UpToDate,
+    Newer(&'a AppImageEntryWithSource),
+    Uncomparable(&'a AppImageEntryWithSource),
+    NotInIndex,
+}
+
+fn index_status<'a>(index: &'a UnifiedIndex, pkg: &InstalledPackage) -> IndexStatus<'a> {
+    // Find latest version in index
+    let Some(latest_entry) = index.find_best_match(&pkg.name, None) else {
+        return IndexStatus::NotInIndex;
+    };
+    
+    // Same AppImage as installed, whatever its version string says
+    if pkg.sha256.as_deref().is_some_and(|sha| sha.eq_ignore_ascii_case(&latest_entry.entry.sha256)) {
+        return IndexStatus::UpToDate;
+    }
+    
+    match version::compare(&latest_entry.entry.version, &pkg.version) {
+        Some(Ordering::Greater) => IndexStatus::Newer(latest_entry),
+        Some(_) => IndexStatus::UpToDate,
+        None => IndexStatus::Uncomparable(latest_entry),
+    }
+}
+
+/// Installed packages named in `names` sorted by name, or all of them if
+/// `names` is empty.
+fn select_packages<'a>(db: &'a PackageDatabase, names: &[String]) -> Result<Vec<&'a InstalledPackage>> {
+    let mut packages = if names.is_empty() {
+        db.list_packages()
+    } else {
+        names.iter()
+            .map(|name| db.get_package(name).ok_or_else(|| anyhow::anyhow!("Package not installed: {}", name)))
+            .collect::<Result<_>>()?
+    };
+    packages.sort_by(|a, b| a.name.cmp(&b.name));
+    packages.dedup_by(|a, b| a.name == b.name);
+    Ok(packages)
+}
+
+/// Upgrades the named packages, or all installed packages if `names` is empty.
+pub async fn upgrade_packages(names: &[String], download_only: bool, dry_run: bool) -> Result<()> {
     let config = Config::new()?;
+    // Fail before downloading anything, not once the first install starts
+    if !dry_run {
+        config.check_privileges()?;
+    }
     
     // Load installed packages
     let db = load_database(&config).await?;
@@ -14,37 +74,367 @@ pub async fn upgrade_all() -> Result<()> {
     // Load unified index
     let index = load_unified_index().await?;
     
+    let fetcher = Fetcher::new(&config)?;
     let mut upgraded = 0;
+    let mut plan = Plan::new();
+    let mut pending = Vec::new();
+    let mut url_pending = Vec::new();
+    let mut uncomparable = Vec::new();
+    
+    for pkg in select_packages(&db, names)? {
+        // Packages installed from a URL are checked against that URL
+        if let Some(origin) = &pkg.origin_url {
+            if download_only {
+                println!("Skipping {}: installed from URL", pkg.name);
+            } else if let Some(download) = check_url_upgrade(&config, &fetcher, pkg, origin).await? {
+                plan.push(url_plan_item(pkg, &download));
+                url_pending.push((pkg, download));
+                continue;
+            }
+            plan.push(PlanItem::for_installed(pkg, Action::Keep));
+            continue;
+        }
+        
+        match index_status(&index, pkg) {
+            IndexStatus::Newer(latest_entry) => {
+                let action = Action::Upgrade { from: pkg.version.clone() };
+                plan.push(PlanItem::for_entry(&config, latest_entry, action, Some(pkg)));
+                pending.push((pkg, latest_entry));
+            }
+            IndexStatus::Uncomparable(latest_entry) => {
+                plan.push(PlanItem::for_installed(pkg, Action::Keep));
+                uncomparable.push((pkg, latest_entry));
+            }
+            IndexStatus::UpToDate | IndexStatus::NotInIndex => {
+                plan.push(PlanItem::for_installed(pkg, Action::Keep));
+            }
+        }
+    }
+    
+    if !uncomparable.is_empty() {
+        println!("Could not compare versions of {} package(s):", uncomparable.len());
+        for (pkg, entry) in &uncomparable {
+            println!("  {}: installed {}, available {}", pkg.name, pkg.version, entry.entry.version);
+        }
+    }
+    
+    if dry_run {
+        plan.print();
+        return Ok(());
+    }
     
-    for pkg in db.list_packages() {
-        // Find latest version in index
-        if let Some(latest_entry) = index.find_best_match(&pkg.name, None) {
-            // Compare versions
-            let current_version = Version::parse(&pkg.version).ok();
-            let latest_version = Version::parse(&latest_entry.entry.version).ok();
+    // Prefer delta updates that reuse the blocks of the installed version
+    for (pkg, entry) in &pending {
+        if pkg_cache::is_cached(&config, &entry.entry.sha256) {
+            continue;
+        }
+        if let Err(e) = zsync::delta_download(&config, &fetcher, entry, &pkg.path).await {
+            println!("Delta update of {} failed ({:#}), downloading the full AppImage", pkg.name, e);
+        }
+    }
+    
+    // Download everything else, so installs only start once all AppImages
+    // are verified and in the package cache
+    let entries: Vec<_> = pending.iter().map(|(_, entry)| *entry).collect();
+    download_entries(&config, &entries).await?;
+    
+    if download_only {
+        upgraded += pending.len();
+    } else {
+        for (pkg, latest_entry) in &pending {
+            println!("Upgrading {} from {} to {}", 
+                pkg.name, pkg.version, latest_entry.entry.version);
             
-            if let (Some(current), Some(latest)) = (current_version, latest_version) {
-                if latest > current {
-                    println!("Upgrading {} from {} to {}", 
-                        pkg.name, pkg.version, latest_entry.entry.version);
-                    
-                    // Uninstall old version
-                    crate::uninstall::uninstall(&pkg.name).await?;
-                    
-                    // Install new version
-                    install_appimage_entry(&config, latest_entry, &index).await?;
-                    
-                    upgraded += 1;
-                }
+            // Switches over to the new version and only then removes the old one
+            install_appimage_entry(&config, latest_entry, &index).await?;
+            
+            upgraded += 1;
+        }
+        
+        for (pkg, download) in &url_pending {
+            if upgrade_from_url(&config, &fetcher, pkg, download).await? {
+                upgraded += 1;
             }
         }
     }
     
-    if upgraded == 0 {
+    if upgraded == 0 && uncomparable.is_empty() {
         println!("All packages are up to date");
+    } else if upgraded == 0 {
+        println!("No packages upgraded");
+    } else if download_only {
+        println!("Downloaded {} package(s) to cache", upgraded);
     } else {
         println!("Upgraded {} package(s)", upgraded);
     }
     
     Ok(())
 }
+
+/// Lists installed packages with a newer version in the index. Returns
+/// whether any updates are available.
+pub async fn list_outdated(names: &[String]) -> Result<bool> {
+    let config = Config::new()?;
+    let index = load_unified_index().await?;
+    let (lines, updates_available) = outdated_report(&config, &index, names).await?;
+    for line in lines {
+        println!("{}", line);
+    }
+    Ok(updates_available)
+}
+
+/// Lines of `aipkg outdated` for the named packages, or all installed ones,
+/// and whether any of them has an update.
+async fn outdated_report(config: &Config, index: &UnifiedIndex, names: &[String]) -> Result<(Vec<String>, bool)> {
+    let db = load_database(config).await?;
+    let fetcher = Fetcher::new(config)?;
+    
+    let mut lines = Vec::new();
+    let mut outdated = 0;
+    let mut uncomparable = Vec::new();
+    let mut unknown_urls = Vec::new();
+    for pkg in select_packages(&db, names)? {
+        // Packages installed from a URL are checked against that URL, as upgrade does
+        if let Some(origin) = &pkg.origin_url {
+            match check_url_upgrade(config, &fetcher, pkg, origin).await {
+                // A checksum or the server's validators show that it changed
+                Ok(Some(download)) if download.sha256.is_some() || !pkg.origin_validators.is_empty() => {
+                    let version = download.version.as_deref().unwrap_or("new build");
+                    lines.push(format!("{} {} -> {} ({})", pkg.name, pkg.version, version, download.url));
+                    outdated += 1;
+                }
+                Ok(Some(download)) => unknown_urls.push((pkg, download.url)),
+                Ok(None) => {}
+                Err(e) => lines.push(format!("Warning: failed to check {} for updates: {:#}", pkg.name, e)),
+            }
+            continue;
+        }
+        
+        match index_status(index, pkg) {
+            IndexStatus::Newer(entry) => {
+                lines.push(format!("{} {} -> {} ({})", pkg.name, pkg.version, entry.entry.version, entry.source_url));
+                outdated += 1;
+            }
+            IndexStatus::Uncomparable(entry) => uncomparable.push((pkg, entry)),
+            IndexStatus::UpToDate | IndexStatus::NotInIndex => {}
+        }
+    }
+    
+    for (pkg, entry) in &uncomparable {
+        lines.push(format!("{} {} ? {} ({}, versions cannot be compared)", pkg.name, pkg.version, entry.entry.version, entry.source_url));
+    }
+    for (pkg, url) in &unknown_urls {
+        lines.push(format!("{} {} ? ({}, only a download can tell whether it changed)", pkg.name, pkg.version, url));
+    }
+    if outdated == 0 && uncomparable.is_empty() && unknown_urls.is_empty() {
+        lines.push("All packages are up to date".to_string());
+    }
+    
+    Ok((lines, outdated > 0))
+}
+
+/// Checks the URL a package was installed from for a different AppImage.
+/// Returns the download if it may be newer than the installed one.
+async fn check_url_upgrade(
+    config: &Config,
+    fetcher: &Fetcher,
+    pkg: &InstalledPackage,
+    origin: &str,
+) -> Result<Option<UrlDownload>> {
+    let download = resolve_url_download(config, fetcher, origin, true).await?;
+    
+    // Same GitHub release asset as installed
+    if GithubReleaseUrl::parse(origin).is_some() && download.url == origin {
+        return Ok(None);
+    }
+    if let (Some(latest), Some(current)) = (&download.sha256, &pkg.sha256) {
+        if latest.eq_ignore_ascii_case(current) {
+            return Ok(None);
+        }
+    }
+    // Plain URLs publish no checksum; ask the server before downloading it all
+    if download.sha256.is_none() && download.url == origin
+        && fetcher.unchanged_since(&download.url, &pkg.origin_validators).await?
+    {
+        return Ok(None);
+    }
+    
+    Ok(Some(download))
+}
+
+fn url_plan_item(pkg: &InstalledPackage, download: &UrlDownload) -> PlanItem {
+    // Without a published checksum only the download shows whether it changed
+    let action = match download.sha256 {
+        Some(_) => Action::Upgrade { from: pkg.version.clone() },
+        None => Action::Check,
+    };
+    let installed_size = std::fs::metadata(&pkg.path).map(|m| m.len()).unwrap_or(0);
+    PlanItem {
+        name: pkg.name.clone(),
+        action,
+        version: download.version.clone().unwrap_or_else(|| "unknown".to_string()),
+        source: Some(download.url.clone()),
+        download_size: download.size,
+        disk_delta: download.size.map(|size| size as i64 - installed_size as i64),
+    }
+}
+
+/// Downloads a newer AppImage found by `check_url_upgrade` and installs it.
+/// Returns whether the package was upgraded.
+async fn upgrade_from_url(config: &Config, fetcher: &Fetcher, pkg: &InstalledPackage, download: &UrlDownload) -> Result<bool> {
+    let (path, validators) = download_url_to_file(config, fetcher, download, None).await?;
+    let path_str = path.to_string_lossy().to_string();
+    
+    // Plain URLs can only be compared by content
+    if pkg.sha256.as_deref() == Some(calculate_sha256(&path_str).await?.as_str()) {
+        let _ = tokio::fs::remove_file(&path).await;
+        // Remember the validators, so the next check needs no download
+        if validators != pkg.origin_validators {
+            let mut pkg = pkg.clone();
+            pkg.origin_validators = validators;
+            update_database(config, pkg).await?;
+        }
+        return Ok(false);
+    }
+    
+    println!("Upgrading {} from {}", pkg.name, download.url);
+    
+    let result = install_local_appimage(config, &path_str, Some(download.url.clone()), validators, download.version.clone()).await;
+    
+    let _ = tokio::fs::remove_file(&path).await;
+    result?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+    use crate::install::save_database;
+    use crate::repo::appimage_yaml::AppImageEntry;
+    use crate::verify::sha256_hex;
+    use std::path::PathBuf;
+
+    const SOURCE: &str = "https://repo.example.com/appimage.yaml";
+
+    async fn temp_config(dir: &tempfile::TempDir) -> Config {
+        let root = dir.path();
+        let mut config = Config::located(Scope::User).unwrap();
+        config.appimages_dir = root.join("appimages");
+        config.desktop_files_dir = root.join("applications");
+        config.bin_dir = root.join("bin");
+        config.config_dir = root.join("config");
+        config.cache_dir = root.join("cache");
+        config.database_file = root.join("config/database.yaml");
+        config.credentials_file = root.join("config/credentials.toml");
+        config.netrc_file = root.join("netrc");
+        config.ensure_directories().await.unwrap();
+        config
+    }
+
+    fn entry(name: &str, version: &str) -> AppImageEntry {
+        AppImageEntry {
+            name: name.to_string(),
+            version: version.to_string(),
+            file: format!("{}-{}.AppImage", name, version),
+            sha256: sha256_hex(format!("{}-{}", name, version).as_bytes()),
+            size: None,
+            description: None,
+            dependencies: Vec::new(),
+            provides: Vec::new(),
+            zsync: None,
+        }
+    }
+
+    fn installed(name: &str, version: &str) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            version: version.to_string(),
+            path: PathBuf::from(format!("/nonexistent/{}", name)),
+            desktop_file: PathBuf::new(),
+            symlink: PathBuf::new(),
+            installed_at: String::new(),
+            sha256: Some(entry(name, version).sha256),
+            origin_url: None,
+            origin_validators: Default::default(),
+            source_url: None,
+            adopted_from: None,
+        }
+    }
+
+    /// Installed: current 1.0, editor 1.0, nightly "nightly" and local 1.0.
+    /// The index has current 1.0, editor 1.0 and 2.0, and nightly 2024.05.01.
+    fn fixture() -> (PackageDatabase, UnifiedIndex) {
+        let mut db = PackageDatabase::new();
+        for (name, version) in [("editor", "1.0"), ("current", "1.0"), ("nightly", "nightly"), ("local", "1.0")] {
+            db.add_package(installed(name, version));
+        }
+        let mut index = UnifiedIndex::new();
+        for (name, version) in [("current", "1.0"), ("editor", "1.0"), ("editor", "2.0"), ("nightly", "2024.05.01")] {
+            index.add_entry(entry(name, version), SOURCE.to_string());
+        }
+        (db, index)
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn named_packages_are_selected_once_and_must_be_installed() {
+        let (db, _) = fixture();
+        let selected = |names: &[String]| -> Vec<String> {
+            select_packages(&db, names).unwrap().iter().map(|pkg| pkg.name.clone()).collect()
+        };
+        assert_eq!(selected(&[]), names(&["current", "editor", "local", "nightly"]));
+        assert_eq!(selected(&names(&["nightly", "editor", "nightly"])), names(&["editor", "nightly"]));
+
+        let error = select_packages(&db, &names(&["editor", "missing"])).unwrap_err();
+        assert_eq!(error.to_string(), "Package not installed: missing");
+    }
+
+    #[test]
+    fn installed_packages_compare_to_the_newest_index_entry() {
+        let (db, index) = fixture();
+        let status = |name: &str| index_status(&index, db.get_package(name).unwrap());
+
+        assert!(matches!(status("editor"), IndexStatus::Newer(entry) if entry.entry.version == "2.0"));
+        assert!(matches!(status("current"), IndexStatus::UpToDate));
+        assert!(matches!(status("nightly"), IndexStatus::Uncomparable(entry) if entry.entry.version == "2024.05.01"));
+        assert!(matches!(status("local"), IndexStatus::NotInIndex));
+
+        // The same AppImage is up to date whatever its version says
+        let mut renamed = installed("nightly", "nightly");
+        renamed.sha256 = Some(entry("nightly", "2024.05.01").sha256.to_uppercase());
+        assert!(matches!(index_status(&index, &renamed), IndexStatus::UpToDate));
+        // An older index entry is no upgrade
+        assert!(matches!(index_status(&index, &installed("editor", "3.0")), IndexStatus::UpToDate));
+    }
+
+    #[tokio::test]
+    async fn outdated_reports_updates_for_the_exit_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = temp_config(&dir).await;
+        let (db, index) = fixture();
+        save_database(&config, &db).await.unwrap();
+
+        let (lines, updates) = outdated_report(&config, &index, &[]).await.unwrap();
+        assert_eq!(lines, vec![
+            format!("editor 1.0 -> 2.0 ({})", SOURCE),
+            format!("nightly nightly ? 2024.05.01 ({}, versions cannot be compared)", SOURCE),
+        ]);
+        assert!(updates);
+
+        // Versions that cannot be compared are shown, but are no update
+        let (lines, updates) = outdated_report(&config, &index, &names(&["nightly"])).await.unwrap();
+        assert_eq!(lines.len(), 1);
+        assert!(!updates);
+
+        let (lines, updates) = outdated_report(&config, &index, &names(&["current", "local"])).await.unwrap();
+        assert_eq!(lines, vec!["All packages are up to date"]);
+        assert!(!updates);
+
+        assert!(outdated_report(&config, &index, &names(&["missing"])).await.is_err());
+        assert_eq!(UPDATES_AVAILABLE_EXIT_CODE, 100);
+    }
+}
@@ -1,7 +1,13 @@
-use anyhow::Result;
-use std::path::Path;
+use anyhow::{Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
 use tokio::fs;
 
+use crate::desktop_entry::{KeyFile, DESKTOP_ENTRY};
+
 #[derive(Debug, Clone)]
 pub struct AppImageMetadata {
     pub name: String,
@@ -10,6 +16,8 @@ pub struct AppImageMetadata {
     pub icon: Option<String>,
     pub categories: Vec<String>,
     pub size: u64,
+    /// The desktop entry embedded in the AppImage, with all its groups
+    pub desktop_entry: Option<KeyFile>,
 }
 
 pub async fn extract_metadata(appimage_path: &str) -> Result<AppImageMetadata> {
@@ -29,29 +37,26 @@ pub async fn extract_metadata(appimage_path: &str) -> Result<AppImageMetadata> {
         .to_string();
     
     // Try to extract desktop entry using AppImage runtime or desktop-file-validate
-    let desktop_entry = extract_desktop_entry(appimage_path).await?;
+    let content = fs::read(appimage_path).await?;
+    let desktop_entry = extract_desktop_entry(&content);
+    let get = |key: &str| desktop_entry.as_ref().and_then(|entry| entry.get(DESKTOP_ENTRY, key));
     
     Ok(AppImageMetadata {
-        name: desktop_entry.name.unwrap_or(name),
-        version: desktop_entry.version,
-        description: desktop_entry.comment,
-        icon: desktop_entry.icon,
-        categories: desktop_entry.categories.unwrap_or_default(),
+        name: get("Name").unwrap_or(name),
+        // The AppImage's version; "Version" is the desktop entry spec version
+        version: get("X-AppImage-Version"),
+        description: get("Comment"),
+        icon: get("Icon"),
+        categories: desktop_entry.as_ref()
+            .and_then(|entry| entry.get_list(DESKTOP_ENTRY, "Categories"))
+            .unwrap_or_default(),
         size,
+        desktop_entry,
     })
 }
 
-#[derive(Debug, Default)]
-struct DesktopEntry {
-    name: Option<String>,
-    version: Option<String>,
-    comment: Option<String>,
-    icon: Option<String>,
-    categories: Option<Vec<String>>,
-}
-
-async fn extract_desktop_entry(appimage_path: &str) -> Result<DesktopEntry> {
-    let mut entry = DesktopEntry::default();
+fn extract_desktop_entry(content: &[u8]) -> Option<KeyFile> {
+    let mut entry = None;
     
     // Try using AppImage runtime to extract desktop file
     // First, try to run the AppImage with --appimage-extract-and-run --appimage-help
@@ -65,16 +70,14 @@ async fn extract_desktop_entry(appimage_path: &str) -> Result<DesktopEntry> {
     // For type 1, it's in the squashfs filesystem
     
     // Simplified: try to find desktop entry in the file
-    let content = fs::read(appimage_path).await?;
-    
     // Look for desktop entry markers
-    if let Some(desktop_start) = find_desktop_entry_start(&content) {
+    if let Some(desktop_start) = find_desktop_entry_start(content) {
         if let Some(desktop_content) = extract_desktop_content(&content[desktop_start..]) {
-            entry = parse_desktop_entry(&desktop_content)?;
+            entry = Some(KeyFile::parse_leading(&desktop_content));
         }
     }
     
-    Ok(entry)
+    entry
 }
 
 fn find_desktop_entry_start(content: &[u8]) -> Option<usize> {
@@ -94,50 +97,194 @@ fn find_desktop_entry_start(content: &[u8]) -> Option<usize> {
 }
 
 fn extract_desktop_content(content: &[u8]) -> Option<String> {
-    // Try to extract readable text that looks like a desktop entry
-    let text = String::from_utf8_lossy(content);
-    
-    // Find [Desktop Entry] section
-    if let Some(start) = text.find("[Desktop Entry]") {
-        let section = &text[start..];
-        // Take up to next section or end
-        let end = section.find("\n[").unwrap_or(section.len());
-        Some(section[..end].to_string())
-    } else {
-        None
-    }
+    // The desktop file runs from [Desktop Entry] to the first byte that
+    // cannot be part of a text file
+    let start = content.windows(15).position(|w| w == b"[Desktop Entry]")?;
+    let content = &content[start..];
+    let end = content.iter()
+        .position(|&b| b == 0 || (b < 0x20 && !matches!(b, b'\n' | b'\r' | b'\t')))
+        .unwrap_or(content.len());
+    Some(String::from_utf8_lossy(&content[..end]).into_owned())
 }
 
-fn parse_desktop_entry(content: &str) -> Result<DesktopEntry> {
-    let mut entry = DesktopEntry::default();
-    
-    for line in content.lines() {
-        let line = line.trim();
-        if line.is_empty() || line.starts_with('#') || line.starts_with('[') {
-            continue;
+/// Offset of the squashfs in a type 2 AppImage, which follows the runtime's
+/// ELF image; `None` for anything without a squashfs there.
+async fn squashfs_offset(appimage: &Path) -> Option<u64> {
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    let mut file = fs::File::open(appimage).await.ok()?;
+    let mut header = [0u8; 64];
+    file.read_exact(&mut header).await.ok()?;
+    let header = goblin::elf::Elf::parse_header(&header).ok()?;
+    let offset = header.e_shoff.checked_add(u64::from(header.e_shentsize) * u64::from(header.e_shnum))?;
+
+    let mut magic = [0u8; 4];
+    file.seek(std::io::SeekFrom::Start(offset)).await.ok()?;
+    file.read_exact(&mut magic).await.ok()?;
+    (&magic == b"hsqs").then_some(offset)
+}
+
+/// Files unpacked from the squashfs of a type 2 AppImage, in a private
+/// temporary directory that is removed on drop.
+pub struct ExtractedFiles {
+    dir: PathBuf,
+}
+
+impl ExtractedFiles {
+    /// How long unpacking the requested files may take
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    pub fn new() -> Result<Self> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "aipkg-extract-{}-{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir)
+            .context(format!("Failed to create {}", dir.display()))?;
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o700))
+            .context(format!("Failed to create {}", dir.display()))?;
+        Ok(Self { dir })
+    }
+
+    /// Where the files are unpacked to, mirroring the AppImage's squashfs.
+    pub fn root(&self) -> PathBuf {
+        self.dir.join("squashfs-root")
+    }
+
+    /// Unpacks the files whose path matches the glob `pattern`. The squashfs
+    /// is read with unsquashfs, so nothing in the AppImage runs; without
+    /// unsquashfs nothing is unpacked. Returns whether the files could be
+    /// unpacked; type 1 AppImages and files that are not AppImages have no
+    /// squashfs to unpack.
+    pub async fn extract(&self, appimage: &Path, pattern: &str) -> bool {
+        let Some(offset) = squashfs_offset(appimage).await else {
+            return false;
+        };
+        let mut unsquashfs = tokio::process::Command::new("unsquashfs");
+        unsquashfs.arg("-offset").arg(offset.to_string())
+            .args(["-no-xattrs", "-force", "-dest"])
+            .arg(self.root())
+            .arg(appimage)
+            .arg(pattern);
+        match self.run(unsquashfs).await {
+            Some(success) => success,
+            None => {
+                static NOTED: AtomicBool = AtomicBool::new(false);
+                if !NOTED.swap(true, Ordering::Relaxed) {
+                    println!("Note: unsquashfs is not installed, skipping icons and file types of AppImages");
+                }
+                false
+            }
+        }
+    }
+
+    /// Runs `command` in the temporary directory, `None` if it cannot be started.
+    async fn run(&self, mut command: tokio::process::Command) -> Option<bool> {
+        let child = command
+            .current_dir(&self.dir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .ok()?;
+        let status = tokio::time::timeout(Self::TIMEOUT, child.wait_with_output()).await;
+        Some(matches!(status, Ok(Ok(output)) if output.status.success()))
+    }
+
+    /// Contents of the unpacked regular file at `path`, relative to the
+    /// squashfs root. Symlinks are not followed, they could point anywhere
+    /// on this system.
+    pub async fn read(&self, path: &str) -> Option<Vec<u8>> {
+        let path = self.root().join(path);
+        if !fs::symlink_metadata(&path).await.ok()?.is_file() {
+            return None;
+        }
+        fs::read(&path).await.ok()
+    }
+
+    /// Target of the unpacked symlink at `path`, as a path relative to the
+    /// squashfs root; `None` if it leaves the squashfs.
+    pub async fn link_target(&self, path: &str) -> Option<String> {
+        let target = fs::read_link(self.root().join(path)).await.ok()?;
+        // Relative targets start from the directory holding the link
+        let mut resolved: Vec<String> = Vec::new();
+        if !target.is_absolute() {
+            resolved.extend(path.split('/').filter(|c| !c.is_empty()).map(str::to_string));
+            resolved.pop();
         }
-        
-        if let Some((key, value)) = line.split_once('=') {
-            let key = key.trim();
-            let value = value.trim();
-            
-            match key {
-                "Name" => entry.name = Some(value.to_string()),
-                "Version" => entry.version = Some(value.to_string()),
-                "Comment" => entry.comment = Some(value.to_string()),
-                "Icon" => entry.icon = Some(value.to_string()),
-                "Categories" => {
-                    entry.categories = Some(
-                        value.split(';')
-                            .map(|s| s.trim().to_string())
-                            .filter(|s| !s.is_empty())
-                            .collect()
-                    );
+        for component in target.components() {
+            match component {
+                std::path::Component::Normal(name) => resolved.push(name.to_str()?.to_string()),
+                std::path::Component::ParentDir => {
+                    resolved.pop()?;
                 }
                 _ => {}
             }
         }
+        Some(resolved.join("/"))
+    }
+}
+
+impl Drop for ExtractedFiles {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Writes `data` next to `path` and renames it into place, so readers see
+/// either the old or the new content.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let file_name = path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("file");
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name));
+    fs::write(&tmp_path, data).await?;
+    if let Err(e) = fs::rename(&tmp_path, path).await {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_regular_files_are_read() {
+        let extracted = ExtractedFiles::new().unwrap();
+        let root = extracted.root();
+        std::fs::create_dir_all(root.join("usr/share/icons")).unwrap();
+        std::fs::write(root.join("usr/share/icons/app.png"), b"\x89PNG").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", root.join(".DirIcon")).unwrap();
+
+        // Never what a symlink points at, which could be anywhere on this system
+        assert_eq!(extracted.read("usr/share/icons/app.png").await.as_deref(), Some(&b"\x89PNG"[..]));
+        assert_eq!(extracted.read(".DirIcon").await, None);
+        assert_eq!(extracted.read("missing.png").await, None);
+    }
+
+    #[tokio::test]
+    async fn links_resolve_inside_the_squashfs_only() {
+        let extracted = ExtractedFiles::new().unwrap();
+        let root = extracted.root();
+        std::fs::create_dir_all(root.join("usr/share/icons")).unwrap();
+        let links = [
+            (".DirIcon", "usr/share/icons/app.png"),
+            ("usr/share/icons/rel.png", "../icons/app.png"),
+            ("abs.png", "/usr/share/icons/app.png"),
+            ("escape.png", "../../etc/passwd"),
+        ];
+        for (link, target) in links {
+            std::os::unix::fs::symlink(target, root.join(link)).unwrap();
+        }
+
+        assert_eq!(extracted.link_target(".DirIcon").await.as_deref(), Some("usr/share/icons/app.png"));
+        assert_eq!(extracted.link_target("usr/share/icons/rel.png").await.as_deref(), Some("usr/share/icons/app.png"));
+        assert_eq!(extracted.link_target("abs.png").await.as_deref(), Some("usr/share/icons/app.png"));
+        assert_eq!(extracted.link_target("escape.png").await, None);
     }
-    
-    Ok(entry)
 }
@@ -1,11 +1,23 @@
 use anyhow::{Context, Result};
 use sha2::{Sha256, Digest};
-use hex;
+use std::path::Path;
 
-pub fn verify_sha256_bytes(data: &[u8], expected_hash: &str) -> Result<bool> {
+use crate::config::{Config, SignaturePolicy};
+use crate::install::load_database;
+
+pub fn sha256_hex(data: &[u8]) -> String {
     let mut hasher = Sha256::new();
     hasher.update(data);
-    let computed_hash = hex::encode(hasher.finalize());
+    hex::encode(hasher.finalize())
+}
+
+/// Whether `s` is a SHA256 digest in hex, as used to name cached files.
+pub fn is_sha256(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn verify_sha256_bytes(data: &[u8], expected_hash: &str) -> Result<bool> {
+    let computed_hash = sha256_hex(data);
     
     Ok(computed_hash == expected_hash.to_lowercase())
 }
@@ -18,3 +30,489 @@ pub async fn calculate_sha256(file_path: &str) -> Result<String> {
     hasher.update(&data);
     Ok(hex::encode(hasher.finalize()))
 }
+
+#[derive(Debug, Clone)]
+pub enum SignatureStatus {
+    /// No embedded signature
+    Unsigned,
+    /// Good signature from a key in the trusted keyring
+    Trusted(String),
+    /// Signed, but the signer is not in the trusted keyring
+    Untrusted(String),
+    /// Signature does not match the AppImage contents
+    Bad(String),
+}
+
+impl std::fmt::Display for SignatureStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignatureStatus::Unsigned => write!(f, "unsigned"),
+            SignatureStatus::Trusted(signer) => write!(f, "trusted ({})", signer),
+            SignatureStatus::Untrusted(reason) => write!(f, "untrusted ({})", reason),
+            SignatureStatus::Bad(reason) => write!(f, "BAD ({})", reason),
+        }
+    }
+}
+
+const SIGNATURE_SECTION: &str = ".sha256_sig";
+const KEY_SECTION: &str = ".sig_key";
+
+/// Returns the file ranges of the named ELF sections.
+pub fn elf_section_ranges(data: &[u8], names: &[&str]) -> Result<Vec<(String, std::ops::Range<usize>)>> {
+    let elf = goblin::elf::Elf::parse(data)
+        .context("Failed to parse AppImage ELF header")?;
+
+    let mut ranges = Vec::new();
+    for section in &elf.section_headers {
+        if let Some(name) = elf.shdr_strtab.get_at(section.sh_name) {
+            if names.contains(&name) {
+                let start = section.sh_offset as usize;
+                let end = start.saturating_add(section.sh_size as usize).min(data.len());
+                ranges.push((name.to_string(), start.min(end)..end));
+            }
+        }
+    }
+    Ok(ranges)
+}
+
+/// Computes the digest defined by the AppImage spec: SHA256 over the whole file
+/// with the signature and key sections treated as zeros.
+fn appimage_signing_digest(data: &[u8], ranges: &[std::ops::Range<usize>]) -> String {
+    let mut ranges = ranges.to_vec();
+    ranges.sort_by_key(|r| r.start);
+
+    let mut hasher = Sha256::new();
+    let mut pos = 0;
+    for range in ranges {
+        if range.start < pos {
+            continue;
+        }
+        hasher.update(&data[pos..range.start]);
+        hasher.update(vec![0u8; range.len()]);
+        pos = range.end;
+    }
+    hasher.update(&data[pos..]);
+    hex::encode(hasher.finalize())
+}
+
+pub fn section_text(data: &[u8], range: &std::ops::Range<usize>) -> String {
+    let bytes = &data[range.clone()];
+    let end = bytes.iter().rposition(|b| *b != 0).map(|p| p + 1).unwrap_or(0);
+    String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+}
+
+/// Checks the GPG signature embedded in an AppImage against the trusted keyring.
+pub async fn verify_embedded_signature(config: &Config, data: &[u8]) -> Result<SignatureStatus> {
+    // Type 1 AppImages and non-ELF files cannot carry an embedded signature
+    let ranges = match elf_section_ranges(data, &[SIGNATURE_SECTION, KEY_SECTION]) {
+        Ok(ranges) => ranges,
+        Err(_) => return Ok(SignatureStatus::Unsigned),
+    };
+
+    let signature = ranges.iter()
+        .find(|(name, _)| name == SIGNATURE_SECTION)
+        .map(|(_, range)| section_text(data, range))
+        .unwrap_or_default();
+    if signature.is_empty() {
+        return Ok(SignatureStatus::Unsigned);
+    }
+
+    if !config.keyring_file.exists() {
+        return Ok(SignatureStatus::Untrusted(format!(
+            "no trusted keyring at {}", config.keyring_file.display()
+        )));
+    }
+
+    let ranges: Vec<_> = ranges.into_iter().map(|(_, range)| range).collect();
+    let digest = appimage_signing_digest(data, &ranges);
+
+    // appimagetool signs the hex digest, which gpgv reads from stdin
+    let signature_file = config.cache_dir.join(format!("signature-{}.asc", std::process::id()));
+    tokio::fs::create_dir_all(&config.cache_dir).await?;
+    tokio::fs::write(&signature_file, &signature).await
+        .context("Failed to write temporary signature file")?;
+
+    let result = run_gpgv(&config.keyring_file, &signature_file, &digest).await;
+    let _ = tokio::fs::remove_file(&signature_file).await;
+
+    result
+}
+
+async fn run_gpgv(keyring: &Path, signature_file: &Path, digest: &str) -> Result<SignatureStatus> {
+    use tokio::io::AsyncWriteExt;
+    use std::process::Stdio;
+
+    let child = tokio::process::Command::new("gpgv")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg("--status-fd")
+        .arg("1")
+        .arg(signature_file)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+
+    let mut child = match child {
+        Ok(child) => child,
+        Err(_) => return Ok(SignatureStatus::Untrusted("gpgv not found".to_string())),
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(digest.as_bytes()).await?;
+    }
+
+    let output = child.wait_with_output().await
+        .context("Failed to run gpgv")?;
+    let status = String::from_utf8_lossy(&output.stdout);
+
+    for line in status.lines() {
+        let line = line.trim_start_matches("[GNUPG:] ");
+        if let Some(rest) = line.strip_prefix("GOODSIG ") {
+            if output.status.success() {
+                let signer = rest.split_once(' ').map(|(_, uid)| uid).unwrap_or(rest);
+                return Ok(SignatureStatus::Trusted(signer.to_string()));
+            }
+        } else if let Some(rest) = line.strip_prefix("BADSIG ") {
+            return Ok(SignatureStatus::Bad(format!("bad signature from {}", rest)));
+        } else if let Some(rest) = line.strip_prefix("NO_PUBKEY ") {
+            return Ok(SignatureStatus::Untrusted(format!("unknown key {}", rest)));
+        }
+    }
+
+    Ok(SignatureStatus::Untrusted("signature could not be checked".to_string()))
+}
+
+/// Runs gpg on the trusted keyring. A throwaway home directory keeps the
+/// user's own keys and settings out of it.
+async fn run_gpg(config: &Config, args: &[&str]) -> Result<std::process::Output> {
+    let home = config.cache_dir.join(format!("gnupg-{}", std::process::id()));
+    tokio::fs::create_dir_all(&home).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(&home, std::fs::Permissions::from_mode(0o700)).await?;
+    }
+
+    let output = tokio::process::Command::new("gpg")
+        .args(["--batch", "--yes", "--no-options", "--no-default-keyring"])
+        .arg("--homedir")
+        .arg(&home)
+        .arg("--keyring")
+        .arg(&config.keyring_file)
+        .args(args)
+        .output().await;
+
+    // gpg may have started an agent for the home directory
+    let _ = tokio::process::Command::new("gpgconf")
+        .arg("--homedir")
+        .arg(&home)
+        .args(["--kill", "all"])
+        .output().await;
+    let _ = tokio::fs::remove_dir_all(&home).await;
+
+    let output = output.context("Failed to run gpg, is GnuPG installed?")?;
+    if !output.status.success() {
+        anyhow::bail!("gpg failed: {}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(output)
+}
+
+pub async fn import_keyring(file: &str) -> Result<()> {
+    let config = Config::new()?;
+    config.check_privileges()?;
+    if let Some(dir) = config.keyring_file.parent() {
+        tokio::fs::create_dir_all(dir).await?;
+    }
+    let file = std::fs::canonicalize(file)
+        .context(format!("Key file not found: {}", file))?;
+
+    let output = run_gpg(&config, &["--import", &file.to_string_lossy()]).await?;
+    // gpg reports each imported key on stderr
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        if let Some(key) = line.strip_prefix("gpg: key ") {
+            println!("Key {}", key);
+        }
+    }
+    println!("Trusted keyring: {}", config.keyring_file.display());
+    Ok(())
+}
+
+pub async fn list_keyring() -> Result<()> {
+    let config = Config::new()?;
+    if !config.keyring_file.exists() {
+        println!("No trusted GPG keys ({} does not exist)", config.keyring_file.display());
+        return Ok(());
+    }
+
+    let output = run_gpg(&config, &["--with-colons", "--list-keys"]).await?;
+    // pub records are followed by their fpr and uid records
+    let mut keys: Vec<(String, Vec<String>)> = Vec::new();
+    let mut in_primary = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields.first().copied() {
+            Some("pub") => {
+                keys.push((String::new(), Vec::new()));
+                in_primary = true;
+            }
+            Some("sub") => in_primary = false,
+            Some("fpr") if in_primary => {
+                if let Some((fingerprint, _)) = keys.last_mut() {
+                    *fingerprint = fields.get(9).unwrap_or(&"").to_string();
+                }
+                in_primary = false;
+            }
+            Some("uid") => {
+                if let Some((_, uids)) = keys.last_mut() {
+                    uids.push(fields.get(9).unwrap_or(&"").to_string());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if keys.is_empty() {
+        println!("No trusted GPG keys");
+        return Ok(());
+    }
+    println!("Trusted GPG keys ({}):", config.keyring_file.display());
+    for (fingerprint, uids) in keys {
+        println!("  {}", fingerprint);
+        for uid in uids {
+            println!("    {}", uid);
+        }
+    }
+    Ok(())
+}
+
+pub async fn remove_from_keyring(fingerprint: &str) -> Result<()> {
+    let config = Config::new()?;
+    config.check_privileges()?;
+    if !config.keyring_file.exists() {
+        anyhow::bail!("No trusted keyring at {}", config.keyring_file.display());
+    }
+    run_gpg(&config, &["--delete-keys", fingerprint]).await?;
+    println!("Removed {} from the trusted keyring", fingerprint);
+    Ok(())
+}
+
+/// Applies the configured signature policy, failing if the AppImage must be refused.
+pub fn check_signature_policy(policy: SignaturePolicy, status: &SignatureStatus, name: &str) -> Result<()> {
+    match (policy, status) {
+        (_, SignatureStatus::Bad(reason)) => {
+            anyhow::bail!("Embedded signature of {} is invalid: {}", name, reason);
+        }
+        (SignaturePolicy::Signed | SignaturePolicy::Trusted, SignatureStatus::Unsigned) => {
+            anyhow::bail!("Refusing unsigned AppImage {} (signature_policy = {:?})", name, policy);
+        }
+        (SignaturePolicy::Trusted, SignatureStatus::Untrusted(reason)) => {
+            anyhow::bail!("Refusing untrusted AppImage {}: {}", name, reason);
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn verify_packages(packages: Vec<String>) -> Result<()> {
+    let config = Config::new()?;
+    let db = load_database(&config).await?;
+
+    let targets: Vec<_> = if packages.is_empty() {
+        db.list_packages()
+    } else {
+        packages.iter()
+            .map(|name| db.get_package(name)
+                .ok_or_else(|| anyhow::anyhow!("Package not installed: {}", name)))
+            .collect::<Result<_>>()?
+    };
+
+    if targets.is_empty() {
+        println!("No packages installed");
+        return Ok(());
+    }
+
+    let mut failed = 0;
+    for pkg in targets {
+        let data = match tokio::fs::read(&pkg.path).await {
+            Ok(data) => data,
+            Err(e) => {
+                println!("{} {}: cannot read {}: {}", pkg.name, pkg.version, pkg.path.display(), e);
+                failed += 1;
+                continue;
+            }
+        };
+
+        // Re-hash against the checksum recorded at install time
+        let hash_status = match &pkg.sha256 {
+            Some(expected) if verify_sha256_bytes(&data, expected)? => "ok",
+            Some(_) => {
+                failed += 1;
+                "MISMATCH"
+            }
+            None => "not recorded",
+        };
+
+        let status = verify_embedded_signature(&config, &data).await?;
+        println!("{} {}: sha256 {}, signature {}", pkg.name, pkg.version, hash_status, status);
+
+        // A mismatch already failed; without a recorded checksum the signature is all there is
+        if hash_status != "MISMATCH" {
+            if let Err(e) = check_signature_policy(config.signature_policy, &status, &pkg.name) {
+                println!("  {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        anyhow::bail!("{} package(s) failed verification", failed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Scope;
+
+    /// A minimal ELF64 file with the given sections, followed by a payload
+    /// like the squashfs image of an AppImage.
+    fn elf_with_sections(sections: &[(&str, &[u8])], payload: &[u8]) -> Vec<u8> {
+        let mut names = vec![0u8];
+        let mut name_offsets = Vec::new();
+        for name in [".shstrtab"].into_iter().chain(sections.iter().map(|(name, _)| *name)) {
+            name_offsets.push(names.len() as u32);
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+
+        let mut data = vec![0u8; 64];
+        // (name, type, offset, size) of every section after the null one
+        let mut headers = vec![(name_offsets[0], 3u32, data.len() as u64, names.len() as u64)];
+        data.extend_from_slice(&names);
+        for ((_, content), name) in sections.iter().zip(&name_offsets[1..]) {
+            headers.push((*name, 1, data.len() as u64, content.len() as u64));
+            data.extend_from_slice(content);
+        }
+        data.resize(data.len().next_multiple_of(8), 0);
+
+        let section_headers = data.len() as u64;
+        data.extend_from_slice(&[0u8; 64]);
+        for (name, kind, offset, size) in headers {
+            data.extend_from_slice(&name.to_le_bytes());
+            data.extend_from_slice(&kind.to_le_bytes());
+            data.extend_from_slice(&[0u8; 16]);
+            data.extend_from_slice(&offset.to_le_bytes());
+            data.extend_from_slice(&size.to_le_bytes());
+            data.extend_from_slice(&[0u8; 8]);
+            data.extend_from_slice(&1u64.to_le_bytes());
+            data.extend_from_slice(&[0u8; 8]);
+        }
+
+        let header = &mut data[..64];
+        header[..8].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+        header[16..18].copy_from_slice(&2u16.to_le_bytes());
+        header[18..20].copy_from_slice(&62u16.to_le_bytes());
+        header[20..24].copy_from_slice(&1u32.to_le_bytes());
+        header[40..48].copy_from_slice(&section_headers.to_le_bytes());
+        header[52..54].copy_from_slice(&64u16.to_le_bytes());
+        header[54..56].copy_from_slice(&56u16.to_le_bytes());
+        header[58..60].copy_from_slice(&64u16.to_le_bytes());
+        header[60..62].copy_from_slice(&(sections.len() as u16 + 2).to_le_bytes());
+        header[62..64].copy_from_slice(&1u16.to_le_bytes());
+
+        data.extend_from_slice(payload);
+        data
+    }
+
+    fn signed_appimage(signature: &[u8]) -> Vec<u8> {
+        let mut signature = signature.to_vec();
+        signature.resize(64, 0);
+        elf_with_sections(&[(SIGNATURE_SECTION, &signature), (KEY_SECTION, &[0u8; 32]), (".text", b"code")], b"squashfs")
+    }
+
+    #[test]
+    fn signature_sections_are_found_by_name() {
+        let data = signed_appimage(b"-----BEGIN PGP SIGNATURE-----");
+        let ranges = elf_section_ranges(&data, &[SIGNATURE_SECTION, KEY_SECTION]).unwrap();
+        let names: Vec<&str> = ranges.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec![SIGNATURE_SECTION, KEY_SECTION]);
+        assert_eq!(ranges[0].1.len(), 64);
+        assert_eq!(section_text(&data, &ranges[0].1), "-----BEGIN PGP SIGNATURE-----");
+        assert_eq!(section_text(&data, &ranges[1].1), "");
+
+        assert!(elf_section_ranges(b"#!/bin/sh\necho type 1\n", &[SIGNATURE_SECTION]).is_err());
+    }
+
+    #[test]
+    fn signing_digest_zeroes_the_signature_sections() {
+        let signed = signed_appimage(b"signature one");
+        let ranges: Vec<_> = elf_section_ranges(&signed, &[SIGNATURE_SECTION, KEY_SECTION]).unwrap()
+            .into_iter()
+            .map(|(_, range)| range)
+            .collect();
+
+        let mut zeroed = signed.clone();
+        for range in &ranges {
+            zeroed[range.clone()].fill(0);
+        }
+        let digest = appimage_signing_digest(&signed, &ranges);
+        assert_eq!(digest, sha256_hex(&zeroed));
+        assert_ne!(digest, sha256_hex(&signed));
+
+        // Another signature signs the same digest, other changes do not
+        assert_eq!(appimage_signing_digest(&signed_appimage(b"signature two"), &ranges), digest);
+        let mut tampered = signed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_ne!(appimage_signing_digest(&tampered, &ranges), digest);
+
+        // Overlapping ranges are only zeroed once
+        let overlapping = [ranges[0].clone(), ranges[0].start + 8..ranges[0].end, ranges[1].clone()];
+        assert_eq!(appimage_signing_digest(&signed, &overlapping), digest);
+    }
+
+    #[tokio::test]
+    async fn appimages_without_a_signature_or_keyring() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::located(Scope::User).unwrap();
+        config.cache_dir = dir.path().join("cache");
+        config.keyring_file = dir.path().join("trusted_keys.gpg");
+
+        let status = |data: Vec<u8>| {
+            let config = &config;
+            async move { verify_embedded_signature(config, &data).await.unwrap().to_string() }
+        };
+        assert_eq!(status(b"not an ELF file".to_vec()).await, "unsigned");
+        assert_eq!(status(signed_appimage(b"")).await, "unsigned");
+        assert_eq!(
+            status(signed_appimage(b"signature")).await,
+            format!("untrusted (no trusted keyring at {})", config.keyring_file.display())
+        );
+    }
+
+    #[test]
+    fn signature_policies() {
+        let statuses = [
+            SignatureStatus::Unsigned,
+            SignatureStatus::Untrusted("unknown key".to_string()),
+            SignatureStatus::Trusted("Alice".to_string()),
+            SignatureStatus::Bad("digest mismatch".to_string()),
+        ];
+        // Whether each policy accepts each status, in the order above
+        for (policy, accepted) in [
+            (SignaturePolicy::Any, [true, true, true, false]),
+            (SignaturePolicy::Signed, [false, true, true, false]),
+            (SignaturePolicy::Trusted, [false, false, true, false]),
+        ] {
+            for (status, accepted) in statuses.iter().zip(accepted) {
+                let result = check_signature_policy(policy, status, "app");
+                assert_eq!(result.is_ok(), accepted, "{:?} with {}", policy, status);
+            }
+        }
+
+        let error = check_signature_policy(SignaturePolicy::Any, &statuses[3], "app").unwrap_err();
+        assert_eq!(error.to_string(), "Embedded signature of app is invalid: digest mismatch");
+    }
+}
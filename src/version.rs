@@ -0,0 +1,329 @@
+use std::cmp::Ordering;
+
+/// Suffixes that mark a version as coming before its release, as in 3.0.0-rc1
+const PRERELEASE_TAGS: &[&str] = &["dev", "alpha", "beta", "pre", "preview", "rc"];
+
+/// A package version as published by AppImage projects: semver, calendar
+/// versions (2024.05.01), versions with fewer or more components (1.2, 1.2.3.4),
+/// a leading "v", an optional Debian-style epoch ("1:2.0") and a suffix.
+///
+/// Missing components count as zero, so 1.2 equals 1.2.0. Suffixes compare
+/// like Debian versions, except that pre-release tags (rc, beta, ...) and "~"
+/// sort before the plain release. Build metadata after "+" is ignored.
+#[derive(Debug, Clone)]
+pub struct Version {
+    epoch: u64,
+    release: Vec<u64>,
+    suffix: String,
+}
+
+impl Version {
+    /// Returns `None` for strings that carry no version number, such as "unknown".
+    pub fn parse(version: &str) -> Option<Self> {
+        let version = version.trim();
+        let version = version.split_once('+').map_or(version, |(v, _)| v);
+
+        let (epoch, rest) = match version.split_once(':') {
+            Some((epoch, rest)) => (epoch.parse().ok()?, rest),
+            None => (0, version),
+        };
+        let rest = rest.strip_prefix(['v', 'V']).unwrap_or(rest);
+
+        let release_end = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' && rest[i + 1..].starts_with(|c: char| c.is_ascii_digit())))
+            .map_or(rest.len(), |(i, _)| i);
+        let (release, suffix) = rest.split_at(release_end);
+        if release.is_empty() {
+            return None;
+        }
+
+        let release = release.split('.')
+            .map(|part| part.parse().ok())
+            .collect::<Option<Vec<u64>>>()?;
+
+        Some(Self {
+            epoch,
+            release,
+            suffix: suffix.trim_start_matches(['-', '_', '.']).to_string(),
+        })
+    }
+
+    fn is_prerelease(&self) -> bool {
+        let suffix = self.suffix.to_ascii_lowercase();
+        suffix.starts_with('~') || PRERELEASE_TAGS.iter().any(|tag| suffix.starts_with(tag))
+    }
+
+    fn component(&self, index: usize) -> u64 {
+        self.release.get(index).copied().unwrap_or(0)
+    }
+
+    /// The version with only its first `components` release components.
+    fn truncated(&self, components: usize) -> Version {
+        Version { epoch: self.epoch, release: self.release[..components].to_vec(), suffix: String::new() }
+    }
+
+    /// Whether the leading components equal `prefix`, as in a "1.2.*" requirement.
+    fn starts_with(&self, prefix: &Version) -> bool {
+        self.epoch == prefix.epoch
+            && (0..prefix.release.len()).all(|i| self.component(i) == prefix.component(i))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let components = self.release.len().max(other.release.len());
+        self.epoch.cmp(&other.epoch)
+            .then_with(|| {
+                (0..components)
+                    .map(|i| self.component(i).cmp(&other.component(i)))
+                    .find(|ordering| ordering.is_ne())
+                    .unwrap_or(Ordering::Equal)
+            })
+            .then_with(|| match (self.suffix.is_empty(), other.suffix.is_empty()) {
+                (true, true) => Ordering::Equal,
+                (true, false) if other.is_prerelease() => Ordering::Greater,
+                (true, false) => Ordering::Less,
+                (false, true) if self.is_prerelease() => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (false, false) => other.is_prerelease().cmp(&self.is_prerelease())
+                    .then_with(|| debian_cmp(&self.suffix, &other.suffix)),
+            })
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
+/// Compares two version strings, or returns `None` if either cannot be parsed.
+pub fn compare(a: &str, b: &str) -> Option<Ordering> {
+    Some(Version::parse(a)?.cmp(&Version::parse(b)?))
+}
+
+/// Sort weight of a character in dpkg's ordering: "~" before everything, even
+/// the end of the string, then letters, then all other characters.
+fn debian_order(c: Option<char>) -> i32 {
+    match c {
+        Some('~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+/// dpkg's comparison of version strings: alternating runs of non-digits, compared
+/// character by character, and digits, compared numerically.
+fn debian_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    while !a.is_empty() || !b.is_empty() {
+        let a_text = a.iter().take_while(|c| !c.is_ascii_digit()).count();
+        let b_text = b.iter().take_while(|c| !c.is_ascii_digit()).count();
+        for i in 0..a_text.max(b_text) {
+            let ca = (i < a_text).then(|| a[i] as char);
+            let cb = (i < b_text).then(|| b[i] as char);
+            let ordering = debian_order(ca).cmp(&debian_order(cb));
+            if ordering.is_ne() {
+                return ordering;
+            }
+        }
+        (a, b) = (&a[a_text..], &b[b_text..]);
+
+        let a_digits = a.iter().take_while(|c| c.is_ascii_digit()).count();
+        let b_digits = b.iter().take_while(|c| c.is_ascii_digit()).count();
+        let number = |digits: &[u8]| {
+            let digits = std::str::from_utf8(digits).unwrap_or("0").trim_start_matches('0');
+            (digits.len(), digits.to_string())
+        };
+        let ordering = number(&a[..a_digits]).cmp(&number(&b[..b_digits]));
+        if ordering.is_ne() {
+            return ordering;
+        }
+        (a, b) = (&a[a_digits..], &b[b_digits..]);
+    }
+    Ordering::Equal
+}
+
+/// A version requirement used to pin packages, with semver's operators:
+/// comma-separated comparators like ">=1.2, <2", "=2024.05.01", "^1.4" (which a
+/// bare "1.4" also means), "~1.4.2", or "1.2.*" for every version starting
+/// with those components.
+#[derive(Debug, Clone)]
+pub struct VersionReq {
+    comparators: Vec<(Op, Version)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Exact,
+    Greater,
+    GreaterEq,
+    Less,
+    LessEq,
+    /// At least this version, below the next increment of its first non-zero component
+    Caret,
+    /// At least this version, below the next minor (or major, if no minor is given)
+    Tilde,
+    /// Every version starting with these components
+    Prefix,
+}
+
+impl VersionReq {
+    pub fn parse(req: &str) -> Option<Self> {
+        let comparators = req.split(',')
+            .map(|part| {
+                let part = part.trim();
+                let (op, version) = [
+                    ("==", Op::Exact), (">=", Op::GreaterEq), ("<=", Op::LessEq),
+                    ("=", Op::Exact), (">", Op::Greater), ("<", Op::Less),
+                    ("^", Op::Caret), ("~", Op::Tilde),
+                ]
+                    .iter()
+                    .find_map(|(prefix, op)| part.strip_prefix(prefix).map(|rest| (Some(*op), rest)))
+                    .unwrap_or((None, part));
+                let version = version.trim();
+                let (op, version) = match (op, version.strip_suffix(".*")) {
+                    (None, Some(prefix)) => (Op::Prefix, prefix),
+                    (op, prefix) => (op.unwrap_or(Op::Caret), prefix.unwrap_or(version)),
+                };
+                Version::parse(version).map(|version| (op, version))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self { comparators })
+    }
+
+    pub fn matches(&self, version: &Version) -> bool {
+        self.comparators.iter().all(|(op, req)| match op {
+            Op::Exact => version == req,
+            Op::Greater => version > req,
+            Op::GreaterEq => version >= req,
+            Op::Less => version < req,
+            Op::LessEq => version <= req,
+            Op::Caret => {
+                // ^1.4 allows 1.x, ^0.4 allows 0.4.x and ^0.0.3 only 0.0.3
+                let kept = req.release.iter().position(|&c| c != 0).unwrap_or(req.release.len() - 1) + 1;
+                version >= req && version.starts_with(&req.truncated(kept))
+            }
+            Op::Tilde => version >= req && version.starts_with(&req.truncated(req.release.len().min(2))),
+            Op::Prefix => version.starts_with(req),
+        })
+    }
+}
+
+/// Whether `version` satisfies `req`. A requirement that is not a valid
+/// version range only matches that exact version string, and "*" matches all.
+pub fn satisfies(req: &str, version: &str) -> bool {
+    if req.trim() == "*" {
+        return true;
+    }
+    match VersionReq::parse(req) {
+        Some(req) => Version::parse(version).is_some_and(|v| req.matches(&v)),
+        None => req == version,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_order(versions: &[&str]) {
+        for pair in versions.windows(2) {
+            assert_eq!(compare(pair[0], pair[1]), Some(Ordering::Less), "{} < {}", pair[0], pair[1]);
+            assert_eq!(compare(pair[1], pair[0]), Some(Ordering::Greater), "{} > {}", pair[1], pair[0]);
+        }
+    }
+
+    #[test]
+    fn prereleases_sort_before_their_release() {
+        assert_order(&[
+            "3.0.0~git1", "3.0.0-alpha", "3.0.0-alpha.2", "3.0.0-beta", "3.0.0-rc1", "3.0.0-rc2",
+            "3.0.0", "3.0.0-1", "3.0.0a", "3.0.1-dev", "3.0.1",
+        ]);
+        assert_order(&["1.9", "1.10", "2.0-rc1", "2.0"]);
+        assert_order(&["2024.05.01", "2024.12.01", "1:0.1"]);
+    }
+
+    #[test]
+    fn equivalent_spellings_compare_equal() {
+        for (a, b) in [("1.2", "1.2.0"), ("v1.2.0", "1.2"), ("1.2.0+build.5", "1.2.0"), ("0:1.0", "1.0"), ("V2", "2.0.0")] {
+            assert_eq!(compare(a, b), Some(Ordering::Equal), "{} == {}", a, b);
+        }
+    }
+
+    #[test]
+    fn unparseable_versions() {
+        for version in ["", "unknown", "latest", "v", "x:1.0", ".5"] {
+            assert!(Version::parse(version).is_none(), "{:?} should not parse", version);
+        }
+        assert_eq!(compare("unknown", "1.0"), None);
+        assert_eq!(compare("1.0", "nightly"), None);
+    }
+
+    #[test]
+    fn requirements() {
+        let cases = [
+            ("*", "0.0.1-rc1", true),
+            ("1.2", "1.2.9", true),
+            ("1.2", "1.3.0", true),
+            ("1.2", "2.0.0", false),
+            ("1.2", "1.1.9", false),
+            ("0.4", "0.5.0", false),
+            ("1.2.*", "1.2.0", true),
+            ("1.2.*", "1.20.0", false),
+            ("=1.2", "1.2.0", true),
+            ("==1.2.0", "1.2.1", false),
+            (">=1.2, <2", "1.9.9", true),
+            (">=1.2, <2", "2.0.0", false),
+            (">=1.2, <2", "2.0.0-rc1", true),
+            (">1.0", "1.0.0", false),
+            ("<=1.0", "1.0.0", true),
+            ("^1.4", "1.9.0", true),
+            ("^1.4", "2.0.0", false),
+            ("^1.4", "1.3.9", false),
+            ("^1.4", "2.0.0-rc1", false),
+            ("^0.4", "0.4.7", true),
+            ("^0.4", "0.9.0", false),
+            ("^0.4.2", "0.4.1", false),
+            ("^0.0.3", "0.0.3", true),
+            ("^0.0.3", "0.0.4", false),
+            ("^0.0", "0.0.9", true),
+            ("^0.0", "0.1.0", false),
+            ("^0", "0.9.9", true),
+            ("^0", "1.0.0", false),
+            ("~1.4.2", "1.4.9", true),
+            ("~1.4.2", "1.4.1", false),
+            ("~1.4.2", "1.5.0", false),
+            ("~1.4", "1.4.7", true),
+            ("~1.4", "1.9.0", false),
+            ("~1.4", "2.0.0", false),
+            ("~1", "1.9.0", true),
+            ("~1", "2.0.0", false),
+            ("~0.4", "0.5.0", false),
+            (" >= 2024.05 ", "2024.06.01", true),
+            ("^1.0", "1:1.0", false),
+            (">=1.0", "unknown", false),
+        ];
+        for (req, version, expected) in cases {
+            assert_eq!(satisfies(req, version), expected, "{} satisfies {}", version, req);
+        }
+    }
+
+    #[test]
+    fn invalid_requirements_match_only_the_same_string() {
+        assert!(VersionReq::parse("nightly").is_none());
+        assert!(VersionReq::parse(">=1.0,").is_none());
+        assert!(satisfies("nightly", "nightly"));
+        assert!(!satisfies("nightly", "1.0"));
+        assert!(!satisfies(">=1.0,", "1.0"));
+    }
+}
@@ -6,6 +6,7 @@ use regex::Regex;
 use crate::repo::appimage_yaml::AppImageYaml;
 use crate::verify::calculate_sha256;
 use crate::utils::extract_metadata;
+use crate::version::Version;
 
 pub async fn generate_appimage_yaml(folder: &str, repo: &str) -> Result<()> {
     let folder_path = Path::new(folder);
@@ -19,7 +20,7 @@ pub async fn generate_appimage_yaml(folder: &str, repo: &str) -> Result<()> {
     }
     
     // Parse owner/repo (for documentation purposes)
-    let _ = if let Some((_o, _r)) = repo.split_once('/') {
+    if let Some((_o, _r)) = repo.split_once('/') {
         // Valid format
     } else {
         anyhow::bail!("Invalid repo format. Expected 'owner/repo', got: {}", repo);
@@ -46,19 +47,29 @@ pub async fn generate_appimage_yaml(folder: &str, repo: &str) -> Result<()> {
                 .unwrap_or("unknown")
                 .to_string();
             
+            // Published delta update file, if any
+            let zsync = Some(format!("{}.zsync", file_path))
+                .filter(|zsync| path.with_file_name(zsync).exists());
+            
+            let version = metadata.version.unwrap_or_else(|| {
+                // Try to extract from filename
+                extract_version_from_filename(&file_path)
+            });
+            if Version::parse(&version).is_none() {
+                println!("Warning: version '{}' of {} cannot be compared, upgrades will not be detected", version, metadata.name);
+            }
+            
             // Create entry
             let app_entry = crate::repo::appimage_yaml::AppImageEntry {
                 name: metadata.name.clone(),
-                version: metadata.version.unwrap_or_else(|| {
-                    // Try to extract from filename
-                    extract_version_from_filename(&file_path)
-                }),
+                version,
                 file: file_path,
                 sha256,
                 size: Some(metadata.size),
                 description: metadata.description,
                 dependencies: Vec::new(), // Could be extracted from AppImage metadata
                 provides: Vec::new(),
+                zsync,
             };
             
             entries.push(app_entry);
@@ -69,8 +80,15 @@ pub async fn generate_appimage_yaml(folder: &str, repo: &str) -> Result<()> {
         anyhow::bail!("No AppImage files found in {}", folder);
     }
     
+    // Directory order is arbitrary, list each app's versions oldest first
+    entries.sort_by_cached_key(|e| (e.name.clone(), Version::parse(&e.version)));
+    
     // Generate YAML
-    let appimage_yaml = AppImageYaml { apps: entries };
+    let appimage_yaml = AppImageYaml {
+        apps: entries,
+        expires: None,
+        serial: Some(chrono::Utc::now().timestamp() as u64),
+    };
     let yaml_content = serde_yaml::to_string(&appimage_yaml)?;
     
     // Write to appimage.yaml
@@ -83,14 +101,16 @@ pub async fn generate_appimage_yaml(folder: &str, repo: &str) -> Result<()> {
     Ok(())
 }
 
-fn extract_version_from_filename(filename: &str) -> String {
+pub fn extract_version_from_filename(filename: &str) -> String {
     // Try to extract version from filename patterns like:
     // app-1.2.3.AppImage
     // app-v1.2.3.AppImage
     // app-1.2.3-x86_64.AppImage
+    // app-2024.05.01.AppImage
+    // app-3.0.0-rc1.AppImage
     
-    // Pattern for semantic version
-    if let Ok(re) = Regex::new(r"v?(\d+\.\d+\.\d+)") {
+    // Dotted version with an optional pre-release tag
+    if let Ok(re) = Regex::new(r"(?i)v?(\d+(?:\.\d+)+(?:[-_.~]?(?:dev|alpha|beta|pre|preview|rc)\.?\d*)?)") {
         if let Some(caps) = re.captures(filename) {
             if let Some(version) = caps.get(1) {
                 return version.as_str().to_string();
@@ -98,8 +118,8 @@ fn extract_version_from_filename(filename: &str) -> String {
         }
     }
     
-    // Fallback: try to find any version-like pattern
-    if let Ok(re) = Regex::new(r"(\d+\.\d+)") {
+    // Fallback: a date such as 20240501
+    if let Ok(re) = Regex::new(r"(\d{8})") {
         if let Some(caps) = re.captures(filename) {
             if let Some(version) = caps.get(1) {
                 return version.as_str().to_string();
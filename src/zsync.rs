@@ -0,0 +1,455 @@
+use anyhow::{Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use md4::{Digest, Md4};
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::fs;
+
+use crate::config::Config;
+use crate::pkg_cache;
+use crate::repo::appimage_yaml::AppImageEntryWithSource;
+use crate::repo::fetcher::Fetcher;
+use crate::repo::forge::{glob_to_regex, ForgeRelease};
+use crate::repo::github::GithubClient;
+use crate::verify::{elf_section_ranges, section_text, verify_sha256_bytes};
+
+/// Missing blocks closer than this are fetched in one range request
+const MERGE_GAP_BLOCKS: usize = 8;
+
+/// Largest block size accepted from a control file; zsync uses a few KiB
+const MAX_BLOCK_SIZE: usize = 1 << 20;
+
+/// Without a size in the index, the new AppImage may be at most this many
+/// times the size of the installed one, so a control file cannot make the
+/// update allocate arbitrary amounts of memory
+const MAX_GROWTH: usize = 4;
+
+/// A parsed .zsync control file
+#[derive(Debug)]
+struct ControlFile {
+    block_size: usize,
+    length: usize,
+    /// Consecutive blocks that must match before a block is trusted
+    seq_matches: usize,
+    rsum_bytes: usize,
+    checksum_bytes: usize,
+    url: Option<String>,
+    blocks: Vec<BlockSum>,
+}
+
+#[derive(Debug)]
+struct BlockSum {
+    rsum: u32,
+    checksum: Vec<u8>,
+}
+
+impl ControlFile {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let header_end = data.windows(2)
+            .position(|w| w == b"\n\n")
+            .context("Invalid zsync file: missing header")?;
+        let header = String::from_utf8_lossy(&data[..header_end]);
+
+        let fields: HashMap<&str, &str> = header.lines()
+            .filter_map(|line| line.split_once(':'))
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .collect();
+
+        let number = |key: &str| -> Result<usize> {
+            fields.get(key)
+                .context(format!("Invalid zsync file: missing {}", key))?
+                .parse()
+                .context(format!("Invalid zsync file: bad {}", key))
+        };
+        let block_size = number("Blocksize")?;
+        let length = number("Length")?;
+        if block_size == 0 || !block_size.is_power_of_two() || block_size > MAX_BLOCK_SIZE {
+            anyhow::bail!("Invalid zsync file: bad Blocksize {}", block_size);
+        }
+
+        let hash_lengths: Vec<usize> = fields.get("Hash-Lengths")
+            .unwrap_or(&"1,4,16")
+            .split(',')
+            .map(|n| n.trim().parse())
+            .collect::<std::result::Result<_, _>>()
+            .context("Invalid zsync file: bad Hash-Lengths")?;
+        let [seq_matches, rsum_bytes, checksum_bytes] = hash_lengths[..] else {
+            anyhow::bail!("Invalid zsync file: bad Hash-Lengths");
+        };
+        if !(1..=2).contains(&seq_matches) || !(1..=4).contains(&rsum_bytes) || !(3..=16).contains(&checksum_bytes) {
+            anyhow::bail!("Invalid zsync file: unsupported Hash-Lengths");
+        }
+
+        let block_count = length.div_ceil(block_size);
+        let entry_size = rsum_bytes + checksum_bytes;
+        let sums = &data[header_end + 2..];
+        if block_count.checked_mul(entry_size).is_none_or(|needed| sums.len() < needed) {
+            anyhow::bail!("Invalid zsync file: truncated block checksums");
+        }
+
+        // The rolling checksum is stored as the last `rsum_bytes` bytes of a
+        // big-endian (a, b) pair
+        let blocks = sums.chunks_exact(entry_size)
+            .take(block_count)
+            .map(|chunk| {
+                let mut rsum = [0u8; 4];
+                rsum[4 - rsum_bytes..].copy_from_slice(&chunk[..rsum_bytes]);
+                BlockSum {
+                    rsum: u32::from_be_bytes(rsum),
+                    checksum: chunk[rsum_bytes..].to_vec(),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            block_size,
+            length,
+            seq_matches,
+            rsum_bytes,
+            checksum_bytes,
+            url: fields.get("URL").map(|url| url.to_string()),
+            blocks,
+        })
+    }
+
+    fn rsum_mask(&self) -> u32 {
+        if self.rsum_bytes == 4 {
+            u32::MAX
+        } else {
+            (1 << (8 * self.rsum_bytes)) - 1
+        }
+    }
+
+    fn checksum_matches(&self, block: usize, data: &[u8]) -> bool {
+        let digest = Md4::digest(data);
+        digest[..self.checksum_bytes] == self.blocks[block].checksum[..]
+    }
+
+    /// Finds the blocks of the target file that already exist in `seed`, which
+    /// must end with a block of zeros like the padded last block of the target.
+    /// Returns the seed offset of each block, if found.
+    fn match_blocks(&self, seed: &[u8]) -> Vec<Option<usize>> {
+        let block_size = self.block_size;
+        let mask = self.rsum_mask();
+        let mut found = vec![None; self.blocks.len()];
+
+        let mut by_rsum: HashMap<u32, Vec<usize>> = HashMap::new();
+        for (id, block) in self.blocks.iter().enumerate() {
+            by_rsum.entry(block.rsum & mask).or_default().push(id);
+        }
+
+        let block_rsum = |offset: usize| rsum(&seed[offset..offset + block_size]);
+        let mut pos = 0;
+        let (mut a, mut b) = block_rsum(0);
+
+        while pos + block_size <= seed.len() {
+            let key = ((a as u32) << 16 | b as u32) & mask;
+            let mut matched = false;
+
+            if let Some(ids) = by_rsum.get(&key) {
+                let window = &seed[pos..pos + block_size];
+                let digest = Md4::digest(window);
+                for &id in ids {
+                    if found[id].is_some() || digest[..self.checksum_bytes] != self.blocks[id].checksum[..] {
+                        continue;
+                    }
+                    // Short checksums are only trusted when the next block matches too
+                    let next_ok = self.seq_matches < 2
+                        || id + 1 >= self.blocks.len()
+                        || (pos + 2 * block_size <= seed.len() && {
+                            let (na, nb) = block_rsum(pos + block_size);
+                            ((na as u32) << 16 | nb as u32) & mask == self.blocks[id + 1].rsum & mask
+                                && self.checksum_matches(id + 1, &seed[pos + block_size..pos + 2 * block_size])
+                        });
+                    if next_ok {
+                        found[id] = Some(pos);
+                        matched = true;
+                    }
+                }
+            }
+
+            if matched {
+                pos += block_size;
+                if pos + block_size <= seed.len() {
+                    (a, b) = block_rsum(pos);
+                }
+                continue;
+            }
+
+            if pos + block_size >= seed.len() {
+                break;
+            }
+            let old = seed[pos] as u16;
+            let new = seed[pos + block_size] as u16;
+            a = a.wrapping_sub(old).wrapping_add(new);
+            b = b.wrapping_sub((block_size as u16).wrapping_mul(old)).wrapping_add(a);
+            pos += 1;
+        }
+
+        found
+    }
+
+    /// Builds the target file from the blocks found in `seed`, leaving the
+    /// missing blocks zeroed. Returns it, `length` bytes rounded up to whole
+    /// blocks, along with the seed offset of each block.
+    fn reuse_blocks(&self, mut seed: Vec<u8>) -> (Vec<u8>, Vec<Option<usize>>) {
+        seed.resize(seed.len() + self.block_size, 0);
+        let found = self.match_blocks(&seed);
+
+        let mut data = vec![0u8; self.blocks.len() * self.block_size];
+        for (id, offset) in found.iter().enumerate() {
+            if let Some(offset) = *offset {
+                let target = id * self.block_size;
+                data[target..target + self.block_size].copy_from_slice(&seed[offset..offset + self.block_size]);
+            }
+        }
+        (data, found)
+    }
+}
+
+/// zsync's rolling checksum of one block.
+fn rsum(data: &[u8]) -> (u16, u16) {
+    let len = data.len();
+    data.iter().enumerate().fold((0u16, 0u16), |(a, b), (i, &byte)| {
+        let byte = byte as u16;
+        (a.wrapping_add(byte), b.wrapping_add(((len - i) as u16).wrapping_mul(byte)))
+    })
+}
+
+/// Groups missing blocks into byte ranges, merging ranges separated by small gaps.
+fn missing_ranges(found: &[Option<usize>], block_size: usize, length: usize) -> Vec<(usize, usize)> {
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for (id, _) in found.iter().enumerate().filter(|(_, offset)| offset.is_none()) {
+        match ranges.last_mut() {
+            Some((_, end)) if id <= *end + MERGE_GAP_BLOCKS => *end = id,
+            _ => ranges.push((id, id)),
+        }
+    }
+    ranges.into_iter()
+        .map(|(first, last)| (first * block_size, ((last + 1) * block_size).min(length)))
+        .collect()
+}
+
+/// Turns AppImage update information into the URL of a .zsync file.
+async fn zsync_url_from_update_info(config: &Config, fetcher: &Fetcher, info: &str) -> Result<Option<String>> {
+    let parts: Vec<&str> = info.split('|').collect();
+    match parts.as_slice() {
+        ["zsync", url] => Ok(Some(url.to_string())),
+        ["gh-releases-zsync", owner, repo, tag, pattern] => {
+            let tag = (*tag != "latest").then_some(*tag);
+            let release: ForgeRelease = GithubClient::new(config)
+                .fetch_release(fetcher, owner, repo, tag).await?
+                .into();
+            let pattern = glob_to_regex(pattern)?;
+            Ok(release.assets.iter()
+                .find(|asset| pattern.is_match(&asset.name))
+                .map(|asset| asset.url.clone()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Reads the `.upd_info` section embedded in an AppImage.
+fn embedded_update_info(data: &[u8]) -> Option<String> {
+    let ranges = elf_section_ranges(data, &[".upd_info"]).ok()?;
+    ranges.first()
+        .map(|(_, range)| section_text(data, range))
+        .filter(|info| !info.is_empty())
+}
+
+/// Downloads the new AppImage of `entry` by reusing the blocks of the installed
+/// AppImage at `installed`, and stores the result in the package cache.
+/// Returns false when neither the index nor the installed AppImage offer a
+/// zsync file.
+pub async fn delta_download(
+    config: &Config,
+    fetcher: &Fetcher,
+    entry: &AppImageEntryWithSource,
+    installed: &Path,
+) -> Result<bool> {
+    let Ok(seed) = fs::read(installed).await else {
+        return Ok(false);
+    };
+
+    let base_url = url::Url::parse(&entry.source_url)?;
+    let zsync_url = match &entry.entry.zsync {
+        Some(zsync) => base_url.join(zsync)?.to_string(),
+        None => match embedded_update_info(&seed) {
+            Some(info) => match zsync_url_from_update_info(config, fetcher, &info).await? {
+                Some(url) => url,
+                None => return Ok(false),
+            },
+            None => return Ok(false),
+        },
+    };
+
+    let control = ControlFile::parse(&fetcher.fetch_bytes(&zsync_url).await?)
+        .context(format!("Failed to read {}", zsync_url))?;
+    match entry.entry.size {
+        Some(size) if size as usize != control.length => {
+            anyhow::bail!("{} describes a different release", zsync_url);
+        }
+        None if control.length > seed.len().saturating_mul(MAX_GROWTH) => {
+            anyhow::bail!("{} describes a file of {} bytes, too large for an update of {}", zsync_url, control.length, entry.entry.name);
+        }
+        _ => {}
+    }
+
+    let target_url = match &control.url {
+        Some(url) => url::Url::parse(&zsync_url)?.join(url)?.to_string(),
+        None => base_url.join(&entry.entry.file)?.to_string(),
+    };
+
+    let (mut data, found) = control.reuse_blocks(seed);
+    let ranges = missing_ranges(&found, control.block_size, control.length);
+    let download_size: usize = ranges.iter().map(|(start, end)| end - start).sum();
+    println!(
+        "Delta update for {}: reusing {} of {} blocks, downloading {:.2} MB of {:.2} MB",
+        entry.entry.name,
+        found.iter().filter(|offset| offset.is_some()).count(),
+        found.len(),
+        download_size as f64 / 1_000_000.0,
+        control.length as f64 / 1_000_000.0,
+    );
+
+    let pb = ProgressBar::new(download_size as u64);
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({eta})")
+            .unwrap()
+            .progress_chars("#>-")
+    );
+    for (start, end) in ranges {
+        let bytes = fetcher.fetch_range(&target_url, start as u64, end as u64 - 1).await?;
+        if bytes.len() != end - start {
+            anyhow::bail!("Short range response from {}", target_url);
+        }
+        data[start..end].copy_from_slice(&bytes);
+        pb.inc(bytes.len() as u64);
+    }
+    pb.finish_and_clear();
+
+    data.truncate(control.length);
+    if !verify_sha256_bytes(&data, &entry.entry.sha256)? {
+        anyhow::bail!("SHA256 verification failed for the delta update of {}", entry.entry.name);
+    }
+
+    pkg_cache::store(config, &entry.entry.name, &entry.entry.version, &entry.entry.sha256, &data).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A control file for `target` with the given hash lengths.
+    fn control_file(target: &[u8], block_size: usize, hash_lengths: (usize, usize, usize)) -> Vec<u8> {
+        let (seq_matches, rsum_bytes, checksum_bytes) = hash_lengths;
+        let mut data = format!(
+            "zsync: 0.6.2\nFilename: app.AppImage\nBlocksize: {}\nLength: {}\nHash-Lengths: {},{},{}\nURL: app.AppImage\n\n",
+            block_size, target.len(), seq_matches, rsum_bytes, checksum_bytes
+        ).into_bytes();
+        for chunk in target.chunks(block_size) {
+            let mut block = chunk.to_vec();
+            block.resize(block_size, 0);
+            let (a, b) = rsum(&block);
+            let sum = ((a as u32) << 16 | b as u32).to_be_bytes();
+            data.extend_from_slice(&sum[4 - rsum_bytes..]);
+            data.extend_from_slice(&Md4::digest(&block)[..checksum_bytes]);
+        }
+        data
+    }
+
+    /// Deterministic bytes without repeating blocks.
+    fn sample(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn control_files_are_parsed() {
+        let target = sample(5000, 1);
+        let control = ControlFile::parse(&control_file(&target, 1024, (2, 3, 5))).unwrap();
+        assert_eq!(control.block_size, 1024);
+        assert_eq!(control.length, 5000);
+        assert_eq!((control.seq_matches, control.rsum_bytes, control.checksum_bytes), (2, 3, 5));
+        assert_eq!(control.url.as_deref(), Some("app.AppImage"));
+        assert_eq!(control.blocks.len(), 5);
+        assert_eq!(control.rsum_mask(), 0x00ff_ffff);
+        assert_eq!(control.blocks[0].checksum, Md4::digest(&target[..1024])[..5].to_vec());
+    }
+
+    #[test]
+    fn invalid_control_files_are_refused() {
+        let valid = control_file(&sample(4096, 2), 1024, (1, 4, 16));
+        let header_end = valid.windows(2).position(|w| w == b"\n\n").unwrap();
+        let replace = |from: &str, to: &str| {
+            let header = String::from_utf8_lossy(&valid[..header_end]).replace(from, to);
+            [header.as_bytes(), &valid[header_end..]].concat()
+        };
+        assert!(ControlFile::parse(&valid).is_ok());
+        assert!(ControlFile::parse(&valid[..valid.len() - 1]).is_err(), "truncated checksums");
+        assert!(ControlFile::parse(&replace("Blocksize: 1024", "Blocksize: 1000")).is_err());
+        assert!(ControlFile::parse(&replace("Blocksize: 1024", "Blocksize: 2097152")).is_err());
+        assert!(ControlFile::parse(&replace("Length: 4096", "Length: 18446744073709551615")).is_err());
+        assert!(ControlFile::parse(&replace("Hash-Lengths: 1,4,16", "Hash-Lengths: 1,4,2")).is_err());
+        assert!(ControlFile::parse(b"zsync: 0.6.2\nBlocksize: 1024\n").is_err(), "missing header end");
+    }
+
+    #[test]
+    fn blocks_are_found_at_shifted_offsets() {
+        let block_size = 512;
+        let target = sample(10 * block_size + 100, 3);
+
+        // The installed version has 37 new bytes up front and a changed fourth block
+        let mut seed = sample(37, 4);
+        seed.extend_from_slice(&target);
+        seed[37 + 3 * block_size + 10] ^= 0xff;
+
+        let control = ControlFile::parse(&control_file(&target, block_size, (1, 4, 16))).unwrap();
+        let (data, found) = control.reuse_blocks(seed.clone());
+        let expected: Vec<Option<usize>> = (0..11)
+            .map(|id| (id != 3).then_some(37 + id * block_size))
+            .collect();
+        assert_eq!(found, expected);
+        assert_eq!(data.len(), 11 * block_size);
+        assert_eq!(data[..3 * block_size], target[..3 * block_size]);
+        assert_eq!(data[4 * block_size..target.len()], target[4 * block_size..]);
+        assert!(data[3 * block_size..4 * block_size].iter().all(|&b| b == 0));
+        assert_eq!(missing_ranges(&found, block_size, target.len()), vec![(3 * block_size, 4 * block_size)]);
+
+        // With short checksums a block also needs the next one to match
+        let control = ControlFile::parse(&control_file(&target, block_size, (2, 2, 4))).unwrap();
+        let (_, found) = control.reuse_blocks(seed);
+        let expected: Vec<Option<usize>> = (0..11)
+            .map(|id| (id != 2 && id != 3).then_some(37 + id * block_size))
+            .collect();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    fn rolling_checksum_matches_a_fresh_one() {
+        let data = sample(300, 5);
+        let block_size = 64;
+        let (mut a, mut b) = rsum(&data[..block_size]);
+        for pos in 0..data.len() - block_size {
+            let old = data[pos] as u16;
+            let new = data[pos + block_size] as u16;
+            a = a.wrapping_sub(old).wrapping_add(new);
+            b = b.wrapping_sub((block_size as u16).wrapping_mul(old)).wrapping_add(a);
+            assert_eq!((a, b), rsum(&data[pos + 1..pos + 1 + block_size]), "offset {}", pos + 1);
+        }
+    }
+
+    #[test]
+    fn nearby_missing_blocks_are_fetched_together() {
+        let mut found = vec![Some(0); 30];
+        for id in [2, 5, 20, 29] {
+            found[id] = None;
+        }
+        assert_eq!(missing_ranges(&found, 100, 2950), vec![(200, 600), (2000, 2100), (2900, 2950)]);
+    }
+}